@@ -1,7 +1,9 @@
 use p256::{
-    ecdsa::{signature::{Signer, Signature, Verifier}}, elliptic_curve::{rand_core::OsRng, sec1::EncodedPoint}, NistP256
+    ecdsa::{signature::{Signer, Signature, Verifier}}, elliptic_curve::{rand_core::OsRng, sec1::EncodedPoint},
+    pkcs8::{DecodePrivateKey, EncodePrivateKey}, NistP256
 };
 use sha2::{Sha256, Digest};
+use aes_gcm::{aead::{Aead, AeadCore, KeyInit, Payload}, Aes256Gcm, Nonce};
 use crate::{Result, HASH_SIZE};
 
 pub use p256::ecdsa::{SigningKey as RawSigningKey, VerifyingKey};
@@ -47,6 +49,42 @@ pub fn export_public_key<'a>(key: &'a SigningKey) -> &'a [u8] {
     key.encoded.as_bytes()
 }
 
+pub async fn export_private_key(key: &SigningKey) -> Result<Vec<u8>> {
+    let doc = key.raw.to_pkcs8_der()
+        .map_err(|x| CryptoError::InternalError(x.to_string()))?;
+    Ok(doc.as_bytes().to_vec())
+}
+
+pub async fn import_private_key(key_data: &[u8]) -> Result<SigningKey> {
+    RawSigningKey::from_pkcs8_der(key_data)
+        .map(SigningKey::from_raw)
+        .map_err(|_| CryptoError::ImportKeyError)
+}
+
+pub type SymmetricKey = Aes256Gcm;
+
+const NONCE_SIZE: usize = 12;
+
+pub async fn import_symmetric_key(key_data: &[u8]) -> Result<SymmetricKey> {
+    Aes256Gcm::new_from_slice(key_data).map_err(|_| CryptoError::ImportKeyError)
+}
+
+pub async fn encrypt(key: &SymmetricKey, data: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+    let nonce = Aes256Gcm::generate_nonce(OsRng);
+    let encrypted = key.encrypt(&nonce, Payload { msg: data, aad })
+        .map_err(|x| CryptoError::InternalError(x.to_string()))?;
+    Ok([nonce.as_slice(), &encrypted].concat())
+}
+
+pub async fn decrypt(key: &SymmetricKey, data: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+    if data.len() < NONCE_SIZE {
+        return Err(CryptoError::DecryptError);
+    }
+    let (nonce, encrypted) = data.split_at(NONCE_SIZE);
+    key.decrypt(Nonce::from_slice(nonce), Payload { msg: encrypted, aad })
+        .map_err(|_| CryptoError::DecryptError)
+}
+
 pub async fn sha2_hash(context: &[u8], data: &[u8]) -> Result<[u8; HASH_SIZE]> {
     let mut hasher = Sha256::new();
 
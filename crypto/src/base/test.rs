@@ -67,3 +67,113 @@ async fn verify_and_hash_test2() {
     let hash = sha2_hash(&CONTEXT, &pub_key_data).await.expect("Hashing failed");
     assert!(hex::encode(hash) == "ddc6c90b1238fab5663118e4b865eeb4430fce9f1f02ceae8fbd41b188799022");
 }
+
+#[ttest]
+async fn symmetric_test() {
+    let key = import_symmetric_key(&[7u8; SYMMETRIC_KEY_SIZE]).await.expect("Error importing key");
+
+    let data = "This data will be encrypted".as_bytes();
+    let encrypted = encrypt(&key, data, b"topic").await.expect("Error encrypting");
+    assert!(!encrypted.windows(data.len()).any(|x| x == data));
+    assert_eq!(decrypt(&key, &encrypted, b"topic").await.unwrap(), data);
+
+    let other = import_symmetric_key(&[8u8; SYMMETRIC_KEY_SIZE]).await.unwrap();
+    assert!(decrypt(&other, &encrypted, b"topic").await.is_err());
+    assert!(decrypt(&key, &encrypted, b"other topic").await.is_err());
+    assert!(decrypt(&key, &encrypted[..8], b"topic").await.is_err());
+    assert!(import_symmetric_key(&[1, 2, 3]).await.is_err());
+}
+
+#[ttest]
+async fn ed25519_test() {
+    let pair = generate_pair_with(KeyAlgorithm::Ed25519).await.expect("Error generating key");
+    assert_eq!(pair.algorithm(), KeyAlgorithm::Ed25519);
+
+    let data = "This is some sample data".as_bytes();
+    let signature = sign(&pair, data).await.expect("Error signing");
+    // Ed25519 signatures are deterministic
+    assert_eq!(sign(&pair, data).await.unwrap(), signature);
+
+    let exported = export_public_key(&pair);
+    assert_eq!(exported.len(), 33);
+    let pub_key = import_pub_key(exported).await.expect("Error importing key");
+    assert_eq!(pub_key.algorithm(), KeyAlgorithm::Ed25519);
+    assert!(verify(&pub_key, &signature, data).await);
+    assert!(!verify(&pub_key, &signature, "Whoops, this is different data!".as_bytes()).await);
+
+    // Keys of one algorithm never verify signatures of the other
+    let p256 = generate_pair().await.unwrap();
+    assert_eq!(p256.algorithm(), KeyAlgorithm::P256);
+    let p256_signature = sign(&p256, data).await.unwrap();
+    assert!(!verify(&pub_key, &p256_signature, data).await);
+    let p256_pub = import_pub_key(export_public_key(&p256)).await.unwrap();
+    assert!(!verify(&p256_pub, &signature, data).await);
+
+    assert!(import_pub_key(&exported[..32]).await.is_err());
+}
+
+#[ttest]
+async fn ed25519_vector_test() {
+    // RFC 8032, test 1 (empty message)
+    let pub_key_data = hex::decode("edd75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a").unwrap();
+    let signature = hex::decode("e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e065224901555fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b").unwrap();
+
+    let pub_key = import_pub_key(&pub_key_data).await.unwrap();
+    assert!(verify(&pub_key, &signature, b"").await);
+
+    let signed_data = "This data will be signed and verified".as_bytes();
+
+    // Generator
+    /*let pair = generate_pair_with(KeyAlgorithm::Ed25519).await.unwrap();
+    let signed = sign(&pair, signed_data).await.unwrap();
+    let pubkey = export_public_key(&pair);
+    panic!("pub: {}, sig: {}", hex::encode(pubkey), hex::encode(signed));*/
+
+    // Generated by native environment
+    let pub_key_data = hex::decode("ed097b4eb9163d39d7d81f52bda157b1f02644dc22f0d641e0bb822b8950016012").unwrap();
+    let signature = hex::decode("2f8c6433278ce7366f76bf76ea4c7d481f4badd8f70f6c09990d443190fd4bbd9e721b977fc590b30c3a8203b5051e708a7a5f723d77e527e1f3a8e7e88b300b").unwrap();
+
+    let pub_key = import_pub_key(&pub_key_data).await.unwrap();
+    assert!(verify(&pub_key, &signature, signed_data).await);
+
+    let hash = sha2_hash(CONTEXT, &pub_key_data).await.expect("Hashing failed");
+    assert!(hex::encode(hash) == "f5f67c5c5e1909f3fadf7bfd42afabfc7cfa35ecd8aa85e145bf0f97e7d49804");
+}
+
+#[ttest]
+async fn private_key_test() {
+    let data = "This is some sample data".as_bytes();
+    for algorithm in [KeyAlgorithm::P256, KeyAlgorithm::Ed25519] {
+        let pair = generate_pair_with(algorithm).await.unwrap();
+        let exported = export_private_key(&pair).await.expect("Error exporting key");
+        let imported = import_private_key(&exported).await.expect("Error importing key");
+        assert_eq!(imported.algorithm(), algorithm);
+        assert_eq!(export_public_key(&imported), export_public_key(&pair));
+
+        // Signatures of the imported key are verified by the original public key
+        let pub_key = import_pub_key(export_public_key(&pair)).await.unwrap();
+        let signature = sign(&imported, data).await.unwrap();
+        assert!(verify(&pub_key, &signature, data).await);
+    }
+    assert!(import_private_key(b"Wait, this is garbage!").await.is_err());
+    assert!(import_private_key(&[0xED, 1, 2, 3]).await.is_err());
+}
+
+#[ttest]
+async fn key_equality_test() {
+    for algorithm in [KeyAlgorithm::P256, KeyAlgorithm::Ed25519] {
+        let (a, b) = (generate_pair_with(algorithm).await.unwrap(), generate_pair_with(algorithm).await.unwrap());
+        assert!(a == a.clone());
+        assert!(a != b);
+
+        let a_pub = import_pub_key(export_public_key(&a)).await.unwrap();
+        let b_pub = import_pub_key(export_public_key(&b)).await.unwrap();
+        assert!(a_pub == import_pub_key(export_public_key(&a)).await.unwrap());
+        assert!(a_pub != b_pub);
+        assert_eq!(a_pub.as_bytes(), export_public_key(&a));
+    }
+
+    assert!(constant_time_eq(b"same bytes", b"same bytes"));
+    assert!(!constant_time_eq(b"same bytes", b"same bytez"));
+    assert!(!constant_time_eq(b"same bytes", b"same"));
+}
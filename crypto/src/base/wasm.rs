@@ -3,7 +3,7 @@ use std::iter::once;
 use js_sys::{Array, Object, Uint8Array, Reflect, ArrayBuffer};
 use wasm_bindgen::{JsValue, JsCast};
 use wasm_bindgen_futures::JsFuture;
-use web_sys::{window, SubtleCrypto, CryptoKey};
+use web_sys::{window, Crypto, SubtleCrypto, CryptoKey};
 
 use crate::{error::{Result, CryptoError}, HASH_SIZE};
 
@@ -19,12 +19,11 @@ fn context() -> &'static CryptoContext {
     INSTANCE.with(|x| *x)
 }
 
-fn subtle() -> SubtleCrypto {
+fn crypto() -> Crypto {
     window()
         .expect("No window object found")
         .crypto()
         .expect("Could not find crypto instance")
-        .subtle()
 }
 
 fn create_algorithm() -> Object {
@@ -51,6 +50,7 @@ impl<T> ToErrInner<T> for core::result::Result<T, JsValue> {
 }
 
 pub struct CryptoContext {
+    crypto: Crypto,
     subtle: SubtleCrypto,
     algorithm: Object,
     sign_params: Object,
@@ -58,8 +58,10 @@ pub struct CryptoContext {
 
 impl CryptoContext {
     pub fn new() -> Self {
+        let crypto = crypto();
         CryptoContext {
-            subtle: subtle(),
+            subtle: crypto.subtle(),
+            crypto,
             algorithm: create_algorithm(),
             sign_params: create_sign_params(),
         }
@@ -86,6 +88,55 @@ pub fn export_public_key<'a>(key: &'a SigningKey) -> &'a [u8] {
     key.exported_public_key()
 }
 
+pub async fn export_private_key(key: &SigningKey) -> Result<Vec<u8>> {
+    key.export_private(context()).await
+}
+
+pub async fn import_private_key(key_data: &[u8]) -> Result<SigningKey> {
+    SigningKey::import_private(context(), key_data).await
+}
+
+/// Verifies an Ed25519 signature through WebCrypto, None if the browser doesn't support it
+pub async fn ed25519_verify(key_data: &[u8], signature: &[u8], data: &[u8]) -> Option<bool> {
+    let ctx = context();
+    let algorithm = Object::new();
+    Reflect::set(&algorithm, &"name".into(), &"Ed25519".into()).unwrap();
+    let usages: Array = once("verify").map(JsValue::from).collect();
+
+    // Safety: the first step of import_key requires copying the buffer.
+    let key_data: Uint8Array = unsafe { Uint8Array::view(key_data) };
+    let promise = ctx.subtle.import_key_with_object("raw", &key_data, &algorithm, false, &usages).ok()?;
+    // Browsers without Ed25519 reject the import
+    let key: CryptoKey = JsFuture::from(promise).await.ok()?.unchecked_into();
+
+    let signature: Uint8Array = unsafe { Uint8Array::view(signature) };
+    let data: Uint8Array = unsafe { Uint8Array::view(data) };
+    let promise = ctx.subtle.verify_with_object_and_buffer_source_and_buffer_source(&algorithm, &key, &signature, &data).ok()?;
+    JsFuture::from(promise).await.ok()?.as_bool()
+}
+
+
+const NONCE_SIZE: usize = 12;
+
+fn create_aes_params(nonce: &Uint8Array, aad: &[u8]) -> Object {
+    let o = Object::new();
+    Reflect::set(&o, &"name".into(), &"AES-GCM".into()).unwrap();
+    Reflect::set(&o, &"iv".into(), nonce).unwrap();
+    Reflect::set(&o, &"additionalData".into(), &Uint8Array::from(aad)).unwrap();
+    o
+}
+
+pub async fn import_symmetric_key(key_data: &[u8]) -> Result<SymmetricKey> {
+    SymmetricKey::import(context(), key_data).await
+}
+
+pub async fn encrypt(key: &SymmetricKey, data: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+    key.encrypt(context(), data, aad).await
+}
+
+pub async fn decrypt(key: &SymmetricKey, data: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+    key.decrypt(context(), data, aad).await
+}
 
 pub async fn sha2_hash(ctx: &[u8], data: &[u8]) -> Result<[u8; HASH_SIZE]> {
     let crypto = context();
@@ -142,6 +193,41 @@ impl SigningKey {
     fn exported_public_key(&self) -> &[u8] {
         &self.public
     }
+
+    async fn export_private(&self, ctx: &CryptoContext) -> Result<Vec<u8>> {
+        let promise = ctx.subtle.export_key("pkcs8", &self.private).map_err_internal()?;
+        let exported: ArrayBuffer = JsFuture::from(promise).await.map_err_internal()?.unchecked_into();
+        Ok(Uint8Array::new(&exported).to_vec())
+    }
+
+    async fn import_private(ctx: &CryptoContext, key_data: &[u8]) -> Result<Self> {
+        let usages: Array = once("sign").map(JsValue::from).collect();
+
+        // Safety: the first step of import_key requires copying the buffer.
+        let key_data: Uint8Array = unsafe { Uint8Array::view(key_data) };
+        let promise = ctx.subtle.import_key_with_object("pkcs8", &key_data, &ctx.algorithm, true, &usages)
+            .map_err(|_| CryptoError::ImportKeyError)?;
+        let private: CryptoKey = JsFuture::from(promise).await
+            .map_err(|_| CryptoError::ImportKeyError)?
+            .unchecked_into();
+
+        // WebCrypto can't derive the public key, but the JWK export of the private key contains it
+        let promise = ctx.subtle.export_key("jwk", &private).map_err_internal()?;
+        let jwk: Object = JsFuture::from(promise).await.map_err_internal()?.unchecked_into();
+        Reflect::delete_property(&jwk, &"d".into()).map_err_internal()?;
+        let usages: Array = once("verify").map(JsValue::from).collect();
+        Reflect::set(&jwk, &"key_ops".into(), &usages).map_err_internal()?;
+        let promise = ctx.subtle.import_key_with_object("jwk", &jwk, &ctx.algorithm, true, &usages)
+            .map_err_internal()?;
+        let public: CryptoKey = JsFuture::from(promise).await.map_err_internal()?.unchecked_into();
+        let export_promise = ctx.subtle.export_key("raw", &public).map_err_internal()?;
+        let exported: ArrayBuffer = JsFuture::from(export_promise).await.map_err_internal()?.unchecked_into();
+
+        Ok(SigningKey {
+            private,
+            public: Uint8Array::new(&exported).to_vec().into_boxed_slice(),
+        })
+    }
 }
 
 #[derive(Clone, PartialEq, Eq)]
@@ -170,6 +256,60 @@ impl VerifyingKey {
     }
 }
 
+#[derive(Clone, PartialEq, Eq)]
+pub struct SymmetricKey(CryptoKey);
+
+impl SymmetricKey {
+    async fn import(ctx: &CryptoContext, key_data: &[u8]) -> Result<Self> {
+        if key_data.len() != crate::SYMMETRIC_KEY_SIZE {
+            return Err(CryptoError::ImportKeyError);
+        }
+        let usages: Array = ["encrypt", "decrypt"].into_iter().map(JsValue::from).collect();
+
+        // Safety: the first step of import_key requires copying the buffer.
+        let key_data: Uint8Array = unsafe { Uint8Array::view(key_data) };
+        let promise = ctx.subtle.import_key_with_str("raw", &key_data, "AES-GCM", false, &usages)
+            .map_err(|_| CryptoError::ImportKeyError)?;
+        let res = JsFuture::from(promise).await
+            .map_err(|_| CryptoError::ImportKeyError)?;
+        Ok(SymmetricKey(res.unchecked_into()))
+    }
+
+    async fn encrypt(&self, ctx: &CryptoContext, data: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        let mut nonce = [0u8; NONCE_SIZE];
+        ctx.crypto.get_random_values_with_u8_array(&mut nonce).map_err_internal()?;
+        let params = create_aes_params(&Uint8Array::from(&nonce[..]), aad);
+
+        // Safety: the first step of encrypt requires copying the buffer.
+        let data: Uint8Array = unsafe { Uint8Array::view(data) };
+        let promise = ctx.subtle.encrypt_with_object_and_buffer_source(&params, &self.0, &data)
+            .map_err_internal()?;
+        let res: ArrayBuffer = JsFuture::from(promise).await
+            .map_err_internal()?
+            .unchecked_into();
+
+        Ok([&nonce[..], &Uint8Array::new(&res).to_vec()].concat())
+    }
+
+    async fn decrypt(&self, ctx: &CryptoContext, data: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        if data.len() < NONCE_SIZE {
+            return Err(CryptoError::DecryptError);
+        }
+        let (nonce, encrypted) = data.split_at(NONCE_SIZE);
+        let params = create_aes_params(&Uint8Array::from(nonce), aad);
+
+        // Safety: the first step of decrypt requires copying the buffer.
+        let encrypted: Uint8Array = unsafe { Uint8Array::view(encrypted) };
+        let promise = ctx.subtle.decrypt_with_object_and_buffer_source(&params, &self.0, &encrypted)
+            .map_err_internal()?;
+        let res: ArrayBuffer = JsFuture::from(promise).await
+            .map_err(|_| CryptoError::DecryptError)?
+            .unchecked_into();
+
+        Ok(Uint8Array::new(&res).to_vec())
+    }
+}
+
 #[doc(hidden)]
 #[cfg(test)]
 pub use wasm_bindgen_test::wasm_bindgen_test as ttest;
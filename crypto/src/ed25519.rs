@@ -0,0 +1,78 @@
+use ed25519_dalek::{Signature, Signer, Verifier, PUBLIC_KEY_LENGTH, SECRET_KEY_LENGTH};
+use rand_core::OsRng;
+
+use crate::{CryptoError, Result};
+
+pub use ed25519_dalek::VerifyingKey;
+
+// Exported Ed25519 keys are prefixed by this tag, P-256 keys start with their SEC1 tag instead
+pub const PUBLIC_KEY_TAG: u8 = 0xED;
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct SigningKey {
+    raw: ed25519_dalek::SigningKey,
+    encoded: [u8; PUBLIC_KEY_LENGTH + 1],
+}
+
+impl SigningKey {
+    pub fn from_raw(raw: ed25519_dalek::SigningKey) -> Self {
+        let mut encoded = [PUBLIC_KEY_TAG; PUBLIC_KEY_LENGTH + 1];
+        encoded[1..].copy_from_slice(raw.verifying_key().as_bytes());
+
+        SigningKey {
+            raw,
+            encoded,
+        }
+    }
+}
+
+pub fn import_pub_key(key_data: &[u8]) -> Result<VerifyingKey> {
+    let key_data = match key_data.split_first() {
+        Some((&PUBLIC_KEY_TAG, x)) => x,
+        _ => return Err(CryptoError::ImportKeyError),
+    };
+    key_data.try_into().ok()
+        .and_then(|x| VerifyingKey::from_bytes(x).ok())
+        .ok_or(CryptoError::ImportKeyError)
+}
+
+pub fn from_seed(seed: &[u8; SECRET_KEY_LENGTH]) -> SigningKey {
+    SigningKey::from_raw(ed25519_dalek::SigningKey::from_bytes(seed))
+}
+
+// Tagged seed, so that it can't be confused with a PKCS#8 document (that starts with 0x30)
+pub fn export_private_key(key: &SigningKey) -> Vec<u8> {
+    [&[PUBLIC_KEY_TAG][..], key.raw.as_bytes()].concat()
+}
+
+pub fn import_private_key(key_data: &[u8]) -> Result<SigningKey> {
+    match key_data.split_first() {
+        Some((&PUBLIC_KEY_TAG, x)) => x.try_into()
+            .map(from_seed)
+            .map_err(|_| CryptoError::ImportKeyError),
+        _ => Err(CryptoError::ImportKeyError),
+    }
+}
+
+pub fn generate_pair() -> SigningKey {
+    SigningKey::from_raw(ed25519_dalek::SigningKey::generate(&mut OsRng))
+}
+
+pub fn sign(key: &SigningKey, data: &[u8]) -> Vec<u8> {
+    key.raw.sign(data).to_bytes().to_vec()
+}
+
+pub async fn verify(key: &VerifyingKey, signature: &[u8], data: &[u8]) -> bool {
+    // Browsers that support Ed25519 verify it natively
+    #[cfg(target_arch = "wasm32")]
+    if let Some(x) = crate::base::ed25519_verify(key.as_bytes(), signature, data).await {
+        return x;
+    }
+    Signature::from_slice(signature)
+        .and_then(|signature| key.verify(data, &signature))
+        .is_ok()
+}
+
+pub fn export_public_key(key: &SigningKey) -> &[u8] {
+    &key.encoded
+}
@@ -5,6 +5,8 @@ use thiserror::Error;
 pub enum CryptoError {
     #[error("Error importing key")]
     ImportKeyError,
+    #[error("Error decrypting data")]
+    DecryptError,
     #[error("Unknown internal error: {0}")]
     InternalError(String),
 }
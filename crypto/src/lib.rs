@@ -1,4 +1,5 @@
 mod base;
+mod ed25519;
 mod error;
 
 #[cfg(test)]
@@ -8,34 +9,172 @@ pub use base::test::*;
 
 pub use error::{CryptoError, Result};
 
+use subtle::ConstantTimeEq;
 
-#[derive(Clone, PartialEq, Eq)]
-pub struct SigningKey(base::SigningKey);
+/// Signature algorithm of a key pair
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum KeyAlgorithm {
+    // ECDSA over P-256 with SHA-256
+    #[default]
+    P256,
+    Ed25519,
+}
+
+/// Key pair, keys are equal if their public keys are equal (compared in constant time)
+#[derive(Clone)]
+pub struct SigningKey(SigningKeyInner);
+
+#[derive(Clone)]
+enum SigningKeyInner {
+    P256(base::SigningKey),
+    Ed25519(ed25519::SigningKey),
+}
+
+impl SigningKey {
+    pub fn algorithm(&self) -> KeyAlgorithm {
+        match self.0 {
+            SigningKeyInner::P256(_) => KeyAlgorithm::P256,
+            SigningKeyInner::Ed25519(_) => KeyAlgorithm::Ed25519,
+        }
+    }
+}
+
+impl PartialEq for SigningKey {
+    fn eq(&self, other: &Self) -> bool {
+        constant_time_eq(export_public_key(self), export_public_key(other))
+    }
+}
+
+impl Eq for SigningKey {}
+
+/// Public key, keys are equal if their encodings are equal (compared in constant time)
+#[derive(Clone)]
+pub struct VerifyingKey {
+    key: VerifyingKeyInner,
+    encoded: Box<[u8]>,
+}
+
+#[derive(Clone)]
+enum VerifyingKeyInner {
+    P256(base::VerifyingKey),
+    Ed25519(ed25519::VerifyingKey),
+}
+
+impl VerifyingKey {
+    pub fn algorithm(&self) -> KeyAlgorithm {
+        match self.key {
+            VerifyingKeyInner::P256(_) => KeyAlgorithm::P256,
+            VerifyingKeyInner::Ed25519(_) => KeyAlgorithm::Ed25519,
+        }
+    }
+
+    /// Encoding of the key, as it was imported
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.encoded
+    }
+}
+
+impl PartialEq for VerifyingKey {
+    fn eq(&self, other: &Self) -> bool {
+        constant_time_eq(&self.encoded, &other.encoded)
+    }
+}
 
-#[derive(Clone, PartialEq, Eq)]
-pub struct VerifyingKey(base::VerifyingKey);
+impl Eq for VerifyingKey {}
+
+/// Compares two byte strings in a time that only depends on their lengths,
+/// use it on secret or authentication material (keys, proofs, ids derived from keys)
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.ct_eq(b).into()
+}
+
+#[derive(Clone)]
+pub struct SymmetricKey(base::SymmetricKey);
 
 const HASH_SIZE: usize = 256 / 8;
+pub const SYMMETRIC_KEY_SIZE: usize = 256 / 8;
+pub const SEED_SIZE: usize = 256 / 8;
 
-// P-256
+// P-256 (default) or Ed25519, the algorithm is encoded in the exported public keys
 pub async fn import_pub_key(key_data: &[u8]) -> Result<VerifyingKey> {
-    base::import_pub_key(key_data).await.map(VerifyingKey)
+    let key = match key_data.first() {
+        Some(&ed25519::PUBLIC_KEY_TAG) => VerifyingKeyInner::Ed25519(ed25519::import_pub_key(key_data)?),
+        _ => VerifyingKeyInner::P256(base::import_pub_key(key_data).await?),
+    };
+    Ok(VerifyingKey { key, encoded: key_data.into() })
 }
 
 pub async fn generate_pair() -> Result<SigningKey> {
-    base::generate_pair().await.map(SigningKey)
+    generate_pair_with(KeyAlgorithm::default()).await
+}
+
+pub async fn generate_pair_with(algorithm: KeyAlgorithm) -> Result<SigningKey> {
+    let key = match algorithm {
+        KeyAlgorithm::P256 => SigningKeyInner::P256(base::generate_pair().await?),
+        KeyAlgorithm::Ed25519 => SigningKeyInner::Ed25519(ed25519::generate_pair()),
+    };
+    Ok(SigningKey(key))
+}
+
+/// Derives an Ed25519 key pair from a seed, the same seed always gives the same key
+pub fn signing_key_from_seed(seed: &[u8; SEED_SIZE]) -> SigningKey {
+    SigningKey(SigningKeyInner::Ed25519(ed25519::from_seed(seed)))
 }
 
 pub async fn sign(key: &SigningKey, data: &[u8]) -> Result<Vec<u8>> {
-    base::sign(&key.0, data).await
+    match &key.0 {
+        SigningKeyInner::P256(x) => base::sign(x, data).await,
+        SigningKeyInner::Ed25519(x) => Ok(ed25519::sign(x, data)),
+    }
 }
 
+/// Checks a signature, the running time doesn't depend on the secret key material.
+///
+/// Only public information (the key algorithm and whether the signature is well formed)
+/// may end the check early, the rest is as constant-time as the underlying implementation
+/// (p256/ed25519-dalek natively, WebCrypto in browsers).
 pub async fn verify(key: &VerifyingKey, signature: &[u8], data: &[u8]) -> bool {
-    base::verify(&key.0, signature, data).await
+    match &key.key {
+        VerifyingKeyInner::P256(x) => base::verify(x, signature, data).await,
+        VerifyingKeyInner::Ed25519(x) => ed25519::verify(x, signature, data).await,
+    }
 }
 
 pub fn export_public_key<'a>(key: &'a SigningKey) -> &'a [u8] {
-    base::export_public_key(&key.0)
+    match &key.0 {
+        SigningKeyInner::P256(x) => base::export_public_key(x),
+        SigningKeyInner::Ed25519(x) => ed25519::export_public_key(x),
+    }
+}
+
+// Private keys are exported as PKCS#8 (P-256) or as their tagged seed (Ed25519)
+pub async fn export_private_key(key: &SigningKey) -> Result<Vec<u8>> {
+    match &key.0 {
+        SigningKeyInner::P256(x) => base::export_private_key(x).await,
+        SigningKeyInner::Ed25519(x) => Ok(ed25519::export_private_key(x)),
+    }
+}
+
+pub async fn import_private_key(key_data: &[u8]) -> Result<SigningKey> {
+    let key = match key_data.first() {
+        Some(&ed25519::PUBLIC_KEY_TAG) => SigningKeyInner::Ed25519(ed25519::import_private_key(key_data)?),
+        _ => SigningKeyInner::P256(base::import_private_key(key_data).await?),
+    };
+    Ok(SigningKey(key))
+}
+
+// AES-256-GCM, ciphertexts are prefixed by their random nonce.
+// The associated data is authenticated but not encrypted, decryption fails if it differs
+pub async fn import_symmetric_key(key_data: &[u8]) -> Result<SymmetricKey> {
+    base::import_symmetric_key(key_data).await.map(SymmetricKey)
+}
+
+pub async fn encrypt(key: &SymmetricKey, data: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+    base::encrypt(&key.0, data, aad).await
+}
+
+pub async fn decrypt(key: &SymmetricKey, data: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+    base::decrypt(&key.0, data, aad).await
 }
 
 // SHA2
@@ -1,30 +1,122 @@
-use std::num::NonZeroU64;
+use std::{num::NonZeroU64, time::Duration};
 
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
 
-#[derive(Clone, PartialEq, Eq, Debug, Default, Deserialize, Serialize)]
+use crate::{search::BasicSearchOptions, Id, IdN};
+
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize)]
 #[serde(default)]
 pub struct SystemConfig {
     pub routing: RoutingConfig,
     pub storage: StorageConfig,
+
+    // Max number of searches running at the same time, other searches wait
+    // for a running one to complete (protects constrained nodes)
+    pub max_concurrent_searches: Option<NonZeroU64>,
+
+    // Nodes that receive a copy of the inserted values
+    pub placement: PlacementStrategy,
+
+    // Options of the searches made by inserts and removals,
+    // the *_with_options variants override them
+    pub default_search: BasicSearchOptions,
+}
+
+impl Default for SystemConfig {
+    fn default() -> Self {
+        Self {
+            routing: RoutingConfig::default(),
+            storage: StorageConfig::default(),
+            max_concurrent_searches: None,
+            placement: PlacementStrategy::default(),
+            default_search: BasicSearchOptions {
+                parallelism: 2,
+                ..Default::default()
+            },
+        }
+    }
+}
+
+/// Configuration currently used by a node: the [`SystemConfig`] it was created
+/// with, where the values changed at runtime replace the original ones.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct EffectiveConfig {
+    pub system: SystemConfig,
+    // Names of the values changed at runtime
+    pub overrides: Vec<&'static str>,
+}
+
+/// Selects the nodes that store an inserted value.
+///
+/// Searches only walk towards the nodes closest to the key, so the extra
+/// random replicas are usually not found by lookups: they trade some
+/// storage (and insert traffic) for resilience, keeping the value alive
+/// when the closest nodes fail together, and hand it back to the closest
+/// nodes once they reconnect.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug, Deserialize, Serialize)]
+pub enum PlacementStrategy {
+    // Only the k closest nodes to the key (k being the bucket size)
+    #[default]
+    ClosestK,
+    // The k closest nodes plus `extra` random nodes of the routing table
+    ClosestKPlusRandom { extra: usize },
+}
+
+/// Smallest accepted [`RoutingConfig::bucket_size`], with no nodes per bucket
+/// the routing table is always empty and searches find nothing
+pub const MIN_BUCKET_SIZE: usize = 1;
+/// Largest accepted [`RoutingConfig::buckets_per_bit`], every entry of the
+/// routing table allocates 2^(buckets_per_bit - 1) buckets
+pub const MAX_BUCKETS_PER_BIT: usize = 8;
+
+/// Degenerate configuration, rejected when the routing table is created
+#[derive(Clone, Debug, Error, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ConfigError {
+    #[error("bucket_size must be at least {MIN_BUCKET_SIZE} (found {0})")]
+    BucketSizeTooSmall(usize),
+    #[error("bucket_replacement_size ({0}) must not exceed bucket_size ({1})")]
+    ReplacementSizeTooLarge(usize, usize),
+    #[error("buckets_per_bit must be between 1 and {MAX_BUCKETS_PER_BIT} (found {0})")]
+    InvalidBucketsPerBit(usize),
+    #[error("high_watermark ({0}) must not be lower than low_watermark ({1})")]
+    InvalidWatermarks(u64, u64),
+    #[error("max_found_nodes_factor must be non-zero")]
+    ZeroFoundNodesFactor,
 }
 
 #[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize)]
 #[serde(default)]
 pub struct RoutingConfig {
-    // Also called k in the original paper
+    // Also called k in the original paper, at least MIN_BUCKET_SIZE
     pub bucket_size: usize,
 
     // Size of the replacements cache (nodes known but not used
-    // for routing unless older nodes go offline)
+    // for routing unless older nodes go offline), at most bucket_size
     pub bucket_replacement_size: usize,
 
     // This increases the routing table exponentially!!
-    // (but decreases routing hops)
+    // (but decreases routing hops), between 1 and MAX_BUCKETS_PER_BIT
     pub buckets_per_bit: usize,
 
     // Max number of nodes in routing table
     pub max_routing_count: Option<NonZeroU64>,
+
+    // The connectivity becomes degraded when the routing table has less than
+    // low_watermark nodes, and recovers only when it reaches high_watermark
+    // (see KademliaDht::subscribe_routing)
+    pub low_watermark: u64,
+    pub high_watermark: u64,
+
+    // Searches only accept the first bucket_size * max_found_nodes_factor nodes
+    // of each FoundNodes response (honest nodes return at most bucket_size)
+    pub max_found_nodes_factor: usize,
+
+    // Disconnected routing nodes keep their place (without being used) for this
+    // long, reconnecting in time restores them without a routing gap.
+    // Expired nodes are removed by KademliaDht::periodic_run
+    pub disconnect_grace: Option<Duration>,
 }
 
 impl Default for RoutingConfig {
@@ -34,7 +126,55 @@ impl Default for RoutingConfig {
             bucket_replacement_size: 2,
             buckets_per_bit: 1,
             max_routing_count: None,
+            low_watermark: 1,
+            high_watermark: 4,
+            max_found_nodes_factor: 2,
+            disconnect_grace: None,
+        }
+    }
+}
+
+impl RoutingConfig {
+    /// Checks that the values can be used by a routing table
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.bucket_size < MIN_BUCKET_SIZE {
+            return Err(ConfigError::BucketSizeTooSmall(self.bucket_size));
+        }
+        if self.bucket_replacement_size > self.bucket_size {
+            return Err(ConfigError::ReplacementSizeTooLarge(self.bucket_replacement_size, self.bucket_size));
+        }
+        if !(1..=MAX_BUCKETS_PER_BIT).contains(&self.buckets_per_bit) {
+            return Err(ConfigError::InvalidBucketsPerBit(self.buckets_per_bit));
+        }
+        if self.high_watermark < self.low_watermark {
+            return Err(ConfigError::InvalidWatermarks(self.high_watermark, self.low_watermark));
         }
+        if self.max_found_nodes_factor == 0 {
+            return Err(ConfigError::ZeroFoundNodesFactor);
+        }
+        Ok(())
+    }
+
+    /// Position of target in the routing table of self_id, as (entry, bucket) indices.
+    ///
+    /// The table has one entry per bit of the id, target goes into the entry
+    /// indexed by the number of leading bits it shares with self_id (the
+    /// leading zeros of their distance), capped so that the last entries still
+    /// have enough bits for the buckets. Each entry has 2^(buckets_per_bit - 1)
+    /// buckets, selected by the buckets_per_bit - 1 distance bits that follow
+    /// the first differing one: with buckets_per_bit = 1 every entry has a
+    /// single bucket, with higher values each entry splits its part of the
+    /// id space in smaller regions, each with its own bucket.
+    pub fn bucket_index_for<const N: usize>(&self, self_id: IdN<N>, target: IdN<N>) -> (usize, usize) {
+        let entryi = self_id
+            .common_prefix_len(&target)
+            .min((IdN::<N>::BITS - self.buckets_per_bit) as u8);
+        let bucketi = if self.buckets_per_bit == 1 {
+            0 // fast path (please compiler optimize it away)
+        } else {
+            (self_id ^ target).bitslice(entryi as u32 + 1, self.buckets_per_bit as u8 - 1) as usize
+        };
+        (entryi as usize, bucketi)
     }
 }
 
@@ -47,8 +187,103 @@ pub struct StorageConfig {
     // Maximum stored lifetime (in seconds)
     pub max_lifetime: u32,
 
+    // Minimum stored lifetime (in seconds), shorter inserts only churn the storage
+    pub min_lifetime: u32,
+
     // Maximum number of stored entries
     pub max_entries: usize,
+
+    // Keep an index of the original topic names (when the publisher sends them)
+    // so that topics can be listed by prefix, this increases storage usage
+    pub topic_index: bool,
+
+    // Maximum length of a topic name (in bytes), names count towards the
+    // size of the entries that carry them
+    pub max_name_len: usize,
+
+    // Maximum number of stored topics (the closest to the peer) checked for
+    // hand-off when a new node connects, 0 disables the hand-off
+    pub handoff_max_topics: usize,
+
+    // Lifetime of the entries prefetched from other nodes (in seconds),
+    // the original lifetime is not known so it should be short
+    pub prefetch_lifetime: u32,
+
+    // Topics that only some publishers can write (insert or swap), a publisher
+    // must be listed in every allowlist matching the topic, topics without
+    // allowlists are open to anyone
+    pub write_allowlists: Vec<TopicAllowlist>,
+
+    // What happens when a publisher inserts in a topic where it already has entries
+    pub publisher_mode: PublisherMode,
+
+    // Maximum number of entries a publisher can append to the same topic,
+    // appending more entries drops the oldest ones
+    pub max_entries_per_publisher: usize,
+
+    // Maximum number of entries a publisher can store in the whole storage
+    // (in any topic), so that a single peer can't fill it
+    pub max_publisher_entries: Option<usize>,
+
+    // Maximum size of the data a publisher can store in the whole storage (in bytes)
+    pub max_publisher_bytes: Option<usize>,
+
+    // Keep the values inserted by this node and republish them (see
+    // KademliaDht::republish), so that they're kept alive until removed
+    // even if the nodes storing them leave
+    pub republish: bool,
+
+    // Max time between two republishes of the same value (in seconds),
+    // values are also republished when half of their lifetime has elapsed
+    pub republish_interval: u32,
+
+    // Still send an insert to the other nodes when this node should store it
+    // but its storage refuses the value (ex. it's full), the local copy is not counted.
+    // When disabled the insert is abandoned
+    pub replicate_on_local_failure: bool,
+}
+
+/// Identity of the entries stored by a publisher in a topic.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug, Deserialize, Serialize)]
+pub enum PublisherMode {
+    // Entries are identified by (topic, publisher), a new insert replaces
+    // the previous entry of the publisher (the last write wins)
+    #[default]
+    Replace,
+    // Entries are identified by (topic, publisher, data), a publisher can
+    // store up to max_entries_per_publisher entries in a topic. Inserting the
+    // same data again only refreshes its lifetime
+    Append,
+}
+
+impl StorageConfig {
+    pub fn can_write(&self, topic: Id, publisher: Id) -> bool {
+        self.write_allowlists
+            .iter()
+            .filter(|x| x.matches(topic))
+            .all(|x| x.publishers.contains(&publisher))
+    }
+}
+
+/// Publishers allowed to write the topics starting with a prefix.
+///
+/// Publishers are identified by the id of the node sending the insert,
+/// that the transport authenticates during the handshake (in wdht the id
+/// is derived from the node's public key).
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize)]
+pub struct TopicAllowlist {
+    // Topics whose first prefix_len bits match the prefix
+    // (a length of 160 bits selects a single topic)
+    pub prefix: Id,
+    pub prefix_len: u8,
+
+    pub publishers: Vec<Id>,
+}
+
+impl TopicAllowlist {
+    pub fn matches(&self, topic: Id) -> bool {
+        topic.common_prefix_len(&self.prefix) >= self.prefix_len
+    }
 }
 
 impl Default for StorageConfig {
@@ -56,7 +291,20 @@ impl Default for StorageConfig {
         Self {
             max_size: 128 * 1024,  // 128 KiB
             max_lifetime: 60 * 60, // 1h
+            min_lifetime: 1,
             max_entries: 1024,     // so 128Mib
+            topic_index: false,
+            max_name_len: 256,
+            handoff_max_topics: 16,
+            prefetch_lifetime: 60,
+            write_allowlists: Vec::new(),
+            publisher_mode: PublisherMode::Replace,
+            max_entries_per_publisher: 16,
+            max_publisher_entries: None,
+            max_publisher_bytes: None,
+            republish: false,
+            republish_interval: 30 * 60,
+            replicate_on_local_failure: true,
         }
     }
 }
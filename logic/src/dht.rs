@@ -1,41 +1,166 @@
 use std::{
-    sync::{Mutex, RwLock},
+    collections::HashMap,
+    num::NonZeroU64,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Mutex, RwLock, TryLockError,
+    },
     time::Duration,
 };
 
-use futures::{stream::FuturesUnordered, StreamExt};
-use rand::Rng;
+use futures::{future, stream::{self, FuturesUnordered}, Stream, StreamExt};
+use instant::Instant;
+use rand::{seq::SliceRandom, Rng};
+use tokio::sync::{broadcast, mpsc, Semaphore, SemaphorePermit};
 use tracing::{debug, error, event, info, instrument, warn, Level};
 
 use crate::{
-    config::SystemConfig,
+    config::{ConfigError, EffectiveConfig, PlacementStrategy, PublisherMode, SystemConfig},
     id::Id,
     ktree::KTree,
-    search::{BasicSearch, BasicSearchOptions, SearchResult, SearchType},
-    storage::Storage,
-    transport::{Contact, RawResponse, Request, Response, TransportListener, TransportSender, TopicEntry},
+    search::{select_entries, BasicSearch, BasicSearchOptions, SearchResult, SearchType},
+    storage::{SerializableStore, Storage, StorageBackend},
+    transport::{
+        Contact, RawResponse, RecordProof, RecordSignature, ReplicaEntry, Request, Response, TopicEntry, TransportError, TransportListener,
+        TransportSender,
+    },
 };
 
+// Failures older than this are forgotten (should be greater than any search cooldown)
+const FAILURE_MEMORY: Duration = Duration::from_secs(10 * 60);
+// Minimum time between two storage hand-offs to the same node
+const HANDOFF_COOLDOWN: Duration = Duration::from_secs(5 * 60);
+
+/// Routing table size crossing the configured watermarks
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RoutingEvent {
+    /// The routing table dropped below the low watermark
+    Degraded { size: u64 },
+    /// The routing table reached the high watermark again
+    Recovered { size: u64 },
+}
+
+// Held while a search is running
+struct SearchGuard<'a> {
+    permit: Option<SemaphorePermit<'a>>,
+    active: &'a AtomicUsize,
+    // Permits to drop instead of releasing them (after the limit was lowered)
+    excess: &'a AtomicUsize,
+}
+
+impl<'a> Drop for SearchGuard<'a> {
+    fn drop(&mut self) {
+        self.active.fetch_sub(1, Ordering::SeqCst);
+        if let Some(permit) = self.permit.take() {
+            if self.excess.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |x| x.checked_sub(1)).is_ok() {
+                permit.forget();
+            }
+        }
+    }
+}
+
+// Value inserted by this node, kept to republish it
+#[derive(Clone)]
+struct OwnedValue {
+    name: Option<String>,
+    data: Vec<u8>,
+    lifetime: u32,
+    append: bool,
+    // Signed values are stored with the publisher of the signature
+    signature: Option<RecordSignature>,
+    // Last time the value was sent to the network
+    published: Instant,
+}
+
+impl OwnedValue {
+    fn request(&self, key: Id) -> Request {
+        if let Some(signature) = &self.signature {
+            return Request::InsertSigned(key, self.lifetime, self.data.clone(), signature.clone());
+        }
+        match (&self.name, self.append) {
+            (_, true) => Request::Append(key, self.lifetime, self.data.clone()),
+            (Some(name), false) => Request::InsertNamed(key, self.lifetime, self.data.clone(), name.clone()),
+            (None, false) => Request::Insert(key, self.lifetime, self.data.clone()),
+        }
+    }
+}
+
 // TODO: push syncronization down the line to improve async performance
-pub struct KademliaDht<T: TransportSender> {
+pub struct KademliaDht<T: TransportSender, S: StorageBackend = Storage> {
     // Immutable data
     config: SystemConfig,
     id: Id,
     // Mutable runtime data
     pub transport: T,
     pub tree: Mutex<KTree>, // TODO: dashmap?
-    pub storage: RwLock<Storage>,
+    pub storage: RwLock<S>,
+    // Last time each (recently failed) node failed to answer
+    failures: Mutex<HashMap<Id, Instant>>,
+    // Last time each node received a storage hand-off
+    handoffs: Mutex<HashMap<Id, Instant>>,
+    // Hand-off requests waiting for the transport to send them
+    pending_handoffs: Mutex<HashMap<Id, Vec<Request>>>,
+    // Values inserted by this node (only tracked if republishing is enabled)
+    owned: Mutex<HashMap<Id, Vec<OwnedValue>>>,
+    // Limits the number of concurrent searches (if configured)
+    search_permits: Option<Semaphore>,
+    // Current limit of search_permits, it can be changed at runtime
+    search_limit: AtomicU64,
+    search_excess: AtomicUsize,
+    active_searches: AtomicUsize,
+    // Connectivity state, events are only sent when it changes
+    routing_degraded: AtomicBool,
+    routing_events: broadcast::Sender<RoutingEvent>,
+}
+
+/// Best-effort snapshot of the DHT internals, used to diagnose hangs.
+///
+/// Locks are only probed (never waited on), a lock is reported as held
+/// even if it's held by the thread taking the snapshot.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct DhtDebugState {
+    pub tree_locked: bool,
+    // Held for reading or writing
+    pub storage_locked: bool,
+    pub active_searches: usize,
+}
+
+fn is_held<T>(res: Result<T, TryLockError<T>>) -> bool {
+    matches!(res, Err(TryLockError::WouldBlock))
 }
 
 impl<T: TransportSender> KademliaDht<T> {
-    pub fn new(config: SystemConfig, id: Id, transport: T) -> Self {
-        Self {
+    /// Creates a DHT storing the entries in memory
+    pub fn new(config: SystemConfig, id: Id, transport: T) -> Result<Self, ConfigError> {
+        let storage = Storage::new(config.storage.clone());
+        Self::with_storage(config, id, transport, storage)
+    }
+}
+
+impl<T: TransportSender, S: StorageBackend> KademliaDht<T, S> {
+    /// Creates a DHT storing the entries in the given backend,
+    /// fails if the routing config is not valid
+    pub fn with_storage(config: SystemConfig, id: Id, transport: T, storage: S) -> Result<Self, ConfigError> {
+        let tree = KTree::try_new(id, config.routing.clone())?;
+        Ok(Self {
             config: config.clone(),
             id,
             transport,
-            tree: Mutex::new(KTree::new(id, config.routing)),
-            storage: RwLock::new(Storage::new(config.storage)),
-        }
+            tree: Mutex::new(tree),
+            storage: RwLock::new(storage),
+            failures: Mutex::new(HashMap::new()),
+            handoffs: Mutex::new(HashMap::new()),
+            pending_handoffs: Mutex::new(HashMap::new()),
+            owned: Mutex::new(HashMap::new()),
+            search_permits: config.max_concurrent_searches.map(|x| Semaphore::new(x.get() as usize)),
+            search_limit: AtomicU64::new(config.max_concurrent_searches.map_or(0, |x| x.get())),
+            search_excess: AtomicUsize::new(0),
+            active_searches: AtomicUsize::new(0),
+            // No node is known yet
+            routing_degraded: AtomicBool::new(true),
+            routing_events: broadcast::channel(16).0,
+        })
     }
 
     pub fn config(&self) -> &SystemConfig {
@@ -50,8 +175,204 @@ impl<T: TransportSender> KademliaDht<T> {
         &self.transport
     }
 
-    pub fn periodic_run(&self) {
-        self.storage.write().unwrap().periodic_run();
+    /// Runs the periodic maintenance, returns the topic and publisher
+    /// of every expired storage entry
+    pub fn periodic_run(&self) -> Vec<(Id, Id)> {
+        let evicted = self.storage.write().unwrap().periodic_run();
+        self.failures.lock().unwrap().retain(|_, x| x.elapsed() < FAILURE_MEMORY);
+        self.handoffs.lock().unwrap().retain(|_, x| x.elapsed() < HANDOFF_COOLDOWN);
+        if let Some(grace) = self.config.routing.disconnect_grace {
+            let mut tree = self.tree.lock().unwrap();
+            let expired = tree.remove_expired_suspects(grace, Instant::now());
+            if !expired.is_empty() {
+                debug!("Grace period expired for {} nodes", expired.len());
+                self.update_routing_state(tree.size());
+            }
+        }
+        evicted
+    }
+
+    /// Subscribes to the routing table watermark events.
+    ///
+    /// The node starts degraded, Recovered is sent when the routing table reaches
+    /// the high watermark and Degraded when it drops below the low one again,
+    /// so each event is sent once per crossing.
+    pub fn subscribe_routing(&self) -> broadcast::Receiver<RoutingEvent> {
+        self.routing_events.subscribe()
+    }
+
+    /// Ids currently in the routing table (not counting the replacement caches)
+    pub fn routing_snapshot(&self) -> Vec<Id> {
+        self.tree.lock().unwrap().iter_ids().collect()
+    }
+
+    /// Number of ids in the routing table (not counting the replacement caches
+    /// nor the disconnected nodes in their grace period)
+    pub fn routing_size(&self) -> u64 {
+        self.tree.lock().unwrap().size()
+    }
+
+    /// Returns true if a connection to id would be used in the routing table,
+    /// either to take back its place or to fill its bucket
+    pub fn is_wanted_for_routing(&self, id: Id) -> bool {
+        let tree = self.tree.lock().unwrap();
+        id != self.id && (tree.is_suspect(id) || (!tree.has(id) && tree.has_room_for(id)))
+    }
+
+    pub fn is_routing_degraded(&self) -> bool {
+        self.routing_degraded.load(Ordering::SeqCst)
+    }
+
+    fn update_routing_state(&self, size: u64) {
+        let routing = &self.config.routing;
+        let event = if size < routing.low_watermark {
+            RoutingEvent::Degraded { size }
+        } else if size >= routing.high_watermark {
+            RoutingEvent::Recovered { size }
+        } else {
+            return;
+        };
+        let degraded = matches!(event, RoutingEvent::Degraded { .. });
+        if self.routing_degraded.swap(degraded, Ordering::SeqCst) != degraded {
+            info!("Routing table size {size}: {event:?}");
+            // No subscriber is not an error
+            let _ = self.routing_events.send(event);
+        }
+    }
+
+    /// Reports that a node could not be contacted (or connected to)
+    pub fn report_failure(&self, id: Id) {
+        self.failures.lock().unwrap().insert(id, Instant::now());
+    }
+
+    /// Returns true if the node failed less than cooldown ago
+    pub fn is_cooling_down(&self, id: Id, cooldown: Duration) -> bool {
+        matches!(self.failures.lock().unwrap().get(&id), Some(x) if x.elapsed() < cooldown)
+    }
+
+    /// Finds the stored entries that a newly connected node should also hold,
+    /// i.e. the topics where it is one of the k closest known nodes.
+    fn compute_handoff(&self, tree: &KTree, peer: Id) -> Vec<Request> {
+        let max_topics = self.config.storage.handoff_max_topics;
+        if max_topics == 0 {
+            return Vec::new();
+        }
+        {
+            let mut handoffs = self.handoffs.lock().unwrap();
+            if matches!(handoffs.get(&peer), Some(x) if x.elapsed() < HANDOFF_COOLDOWN) {
+                return Vec::new();
+            }
+            handoffs.insert(peer, Instant::now());
+        }
+
+        let storage = self.storage.read().unwrap();
+        // Only the topics closest to the peer might have it as one of the k closest
+        storage.closest_topics(peer, max_topics)
+            .into_iter()
+            .filter(|topic| tree.get_closer_n(*topic, self.config.routing.bucket_size).contains(&peer))
+            .filter_map(|topic| self.handoff_request(&storage, topic))
+            .collect()
+    }
+
+    /// Builds the request handing off the entries of a topic that the receiver can
+    /// authenticate: the ones published by this node and the signed records.
+    ///
+    /// Appended entries are sent with the lifetime of the most recent entry of their publisher.
+    fn handoff_request(&self, storage: &S, topic: Id) -> Option<Request> {
+        let entries: Vec<_> = storage.get(topic)?
+            .into_iter()
+            .filter_map(|entry| {
+                let proof = storage.proof(topic, &entry);
+                if entry.publisher != self.id && proof.is_none() {
+                    return None;
+                }
+                let lifetime = storage.remaining_lifetime(topic, entry.publisher)?;
+                if lifetime < self.config.storage.min_lifetime {
+                    return None;
+                }
+                Some(ReplicaEntry { publisher: entry.publisher, lifetime, data: entry.data, proof })
+            })
+            .collect();
+        if entries.is_empty() {
+            return None;
+        }
+        Some(Request::Replicate(topic, entries))
+    }
+
+    /// Number of searches currently running (not counting the queued ones)
+    pub fn active_searches(&self) -> usize {
+        self.active_searches.load(Ordering::SeqCst)
+    }
+
+    /// Probes the locks and counters without blocking
+    pub fn debug_state(&self) -> DhtDebugState {
+        DhtDebugState {
+            tree_locked: is_held(self.tree.try_lock()),
+            storage_locked: is_held(self.storage.try_write()),
+            active_searches: self.active_searches(),
+        }
+    }
+
+    async fn start_search(&self) -> SearchGuard<'_> {
+        let permit = match &self.search_permits {
+            // The semaphore is never closed
+            Some(x) => Some(x.acquire().await.unwrap()),
+            None => None,
+        };
+        self.active_searches.fetch_add(1, Ordering::SeqCst);
+        SearchGuard {
+            permit,
+            active: &self.active_searches,
+            excess: &self.search_excess,
+        }
+    }
+
+    /// Changes the max number of concurrent searches, only if a limit was
+    /// configured (returns false otherwise).
+    ///
+    /// When the limit is lowered the running searches are not stopped,
+    /// new searches wait until they're below the new limit.
+    pub fn set_max_concurrent_searches(&self, max: NonZeroU64) -> bool {
+        let permits = match &self.search_permits {
+            Some(x) => x,
+            None => return false,
+        };
+        let max = max.get();
+        let old = self.search_limit.swap(max, Ordering::SeqCst);
+        if max > old {
+            // Permits still waiting to be dropped are kept instead
+            let mut added = (max - old) as usize;
+            let kept = self.search_excess
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |x| Some(x.saturating_sub(added)))
+                .unwrap();
+            added -= kept.min(added);
+            permits.add_permits(added);
+        } else {
+            // Free permits are dropped now, the others when their search completes
+            let mut removed = (old - max) as usize;
+            while removed > 0 {
+                match permits.try_acquire() {
+                    Ok(x) => x.forget(),
+                    Err(_) => break,
+                }
+                removed -= 1;
+            }
+            self.search_excess.fetch_add(removed, Ordering::SeqCst);
+        }
+        info!("Max concurrent searches changed from {old} to {max}");
+        true
+    }
+
+    /// Returns the configuration currently in use, with the values changed at runtime
+    pub fn effective_config(&self) -> EffectiveConfig {
+        let mut system = self.config.clone();
+        let mut overrides = Vec::new();
+        let searches = NonZeroU64::new(self.search_limit.load(Ordering::SeqCst));
+        if searches != system.max_concurrent_searches {
+            system.max_concurrent_searches = searches;
+            overrides.push("max_concurrent_searches");
+        }
+        EffectiveConfig { system, overrides }
     }
 
     fn get_closer_bucket(&self, key: Id) -> Vec<T::Contact> {
@@ -64,16 +385,92 @@ impl<T: TransportSender> KademliaDht<T> {
             .collect()
     }
 
-    pub async fn query_value(&self, key: Id, max_entry_count: u32, options: BasicSearchOptions) -> Vec<TopicEntry> {
+    /// Searches the entries of key, at most max_entry_count of them are returned
+    /// (see [`select_entries`] for the ones that are kept).
+    pub async fn query_value(&self, key: impl Into<Id>, max_entry_count: u32, options: BasicSearchOptions) -> Vec<TopicEntry> {
+        let key = key.into();
+        let entries = self.query_value_stream(key, max_entry_count, options).collect().await;
+        select_entries(key, entries, max_entry_count)
+    }
+
+    /// Searches the entries of key, yielding them as soon as the nodes storing them answer.
+    ///
+    /// The entries of a publisher are taken from the first answer including it,
+    /// the local entries are yielded when the search completes (only for the
+    /// publishers that no node returned). Every node returns at most max_entry_count
+    /// entries, but more can be yielded in total.
+    pub fn query_value_stream(
+        &self,
+        key: impl Into<Id>,
+        max_entry_count: u32,
+        options: BasicSearchOptions,
+    ) -> impl Stream<Item = TopicEntry> + '_ {
+        let key = key.into();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let search = async move {
+            let _guard = self.start_search().await;
+            let bucket = self.get_closer_bucket(key);
+            BasicSearch::create(self, options, SearchType::Data(max_entry_count), key)
+                .stream_to(tx)
+                .search(bucket)
+                .await;
+        };
+        // The search only runs while the entries are polled, dropping the sender when done
+        let search = stream::once(search).filter_map(|()| future::ready(None));
+        stream::select(search, stream::poll_fn(move |cx| rx.poll_recv(cx)))
+    }
+
+    /// Searches any entry of key accepted by accept (e.g. one matching a content hash),
+    /// returning as soon as one is found without waiting for the search to converge.
+    pub async fn query_value_any(
+        &self,
+        key: impl Into<Id>,
+        options: BasicSearchOptions,
+        accept: impl Fn(&TopicEntry) -> bool + Sync,
+    ) -> Option<TopicEntry> {
+        let key = key.into();
+        let _guard = self.start_search().await;
         let bucket = self.get_closer_bucket(key);
-        let searcher = BasicSearch::create(self, options, SearchType::Data(max_entry_count), key);
+        let searcher = BasicSearch::create(self, options, SearchType::Data(u32::MAX), key)
+            .first_match(&accept);
         match searcher.search(bucket).await {
-            SearchResult::CloserNodes(_) => Vec::new(),
-            SearchResult::DataFound(x) => x,
+            SearchResult::CloserNodes(_) => None,
+            SearchResult::DataFound(x) => x.into_iter().next(),
+        }
+    }
+
+    /// Entries of key stored by this node, without any network search
+    pub fn query_local(&self, key: Id, max_entry_count: u32) -> Vec<TopicEntry> {
+        let storage = self.storage.read().unwrap();
+        match storage.get(key) {
+            Some(entries) => select_entries(key, entries.into_iter().collect(), max_entry_count),
+            None => Vec::new(),
+        }
+    }
+
+    /// Stores entries found on the network so that following local queries see them.
+    /// Like prefetched entries they are kept for the configured prefetch lifetime,
+    /// entries already stored with the same data are left untouched.
+    /// Returns the number of entries stored.
+    pub fn cache_entries(&self, key: Id, entries: Vec<TopicEntry>) -> usize {
+        let lifetime = self.config.storage.prefetch_lifetime;
+        let mut storage = self.storage.write().unwrap();
+        let mut count = 0;
+        for entry in entries {
+            let present = matches!(storage.get(key), Some(x) if x.contains(&entry));
+            if present {
+                continue;
+            }
+            match storage.insert(key, None, entry.publisher, lifetime, entry.data) {
+                Ok(()) => count += 1,
+                Err(x) => debug!("Cannot cache entry: {x}"),
+            }
         }
+        count
     }
 
     pub async fn query_nodes(&self, key: Id, options: BasicSearchOptions) -> Vec<T::Contact> {
+        let _guard = self.start_search().await;
         let bucket = self.get_closer_bucket(key);
         let searcher = BasicSearch::create(self, options, SearchType::Nodes, key);
         match searcher.search(bucket).await {
@@ -82,6 +479,16 @@ impl<T: TransportSender> KademliaDht<T> {
         }
     }
 
+    /// Like [`KademliaDht::query_nodes`], with the length of the prefix that each
+    /// contact shares with key (higher is closer), closest first
+    pub async fn query_nodes_with_distance(&self, key: Id, options: BasicSearchOptions) -> Vec<(u8, T::Contact)> {
+        // The search window is already sorted by distance
+        self.query_nodes(key, options).await
+            .into_iter()
+            .map(|x| (x.id().common_prefix_len(&key), x))
+            .collect()
+    }
+
     pub async fn bootstrap<R: Rng>(&self, options: BasicSearchOptions, rng: &mut R) {
         let nodes = self.query_nodes(self.id, options.clone()).await;
 
@@ -92,7 +499,7 @@ impl<T: TransportSender> KademliaDht<T> {
             Some(x) => x,
         };
 
-        let max_leading_zeros = (self.id ^ closest_sibling.id()).leading_zeros();
+        let max_leading_zeros = self.id.common_prefix_len(&closest_sibling.id());
 
         let mut fu = (0..max_leading_zeros)
             .rev()
@@ -110,72 +517,456 @@ impl<T: TransportSender> KademliaDht<T> {
         }
     }
 
-    async fn send_request_and_count(&self, nodes: Vec<T::Contact>, request: Request) -> usize {
-        let mut answers = nodes
+    /// Leaves the network: hands off the stored entries and shuts down the transport.
+    ///
+    /// For each stored topic where this node is one of the k closest (known)
+    /// nodes, the entries are sent to the node that becomes one of the
+    /// k closest once this node leaves (as in the hand-off to newly connected nodes).
+    /// Only the entries of this node and the signed records can be handed off.
+    /// Returns the number of topics handed off successfully.
+    pub async fn leave(&self) -> usize {
+        let k = self.config.routing.bucket_size;
+        let handoffs: Vec<(Id, Request)> = {
+            let tree = self.tree.lock().unwrap();
+            let storage = self.storage.read().unwrap();
+            storage.topics()
+                .into_iter()
+                .filter_map(|topic| {
+                    // Self is never in the routing tree
+                    let nodes = tree.get_closer_n(topic, k);
+                    let self_distance = self.id ^ topic;
+                    if nodes.iter().filter(|x| (**x ^ topic) < self_distance).count() >= k {
+                        return None;
+                    }
+                    let replacement = *nodes.get(k - 1).or_else(|| nodes.last())?;
+                    Some((replacement, self.handoff_request(&storage, topic)?))
+                })
+                .collect()
+        };
+        info!("Leaving the network, handing off {} topics", handoffs.len());
+
+        let mut answers = handoffs.into_iter()
+            .map(|(id, req)| self.transport.send(id, req))
+            .collect::<FuturesUnordered<_>>();
+        let mut count = 0;
+        while let Some(res) = answers.next().await {
+            match res {
+                Ok(RawResponse::Done) => count += 1,
+                Ok(_) => warn!("Hand-off refused"),
+                Err(x) => warn!("Transport error during hand-off: {x}"),
+            }
+        }
+
+        self.transport.shutdown();
+        count
+    }
+
+    /// Fetches the entries of the given topics from a peer and stores them locally.
+    ///
+    /// Useful right after joining to serve the region near this node, peer must
+    /// be reachable (ex. in the routing table).
+    /// Since the original lifetime is unknown the entries are kept for
+    /// the configured prefetch lifetime. Returns the number of entries stored.
+    pub async fn prefetch_region(&self, peer: Id, topics: &[Id]) -> usize {
+        let mut answers = topics
             .iter()
-            .filter(|x| x.id() != self.id)
-            .map(|x| async {
-                // tag the future (to know which clients started it)
-                (
-                    x.clone(),
-                    self.transport.send(x.id(), request.clone()).await,
-                )
+            .map(|topic| async move {
+                (*topic, self.transport.send(peer, Request::FindData(*topic, u32::MAX)).await)
             })
             .collect::<FuturesUnordered<_>>();
 
+        let lifetime = self.config.storage.prefetch_lifetime;
         let mut count = 0;
-
-        while let Some((id, x)) = answers.next().await {
-            match x {
-                Ok(RawResponse::Done) => count += 1,
-                Ok(RawResponse::Error) => warn!("{id:?} returned an error"),
-                Ok(_) => warn!("Unknown response received from {id:?}"),
-                Err(x) => warn!("Transport error querying {id:?}: {x}"),
+        while let Some((topic, res)) = answers.next().await {
+            let entries = match res {
+                Ok(RawResponse::FoundData(x) | RawResponse::FoundDataAndNodes(x, _)) => x,
+                // The peer doesn't store the topic
+                Ok(RawResponse::FoundNodes(_)) => continue,
+                Ok(_) => {
+                    warn!("Invalid prefetch response from {peer}");
+                    continue;
+                }
+                Err(x) => {
+                    warn!("Transport error prefetching from {peer}: {x}");
+                    continue;
+                }
+            };
+            let mut storage = self.storage.write().unwrap();
+            for entry in entries {
+                // Don't overwrite fresher entries
+                let present = matches!(storage.get(topic), Some(x) if x.iter().any(|x| x.publisher == entry.publisher));
+                if present {
+                    continue;
+                }
+                match storage.insert(topic, None, entry.publisher, lifetime, entry.data) {
+                    Ok(()) => count += 1,
+                    Err(x) => debug!("Cannot store prefetched entry: {x}"),
+                }
             }
         }
-
         count
     }
 
+    // Picks up to count random routing table nodes, excluding self and the ones in exclude
+    fn random_placement(&self, exclude: &[T::Contact], count: usize) -> Vec<T::Contact> {
+        let mut candidates: Vec<Id> = self.tree
+            .lock()
+            .unwrap()
+            .get_closer_n(self.id, usize::MAX)
+            .into_iter()
+            .filter(|x| *x != self.id && exclude.iter().all(|c| c.id() != *x))
+            .collect();
+        let (chosen, _) = candidates.partial_shuffle(&mut rand::thread_rng(), count);
+        chosen.iter().map(|x| self.transport.wrap_contact(*x)).collect()
+    }
+
+    // Sends the request to every node (except self), yielding each answer as it arrives
+    fn send_request_acks(
+        &self,
+        nodes: Vec<T::Contact>,
+        request: Request,
+    ) -> impl Stream<Item = (Id, Result<(), TransportError>)> + '_ {
+        nodes
+            .into_iter()
+            .filter(|x| x.id() != self.id)
+            .map(move |x| {
+                let request = request.clone();
+                async move {
+                    // The contact is kept alive until the answer arrives
+                    let id = x.id();
+                    let res = match self.transport.send(id, request).await {
+                        Ok(RawResponse::Done) => Ok(()),
+                        Ok(RawResponse::Error) => {
+                            warn!("{id:?} returned an error");
+                            Err(TransportError::Refused)
+                        }
+                        Ok(_) => {
+                            warn!("Unknown response received from {id:?}");
+                            Err("Unknown response".into())
+                        }
+                        Err(x) => {
+                            warn!("Transport error querying {id:?}: {x}");
+                            Err(x)
+                        }
+                    };
+                    (id, res)
+                }
+            })
+            .collect::<FuturesUnordered<_>>()
+    }
+
+    async fn send_request_and_count(&self, nodes: Vec<T::Contact>, request: Request) -> usize {
+        self.send_request_acks(nodes, request)
+            .filter(|(_, x)| future::ready(x.is_ok()))
+            .count()
+            .await
+    }
+
+    /// Snapshots the locally stored entries
+    pub fn export_storage(&self) -> SerializableStore {
+        self.storage.read().unwrap().export()
+    }
+
+    /// Restores a storage snapshot, returns the number of imported entries
+    pub fn import_storage(&self, store: SerializableStore) -> usize {
+        self.storage.write().unwrap().import(store)
+    }
+
     pub async fn insert(
+        &self,
+        key: impl Into<Id>,
+        lifetime: Duration,
+        value: Vec<u8>,
+    ) -> Result<usize, crate::storage::Error> {
+        self.insert_named(key, None, lifetime, value).await
+    }
+
+    /// Like [`KademliaDht::insert`], searching the storing nodes with options
+    /// instead of [`SystemConfig::default_search`]
+    pub async fn insert_with_options(
+        &self,
+        key: impl Into<Id>,
+        lifetime: Duration,
+        value: Vec<u8>,
+        options: BasicSearchOptions,
+    ) -> Result<usize, crate::storage::Error> {
+        self.insert_owned(key.into(), None, lifetime, value, options).await
+    }
+
+    /// Inserts a value also sending the original topic name,
+    /// nodes with a topic index will be able to list it by prefix.
+    ///
+    /// The key must be the id the transport derives from the name
+    /// (see [`TransportSender::verify_topic_name`]), otherwise the name is not indexed.
+    pub async fn insert_named(
+        &self,
+        key: impl Into<Id>,
+        name: Option<String>,
+        lifetime: Duration,
+        value: Vec<u8>,
+    ) -> Result<usize, crate::storage::Error> {
+        self.insert_owned(key.into(), name, lifetime, value, self.config.default_search.clone()).await
+    }
+
+    async fn insert_owned(
         &self,
         key: Id,
+        name: Option<String>,
         lifetime: Duration,
         value: Vec<u8>,
+        options: BasicSearchOptions,
     ) -> Result<usize, crate::storage::Error> {
         // Insert key in the k closest nodes
         let lifetime = lifetime.as_secs() as u32;
 
-        Storage::check_entry(&self.config.storage, key, self.id, lifetime, &value)?;
+        self.storage.read().unwrap().check_entry(key, name.as_deref(), self.id, lifetime, &value)?;
 
         info!("Inserting {key:?} into the network for {lifetime}s -> '{value:x?}'");
 
-        let search_options = BasicSearchOptions { parallelism: 2 };
-        let nodes = self.query_nodes(key, search_options).await;
+        let value = OwnedValue { name, data: value, lifetime, append: false, signature: None, published: Instant::now() };
+        self.track_owned(key, &value);
+        Ok(self.publish(key, value, options).await)
+    }
+
+    /// Inserts a value like [`KademliaDht::insert`], yielding the answer of
+    /// each storing node as it arrives (this node included) so that callers
+    /// can follow the replication.
+    ///
+    /// The stream starts with the search of the closest nodes, values are only
+    /// sent when it's polled.
+    pub fn insert_stream(
+        &self,
+        key: impl Into<Id>,
+        lifetime: Duration,
+        value: Vec<u8>,
+    ) -> Result<impl Stream<Item = (Id, Result<(), TransportError>)> + '_, crate::storage::Error> {
+        let key = key.into();
+        let lifetime = lifetime.as_secs() as u32;
+
+        self.storage.read().unwrap().check_entry(key, None, self.id, lifetime, &value)?;
+
+        info!("Inserting {key:?} into the network for {lifetime}s -> '{value:x?}'");
+
+        let value = OwnedValue { name: None, data: value, lifetime, append: false, signature: None, published: Instant::now() };
+        self.track_owned(key, &value);
+        Ok(self.publish_stream(key, value, self.config.default_search.clone()))
+    }
+
+    /// Adds a value after the values previously inserted by this node,
+    /// instead of replacing them (ex. messages of a chat or a feed).
+    ///
+    /// Queries return every value, the storing nodes keep at most
+    /// `max_entries_per_publisher` values per publisher (dropping the oldest).
+    pub async fn insert_append(
+        &self,
+        key: impl Into<Id>,
+        lifetime: Duration,
+        value: Vec<u8>,
+    ) -> Result<usize, crate::storage::Error> {
+        let key = key.into();
+        let lifetime = lifetime.as_secs() as u32;
+
+        self.storage.read().unwrap().check_entry(key, None, self.id, lifetime, &value)?;
+
+        info!("Appending to {key:?} into the network for {lifetime}s -> '{value:x?}'");
+
+        let value = OwnedValue { name: None, data: value, lifetime, append: true, signature: None, published: Instant::now() };
+        self.track_owned(key, &value);
+        Ok(self.publish(key, value, self.config.default_search.clone()).await)
+    }
+
+    /// Inserts a value signed by the owner of a key (see [`RecordSignature`]),
+    /// the value is stored with the publisher of the signature.
+    ///
+    /// The lifetime is covered by the signature, so it's sent with a precision
+    /// of one second and it must match the signed one.
+    pub async fn insert_signed(
+        &self,
+        key: impl Into<Id>,
+        lifetime: Duration,
+        value: Vec<u8>,
+        signature: RecordSignature,
+    ) -> Result<usize, crate::storage::Error> {
+        let key = key.into();
+        let lifetime = lifetime.as_secs() as u32;
+
+        self.storage.read().unwrap().check_entry(key, None, signature.publisher, lifetime, &value)?;
+
+        info!("Inserting {key:?} signed by {:?} for {lifetime}s -> '{value:x?}'", signature.publisher);
+
+        let value = OwnedValue { name: None, data: value, lifetime, append: false, signature: Some(signature), published: Instant::now() };
+        self.track_owned(key, &value);
+        Ok(self.publish(key, value, self.config.default_search.clone()).await)
+    }
+
+    /// Republishes the values inserted by this node that were not sent to the
+    /// network for `republish_interval` (or for half of their lifetime), so
+    /// that they survive the churn of the nodes storing them.
+    ///
+    /// Only values inserted while `StorageConfig::republish` is enabled are
+    /// republished, they're kept alive until removed. Returns the number of
+    /// republished values.
+    pub async fn republish(&self) -> usize {
+        let interval = Duration::from_secs(self.config.storage.republish_interval as u64);
+        let now = Instant::now();
+        let due: Vec<(Id, OwnedValue)> = self.owned.lock().unwrap()
+            .iter_mut()
+            .flat_map(|(key, values)| values.iter_mut().map(move |x| (*key, x)))
+            .filter(|(_, x)| {
+                let max_age = interval.min(Duration::from_secs(x.lifetime as u64) / 2);
+                now.saturating_duration_since(x.published) >= max_age
+            })
+            .map(|(key, x)| {
+                x.published = now;
+                (key, x.clone())
+            })
+            .collect();
 
-        let mut installation_count = 0;
+        debug!("Republishing {} values", due.len());
+        let count = due.len();
+        for (key, value) in due {
+            self.publish(key, value, self.config.default_search.clone()).await;
+        }
+        count
+    }
+
+    // Remembers a value inserted by this node, replacing the values it overwrites
+    fn track_owned(&self, key: Id, value: &OwnedValue) {
+        if !self.config.storage.republish {
+            return;
+        }
+        let mut owned = self.owned.lock().unwrap();
+        let values = owned.entry(key).or_default();
+        if !value.append && self.config.storage.publisher_mode == PublisherMode::Replace {
+            values.clear();
+        }
+        values.retain(|x| x.data != value.data);
+        values.push(value.clone());
+        let max = self.config.storage.max_entries_per_publisher.max(1);
+        let excess = values.len().saturating_sub(max);
+        values.drain(..excess);
+    }
+
+    // Sends the value to the network, returns the number of nodes that stored it
+    async fn publish(&self, key: Id, value: OwnedValue, options: BasicSearchOptions) -> usize {
+        self.publish_stream(key, value, options)
+            .filter(|(_, x)| future::ready(x.is_ok()))
+            .count()
+            .await
+    }
+
+    // Sends the value to the nodes that should store it (storing it locally if this
+    // node is one of them), yielding the answers
+    fn publish_stream(
+        &self,
+        key: Id,
+        value: OwnedValue,
+        options: BasicSearchOptions,
+    ) -> impl Stream<Item = (Id, Result<(), TransportError>)> + '_ {
+        let acks = async move {
+            let mut nodes = self.query_nodes(key, options).await;
+            if let PlacementStrategy::ClosestKPlusRandom { extra } = self.config.placement {
+                let extra_nodes = self.random_placement(&nodes, extra);
+                debug!("Placing {} extra copies of {key:?}", extra_nodes.len());
+                nodes.extend(extra_nodes);
+            }
+
+            let request = value.request(key);
+            let local = if nodes.iter().any(|x| x.id() == self.id) {
+                Some((self.id, self.store_local(key, value)))
+            } else {
+                None
+            };
+            if matches!(local, Some((_, Err(_)))) && !self.config.storage.replicate_on_local_failure {
+                debug!("Local store of {key:?} failed, not replicating it");
+                nodes.clear();
+            }
+            stream::iter(local).chain(self.send_request_acks(nodes, request))
+        };
+        stream::once(acks).flatten()
+    }
+
+    fn store_local(&self, key: Id, value: OwnedValue) -> Result<(), TransportError> {
+        let mut storage = self.storage.write().unwrap();
+        let res = if let Some(signature) = value.signature {
+            let proof = RecordProof { lifetime: value.lifetime, signature };
+            storage.insert_signed(key, value.lifetime, value.data, proof)
+        } else if value.append {
+            storage.append(key, self.id, value.lifetime, value.data)
+        } else {
+            storage.insert(key, value.name, self.id, value.lifetime, value.data)
+        };
+        res.map_err(|x| {
+            warn!("Cannot store {key:?} locally: {x}");
+            TransportError::Refused
+        })
+    }
+
+    /// Replaces this node's value only where it currently matches expected.
+    ///
+    /// This is best-effort: each of the closest nodes checks its own replica,
+    /// the swap succeeds if the majority of the replicas has been swapped.
+    pub async fn compare_and_swap(
+        &self,
+        key: Id,
+        expected: Option<Vec<u8>>,
+        new: Vec<u8>,
+        lifetime: Duration,
+    ) -> Result<bool, crate::storage::Error> {
+        let lifetime = lifetime.as_secs() as u32;
+
+        self.storage.read().unwrap().check_entry(key, None, self.id, lifetime, &new)?;
+
+        info!("Compare and swap {key:?} for {lifetime}s -> '{new:x?}'");
+
+        let nodes = self.query_nodes(key, self.config.default_search.clone()).await;
+
+        let mut swap_count = 0;
 
         if nodes.iter().any(|x| x.id() == self.id) {
-            self.storage
+            let res = self.storage
                 .write()
                 .unwrap()
-                .insert(key, self.id, lifetime, value.clone())
-                .unwrap();
-            installation_count += 1;
+                .compare_and_swap(key, self.id, lifetime, expected.as_deref(), new.clone());
+            match res {
+                Ok(true) => swap_count += 1,
+                Ok(false) => {},
+                Err(x) => {
+                    warn!("Cannot swap {key:?} locally: {x}");
+                    if !self.config.storage.replicate_on_local_failure {
+                        return Err(x);
+                    }
+                },
+            }
         }
 
-        let request = Request::Insert(key, lifetime, value);
+        let value = OwnedValue { name: None, data: new.clone(), lifetime, append: false, signature: None, published: Instant::now() };
+        let request = Request::Cas(key, lifetime, expected.clone(), new);
+        swap_count += self.send_request_and_count(nodes.clone(), request).await;
 
-        installation_count += self.send_request_and_count(nodes, request).await;
+        let swapped = swap_count > nodes.len() / 2;
+        if swapped {
+            // The swapped value must not be republished
+            if let Some(values) = self.owned.lock().unwrap().get_mut(&key) {
+                values.retain(|x| Some(&x.data) != expected.as_ref());
+            }
+            self.track_owned(key, &value);
+        }
+        Ok(swapped)
+    }
 
-        Ok(installation_count)
+    pub async fn remove(&self, key: impl Into<Id>) -> usize {
+        self.remove_with_options(key, self.config.default_search.clone()).await
     }
 
-    pub async fn remove(&self, key: Id) -> usize {
+    /// Like [`KademliaDht::remove`], searching the storing nodes with options
+    /// instead of [`SystemConfig::default_search`]
+    pub async fn remove_with_options(&self, key: impl Into<Id>, options: BasicSearchOptions) -> usize {
+        let key = key.into();
         info!("Removing {key:?} into the network");
+        self.owned.lock().unwrap().remove(&key);
 
-        let search_options = BasicSearchOptions { parallelism: 2 };
-        let nodes = self.query_nodes(key, search_options).await;
+        let nodes = self.query_nodes(key, options).await;
 
         let mut removed_count = 0;
 
@@ -184,7 +975,7 @@ impl<T: TransportSender> KademliaDht<T> {
                 .write()
                 .unwrap()
                 .remove(key, self.id);
-                removed_count += 1;
+            removed_count += 1;
         }
 
         let request = Request::Remove(key);
@@ -192,25 +983,67 @@ impl<T: TransportSender> KademliaDht<T> {
         removed_count += self.send_request_and_count(nodes, request).await;
         removed_count
     }
-}
-
-impl<T: TransportSender> TransportListener for KademliaDht<T> {
-    fn on_connect(&self, id: Id) -> bool {
-        event!(Level::INFO, kad_id=%self.id, "Connnected {id}");
-        self.tree.lock().unwrap().insert(id, &self.transport)
-    }
 
-    fn on_disconnect(&self, id: Id) {
-        event!(Level::INFO, kad_id=%self.id, "Disconnected {id}");
-        self.tree.lock().unwrap().remove(id);
+    /// Answers a request like [`TransportListener::on_request`], checking the
+    /// signatures of signed records (and replicas) with [`TransportSender::verify_record`]
+    /// and the topic names with [`TransportSender::verify_topic_name`] first.
+    ///
+    /// `on_request` can't wait for the transport, so it refuses every signed
+    /// record and ignores the topic names: transports should pass the requests
+    /// through this function.
+    pub async fn handle_request(&self, sender: Id, mut request: Request) -> Response {
+        let verified = match &mut request {
+            Request::InsertSigned(topic, lifetime, data, signature) => {
+                self.transport.verify_record(*topic, *lifetime, data, signature).await
+            }
+            Request::InsertNamed(topic, .., name) => self.transport.verify_topic_name(*topic, name).await,
+            Request::Replicate(topic, entries) => {
+                // Invalid proofs are dropped, the entry is then kept only if sent by its publisher
+                for entry in entries.iter_mut() {
+                    let valid = match &entry.proof {
+                        Some(proof) => {
+                            proof.signature.publisher == entry.publisher
+                                && entry.lifetime <= proof.lifetime
+                                && self.transport.verify_record(*topic, proof.lifetime, &entry.data, &proof.signature).await
+                        }
+                        None => true,
+                    };
+                    if !valid {
+                        warn!("Invalid replica proof of {:?}", entry.publisher);
+                        entry.proof = None;
+                    }
+                }
+                true
+            }
+            _ => false,
+        };
+        self.process_request(sender, request, verified)
     }
 
+    // Answers a request, verified is true only if the signatures it carries were checked
     #[instrument(level = "debug", skip(self), fields(kad_id=%self.id, %sender))]
-    fn on_request(&self, sender: Id, message: Request) -> Response {
+    fn process_request(&self, sender: Id, message: Request, verified: bool) -> Response {
         debug!("Request: {:?}", message);
         let mut tree = self.tree.lock().unwrap();
         tree.refresh(sender);
 
+        if let Request::InsertSigned(topic, .., signature) = &message {
+            if !self.config.storage.can_write(*topic, signature.publisher) {
+                debug!("| Unauthorized write to {topic:?}");
+                return Response::Error;
+            }
+        }
+        if let Request::Insert(topic, ..)
+            | Request::InsertNamed(topic, ..)
+            | Request::Append(topic, ..)
+            | Request::Cas(topic, ..) = &message
+        {
+            if !self.config.storage.can_write(*topic, sender) {
+                debug!("| Unauthorized write to {topic:?}");
+                return Response::Error;
+            }
+        }
+
         match message {
             Request::FindNodes(topic) => {
                 // TODO: how many nodes to search?
@@ -222,23 +1055,25 @@ impl<T: TransportSender> TransportListener for KademliaDht<T> {
             }
 
             Request::FindData(topic, limit) => {
-                // Send data if stored
+                // Send data if stored, with the known nodes closer to the topic (if any)
                 // Else send closer nodes known
                 let storage = self.storage.read().unwrap();
+                let closer = tree.get_closer_n(topic, self.config.routing.bucket_size)
+                    .into_iter()
+                    .filter(|x| *x != sender);
                 let res = match storage.get(topic) {
-                    Some(entries) => Response::FoundData(
-                        entries.iter()
-                            // Always get the last entries (skip the first entries - limit entries)
-                            .skip(entries.len().saturating_sub(limit as usize))
-                            .cloned()
-                            .collect()
-                    ),
-                    None => Response::FoundNodes(
-                        tree.get_closer_n(topic, self.config.routing.bucket_size)
-                            .into_iter()
-                            .filter(|x| *x != sender)
-                            .collect(),
-                    ),
+                    Some(entries) => {
+                        // Same selection as the searches, so that the nodes agree on the entries
+                        let entries = select_entries(topic, entries.to_vec(), limit);
+                        let distance = self.id ^ topic;
+                        let closer: Vec<_> = closer.filter(|x| (*x ^ topic) < distance).collect();
+                        if closer.is_empty() {
+                            Response::FoundData(entries)
+                        } else {
+                            Response::FoundDataAndNodes(entries, closer)
+                        }
+                    },
+                    None => Response::FoundNodes(closer.collect()),
                 };
                 debug!("Find data {topic:?}({limit}): {res:?}");
                 res
@@ -248,7 +1083,43 @@ impl<T: TransportSender> TransportListener for KademliaDht<T> {
                 // TODO: protection against SPAM attacks? (ex. merkle challenges?)
                 debug!("| Insert {topic:?} {lifetime}s -> '{data:x?}'");
                 let mut storage = self.storage.write().unwrap();
-                match storage.insert(topic, sender, lifetime, data) {
+                match storage.insert(topic, None, sender, lifetime, data) {
+                    Ok(_) => Response::Done,
+                    Err(x) => {
+                        error!("Error inserting value: {x}");
+                        Response::Error
+                    }
+                }
+            }
+
+            Request::InsertNamed(topic, lifetime, data, name) => {
+                debug!("| Insert {topic:?} ({name}) {lifetime}s -> '{data:x?}'");
+                // A name that doesn't match the topic would take the name of another topic
+                let name = if verified {
+                    Some(name)
+                } else {
+                    debug!("| Unverified topic name, not indexed");
+                    None
+                };
+                let mut storage = self.storage.write().unwrap();
+                match storage.insert(topic, name, sender, lifetime, data) {
+                    Ok(_) => Response::Done,
+                    Err(x) => {
+                        error!("Error inserting value: {x}");
+                        Response::Error
+                    }
+                }
+            }
+
+            Request::InsertSigned(topic, lifetime, data, signature) => {
+                debug!("| Insert {topic:?} signed by {:?} {lifetime}s -> '{data:x?}'", signature.publisher);
+                if !verified {
+                    warn!("| Unverified signature of {:?}", signature.publisher);
+                    return Response::Error;
+                }
+                let mut storage = self.storage.write().unwrap();
+                let proof = RecordProof { lifetime, signature };
+                match storage.insert_signed(topic, lifetime, data, proof) {
                     Ok(_) => Response::Done,
                     Err(x) => {
                         error!("Error inserting value: {x}");
@@ -257,12 +1128,762 @@ impl<T: TransportSender> TransportListener for KademliaDht<T> {
                 }
             }
 
+            Request::Append(topic, lifetime, data) => {
+                debug!("| Append {topic:?} {lifetime}s -> '{data:x?}'");
+                let mut storage = self.storage.write().unwrap();
+                match storage.append(topic, sender, lifetime, data) {
+                    Ok(_) => Response::Done,
+                    Err(x) => {
+                        error!("Error appending value: {x}");
+                        Response::Error
+                    }
+                }
+            }
+
+            Request::Cas(topic, lifetime, expected, data) => {
+                debug!("| CAS {topic:?} {lifetime}s '{expected:x?}' -> '{data:x?}'");
+                let mut storage = self.storage.write().unwrap();
+                match storage.compare_and_swap(topic, sender, lifetime, expected.as_deref(), data) {
+                    Ok(true) => Response::Done,
+                    Ok(false) => {
+                        debug!("CAS conflict");
+                        Response::Error
+                    }
+                    Err(x) => {
+                        error!("Error swapping value: {x}");
+                        Response::Error
+                    }
+                }
+            }
+
+            Request::Replicate(topic, entries) => {
+                debug!("| Replicate {topic:?} ({} entries)", entries.len());
+                let mut storage = self.storage.write().unwrap();
+                // The entries already stored are at least as fresh as the replicas
+                let present: Vec<Id> = storage.get(topic)
+                    .map_or_else(Vec::new, |x| x.iter().map(|x| x.publisher).collect());
+                let mut replicated: Vec<Id> = Vec::new();
+                let mut failed = 0;
+                for entry in entries {
+                    // Entries of other publishers are only trusted if signed
+                    let proof = entry.proof.filter(|_| verified);
+                    if entry.publisher != sender && proof.is_none() {
+                        debug!("| Unauthenticated replica of {:?}", entry.publisher);
+                        continue;
+                    }
+                    if present.contains(&entry.publisher) || !self.config.storage.can_write(topic, entry.publisher) {
+                        continue;
+                    }
+                    let res = match proof {
+                        Some(proof) => storage.insert_signed(topic, entry.lifetime, entry.data, proof),
+                        None if replicated.contains(&entry.publisher) => {
+                            storage.append(topic, entry.publisher, entry.lifetime, entry.data)
+                        }
+                        None => storage.insert(topic, None, entry.publisher, entry.lifetime, entry.data),
+                    };
+                    match res {
+                        Ok(()) => replicated.push(entry.publisher),
+                        Err(x) => {
+                            debug!("Cannot store replicated entry: {x}");
+                            failed += 1;
+                        }
+                    }
+                }
+                if replicated.is_empty() && failed > 0 {
+                    Response::Error
+                } else {
+                    Response::Done
+                }
+            }
+
             Request::Remove(topic) => {
                 debug!("| Remove {topic:?}");
                 let mut storage = self.storage.write().unwrap();
                 storage.remove(topic, sender);
                 Response::Done
             }
+
+            Request::Unknown => {
+                // Sent by a newer node, it can still use the other requests
+                debug!("| Unknown request");
+                Response::Error
+            }
+        }
+    }
+}
+
+impl<T: TransportSender, S: StorageBackend> TransportListener for KademliaDht<T, S> {
+    fn on_connect(&self, id: Id) -> bool {
+        event!(Level::INFO, kad_id=%self.id, "Connnected {id}");
+        let mut tree = self.tree.lock().unwrap();
+        let routed = tree.insert(id, &self.transport);
+        self.update_routing_state(tree.size());
+        if routed {
+            let handoff = self.compute_handoff(&tree, id);
+            if !handoff.is_empty() {
+                debug!("Handing off {} entries to {id}", handoff.len());
+                self.pending_handoffs.lock().unwrap().insert(id, handoff);
+            }
+        }
+        routed
+    }
+
+    fn on_disconnect(&self, id: Id) -> bool {
+        event!(Level::INFO, kad_id=%self.id, "Disconnected {id}");
+        let mut tree = self.tree.lock().unwrap();
+        let routed = tree.is_routed(id);
+        let suspect = self.config.routing.disconnect_grace.is_some() && tree.mark_suspect(id, Instant::now());
+        if suspect {
+            debug!("Keeping {id} in routing during its grace period");
+        } else {
+            tree.remove(id);
         }
+        // No replacement took its place
+        let wanted = routed && (suspect || tree.has_room_for(id));
+        self.update_routing_state(tree.size());
+        drop(tree);
+        self.pending_handoffs.lock().unwrap().remove(&id);
+        wanted
+    }
+
+    fn take_pending_requests(&self, id: Id) -> Vec<Request> {
+        self.pending_handoffs.lock().unwrap().remove(&id).unwrap_or_default()
+    }
+
+    fn on_request(&self, sender: Id, message: Request) -> Response {
+        self.process_request(sender, message, false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        config::{RoutingConfig, StorageConfig, TopicAllowlist},
+        consts::ID_LEN,
+        storage::Error as StorageError,
+        search::{ConnectionLimitPolicy, SearchCancel},
+        transport::{simulate::{sign_simulated, simulated_topic_id}, testing::ConfigurableTestTransport},
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn ping_eviction() {
+        let config = SystemConfig {
+            routing: RoutingConfig {
+                bucket_size: 2,
+                bucket_replacement_size: 1,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let transport = ConfigurableTestTransport::default();
+        let dht = KademliaDht::new(config, Id::from_hex("a0000000"), transport.clone()).unwrap();
+
+        let [stale, alive, replacement] = ["a0000100", "a0000101", "a0000110"].map(Id::from_hex);
+        for id in [stale, alive, replacement] {
+            transport.set_response(id, Ok(Response::FoundNodes(vec![])));
+        }
+        transport.set_ping_fails(stale, true);
+
+        assert!(dht.on_connect(stale));
+        assert!(dht.on_connect(alive));
+        assert!(transport.pings().is_empty());
+        // The bucket is full, the new node goes into the replacement cache
+        // and the bucket entries are pinged
+        assert!(dht.on_connect(replacement));
+        assert_eq!(transport.pings(), HashMap::from([(stale, 1), (alive, 1)]));
+
+        let search_options = BasicSearchOptions::default();
+        let found: Vec<_> = dht.query_nodes(stale, search_options.clone()).await;
+        assert!(found.contains(&stale));
+        assert!(!found.contains(&replacement));
+
+        // The transport disconnects the node that failed the ping
+        let disconnected = transport.take_disconnected();
+        assert_eq!(disconnected, vec![stale]);
+        for id in disconnected {
+            dht.on_disconnect(id);
+        }
+
+        // The replacement has been promoted in place of the stale node
+        let found: Vec<_> = dht.query_nodes(stale, search_options).await;
+        assert!(!found.contains(&stale));
+        assert!(found.contains(&replacement));
+        assert!(found.contains(&alive));
+        assert!(transport.take_disconnected().is_empty());
+    }
+
+    #[tokio::test]
+    async fn parallelism_headroom() {
+        let transport = ConfigurableTestTransport::default();
+        let dht = KademliaDht::new(SystemConfig::default(), Id::from_hex("a0000000"), transport.clone()).unwrap();
+        for i in 1..=8u8 {
+            let id = Id::ZERO.set_bit(i);
+            transport.set_response(id, Ok(Response::FoundNodes(vec![])));
+            assert!(dht.on_connect(id));
+        }
+        // Only 2 more connections can be opened
+        transport.set_headroom(Some(2));
+
+        let options = BasicSearchOptions { parallelism: 4, ..Default::default() };
+        dht.query_nodes(Id::MAX, options.clone()).await;
+        assert_eq!(transport.max_in_flight(), 2);
+
+        // Queueing leaves the parallelism untouched
+        let options = BasicSearchOptions { connection_limit: ConnectionLimitPolicy::Queue, ..options };
+        dht.query_nodes(Id::MAX, options).await;
+        assert_eq!(transport.max_in_flight(), 4);
+    }
+
+    #[tokio::test]
+    async fn insert_search_options() {
+        // The nodes never answer, so the value is only stored locally
+        let insert_in_flight = |options: Option<BasicSearchOptions>| async move {
+            let transport = ConfigurableTestTransport::default();
+            let dht = KademliaDht::new(SystemConfig::default(), Id::from_hex("a0000000"), transport.clone()).unwrap();
+            for i in 1..=8u8 {
+                assert!(dht.on_connect(Id::ZERO.set_bit(i)));
+            }
+            let key = Id::ZERO.set_bit(4);
+            let lifetime = Duration::from_secs(60);
+            match options {
+                Some(options) => dht.insert_with_options(key, lifetime, vec![1], options).await,
+                None => dht.insert(key, lifetime, vec![1]).await,
+            }.unwrap();
+            transport.max_in_flight()
+        };
+
+        let default = insert_in_flight(None).await;
+        assert_eq!(default, SystemConfig::default().default_search.parallelism as usize);
+        let options = BasicSearchOptions { parallelism: 1, ..Default::default() };
+        assert!(insert_in_flight(Some(options)).await < default);
+    }
+
+    #[tokio::test]
+    async fn cancel_search() {
+        let transport = ConfigurableTestTransport::default();
+        let dht = KademliaDht::new(SystemConfig::default(), Id::from_hex("a0000000"), transport.clone()).unwrap();
+        let (answering, stalled) = (Id::ZERO.set_bit(1), Id::ZERO.set_bit(2));
+        let topic = Id::ZERO;
+        let entry = TopicEntry { publisher: answering, data: vec![1] };
+        transport.set_response(answering, Ok(Response::FoundData(vec![entry.clone()])));
+        transport.set_stalled(stalled);
+        assert!(dht.on_connect(answering));
+        assert!(dht.on_connect(stalled));
+
+        // Without cancelling the search would wait forever for the stalled node
+        let cancel = SearchCancel::new();
+        let options = BasicSearchOptions { cancel: Some(cancel.clone()), ..Default::default() };
+        let search = dht.query_value(topic, 10, options);
+        let cancel_later = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            cancel.cancel();
+        };
+        let (found, _) = tokio::time::timeout(Duration::from_secs(5), future::join(search, cancel_later))
+            .await
+            .unwrap();
+        // The entries found until then are returned
+        assert_eq!(found, vec![entry]);
+        assert_eq!(transport.requests().get(&stalled), Some(&1));
+
+        // Already cancelled searches return the local results
+        let options = BasicSearchOptions { cancel: Some(cancel), ..Default::default() };
+        let nodes = dht.query_nodes(topic, options).await;
+        assert!(nodes.contains(&stalled));
+    }
+
+    #[tokio::test]
+    async fn query_value_any() {
+        let transport = ConfigurableTestTransport::default();
+        let dht = KademliaDht::new(SystemConfig::default(), Id::from_hex("a0000000"), transport.clone()).unwrap();
+        let (invalid, valid, stalled) = (Id::ZERO.set_bit(1), Id::ZERO.set_bit(2), Id::ZERO.set_bit(3));
+        let topic = Id::ZERO;
+        let entry = |publisher, data| TopicEntry { publisher, data };
+        transport.set_response(invalid, Ok(Response::FoundData(vec![entry(invalid, vec![0])])));
+        transport.set_response(valid, Ok(Response::FoundData(vec![entry(valid, vec![1])])));
+        transport.set_stalled(stalled);
+        for id in [invalid, valid, stalled] {
+            assert!(dht.on_connect(id));
+        }
+
+        // Returns at the first accepted entry, without waiting for the stalled node
+        let found = tokio::time::timeout(
+            Duration::from_secs(5),
+            dht.query_value_any(topic, BasicSearchOptions::default(), |x| x.data == [1]),
+        ).await.unwrap();
+        assert_eq!(found, Some(entry(valid, vec![1])));
+        assert_eq!(transport.requests().get(&stalled), Some(&1));
+
+        // Nothing acceptable, the search converges
+        transport.set_response(stalled, Err(TransportError::ConnectionLost));
+        let found = dht.query_value_any(topic, BasicSearchOptions::default(), |x| x.data == [2]).await;
+        assert_eq!(found, None);
+    }
+
+    // Backend keeping the entries in a map (without expiration), logging the writes
+    #[derive(Default)]
+    struct LoggingBackend {
+        entries: HashMap<Id, Vec<TopicEntry>>,
+        log: Vec<&'static str>,
+    }
+
+    impl StorageBackend for LoggingBackend {
+        fn get(&self, topic: Id) -> Option<Vec<TopicEntry>> {
+            self.entries.get(&topic).cloned()
+        }
+
+        fn topics(&self) -> Vec<Id> {
+            self.entries.keys().copied().collect()
+        }
+
+        fn remaining_lifetime(&self, _topic: Id, _publisher: Id) -> Option<u32> {
+            None
+        }
+
+        fn check_entry(&self, _topic: Id, _name: Option<&str>, _publisher: Id, _lifetime: u32, data: &[u8]) -> Result<(), StorageError> {
+            if data.is_empty() {
+                return Err(StorageError::InvalidData);
+            }
+            Ok(())
+        }
+
+        fn insert(&mut self, topic: Id, _name: Option<String>, publisher: Id, lifetime: u32, data: Vec<u8>) -> Result<(), StorageError> {
+            self.log.push("insert");
+            self.check_entry(topic, None, publisher, lifetime, &data)?;
+            let entries = self.entries.entry(topic).or_default();
+            entries.retain(|x| x.publisher != publisher);
+            entries.push(TopicEntry { publisher, data });
+            Ok(())
+        }
+
+        fn insert_signed(&mut self, topic: Id, lifetime: u32, data: Vec<u8>, proof: RecordProof) -> Result<(), StorageError> {
+            self.log.push("insert_signed");
+            self.insert(topic, None, proof.signature.publisher, lifetime, data)
+        }
+
+        fn proof(&self, _topic: Id, _entry: &TopicEntry) -> Option<RecordProof> {
+            None
+        }
+
+        fn append(&mut self, topic: Id, publisher: Id, lifetime: u32, data: Vec<u8>) -> Result<(), StorageError> {
+            self.log.push("append");
+            self.check_entry(topic, None, publisher, lifetime, &data)?;
+            self.entries.entry(topic).or_default().push(TopicEntry { publisher, data });
+            Ok(())
+        }
+
+        fn compare_and_swap(
+            &mut self,
+            _topic: Id,
+            _publisher: Id,
+            _lifetime: u32,
+            _expected: Option<&[u8]>,
+            _data: Vec<u8>,
+        ) -> Result<bool, StorageError> {
+            self.log.push("cas");
+            Ok(false)
+        }
+
+        fn remove(&mut self, topic: Id, publisher: Id) {
+            self.log.push("remove");
+            if let Some(entries) = self.entries.get_mut(&topic) {
+                entries.retain(|x| x.publisher != publisher);
+                if entries.is_empty() {
+                    self.entries.remove(&topic);
+                }
+            }
+        }
+
+        fn periodic_run(&mut self) -> Vec<(Id, Id)> {
+            self.log.push("periodic_run");
+            Vec::new()
+        }
+
+        fn export(&self) -> SerializableStore {
+            SerializableStore::default()
+        }
+
+        fn import(&mut self, _store: SerializableStore) -> usize {
+            0
+        }
+    }
+
+    #[tokio::test]
+    async fn custom_backend() {
+        let transport = ConfigurableTestTransport::default();
+        let dht = KademliaDht::with_storage(SystemConfig::default(), Id::MAX, transport, LoggingBackend::default()).unwrap();
+        let (topic, sender) = (Id::ZERO, Id::ZERO.set_bit(1));
+
+        assert_eq!(dht.on_request(sender, Request::Insert(topic, 60, vec![1])), Response::Done);
+        assert_eq!(dht.on_request(sender, Request::Append(topic, 60, vec![2])), Response::Done);
+        let expected = vec![
+            TopicEntry { publisher: sender, data: vec![1] },
+            TopicEntry { publisher: sender, data: vec![2] },
+        ];
+        assert_eq!(dht.on_request(sender, Request::FindData(topic, 10)), Response::FoundData(expected.clone()));
+        // No other node is known, the entries are found locally
+        assert_eq!(dht.query_value(topic, 10, BasicSearchOptions::default()).await, expected);
+
+        // Values are checked by the backend before being sent
+        assert!(matches!(
+            dht.insert(topic, Duration::from_secs(60), vec![]).await,
+            Err(StorageError::InvalidData)
+        ));
+
+        assert_eq!(dht.on_request(sender, Request::Remove(topic)), Response::Done);
+        dht.periodic_run();
+        assert!(dht.query_local(topic, 10).is_empty());
+        assert_eq!(dht.storage.read().unwrap().log, ["insert", "append", "remove", "periodic_run"]);
+    }
+
+    #[test]
+    fn debug_state() {
+        let transport = ConfigurableTestTransport::default();
+        let dht = KademliaDht::new(SystemConfig::default(), Id::MAX, transport).unwrap();
+        assert_eq!(dht.debug_state(), DhtDebugState {
+            tree_locked: false,
+            storage_locked: false,
+            active_searches: 0,
+        });
+
+        let tree = dht.tree.lock().unwrap();
+        let storage = dht.storage.read().unwrap();
+        let state = dht.debug_state();
+        assert!(state.tree_locked);
+        assert!(state.storage_locked);
+        drop((tree, storage));
+        assert!(!dht.debug_state().tree_locked);
+    }
+
+    #[test]
+    fn invalid_routing_config() {
+        let mut config = SystemConfig::default();
+        config.routing.bucket_size = 0;
+        let dht = KademliaDht::new(config, Id::ZERO, ConfigurableTestTransport::default());
+        assert_eq!(dht.err(), Some(ConfigError::BucketSizeTooSmall(0)));
+    }
+
+    #[test]
+    fn unknown_request() {
+        let transport = ConfigurableTestTransport::default();
+        let dht = KademliaDht::new(SystemConfig::default(), Id::MAX, transport).unwrap();
+        let sender = Id::ZERO.set_bit(1);
+
+        // Sent by a newer node: answered with an error, the sender is still a valid contact
+        assert_eq!(dht.on_request(sender, Request::Unknown), Response::Error);
+        assert_eq!(dht.on_request(sender, Request::FindNodes(Id::ZERO)), Response::FoundNodes(vec![]));
+    }
+
+    #[tokio::test]
+    async fn signed_records() {
+        let dht = KademliaDht::new(SystemConfig::default(), Id::ZERO, ConfigurableTestTransport::default()).unwrap();
+        let [sender, publisher] = ["a0", "b0"].map(Id::from_hex);
+        let topic = Id::MAX;
+        let signature = sign_simulated(publisher, topic, 60, &[1]);
+        let insert = |data, signature: &RecordSignature| Request::InsertSigned(topic, 60, data, signature.clone());
+
+        // The signature can't be checked without waiting for the transport
+        assert_eq!(dht.on_request(sender, insert(vec![1], &signature)), Response::Error);
+        // Signed data doesn't match
+        assert_eq!(dht.handle_request(sender, insert(vec![2], &signature)).await, Response::Error);
+        // Signature taken from another publisher
+        let forged = RecordSignature { publisher: sender, ..signature.clone() };
+        assert_eq!(dht.handle_request(sender, insert(vec![1], &forged)).await, Response::Error);
+        assert_eq!(dht.query_local(topic, 10), vec![]);
+
+        // Stored with the publisher of the signature, the signature is kept
+        assert_eq!(dht.handle_request(sender, insert(vec![1], &signature)).await, Response::Done);
+        let entry = TopicEntry { publisher, data: vec![1] };
+        let storage = dht.storage.read().unwrap();
+        assert_eq!(storage.get(topic), Some(&vec![entry.clone()]));
+        assert_eq!(storage.proof(topic, &entry), Some(&RecordProof { lifetime: 60, signature }));
+    }
+
+    #[tokio::test]
+    async fn named_topics() {
+        let config = SystemConfig {
+            storage: StorageConfig {
+                topic_index: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let dht = KademliaDht::new(config, Id::ZERO, ConfigurableTestTransport::default()).unwrap();
+        let [sender, squatter] = ["a0", "b0"].map(Id::from_hex);
+        let topic = simulated_topic_id("chat");
+        let insert = |topic| Request::InsertNamed(topic, 60, vec![1], "chat".to_owned());
+
+        // Names that don't match the topic are dropped, the data is still stored
+        assert_eq!(dht.handle_request(squatter, insert(Id::MAX)).await, Response::Done);
+        // Names can't be checked without waiting for the transport
+        assert_eq!(dht.on_request(sender, insert(topic)), Response::Done);
+        assert!(dht.storage.read().unwrap().list_topics("").is_empty());
+
+        assert_eq!(dht.handle_request(sender, insert(topic)).await, Response::Done);
+        let storage = dht.storage.read().unwrap();
+        assert_eq!(storage.list_topics(""), vec![("chat".to_owned(), topic)]);
+        assert!(storage.get(Id::MAX).is_some());
+    }
+
+    #[tokio::test]
+    async fn replicate() {
+        let dht = KademliaDht::new(SystemConfig::default(), Id::ZERO, ConfigurableTestTransport::default()).unwrap();
+        let [sender, signer, stored, forged] = ["a0", "b0", "c0", "d0"].map(Id::from_hex);
+        let topic = Id::MAX;
+        assert_eq!(dht.on_request(stored, Request::Insert(topic, 60, vec![9])), Response::Done);
+
+        let replica = |publisher, data: Vec<u8>, signed: bool| ReplicaEntry {
+            publisher,
+            lifetime: 50,
+            proof: signed.then(|| RecordProof { lifetime: 60, signature: sign_simulated(publisher, topic, 60, &data) }),
+            data,
+        };
+        let mut bad_proof = replica(forged, vec![4], true);
+        bad_proof.data = vec![5];
+        let request = Request::Replicate(topic, vec![
+            replica(sender, vec![1], false),
+            replica(signer, vec![2], true),
+            replica(stored, vec![3], true),
+            replica(forged, vec![4], false),
+            bad_proof,
+        ]);
+        assert_eq!(dht.handle_request(sender, request).await, Response::Done);
+        // Replicas keep their publisher and don't overwrite the stored entries,
+        // entries of other publishers need a valid signature
+        let entries = dht.storage.read().unwrap().get(topic).unwrap().to_vec();
+        assert_eq!(entries, vec![
+            TopicEntry { publisher: stored, data: vec![9] },
+            TopicEntry { publisher: sender, data: vec![1] },
+            TopicEntry { publisher: signer, data: vec![2] },
+        ]);
+
+        // The forged replica didn't take the slot of its publisher
+        let request = Request::Replicate(topic, vec![replica(forged, vec![6], false)]);
+        assert_eq!(dht.on_request(forged, request), Response::Done);
+        // Signatures can't be checked without waiting for the transport
+        let request = Request::Replicate(Id::ZERO, vec![replica(signer, vec![2], true)]);
+        assert_eq!(dht.on_request(sender, request), Response::Done);
+        let storage = dht.storage.read().unwrap();
+        assert_eq!(storage.get(topic).unwrap().last(), Some(&TopicEntry { publisher: forged, data: vec![6] }));
+        assert_eq!(storage.get(Id::ZERO), None);
+    }
+
+    #[tokio::test]
+    async fn disconnect_grace() {
+        let config = SystemConfig {
+            routing: RoutingConfig {
+                bucket_size: 2,
+                bucket_replacement_size: 1,
+                disconnect_grace: Some(Duration::from_secs(60)),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let transport = ConfigurableTestTransport::default();
+        let dht = KademliaDht::new(config, Id::from_hex("a0000000"), transport.clone()).unwrap();
+
+        let [flaky, alive, replacement] = ["a0000100", "a0000101", "a0000110"].map(Id::from_hex);
+        for id in [flaky, alive, replacement] {
+            assert!(dht.on_connect(id));
+        }
+        let closest = |dht: &KademliaDht<_>| dht.tree.lock().unwrap().get_closer_n(flaky, 2);
+        assert_eq!(closest(&dht), vec![flaky, alive]);
+
+        // The node is not used while disconnected but the replacement is not promoted
+        dht.on_disconnect(flaky);
+        assert_eq!(closest(&dht), vec![alive]);
+        assert!(dht.tree.lock().unwrap().is_suspect(flaky));
+        // Suspects still hold their place but don't count as routing nodes
+        assert_eq!(dht.routing_size(), 2);
+
+        // A quick reconnect restores it in place, without any lookup
+        assert!(dht.on_connect(flaky));
+        assert_eq!(closest(&dht), vec![flaky, alive]);
+        assert_eq!(dht.routing_size(), 3);
+        assert!(transport.requests().is_empty());
+
+        // Once the grace period expires the node is removed
+        dht.on_disconnect(flaky);
+        dht.periodic_run();
+        assert!(dht.tree.lock().unwrap().has(flaky));
+        let expired = dht.tree.lock().unwrap()
+            .remove_expired_suspects(Duration::from_secs(60), Instant::now() + Duration::from_secs(60));
+        assert_eq!(expired, vec![flaky]);
+        assert_eq!(closest(&dht), vec![alive, replacement]);
+        assert_eq!(dht.routing_size(), 2);
+    }
+
+    #[tokio::test]
+    async fn disconnect_wanted() {
+        let config = SystemConfig {
+            routing: RoutingConfig {
+                bucket_size: 2,
+                bucket_replacement_size: 1,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let dht = KademliaDht::new(config, Id::from_hex("a0000000"), ConfigurableTestTransport::default()).unwrap();
+
+        let [first, second, replacement] = ["a0000100", "a0000101", "a0000110"].map(Id::from_hex);
+        for id in [first, second, replacement] {
+            assert!(dht.on_connect(id));
+        }
+
+        // The replacement takes its place, nothing to reconnect
+        assert!(!dht.on_disconnect(first));
+        assert!(!dht.is_wanted_for_routing(first));
+
+        // The bucket is left with a free place
+        assert!(dht.on_disconnect(second));
+        assert!(dht.is_wanted_for_routing(second));
+        assert!(dht.on_connect(second));
+        assert!(!dht.is_wanted_for_routing(second));
+    }
+
+    #[tokio::test]
+    async fn oversized_found_nodes() {
+        let config = SystemConfig {
+            routing: RoutingConfig {
+                bucket_size: 2,
+                max_found_nodes_factor: 2,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let transport = ConfigurableTestTransport::default();
+        let dht = KademliaDht::new(config, Id::MAX, transport.clone()).unwrap();
+
+        let malicious = Id::ZERO.set_bit(1);
+        let honest: Vec<_> = (8..12).map(|x| Id::ZERO.set_bit(x)).collect();
+        // Closer to the target than the honest nodes, but past the cap
+        let junk: Vec<_> = (0..1000u16).map(|x| {
+            let mut id = Id::ZERO.set_bit(100);
+            id.0[ID_LEN - 2..].copy_from_slice(&x.to_be_bytes());
+            id
+        }).collect();
+        let response = honest.iter().chain(junk.iter()).copied().collect();
+        transport.set_response(malicious, Ok(Response::FoundNodes(response)));
+        for id in honest.iter() {
+            transport.set_response(*id, Ok(Response::FoundNodes(vec![])));
+        }
+        assert!(dht.on_connect(malicious));
+
+        let found = dht.query_nodes(Id::ZERO, BasicSearchOptions::default()).await;
+        assert_eq!(found, vec![honest[3], honest[2]]);
+        let requests = transport.requests();
+        assert!(junk.iter().all(|x| !requests.contains_key(x)));
+        assert_eq!(requests[&malicious], 1);
+    }
+
+    #[tokio::test]
+    async fn routing_watermarks() {
+        let config = SystemConfig {
+            routing: RoutingConfig {
+                low_watermark: 2,
+                high_watermark: 4,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let dht = KademliaDht::new(config, Id::ZERO, ConfigurableTestTransport::default()).unwrap();
+        let mut events = dht.subscribe_routing();
+        let ids: Vec<_> = (0..4).map(|x| Id::ZERO.set_bit(x * 8)).collect();
+        assert!(dht.is_routing_degraded());
+
+        for id in ids.iter() {
+            dht.on_connect(*id);
+        }
+        assert_eq!(events.try_recv().unwrap(), RoutingEvent::Recovered { size: 4 });
+        assert!(!dht.is_routing_degraded());
+
+        // Still above the low watermark
+        dht.on_disconnect(ids[0]);
+        dht.on_disconnect(ids[1]);
+        assert!(events.try_recv().is_err());
+
+        dht.on_disconnect(ids[2]);
+        assert_eq!(events.try_recv().unwrap(), RoutingEvent::Degraded { size: 1 });
+
+        // Hysteresis: no event until the high watermark is reached again
+        dht.on_connect(ids[0]);
+        dht.on_disconnect(ids[0]);
+        dht.on_connect(ids[0]);
+        dht.on_connect(ids[1]);
+        assert!(events.try_recv().is_err());
+        assert!(dht.is_routing_degraded());
+
+        dht.on_connect(ids[2]);
+        assert_eq!(events.try_recv().unwrap(), RoutingEvent::Recovered { size: 4 });
+        assert!(events.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn runtime_search_limit() {
+        use futures::FutureExt;
+
+        let config = SystemConfig {
+            max_concurrent_searches: NonZeroU64::new(4),
+            ..Default::default()
+        };
+        let dht = KademliaDht::new(config.clone(), Id::MAX, ConfigurableTestTransport::default()).unwrap();
+        assert!(dht.effective_config().overrides.is_empty());
+
+        let running = [dht.start_search().await, dht.start_search().await];
+        assert!(dht.set_max_concurrent_searches(NonZeroU64::new(1).unwrap()));
+
+        let effective = dht.effective_config();
+        assert_eq!(effective.system.max_concurrent_searches, NonZeroU64::new(1));
+        assert_eq!(effective.overrides, ["max_concurrent_searches"]);
+        assert_eq!(dht.config(), &config);
+
+        // Running searches complete, new ones wait for the new limit
+        assert!(dht.start_search().now_or_never().is_none());
+        let [first, second] = running;
+        drop(first);
+        assert!(dht.start_search().now_or_never().is_none());
+        drop(second);
+        let guard = dht.start_search().now_or_never().unwrap();
+        assert!(dht.start_search().now_or_never().is_none());
+
+        // Raising the limit applies immediately
+        assert!(dht.set_max_concurrent_searches(NonZeroU64::new(2).unwrap()));
+        assert!(dht.start_search().now_or_never().is_some());
+        drop(guard);
+
+        // Searches without a configured limit stay unlimited
+        let dht = KademliaDht::new(SystemConfig::default(), Id::MAX, ConfigurableTestTransport::default()).unwrap();
+        assert!(!dht.set_max_concurrent_searches(NonZeroU64::new(1).unwrap()));
+        assert!(dht.effective_config().overrides.is_empty());
+    }
+
+    #[test]
+    fn write_allowlist() {
+        let [allowed, other] = ["b0", "c0"].map(Id::from_hex);
+        let curated = Id::ZERO.set_bit(0);
+        let config = SystemConfig {
+            storage: StorageConfig {
+                write_allowlists: vec![TopicAllowlist {
+                    prefix: curated,
+                    prefix_len: 8,
+                    publishers: vec![allowed],
+                }],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let dht = KademliaDht::new(config, Id::ZERO, ConfigurableTestTransport::default()).unwrap();
+        let topic = curated.set_bit(100);
+
+        assert_eq!(dht.on_request(other, Request::Insert(topic, 60, vec![1])), Response::Error);
+        assert_eq!(dht.on_request(other, Request::Cas(topic, 60, None, vec![1])), Response::Error);
+        assert_eq!(dht.on_request(allowed, Request::Insert(topic, 60, vec![2])), Response::Done);
+        let entries = dht.storage.read().unwrap().get(topic).unwrap().to_vec();
+        assert_eq!(entries, vec![TopicEntry { publisher: allowed, data: vec![2] }]);
+
+        // Topics outside of the prefix are open to anyone
+        let open = Id::ZERO.set_bit(1);
+        assert_eq!(dht.on_request(other, Request::Insert(open, 60, vec![3])), Response::Done);
     }
 }
@@ -9,20 +9,41 @@ use itertools::izip;
 use rand::{distributions::Standard, prelude::Distribution, Rng};
 #[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
 
 use crate::consts::ID_LEN;
 
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum IdParseError {
+    #[error("Odd number of hex digits")]
+    OddLength,
+    #[error("Invalid hex character {c:?} at position {index}")]
+    InvalidCharacter { c: char, index: usize },
+    #[error("Id too long, found {0} hex digits")]
+    TooLong(usize),
+}
+
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+#[error("Invalid id length {0}")]
+pub struct InvalidIdLength(pub usize);
+
+/// Identifier of N bytes (both for nodes and for keys), the DHT uses [`Id`].
 #[derive(Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
-pub struct Id(pub [u8; ID_LEN]);
+pub struct IdN<const N: usize>(pub [u8; N]);
+
+pub type Id = IdN<ID_LEN>;
 
+/// Ids are serialized as base64 strings in human-readable formats,
+/// as in the JSON wire protocol, and as raw bytes in binary formats.
 #[cfg(feature = "serde")]
-impl Serialize for Id {
+impl<const N: usize> Serialize for IdN<N> {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: serde::Serializer,
     {
         if serializer.is_human_readable() {
-            hex::serde::serialize(&self.0, serializer)
+            serializer.collect_str(&base64::display::Base64Display::with_config(&self.0, base64::STANDARD))
         } else {
             serializer.serialize_bytes(&self.0)
         }
@@ -30,59 +51,111 @@ impl Serialize for Id {
 }
 
 #[cfg(feature = "serde")]
-impl<'de> Deserialize<'de> for Id {
+impl<'de, const N: usize> Deserialize<'de> for IdN<N> {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: serde::Deserializer<'de>,
     {
         let raw = if deserializer.is_human_readable() {
-            hex::serde::deserialize(deserializer)?
+            let raw = String::deserialize(deserializer)?;
+            let raw = base64::decode(raw).map_err(serde::de::Error::custom)?;
+            raw.as_slice().try_into()
+                .map_err(|_| serde::de::Error::invalid_length(raw.len(), &IdBytesVisitor::<N>))?
         } else {
-            <_ as Deserialize>::deserialize(deserializer)?
+            deserializer.deserialize_bytes(IdBytesVisitor::<N>)?
         };
-        Ok(Id(raw))
+        Ok(IdN(raw))
     }
 }
 
-impl BitXor<Id> for Id {
-    type Output = Id;
+#[cfg(feature = "serde")]
+struct IdBytesVisitor<const N: usize>;
+
+#[cfg(feature = "serde")]
+impl<'de, const N: usize> serde::de::Visitor<'de> for IdBytesVisitor<N> {
+    type Value = [u8; N];
 
-    fn bitxor(self, rhs: Id) -> Self::Output {
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(formatter, "{N} bytes")
+    }
+
+    fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        v.try_into().map_err(|_| E::invalid_length(v.len(), &self))
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+    where
+        A: serde::de::SeqAccess<'de>,
+    {
+        // Formats without native bytes encode them as a sequence
+        let mut res = [0u8; N];
+        for (i, x) in res.iter_mut().enumerate() {
+            *x = seq.next_element()?.ok_or_else(|| serde::de::Error::invalid_length(i, &self))?;
+        }
+        if seq.next_element::<u8>()?.is_some() {
+            return Err(serde::de::Error::invalid_length(N + 1, &self));
+        }
+        Ok(res)
+    }
+}
+
+impl<const N: usize> BitXor<IdN<N>> for IdN<N> {
+    type Output = IdN<N>;
+
+    fn bitxor(self, rhs: IdN<N>) -> Self::Output {
         self.bimap_bytes(rhs, |a, b| a ^ b)
     }
 }
 
-impl BitAnd<Id> for Id {
-    type Output = Id;
+impl<const N: usize> BitAnd<IdN<N>> for IdN<N> {
+    type Output = IdN<N>;
 
-    fn bitand(self, rhs: Id) -> Self::Output {
+    fn bitand(self, rhs: IdN<N>) -> Self::Output {
         self.bimap_bytes(rhs, |a, b| a & b)
     }
 }
 
-impl BitOr<Id> for Id {
-    type Output = Id;
+impl<const N: usize> BitOr<IdN<N>> for IdN<N> {
+    type Output = IdN<N>;
 
-    fn bitor(self, rhs: Id) -> Self::Output {
+    fn bitor(self, rhs: IdN<N>) -> Self::Output {
         self.bimap_bytes(rhs, |a, b| a | b)
     }
 }
 
-impl Not for Id {
-    type Output = Id;
+impl<const N: usize> Not for IdN<N> {
+    type Output = IdN<N>;
 
     fn not(self) -> Self::Output {
         self.map_bytes(|x| !x)
     }
 }
 
-impl Id {
-    pub const ZERO: Id = Id([0; ID_LEN]);
-    pub const MAX: Id = Id([0xFF; ID_LEN]);
+impl<const N: usize> IdN<N> {
+    pub const ZERO: Self = IdN([0; N]);
+    pub const MAX: Self = IdN([0xFF; N]);
+    pub const BITS: usize = N * 8;
+
+    pub fn as_bytes(&self) -> &[u8; N] {
+        &self.0
+    }
+
+    /// Id made of the first `N` bytes of hash (the rest is discarded),
+    /// every id derived from a hash (topics, node identities) must use this.
+    ///
+    /// # Panics
+    /// If hash is shorter than `N` bytes.
+    pub fn from_hash_truncated(hash: &[u8]) -> Self {
+        assert!(hash.len() >= N, "Hash too short for an id ({} bytes)", hash.len());
+        Self::try_from(&hash[..N]).unwrap()
+    }
 
     pub fn create_left_mask(mut len: u8) -> Self {
         let mut res = Self::ZERO;
-        let mut i = ID_LEN - 1;
+        let mut i = N - 1;
         loop {
             if len > 8 {
                 res.0[i] = 0xFF;
@@ -103,8 +176,8 @@ impl Id {
         res
     }
 
-    pub fn bimap_bytes(self, rhs: Id, fun: impl Fn(u8, u8) -> u8) -> Self {
-        let mut res = Id([0u8; ID_LEN]);
+    pub fn bimap_bytes(self, rhs: Self, fun: impl Fn(u8, u8) -> u8) -> Self {
+        let mut res = Self::ZERO;
         for (a, b, r) in izip!(&self.0, &rhs.0, &mut res.0) {
             *r = fun(*a, *b);
         }
@@ -113,7 +186,7 @@ impl Id {
     }
 
     pub fn map_bytes(self, fun: impl Fn(u8) -> u8) -> Self {
-        let mut res = Id([0u8; ID_LEN]);
+        let mut res = Self::ZERO;
         for (a, r) in self.0.iter().zip(&mut res.0) {
             *r = fun(*a);
         }
@@ -121,19 +194,26 @@ impl Id {
         res
     }
 
+    /// Saturates at 255 for ids longer than 31 bytes.
     pub fn leading_zeros(&self) -> u8 {
-        let mut res = 0u8;
+        let mut res: u8 = 0;
         for x in self.0 {
             if x == 0 {
-                res += 8;
+                res = res.saturating_add(8);
             } else {
-                res += x.leading_zeros() as u8;
+                res = res.saturating_add(x.leading_zeros() as u8);
                 break;
             }
         }
         res
     }
 
+    /// Number of leading bits shared with other (the leading zeros of their
+    /// XOR distance), higher values mean closer ids.
+    pub fn common_prefix_len(&self, other: &Self) -> u8 {
+        (*self ^ *other).leading_zeros()
+    }
+
     pub fn bitslice(&self, index: u32, len: u8) -> u8 {
         let entryi = (index / 8) as usize;
         let bytei = index as u8 & 7;
@@ -151,50 +231,75 @@ impl Id {
         }
     }
 
+    /// Full fixed-width hex encoding, the canonical textual representation
+    /// (the wire protocol uses base64, see the serde impl).
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.0)
+    }
+
+    /// Hex encoding without leading zero bytes, shorter but only meant for
+    /// humans (ex. logs and [`Display`]), prefer [`IdN::to_hex`] when the id
+    /// will be read back.
     pub fn as_short_hex(&self) -> String {
-        let hex_id = hex::encode(&self.0);
+        let hex_id = hex::encode(self.0);
         hex_id.trim_start_matches("00").to_owned()
     }
 
-    pub fn from_hex(data: &str) -> Id {
+    pub fn from_hex(data: &str) -> Self {
         Self::from_str(data).expect("Invalid provided string")
     }
 }
 
-impl FromStr for Id {
-    type Err = FromHexError;
+/// Parses both full-length and short (as in [`IdN::as_short_hex`]) hex ids,
+/// short ids are padded with zeros on the left.
+impl<const N: usize> FromStr for IdN<N> {
+    type Err = IdParseError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut id = Id([0; ID_LEN]);
-        let r = hex::decode(s)?;
-        for (i, r) in r.iter().rev().enumerate() {
-            id.0[id.0.len() - i - 1] = *r;
+        if s.len() > N * 2 {
+            return Err(IdParseError::TooLong(s.len()));
         }
+        let r = hex::decode(s).map_err(|e| match e {
+            FromHexError::InvalidHexCharacter { c, index } => IdParseError::InvalidCharacter { c, index },
+            _ => IdParseError::OddLength,
+        })?;
+        let mut id = Self::ZERO;
+        id.0[N - r.len()..].copy_from_slice(&r);
         Ok(id)
     }
 }
 
-impl Debug for Id {
+impl<const N: usize> TryFrom<&[u8]> for IdN<N> {
+    type Error = InvalidIdLength;
+
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        value.try_into()
+            .map(IdN)
+            .map_err(|_| InvalidIdLength(value.len()))
+    }
+}
+
+impl<const N: usize> Debug for IdN<N> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let short_id = self.as_short_hex();
         f.debug_tuple("Id").field(&short_id).finish()
     }
 }
 
-impl Display for Id {
+impl<const N: usize> Display for IdN<N> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let short_id = self.as_short_hex();
         f.write_str(&short_id)
     }
 }
 
-impl Distribution<Id> for Standard {
-    fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> Id {
-        let mut id = [0u8; ID_LEN];
+impl<const N: usize> Distribution<IdN<N>> for Standard {
+    fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> IdN<N> {
+        let mut id = [0u8; N];
         for i in &mut id {
             *i = rng.gen();
         }
-        Id(id)
+        IdN(id)
     }
 }
 
@@ -204,8 +309,8 @@ mod tests {
 
     #[test]
     fn operations() {
-        let a = Id([1; ID_LEN]);
-        let b = Id([0; ID_LEN]);
+        let a = IdN([1; ID_LEN]);
+        let b = IdN([0; ID_LEN]);
         // Xor
         assert_eq!(a ^ a, b);
         assert_eq!(a ^ b, a);
@@ -252,16 +357,31 @@ mod tests {
 
     #[test]
     fn leading_zeros() {
-        let mut a = Id([0; ID_LEN]);
+        let mut a = IdN([0; ID_LEN]);
         a.0[9] = 2;
         assert_eq!(a.leading_zeros(), 9 * 8 + 6);
         a.0[0] = 1;
         assert_eq!(a.leading_zeros(), 7);
     }
 
+    #[test]
+    fn common_prefix_len() {
+        let a = Id::ZERO.set_bit(10);
+        assert_eq!(a.common_prefix_len(&a), ID_LEN as u8 * 8);
+        assert_eq!(a.common_prefix_len(&Id::ZERO), 10);
+        assert_eq!(Id::ZERO.common_prefix_len(&Id::MAX), 0);
+
+        let mut rng = rand::thread_rng();
+        for _ in 0..100 {
+            let (a, b): (Id, Id) = (rng.gen(), rng.gen());
+            assert_eq!(a.common_prefix_len(&b), (a ^ b).leading_zeros());
+            assert_eq!(a.common_prefix_len(&b), b.common_prefix_len(&a));
+        }
+    }
+
     #[test]
     fn bitslice() {
-        let mut a = Id([0; ID_LEN]);
+        let mut a = IdN([0; ID_LEN]);
         a.0[3] = 0b11111111;
         a.0[4] = 0b10101010;
         a.0[5] = 0b01010101;
@@ -279,4 +399,86 @@ mod tests {
 
         assert_eq!(a.bitslice(3 * 8 + 4, 8), 0b11111010);
     }
+
+    #[test]
+    fn parse() {
+        assert_eq!("".parse::<Id>(), Ok(Id::ZERO));
+        assert_eq!("0".repeat(ID_LEN * 2).parse::<Id>(), Ok(Id::ZERO));
+        assert_eq!("ff".repeat(ID_LEN).parse::<Id>(), Ok(Id::MAX));
+
+        let mut a = Id::ZERO;
+        a.0[ID_LEN - 2] = 0x12;
+        a.0[ID_LEN - 1] = 0xab;
+        assert_eq!("12AB".parse::<Id>(), Ok(a));
+        assert_eq!(format!("{}12ab", "0".repeat(ID_LEN * 2 - 4)).parse::<Id>(), Ok(a));
+
+        assert_eq!("123".parse::<Id>(), Err(IdParseError::OddLength));
+        assert_eq!("12zz".parse::<Id>(), Err(IdParseError::InvalidCharacter { c: 'z', index: 2 }));
+        assert_eq!("00".repeat(ID_LEN + 1).parse::<Id>(), Err(IdParseError::TooLong(ID_LEN * 2 + 2)));
+
+        let mut rng = rand::thread_rng();
+        for id in [Id::ZERO, Id::MAX, a, Id::ZERO.set_bit(0), rng.gen(), rng.gen()] {
+            assert_eq!(Id::from_str(&id.as_short_hex()), Ok(id));
+            assert_eq!(Id::from_str(&id.to_hex()), Ok(id));
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_roundtrip() {
+        let a: Id = rand::thread_rng().gen();
+
+        let json = serde_json::to_string(&a).unwrap();
+        assert_eq!(json, format!("\"{}\"", base64::encode(a.0)));
+        assert_eq!(serde_json::from_str::<Id>(&json).unwrap(), a);
+        assert_eq!(serde_json::to_string(&Id::ZERO).unwrap(), format!("\"{}=\"", "A".repeat(27)));
+        // Wrong length or not base64
+        assert!(serde_json::from_str::<Id>(&format!("\"{}\"", base64::encode([1; ID_LEN - 1]))).is_err());
+        assert!(serde_json::from_str::<Id>(&format!("\"{}\"", a.to_hex())).is_err());
+
+        // Length prefix + raw bytes
+        let binary = bincode::serialize(&a).unwrap();
+        assert_eq!(binary.len(), 8 + ID_LEN);
+        assert_eq!(&binary[8..], &a.0);
+        assert_eq!(bincode::deserialize::<Id>(&binary).unwrap(), a);
+
+        assert!(bincode::deserialize::<Id>(&bincode::serialize(&[1u8; ID_LEN - 1][..]).unwrap()).is_err());
+    }
+
+    #[test]
+    fn from_bytes() {
+        let bytes: Vec<u8> = (0..ID_LEN as u8).collect();
+        let a = Id::try_from(&bytes[..]).unwrap();
+        assert_eq!(&a.as_bytes()[..], &bytes[..]);
+
+        assert_eq!(Id::try_from(&bytes[..ID_LEN - 1]), Err(InvalidIdLength(ID_LEN - 1)));
+        assert_eq!(Id::try_from(&[][..]), Err(InvalidIdLength(0)));
+        let long = [0u8; ID_LEN + 1];
+        assert_eq!(Id::try_from(&long[..]), Err(InvalidIdLength(ID_LEN + 1)));
+    }
+
+    #[test]
+    fn from_hash_truncated() {
+        // SHA-256 sized hash, the leading bytes are kept
+        let hash: Vec<u8> = (0..32).collect();
+        let id = Id::from_hash_truncated(&hash);
+        assert_eq!(id.to_hex(), "000102030405060708090a0b0c0d0e0f10111213");
+        assert_eq!(Id::from_hash_truncated(&hash[..ID_LEN]), id);
+    }
+
+    #[test]
+    #[should_panic]
+    fn from_hash_too_short() {
+        Id::from_hash_truncated(&[0; ID_LEN - 1]);
+    }
+
+    #[test]
+    fn format() {
+        let a = Id::ZERO.set_bit(ID_LEN as u8 * 8 - 1);
+        let full = format!("{}01", "00".repeat(ID_LEN - 1));
+        assert_eq!(a.to_hex(), full);
+        assert_eq!(a.as_short_hex(), "01");
+        assert_eq!(a.to_string(), "01");
+        assert_eq!(format!("{a:?}"), r#"Id("01")"#);
+    }
 }
@@ -1,13 +1,33 @@
-use crate::{config::RoutingConfig, id::Id, transport::TransportSender};
+use crate::{config::RoutingConfig, consts::ID_LEN, id::{Id, IdN}, transport::TransportSender};
 
-#[derive(Debug, Default)]
-pub struct KBucket {
-    pub entries: Vec<Id>,
-    pub replacement_cache: Vec<Id>,
+/// Pings the nodes of full buckets (to find the ones to replace)
+pub trait Pinger<const N: usize> {
+    fn ping(&self, id: IdN<N>);
 }
 
-impl KBucket {
-    pub fn refresh_node(&mut self, id: Id) -> bool {
+impl<T: TransportSender> Pinger<ID_LEN> for T {
+    fn ping(&self, id: Id) {
+        TransportSender::ping(self, id)
+    }
+}
+
+#[derive(Debug)]
+pub struct KBucket<const N: usize = ID_LEN> {
+    pub entries: Vec<IdN<N>>,
+    pub replacement_cache: Vec<IdN<N>>,
+}
+
+impl<const N: usize> Default for KBucket<N> {
+    fn default() -> Self {
+        KBucket {
+            entries: Vec::new(),
+            replacement_cache: Vec::new(),
+        }
+    }
+}
+
+impl<const N: usize> KBucket<N> {
+    pub fn refresh_node(&mut self, id: IdN<N>) -> bool {
         let entry = self.entries.iter_mut().enumerate().find(|(_, x)| **x == id);
 
         match entry {
@@ -20,13 +40,13 @@ impl KBucket {
         }
     }
 
-    pub fn has(&self, id: Id) -> bool {
+    pub fn has(&self, id: IdN<N>) -> bool {
         (self.entries.iter().chain(self.replacement_cache.iter())).any(|x| *x == id)
     }
 
-    pub fn insert<T: TransportSender>(
+    pub fn insert<T: Pinger<N>>(
         &mut self,
-        id: Id,
+        id: IdN<N>,
         config: &RoutingConfig,
         contacter: &T,
     ) -> bool {
@@ -49,7 +69,7 @@ impl KBucket {
         }
     }
 
-    pub fn remove(&mut self, id: Id) -> bool {
+    pub fn remove(&mut self, id: IdN<N>) -> bool {
         let i = self.entries.iter().position(|x| *x == id);
         if let Some(i) = i {
             self.entries.remove(i);
@@ -1,73 +1,108 @@
+use std::{collections::HashMap, time::Duration};
+
+use instant::Instant;
+
 use crate::{
-    config::RoutingConfig, consts::ID_LEN_BITS, id::Id, kbucket::KBucket,
-    transport::TransportSender,
+    config::{ConfigError, RoutingConfig}, consts::ID_LEN, id::IdN, kbucket::{KBucket, Pinger},
 };
 
-pub struct KTreeEntry {
-    buckets: Vec<KBucket>,
+pub struct KTreeEntry<const N: usize> {
+    buckets: Vec<KBucket<N>>,
 }
 
-impl KTreeEntry {
+impl<const N: usize> KTreeEntry<N> {
     pub fn new(config: &RoutingConfig) -> Self {
         KTreeEntry {
             // Create 2**buckets_per_bit buckets (each bit is one entry)
-            buckets: (0..1 << (config.buckets_per_bit as usize - 1))
-                .into_iter()
+            buckets: (0..1 << (config.buckets_per_bit - 1))
                 .map(|_| KBucket::default())
                 .collect(),
         }
     }
 }
 
-pub struct KTree {
-    id: Id,
+/// Routing table of ids with N bytes (one entry per bit)
+pub struct KTree<const N: usize = ID_LEN> {
+    id: IdN<N>,
     config: RoutingConfig,
-    nodes: [KTreeEntry; ID_LEN_BITS],
+    nodes: Vec<KTreeEntry<N>>,
+    // Nodes held by the buckets, suspects included (they keep their place)
     size: u64,
+    // Disconnected nodes that keep their place until they reconnect or
+    // their grace period expires (with the disconnection time)
+    suspects: HashMap<IdN<N>, Instant>,
 }
 
-impl KTree {
-    pub fn new(id: Id, config: RoutingConfig) -> Self {
-        let nodes = [(); ID_LEN_BITS].map(|_| KTreeEntry::new(&config));
-        KTree {
+impl<const N: usize> KTree<N> {
+    /// Panics if the config is not valid, see [`KTree::try_new`]
+    pub fn new(id: IdN<N>, config: RoutingConfig) -> Self {
+        match Self::try_new(id, config) {
+            Ok(x) => x,
+            Err(e) => panic!("Invalid routing config: {e}"),
+        }
+    }
+
+    pub fn try_new(id: IdN<N>, config: RoutingConfig) -> Result<Self, ConfigError> {
+        config.validate()?;
+        let nodes = (0..IdN::<N>::BITS).map(|_| KTreeEntry::new(&config)).collect();
+        Ok(KTree {
             id,
             config,
             nodes,
             size: 0,
-        }
+            suspects: HashMap::new(),
+        })
     }
 
-    fn get_bucket_index(&self, id: Id) -> (usize, usize) {
-        let nid = self.id ^ id;
-        let entryi = nid
-            .leading_zeros()
-            .min((ID_LEN_BITS - self.config.buckets_per_bit) as u8);
-        let bucketi = if self.config.buckets_per_bit == 1 {
-            0 // fast path (please compiler optimize it away)
-        } else {
-            nid.bitslice(entryi as u32 + 1, self.config.buckets_per_bit as u8 - 1) as usize
-        };
-        (entryi as usize, bucketi)
+    fn get_bucket_index(&self, id: IdN<N>) -> (usize, usize) {
+        self.config.bucket_index_for(self.id, id)
     }
 
-    fn get_bucket(&self, id: Id) -> &KBucket {
+    fn get_bucket(&self, id: IdN<N>) -> &KBucket<N> {
         let indexes = self.get_bucket_index(id);
         &self.nodes[indexes.0].buckets[indexes.1]
     }
 
-    fn get_bucket_mut(&mut self, id: Id) -> &mut KBucket {
+    fn get_bucket_mut(&mut self, id: IdN<N>) -> &mut KBucket<N> {
         let indexes = self.get_bucket_index(id);
         &mut self.nodes[indexes.0].buckets[indexes.1]
     }
 
-    pub fn has(&self, id: Id) -> bool {
+    /// Number of nodes in the routing table (replacement caches included),
+    /// suspect nodes are not counted since they are not used in lookups
+    pub fn size(&self) -> u64 {
+        self.size - self.suspects.len() as u64
+    }
+
+    pub fn has(&self, id: IdN<N>) -> bool {
         self.get_bucket(id).has(id)
     }
 
-    pub fn insert<T: TransportSender>(&mut self, id: Id, contacter: &T) -> bool {
+    /// Returns true if id is held in its bucket (replacement cache excluded)
+    pub fn is_routed(&self, id: IdN<N>) -> bool {
+        self.get_bucket(id).entries.contains(&id)
+    }
+
+    /// Returns true if the bucket of id has free places
+    pub fn has_room_for(&self, id: IdN<N>) -> bool {
+        self.get_bucket(id).entries.len() < self.config.bucket_size
+    }
+
+    /// Ids held in the buckets (replacement caches excluded)
+    pub fn iter_ids(&self) -> impl Iterator<Item = IdN<N>> + '_ {
+        self.nodes.iter()
+            .flat_map(|x| x.buckets.iter())
+            .flat_map(|x| x.entries.iter().copied())
+    }
+
+    pub fn insert<T: Pinger<N>>(&mut self, id: IdN<N>, contacter: &T) -> bool {
         if id == self.id {
             return false;
         }
+        // The node reconnected during its grace period, it never lost its place
+        if self.suspects.remove(&id).is_some() {
+            return true;
+        }
         // Check max connection count
         if self
             .config
@@ -85,7 +120,8 @@ impl KTree {
         inserted
     }
 
-    pub fn remove(&mut self, id: Id) -> bool {
+    pub fn remove(&mut self, id: IdN<N>) -> bool {
+        self.suspects.remove(&id);
         let removed = self.get_bucket_mut(id).remove(id);
         if removed {
             self.size -= 1;
@@ -93,12 +129,39 @@ impl KTree {
         removed
     }
 
-    pub fn refresh(&mut self, id: Id) -> bool {
+    pub fn refresh(&mut self, id: IdN<N>) -> bool {
         self.get_bucket_mut(id).refresh_node(id)
     }
 
-    pub fn get_closer_n(&self, closer_to: Id, size: usize) -> Vec<Id> {
-        let mut res = NodeAggregator::new(size);
+    /// Keeps a disconnected routing node in its bucket, without returning it
+    /// in lookups, until it reconnects (see [`KTree::insert`]) or is removed.
+    /// Nodes in the replacement caches are not marked, returns true if marked
+    pub fn mark_suspect(&mut self, id: IdN<N>, now: Instant) -> bool {
+        if !self.is_routed(id) {
+            return false;
+        }
+        self.suspects.entry(id).or_insert(now);
+        true
+    }
+
+    pub fn is_suspect(&self, id: IdN<N>) -> bool {
+        self.suspects.contains_key(&id)
+    }
+
+    /// Removes the suspect nodes disconnected for at least grace, returning them
+    pub fn remove_expired_suspects(&mut self, grace: Duration, now: Instant) -> Vec<IdN<N>> {
+        let expired: Vec<_> = self.suspects.iter()
+            .filter(|(_, since)| now.saturating_duration_since(**since) >= grace)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in expired.iter() {
+            self.remove(*id);
+        }
+        expired
+    }
+
+    pub fn get_closer_n(&self, closer_to: IdN<N>, size: usize) -> Vec<IdN<N>> {
+        let mut res = NodeAggregator::new(size, &self.suspects);
         let index = self.get_bucket_index(closer_to);
 
         let fentry = &self.nodes[index.0];
@@ -193,16 +256,19 @@ impl KTree {
 }
 
 /// Utility struct that manages nodes aggregation for closer_n queries
-struct NodeAggregator {
-    nodes: Vec<Id>,
+struct NodeAggregator<'a, const N: usize> {
+    nodes: Vec<IdN<N>>,
     limit: usize,
+    // Nodes never returned
+    suspects: &'a HashMap<IdN<N>, Instant>,
 }
 
-impl NodeAggregator {
-    pub fn new(limit: usize) -> Self {
+impl<'a, const N: usize> NodeAggregator<'a, N> {
+    pub fn new(limit: usize, suspects: &'a HashMap<IdN<N>, Instant>) -> Self {
         NodeAggregator {
             nodes: Vec::new(),
             limit,
+            suspects,
         }
     }
 
@@ -210,22 +276,23 @@ impl NodeAggregator {
         self.nodes.len() >= self.limit
     }
 
-    pub fn add_bucket(&mut self, bucket: &KBucket) {
-        for x in bucket.entries.iter() {
+    pub fn add_bucket(&mut self, bucket: &KBucket<N>) {
+        for x in bucket.entries.iter().filter(|x| !self.suspects.contains_key(x)) {
             self.nodes.push(*x);
         }
     }
 
-    pub fn add_entry(&mut self, entry: &KTreeEntry) {
+    pub fn add_entry(&mut self, entry: &KTreeEntry<N>) {
         for x in entry.buckets.iter() {
             self.add_bucket(x);
         }
     }
 
-    pub fn finish(self, closer_to: Id) -> Vec<Id> {
+    pub fn finish(self, closer_to: IdN<N>) -> Vec<IdN<N>> {
         let Self {
             nodes: mut vec,
             limit,
+            ..
         } = self;
         vec.sort_unstable_by_key(|x| closer_to ^ *x);
         vec.truncate(limit);
@@ -235,32 +302,61 @@ impl NodeAggregator {
 
 #[cfg(test)]
 mod tests {
-    use std::{
-        collections::HashMap,
-        future,
-        sync::{Arc, Mutex, MutexGuard},
-    };
+    use std::collections::HashMap;
+
+    use itertools::Itertools;
 
-    use crate::transport::{Response, TransportError};
+    use crate::{config::MAX_BUCKETS_PER_BIT, transport::testing::ConfigurableTestTransport, Id};
 
     use super::*;
 
-    #[derive(Clone)]
-    struct IgnoreContacter;
+    struct NoPing;
+
+    impl<const N: usize> Pinger<N> for NoPing {
+        fn ping(&self, _id: IdN<N>) {}
+    }
 
-    impl TransportSender for IgnoreContacter {
-        fn ping(&self, _id: Id) {}
+    fn closest_of_width<const N: usize>() {
+        let config = RoutingConfig { bucket_size: 2, ..Default::default() };
+        let mut tree = KTree::new(IdN::<N>::ZERO, config);
+        assert_eq!(tree.nodes.len(), N * 8);
 
-        type Fut = future::Ready<Result<Response, TransportError>>;
-        fn send(&self, _id: Id, _msg: crate::transport::Request) -> Self::Fut {
-            panic!();
+        // One id per entry, the last bit is the closest one
+        let ids: Vec<_> = (0..N * 8).map(|x| IdN::<N>::ZERO.set_bit(x as u8)).collect();
+        for id in ids.iter() {
+            assert!(tree.insert(*id, &NoPing));
         }
+        assert_eq!(tree.size(), (N * 8) as u64);
+        assert_eq!(tree.get_bucket_index(ids[N * 8 - 1]), (N * 8 - 1, 0));
+        assert_eq!(tree.get_closer_n(IdN::ZERO, 2), vec![ids[N * 8 - 1], ids[N * 8 - 2]]);
+        assert_eq!(tree.get_closer_n(IdN::MAX, 1), vec![ids[0]]);
+    }
+
+    #[test]
+    fn id_widths() {
+        closest_of_width::<ID_LEN>();
+        closest_of_width::<32>();
+    }
 
-        type Contact = Id;
+    #[test]
+    fn iter_ids() {
+        let config = RoutingConfig {
+            bucket_size: 2,
+            bucket_replacement_size: 1,
+            ..Default::default()
+        };
+        let mut tree = KTree::new(Id::ZERO, config);
+        assert_eq!(tree.iter_ids().count(), 0);
 
-        fn wrap_contact(&self, _id: Id) -> Self::Contact {
-            panic!();
+        let far: Vec<_> = (1..4).map(|x| Id::ZERO.set_bit(0).set_bit(150 + x)).collect();
+        let near = Id::ZERO.set_bit(100);
+        for id in far.iter().chain([&near]) {
+            assert!(tree.insert(*id, &NoPing));
         }
+        // The third far id only entered the replacement cache
+        let ids: Vec<_> = tree.iter_ids().sorted().collect();
+        assert_eq!(ids, [near, far[1], far[0]]);
+        assert_eq!(tree.size(), 4);
     }
 
     #[test]
@@ -275,7 +371,7 @@ mod tests {
         let mut tree = KTree::new(id, config);
         // Add similar entries to the same bucket,
         // since the bucket size is 2 it will overflow
-        let contacter = &mut IgnoreContacter;
+        let contacter = &ConfigurableTestTransport::default();
         assert_eq!(tree.insert(Id::from_hex("b0000001"), contacter), true);
         assert_eq!(tree.insert(Id::from_hex("b0000010"), contacter), true);
         assert_eq!(tree.insert(Id::from_hex("b0000011"), contacter), true); // cache
@@ -308,7 +404,7 @@ mod tests {
         };
 
         let mut tree = KTree::new(id, config);
-        let contacter = &mut IgnoreContacter;
+        let contacter = &ConfigurableTestTransport::default();
 
         tree.insert(Id::from_hex("b0000000"), contacter);
         tree.insert(Id::from_hex("b0001000"), contacter);
@@ -331,32 +427,6 @@ mod tests {
         );
     }
 
-    #[derive(Clone)]
-    struct MapContacter(pub Arc<Mutex<HashMap<Id, usize>>>);
-
-    impl MapContacter {
-        pub fn inner(&self) -> MutexGuard<HashMap<Id, usize>> {
-            self.0.lock().unwrap()
-        }
-    }
-
-    impl TransportSender for MapContacter {
-        fn ping(&self, id: Id) {
-            *self.inner().entry(id).or_insert(0) += 1;
-        }
-
-        type Fut = future::Ready<Result<Response, TransportError>>;
-        fn send(&self, _id: Id, _msg: crate::transport::Request) -> Self::Fut {
-            panic!();
-        }
-
-        type Contact = Id;
-
-        fn wrap_contact(&self, _id: Id) -> Self::Contact {
-            panic!()
-        }
-    }
-
     #[test]
     fn ping() {
         let id = Id::from_hex("a0000000");
@@ -369,44 +439,44 @@ mod tests {
         let mut tree = KTree::new(id, config);
         // Add similar entries to the same bucket,
         // since the bucket size is 2 it will overflow
-        let mut contacter = MapContacter(Default::default());
+        let contacter = ConfigurableTestTransport::default();
 
         // closer bucket (0)
-        assert_eq!(tree.insert(Id::from_hex("a0000001"), &mut contacter), true);
+        assert_eq!(tree.insert(Id::from_hex("a0000001"), &contacter), true);
         // bucket 1
-        assert_eq!(tree.insert(Id::from_hex("a0000010"), &mut contacter), true);
-        assert_eq!(tree.insert(Id::from_hex("a0000011"), &mut contacter), true);
+        assert_eq!(tree.insert(Id::from_hex("a0000010"), &contacter), true);
+        assert_eq!(tree.insert(Id::from_hex("a0000011"), &contacter), true);
         // bucket 2
-        assert_eq!(tree.insert(Id::from_hex("a0000100"), &mut contacter), true);
-        assert_eq!(tree.insert(Id::from_hex("a0000101"), &mut contacter), true);
-        assert!(contacter.inner().is_empty());
-        assert_eq!(tree.insert(Id::from_hex("a0000110"), &mut contacter), true); // cache
+        assert_eq!(tree.insert(Id::from_hex("a0000100"), &contacter), true);
+        assert_eq!(tree.insert(Id::from_hex("a0000101"), &contacter), true);
+        assert!(contacter.pings().is_empty());
+        assert_eq!(tree.insert(Id::from_hex("a0000110"), &contacter), true); // cache
                                                                                  // should only ping bucket 2!
         assert_eq!(
-            *contacter.inner(),
+            contacter.pings(),
             HashMap::from([
                 (Id::from_hex("a0000100"), 1usize),
                 (Id::from_hex("a0000101"), 1),
             ])
         );
         // second cache entry SHOULD reping, it's the contacter job do deduplicate pings
-        assert_eq!(tree.insert(Id::from_hex("a0000111"), &mut contacter), true); // cache 2
+        assert_eq!(tree.insert(Id::from_hex("a0000111"), &contacter), true); // cache 2
         assert_eq!(
-            *contacter.inner(),
+            contacter.pings(),
             HashMap::from([
                 (Id::from_hex("a0000100"), 2usize),
                 (Id::from_hex("a0000101"), 2),
             ])
         );
 
-        let old_map = contacter.inner().clone();
+        let old_map = contacter.pings();
         // client a100 disconnects, so a110 enters cache and we can insert a111 (well, in the cache)
         tree.remove(Id::from_hex("a0000100"));
-        assert_eq!(*contacter.inner(), old_map);
-        contacter.inner().clear();
-        assert_eq!(tree.insert(Id::from_hex("a0000100"), &mut contacter), true); // cached
+        assert_eq!(contacter.pings(), old_map);
+        contacter.clear_pings();
+        assert_eq!(tree.insert(Id::from_hex("a0000100"), &contacter), true); // cached
         assert_eq!(
-            *contacter.inner(),
+            contacter.pings(),
             HashMap::from([
                 (Id::from_hex("a0000101"), 1),
                 (Id::from_hex("a0000110"), 1), // promoted from cache and contacted
@@ -427,7 +497,7 @@ mod tests {
 
         // Add similar entries to the same bucket,
         // since the bucket size is 2 it will overflow
-        let contacter = &mut IgnoreContacter;
+        let contacter = &ConfigurableTestTransport::default();
         assert_eq!(tree.insert(Id::from_hex("b0000001"), contacter), true);
         assert_eq!(tree.insert(Id::from_hex("b0000010"), contacter), true);
         assert_eq!(tree.insert(Id::from_hex("b0000011"), contacter), true); // cache
@@ -446,4 +516,59 @@ mod tests {
         assert_eq!(tree.insert(Id::from_hex("e0000011"), contacter), true); // cache
         assert_eq!(tree.insert(Id::from_hex("e0000100"), contacter), false); // full
     }
+
+    #[test]
+    fn bucket_index_for() {
+        let id = Id::from_hex("a0000000");
+        let contacter = &ConfigurableTestTransport::default();
+        let targets = ["a0000001", "a0000003", "b0000000", "20000000", "a8000000"].map(Id::from_hex);
+        for buckets_per_bit in 1..=3 {
+            let config = RoutingConfig {
+                buckets_per_bit,
+                ..Default::default()
+            };
+            let mut tree = KTree::new(id, config.clone());
+            for target in targets {
+                let (entryi, bucketi) = config.bucket_index_for(id, target);
+                assert!(bucketi < 1 << (buckets_per_bit - 1));
+                tree.insert(target, contacter);
+                assert!(tree.nodes[entryi].buckets[bucketi].has(target));
+            }
+        }
+
+        // a0000000 ^ a0000003 = 3: 158 leading zeros, then the bits "1"
+        let config = RoutingConfig { buckets_per_bit: 2, ..Default::default() };
+        assert_eq!(config.bucket_index_for(id, targets[1]), (158, 1));
+        // The last entries are merged to leave space for the bucket bits
+        assert_eq!(config.bucket_index_for(id, id), (158, 0));
+        assert_eq!(config.bucket_index_for(id, targets[0]), (158, 1));
+        // 10000000: 131 leading zeros, the next bit is 0
+        assert_eq!(config.bucket_index_for(id, targets[2]), (131, 0));
+    }
+
+    #[test]
+    fn invalid_config() {
+        let tree = |config| KTree::try_new(Id::ZERO, config).map(|_| ());
+        assert_eq!(tree(RoutingConfig::default()), Ok(()));
+
+        let zero_bucket = RoutingConfig { bucket_size: 0, bucket_replacement_size: 0, ..Default::default() };
+        assert_eq!(tree(zero_bucket), Err(ConfigError::BucketSizeTooSmall(0)));
+        let large_replacement = RoutingConfig { bucket_size: 2, bucket_replacement_size: 3, ..Default::default() };
+        assert_eq!(tree(large_replacement), Err(ConfigError::ReplacementSizeTooLarge(3, 2)));
+        for buckets_per_bit in [0, MAX_BUCKETS_PER_BIT + 1] {
+            let config = RoutingConfig { buckets_per_bit, ..Default::default() };
+            assert_eq!(tree(config), Err(ConfigError::InvalidBucketsPerBit(buckets_per_bit)));
+        }
+        let watermarks = RoutingConfig { low_watermark: 5, high_watermark: 4, ..Default::default() };
+        assert_eq!(tree(watermarks), Err(ConfigError::InvalidWatermarks(4, 5)));
+        let factor = RoutingConfig { max_found_nodes_factor: 0, ..Default::default() };
+        assert_eq!(tree(factor), Err(ConfigError::ZeroFoundNodesFactor));
+    }
+
+    #[test]
+    #[should_panic(expected = "bucket_size must be at least")]
+    fn zero_bucket_size_panics() {
+        let config = RoutingConfig { bucket_size: 0, bucket_replacement_size: 0, ..Default::default() };
+        KTree::new(Id::ZERO, config);
+    }
 }
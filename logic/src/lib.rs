@@ -11,5 +11,6 @@ pub mod search;
 mod storage;
 pub mod transport;
 
-pub use dht::KademliaDht;
-pub use id::Id;
+pub use dht::{DhtDebugState, KademliaDht, RoutingEvent};
+pub use id::{Id, IdN, IdParseError, InvalidIdLength};
+pub use storage::{Error as StorageError, SerializableStore, Storage, StorageBackend, StoredEntry};
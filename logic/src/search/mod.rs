@@ -1,19 +1,171 @@
-use std::{cmp::Reverse, collections::{HashSet, HashMap}, iter};
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+    fmt, mem,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 use futures::prelude::*;
 use futures::stream::FuturesUnordered;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, Notify};
 use tracing::{debug, instrument, warn};
+use wdht_wasync::sleep;
 
 use crate::{
-    transport::{Contact, RawResponse, Request, TransportError, TransportSender, TopicEntry},
+    transport::{ConnectionBudget, Contact, RawResponse, Request, TransportError, TransportSender, TopicEntry},
+    storage::{Storage, StorageBackend},
     Id, KademliaDht,
 };
 
-#[derive(Clone, Debug)]
+// The region backoff grows up to 2^MAX_BACKOFF_SHIFT times region_backoff
+const MAX_BACKOFF_SHIFT: u32 = 4;
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
 pub struct BasicSearchOptions {
     // Also called alpha in the original paper
     // n. of nodes searched in parallel
     pub parallelism: u32,
+
+    // When either is set the parallelism adapts to the search progress:
+    // it starts from min (default 1), grows toward max (default parallelism)
+    // as the window of closest nodes fills and shrinks again when only
+    // the closest nodes are left to query
+    pub min_parallelism: Option<u32>,
+    pub max_parallelism: Option<u32>,
+
+    // Number of disjoint paths the search is split into (S/Kademlia), each one
+    // with its own window and parallelism. A node is only ever queried by one
+    // path, so a malicious node can only mislead the paths that reach it
+    pub disjoint_paths: u32,
+
+    // Nodes that failed to answer in this interval are not contacted again
+    pub connect_cooldown: Duration,
+
+    // After this many failed requests the search stops contacting new nodes
+    pub max_connect_failures: u32,
+
+    // Delay before contacting a node in a region (same common prefix with the target)
+    // where requests already failed, doubled on every further failure
+    pub region_backoff: Duration,
+
+    // Max number of new connections opened while resolving the nodes found
+    // by a single search (None for no limit)
+    pub max_new_connections: Option<u32>,
+
+    // What to do when the parallelism is higher than the connections
+    // the transport can still open
+    pub connection_limit: ConnectionLimitPolicy,
+
+    // Stops the search early, returning the results found until then
+    #[serde(skip)]
+    pub cancel: Option<SearchCancel>,
+}
+
+/// Cancels the searches it's passed to, cheap to clone.
+///
+/// A cancelled search doesn't wait for the pending requests and returns
+/// the best results found so far.
+#[derive(Clone, Default)]
+pub struct SearchCancel(Arc<CancelState>);
+
+#[derive(Default)]
+struct CancelState {
+    cancelled: AtomicBool,
+    notify: Notify,
+}
+
+impl SearchCancel {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.cancelled.store(true, Ordering::SeqCst);
+        self.0.notify.notify_waiters();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Completes when the search is cancelled
+    pub async fn cancelled(&self) {
+        loop {
+            // Created before the check so that no notification is missed
+            let notified = self.0.notify.notified();
+            if self.is_cancelled() {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+// Handles are equal if they cancel the same searches
+impl PartialEq for SearchCancel {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for SearchCancel {}
+
+impl fmt::Debug for SearchCancel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("SearchCancel").field(&self.is_cancelled()).finish()
+    }
+}
+
+/// How the search parallelism is matched to the transport connection limit
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub enum ConnectionLimitPolicy {
+    /// Query at most as many nodes as the new connections available (at least one)
+    Clamp,
+    /// Keep the configured parallelism, the transport decides what to do
+    /// with the connections over the limit
+    Queue,
+}
+
+impl Default for BasicSearchOptions {
+    fn default() -> Self {
+        Self {
+            parallelism: 4,
+            min_parallelism: None,
+            max_parallelism: None,
+            disjoint_paths: 1,
+            connect_cooldown: Duration::from_secs(30),
+            max_connect_failures: 8,
+            region_backoff: Duration::from_millis(50),
+            max_new_connections: None,
+            connection_limit: ConnectionLimitPolicy::Clamp,
+            cancel: None,
+        }
+    }
+}
+
+/// Keeps at most limit of the entries found by a search.
+///
+/// The entries of the publishers closest to target are kept first (the newest ones
+/// when a publisher has too many), the kept entries are returned in their original order.
+pub fn select_entries(target: Id, entries: Vec<TopicEntry>, limit: u32) -> Vec<TopicEntry> {
+    if entries.len() <= limit as usize {
+        return entries;
+    }
+    // The entries of a publisher are in storage order, the newest are last
+    let mut ranked: Vec<usize> = (0..entries.len()).collect();
+    ranked.sort_by_key(|&i| (entries[i].publisher ^ target, Reverse(i)));
+    let kept: HashSet<usize> = ranked.into_iter().take(limit as usize).collect();
+    entries.into_iter()
+        .enumerate()
+        .filter(|(i, _)| kept.contains(i))
+        .map(|(_, x)| x)
+        .collect()
 }
 
 /// Basic search, taken from the Kademlia original paper
@@ -26,11 +178,16 @@ pub struct BasicSearchOptions {
 /// 4. If all of the nodes in the closest window have been queried then there
 ///    are no closer nodes, finish the search.
 ///
-pub struct BasicSearch<'a, T: TransportSender> {
-    dht: &'a KademliaDht<T>,
+pub struct BasicSearch<'a, T: TransportSender, S: StorageBackend = Storage> {
+    dht: &'a KademliaDht<T, S>,
     options: BasicSearchOptions,
     search_type: SearchType,
     target_id: Id,
+    budget: ConnectionBudget,
+    // When set the search stops at the first accepted entry
+    accept: Option<&'a (dyn Fn(&TopicEntry) -> bool + Sync)>,
+    // Receives the entries as they are found
+    entry_sink: Option<mpsc::UnboundedSender<TopicEntry>>,
 }
 
 #[derive(PartialEq, Eq, Clone, Copy, Debug)]
@@ -38,6 +195,14 @@ enum QueryState {
     Waiting,
     Querying,
     Queried,
+    // Recently failed, not contacted (and not returned)
+    Skipped,
+}
+
+impl QueryState {
+    fn is_done(self) -> bool {
+        matches!(self, QueryState::Queried | QueryState::Skipped)
+    }
 }
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
@@ -51,32 +216,82 @@ pub enum SearchResult<C: Contact> {
     DataFound(Vec<TopicEntry>),
 }
 
-impl<'a, T: TransportSender> BasicSearch<'a, T> {
+impl<'a, T: TransportSender, S: StorageBackend> BasicSearch<'a, T, S> {
     pub fn create(
-        dht: &'a KademliaDht<T>,
+        dht: &'a KademliaDht<T, S>,
         options: BasicSearchOptions,
         search_type: SearchType,
         target_id: Id,
     ) -> Self {
+        let budget = match options.max_new_connections {
+            Some(x) => ConnectionBudget::new(x),
+            None => ConnectionBudget::unlimited(),
+        };
         Self {
             dht,
             options,
             search_type,
             target_id,
+            budget,
+            accept: None,
+            entry_sink: None,
+        }
+    }
+
+    /// Sends the entries found by data searches to sink as the responses arrive.
+    /// Only the first response including a publisher is sent, later ones are ignored.
+    pub fn stream_to(mut self, sink: mpsc::UnboundedSender<TopicEntry>) -> Self {
+        self.entry_sink = Some(sink);
+        self
+    }
+
+    fn stream_entries(&self, entries: &[TopicEntry], streamed: &mut HashSet<Id>) {
+        let sink = match &self.entry_sink {
+            Some(x) => x,
+            None => return,
+        };
+        // A response can contain many entries of the same publisher (appended values)
+        let new_publishers: HashSet<Id> = entries.iter()
+            .map(|x| x.publisher)
+            .filter(|x| !streamed.contains(x))
+            .collect();
+        for entry in entries.iter().filter(|x| new_publishers.contains(&x.publisher)) {
+            // The receiver might not be interested anymore
+            let _ = sink.send(entry.clone());
+        }
+        streamed.extend(new_publishers);
+    }
+
+    /// Makes data searches return as soon as an entry accepted by accept is found,
+    /// the requests still pending are dropped
+    pub fn first_match(mut self, accept: &'a (dyn Fn(&TopicEntry) -> bool + Sync)) -> Self {
+        self.accept = Some(accept);
+        self
+    }
+
+    /// Backoff before contacting a node in a region where that many requests failed already
+    fn region_delay(&self, failures: u32) -> Duration {
+        match failures {
+            0 => Duration::ZERO,
+            x => self.options.region_backoff * (1 << (x - 1).min(MAX_BACKOFF_SHIFT)),
         }
     }
 
     fn start_query(
         &self,
+        path: usize,
         queries: &mut [(QueryState, T::Contact)],
-    ) -> Option<impl Future<Output = (Id, Result<RawResponse<T::Contact>, TransportError>)>> {
-        let to = queries.iter_mut().find(|x| x.0 == QueryState::Waiting);
+        region_failures: &HashMap<u8, u32>,
+    ) -> Option<impl Future<Output = (usize, Id, Result<RawResponse<T::Contact>, TransportError>)> + 'a> {
         // Note: picking the first entry will always pick the closest node since they're
         // always ordered by increasing distance (or decreased xored leading zeroes).
-
-        let to = match to {
-            Some(x) => x,
-            None => return None,
+        let to = loop {
+            let to = queries.iter_mut().find(|x| x.0 == QueryState::Waiting)?;
+            if !self.dht.is_cooling_down(to.1.id(), self.options.connect_cooldown) {
+                break to;
+            }
+            debug!("Skipping {:?}, it failed recently", to.1.id());
+            to.0 = QueryState::Skipped;
         };
 
         to.0 = QueryState::Querying;
@@ -87,54 +302,146 @@ impl<'a, T: TransportSender> BasicSearch<'a, T> {
             SearchType::Data(limit) => Request::FindData(self.target_id, limit),
         };
 
-        let fut = self.dht.transport().send(used_id, message);
-        Some(fut.map(move |x| (used_id, x)))
+        let region = used_id.common_prefix_len(&self.target_id);
+        let delay = self.region_delay(region_failures.get(&region).copied().unwrap_or(0));
+        let transport = self.dht.transport();
+        let budget = self.budget.clone();
+        let fut = if delay.is_zero() {
+            transport.send_with_budget(used_id, message, budget).left_future()
+        } else {
+            debug!("Backing off {:?} before contacting {:?}", delay, used_id);
+            sleep(delay)
+                .then(move |_| transport.send_with_budget(used_id, message, budget))
+                .right_future()
+        };
+        Some(fut.map(move |x| (path, used_id, x)))
+    }
+
+    /// Min and max number of nodes queried at the same time, limited by the connection headroom
+    fn effective_parallelism(&self) -> (u32, u32) {
+        let options = &self.options;
+        let (min, parallelism) = match (options.min_parallelism, options.max_parallelism) {
+            (None, None) => (options.parallelism, options.parallelism),
+            (min, max) => {
+                let max = max.unwrap_or(options.parallelism).max(1);
+                (min.unwrap_or(1).clamp(1, max), max)
+            }
+        };
+        let headroom = match options.connection_limit {
+            ConnectionLimitPolicy::Clamp => self.dht.transport().connection_headroom(),
+            ConnectionLimitPolicy::Queue => None,
+        };
+        match headroom {
+            Some(x) if x < parallelism => {
+                debug!("Clamping parallelism {} to the connection headroom {}", parallelism, x);
+                let max = x.max(1);
+                (min.min(max), max)
+            }
+            _ => (min, parallelism),
+        }
+    }
+
+    /// Number of queries that should be running given the state of the window
+    fn parallelism_target(&self, (min, max): (u32, u32), to_query: &[(QueryState, T::Contact)]) -> usize {
+        let (min, max) = (min as usize, max as usize);
+        if min == max {
+            return max;
+        }
+        let bucket_size = self.dht.config().routing.bucket_size;
+        let known = to_query.len().min(bucket_size);
+        let ramp = min + (max - min) * known / bucket_size;
+        // Nodes not queried yet (or still answering)
+        let remaining = to_query.iter().filter(|x| !x.0.is_done()).count();
+        ramp.min(remaining).max(min)
     }
 
     fn sort_bucket(&self, bucket: &mut [(QueryState, T::Contact)]) {
         // Sort with leading zeros in descending order:
         // the first entries will have MORE leading zeros (so they'll be closer)
-        bucket.sort_by_key(|x| Reverse((x.1.id() ^ self.target_id.id()).leading_zeros()));
+        bucket.sort_by_key(|x| Reverse(x.1.id().common_prefix_len(&self.target_id)));
     }
 
     #[instrument(skip_all)]
     pub async fn search(&self, first_bucket: Vec<T::Contact>) -> SearchResult<T::Contact> {
+        let cancel = self.options.cancel.clone().unwrap_or_default();
+        self.search_with_cancel(first_bucket, &cancel).await
+    }
+
+    /// Like search, but it stops as soon as cancel is triggered
+    pub async fn search_with_cancel(&self, first_bucket: Vec<T::Contact>, cancel: &SearchCancel) -> SearchResult<T::Contact> {
         let bucket_size = self.dht.config().routing.bucket_size;
-        let parallelism = self.options.parallelism;
+        let max_found_nodes = bucket_size * self.dht.config().routing.max_found_nodes_factor;
+        let parallelism = self.effective_parallelism();
 
-        let mut data_entries: HashMap<Id, Vec<u8>> = HashMap::new();
+        // Entries found, in storage order (a publisher can store many entries in a topic)
+        let mut data_entries: Vec<TopicEntry> = Vec::new();
         if let SearchType::Data(_) = self.search_type {
             let storage = self.dht.storage.read().unwrap();
             if let Some(data) = storage.get(self.target_id) {
-                for entry in data {
-                    data_entries.insert(entry.publisher, entry.data.clone());
-                }
+                data_entries.extend(data.iter().cloned());
             }
         }
+        if let Some(accept) = self.accept {
+            // The most recent local entry is preferred
+            if let Some(entry) = mem::take(&mut data_entries).into_iter().rev().find(|x| accept(x)) {
+                return SearchResult::DataFound(vec![entry]);
+            }
+        }
+        // Entries from the network replace the local ones, these are streamed last
+        let local_entries = data_entries.clone();
+        let mut streamed = HashSet::new();
 
         let mut queried: HashSet<Id> = first_bucket.iter().map(|x| x.id()).collect();
         queried.insert(self.dht.id()); // We already queried ourself
         debug!("First bucket: {:?}", first_bucket);
 
         let self_contact = self.dht.transport().wrap_contact(self.dht.id());
-        // Must always be of bucket length, similar to a window of the closest Ids that we know
-        let mut to_query: Vec<(QueryState, T::Contact)> = first_bucket
-            .into_iter()
+        let paths = self.options.disjoint_paths.max(1) as usize;
+        // Must always be of bucket length, similar to a window of the closest Ids that we know.
+        // Every path has its own window, the first bucket is dealt between them
+        let mut windows: Vec<Vec<(QueryState, T::Contact)>> = vec![Vec::new(); paths];
+        let mut first_bucket: Vec<_> = first_bucket.into_iter()
             .map(|x| (QueryState::Waiting, x))
-            .chain(iter::once((QueryState::Queried, self_contact)))
             .collect();
-        self.sort_bucket(&mut to_query);
+        self.sort_bucket(&mut first_bucket);
+        for (i, x) in first_bucket.into_iter().enumerate() {
+            windows[i % paths].push(x);
+        }
 
-        let pending: FuturesUnordered<_> = (0..parallelism)
-            .into_iter()
-            .filter_map(|_| self.start_query(&mut to_query))
-            .collect();
+        // Failed requests per region (common prefix length with the target)
+        let mut region_failures: HashMap<u8, u32> = HashMap::new();
 
-        let mut available_futures = parallelism - pending.len() as u32;
+        let pending = FuturesUnordered::new();
+        // Queries running on each path
+        let mut in_flight = vec![0usize; paths];
+        let mut failures = 0u32;
+        for (path, to_query) in windows.iter_mut().enumerate() {
+            to_query.push((QueryState::Queried, self_contact.clone()));
+            self.sort_bucket(to_query);
+            while in_flight[path] < self.parallelism_target(parallelism, to_query) {
+                match self.start_query(path, to_query, &region_failures) {
+                    None => break,
+                    Some(x) => pending.push(x),
+                };
+                in_flight[path] += 1;
+            }
+        }
 
         tokio::pin!(pending);
-        while let Some((id, res)) = pending.next().await {
-            available_futures += 1; // 1 space available again
+        loop {
+            let next = tokio::select! {
+                x = pending.next() => x,
+                _ = cancel.cancelled() => {
+                    debug!("Search cancelled");
+                    break;
+                }
+            };
+            let (path, id, res) = match next {
+                Some(x) => x,
+                None => break,
+            };
+            in_flight[path] -= 1;
+            let to_query = &mut windows[path];
             let entry = to_query.iter_mut().find(|x| x.1.id() == id);
 
             match entry {
@@ -149,65 +456,110 @@ impl<'a, T: TransportSender> BasicSearch<'a, T> {
             }
             debug!("Response from {:?}: {:?}", id, res);
             use RawResponse::*;
-            match res {
+            let (data, nodes) = match res {
                 Err(x) => {
                     debug!("Error requesting from {:?}: {}", id, x);
-                }
-                Ok(FoundNodes(nodes)) => {
-                    // found other nodes
-                    to_query.extend(
-                        nodes
-                            .iter()
-                            .cloned() // Transform &Id to Id
-                            // Only take non-previously queried nodes
-                            .filter(|x| queried.insert(x.id()))
-                            .map(|x| (QueryState::Waiting, x)),
-                    );
-                    self.sort_bucket(&mut to_query);
-                    to_query.truncate(bucket_size);
-                    while available_futures > 0 {
-                        match self.start_query(&mut to_query) {
+                    self.dht.report_failure(id);
+                    failures += 1;
+                    *region_failures.entry(id.common_prefix_len(&self.target_id)).or_default() += 1;
+                    // Replace the failed node, unless we failed too many times already
+                    while in_flight[path] < self.parallelism_target(parallelism, to_query)
+                        && failures < self.options.max_connect_failures {
+                        match self.start_query(path, to_query, &region_failures) {
                             None => break,
                             Some(x) => pending.push(x),
                         };
-                        available_futures -= 1;
+                        in_flight[path] += 1;
                     }
+                    (None, None)
+                }
+                Ok(FoundNodes(nodes)) => (None, Some(nodes)),
+                Ok(FoundData(x)) => (Some(x), None),
+                // The node stores some entries, but closer nodes might store others
+                Ok(FoundDataAndNodes(x, nodes)) => (Some(x), Some(nodes)),
+                Ok(Error) => {
+                    warn!("Node {:?} returned error", id);
+                    (None, None)
                 }
-                Ok(FoundData(x)) => {
-                    if let SearchType::Data(_) = self.search_type {
-                        // If multiple data entries are available then we might need every response
-                        // (at least, we might need the full response of the closest bucket)
-                        for entry in x {
-                            // TODO: conflicts?
-                            data_entries.insert(entry.publisher, entry.data);
-                        }
-                    } else {
-                        warn!(
-                            "Node {:?} returned data even if only nodes are requested",
-                            id
-                        )
+                Ok(x) => {
+                    warn!("Node {:?} returned invalid response: {:?}", id, x);
+                    (None, None)
+                }
+            };
+
+            if let Some(x) = data {
+                if let (SearchType::Data(_), Some(accept)) = (self.search_type, self.accept) {
+                    if let Some(entry) = x.into_iter().find(|x| accept(x)) {
+                        debug!("Accepted entry from {:?}", id);
+                        return SearchResult::DataFound(vec![entry]);
                     }
+                    debug!("No acceptable entry from {:?}", id);
+                } else if let SearchType::Data(_) = self.search_type {
+                    self.stream_entries(&x, &mut streamed);
+                    // If multiple data entries are available then we might need every response
+                    // (at least, we might need the full response of the closest bucket)
+                    // TODO: conflicts?
+                    // The entries of a publisher are replaced by the ones of the last response
+                    data_entries.retain(|e| !x.iter().any(|n| n.publisher == e.publisher));
+                    data_entries.extend(x);
+                } else {
+                    warn!(
+                        "Node {:?} returned data even if only nodes are requested",
+                        id
+                    )
                 }
-                Ok(Error) => warn!("Node {:?} returned error", id),
-                Ok(x) => warn!("Node {:?} returned invalid response: {:?}", id, x),
             }
 
-            if to_query.iter().all(|x| x.0 == QueryState::Queried) {
+            if let Some(nodes) = nodes {
+                // found other nodes
+                if nodes.len() > max_found_nodes {
+                    warn!("Node {:?} returned {} nodes, only {} are used", id, nodes.len(), max_found_nodes);
+                }
+                to_query.extend(
+                    nodes
+                        .iter()
+                        .take(max_found_nodes)
+                        .cloned() // Transform &Id to Id
+                        // Only take non-previously queried nodes (by any path)
+                        .filter(|x| queried.insert(x.id()))
+                        .map(|x| (QueryState::Waiting, x)),
+                );
+                self.sort_bucket(to_query);
+                to_query.truncate(bucket_size);
+                while in_flight[path] < self.parallelism_target(parallelism, to_query)
+                    && failures < self.options.max_connect_failures {
+                    match self.start_query(path, to_query, &region_failures) {
+                        None => break,
+                        Some(x) => pending.push(x),
+                    };
+                    in_flight[path] += 1;
+                }
+            }
+
+            if windows.iter().flatten().all(|x| x.0.is_done()) {
                 // All of the closest nodes responded, other queried nodes should not know any
                 // other closer node
                 break;
             }
         }
 
+        self.stream_entries(&local_entries, &mut streamed);
         if !data_entries.is_empty() {
-            if let SearchType::Data(_) = self.search_type {
-                let res = data_entries.into_iter()
-                    .map(|(publisher, data)| TopicEntry { publisher, data })
-                    .collect::<Vec<_>>();
-                return SearchResult::DataFound(res);
+            if let SearchType::Data(limit) = self.search_type {
+                return SearchResult::DataFound(select_entries(self.target_id, data_entries, limit));
             }
         }
-        let nodes = to_query.into_iter().map(|x| x.1).collect();
-        SearchResult::CloserNodes(nodes)
+        let mut nodes: Vec<_> = windows.into_iter()
+            .flatten()
+            .filter(|x| x.0 != QueryState::Skipped)
+            .collect();
+        if paths > 1 {
+            // Merge the windows, every path has its copy of this node
+            self.sort_bucket(&mut nodes);
+            let mut seen = HashSet::new();
+            nodes.retain(|x| seen.insert(x.1.id()));
+            nodes.truncate(bucket_size);
+        }
+        SearchResult::CloserNodes(nodes.into_iter().map(|x| x.1).collect())
     }
 }
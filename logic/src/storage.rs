@@ -1,14 +1,22 @@
 use instant::Instant;
 use priority_queue::PriorityQueue;
 use std::{
-    collections::{HashMap, hash_map::Entry},
+    cmp::Reverse,
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap},
+    hash::{Hash, Hasher},
     time::Duration,
 };
 
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use tracing::info;
 
-use crate::{config::StorageConfig, id::Id, transport::TopicEntry};
+use crate::{
+    config::{PublisherMode, StorageConfig},
+    id::Id,
+    transport::{RecordProof, TopicEntry},
+};
 
 #[derive(Error, Debug)]
 #[non_exhaustive]
@@ -19,6 +27,104 @@ pub enum Error {
     InvalidLifetime,
     #[error("Invalid data")]
     InvalidData,
+    #[error("Storage quota of the publisher exceeded")]
+    PublisherQuotaExceeded,
+}
+
+/// Single entry of a [`SerializableStore`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct StoredEntry {
+    pub topic: Id,
+    // Original topic name (only present if the topic index was enabled)
+    pub name: Option<String>,
+    pub publisher: Id,
+    // Milliseconds left before the entry expires, relative to the export time
+    pub remaining_ms: u64,
+    pub data: Vec<u8>,
+    // Signature of the entry (only present for signed records)
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub proof: Option<RecordProof>,
+}
+
+/// Snapshot of the storage contents, used for backups and migrations.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct SerializableStore {
+    pub entries: Vec<StoredEntry>,
+}
+
+/// Storage of the entries that a node keeps for the network.
+///
+/// [`Storage`] keeps everything in memory (and is the default backend of
+/// `KademliaDht`), other backends can persist the entries (ex. on disk) so that
+/// they survive restarts. Backends must enforce the limits of their own
+/// configuration, entries are returned oldest first.
+pub trait StorageBackend {
+    /// Entries of the topic, None if nothing is stored
+    fn get(&self, topic: Id) -> Option<Vec<TopicEntry>>;
+
+    fn topics(&self) -> Vec<Id>;
+
+    /// Returns the n stored topics closest to the given id (by XOR distance).
+    fn closest_topics(&self, to: Id, n: usize) -> Vec<Id> {
+        let mut topics = self.topics();
+        topics.sort_unstable_by_key(|x| to ^ *x);
+        topics.truncate(n);
+        topics
+    }
+
+    /// Returns the seconds left before the publisher's most recent entry expires
+    fn remaining_lifetime(&self, topic: Id, publisher: Id) -> Option<u32>;
+
+    /// Checks an entry (and its topic name, if any) before it's inserted (locally or in the network)
+    fn check_entry(&self, topic: Id, name: Option<&str>, publisher: Id, lifetime: u32, data: &[u8]) -> Result<(), Error>;
+
+    /// Inserts the entry of a publisher, see [`PublisherMode`] for what happens to its previous entries
+    fn insert(&mut self, topic: Id, name: Option<String>, publisher: Id, lifetime: u32, data: Vec<u8>) -> Result<(), Error>;
+
+    /// Inserts a record signed by `proof.signature.publisher`, the proof
+    /// is kept with the entry. The signature must already be verified
+    fn insert_signed(&mut self, topic: Id, lifetime: u32, data: Vec<u8>, proof: RecordProof) -> Result<(), Error>;
+
+    /// Proof of a stored entry, None if it was not inserted as a signed record
+    fn proof(&self, topic: Id, entry: &TopicEntry) -> Option<RecordProof>;
+
+    /// Adds an entry after the previous entries of the publisher
+    fn append(&mut self, topic: Id, publisher: Id, lifetime: u32, data: Vec<u8>) -> Result<(), Error>;
+
+    /// Replaces the most recent entry of the publisher only if its data matches expected,
+    /// returns false if it doesn't
+    fn compare_and_swap(
+        &mut self,
+        topic: Id,
+        publisher: Id,
+        lifetime: u32,
+        expected: Option<&[u8]>,
+        data: Vec<u8>,
+    ) -> Result<bool, Error>;
+
+    /// Removes every entry of the publisher from the topic
+    fn remove(&mut self, topic: Id, publisher: Id);
+
+    /// Removes the expired entries, called periodically.
+    /// Returns the topic and publisher of every removed entry
+    fn periodic_run(&mut self) -> Vec<(Id, Id)>;
+
+    fn export(&self) -> SerializableStore;
+
+    /// Imports an exported store, returns the number of imported entries
+    fn import(&mut self, store: SerializableStore) -> usize;
+}
+
+// Identity of a stored entry: topic, publisher and sub-key (the hash of the data,
+// so that a publisher can store many distinct entries in the same topic)
+type EntryKey = (Id, Id, u64);
+
+fn sub_key(data: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    data.hash(&mut hasher);
+    hasher.finish()
 }
 
 #[derive(Clone, Debug)]
@@ -26,7 +132,17 @@ pub struct Storage {
     config: StorageConfig,
     entry_count: usize,
     topics: HashMap<Id, Vec<TopicEntry>>,
-    deadlines: PriorityQueue<(Id, Id), Instant>,
+    // Number of entries and bytes stored by each publisher
+    publisher_usage: HashMap<Id, (usize, usize)>,
+    // Earliest deadline first
+    deadlines: PriorityQueue<EntryKey, Reverse<Instant>>,
+    // Original topic names (only used when config.topic_index is enabled)
+    topic_names: BTreeMap<String, Id>,
+    names: HashMap<Id, String>,
+    // Signatures of the signed records
+    proofs: HashMap<EntryKey, RecordProof>,
+    // Bytes of the topic name charged to the entries that indexed it
+    name_bytes: HashMap<EntryKey, usize>,
     // TODO: cache
     // cache: HashMap<Id, Vec<u8>>,
     // cache_deadlines: BinaryHeap<(Instant, Id)>,
@@ -38,7 +154,12 @@ impl Storage {
             config,
             entry_count: 0,
             topics: Default::default(),
+            publisher_usage: Default::default(),
             deadlines: Default::default(),
+            topic_names: Default::default(),
+            names: Default::default(),
+            proofs: Default::default(),
+            name_bytes: Default::default(),
         }
     }
 
@@ -46,31 +167,84 @@ impl Storage {
         self.topics.get(&id)
     }
 
-    pub fn periodic_run(&mut self) {
+    pub fn topics(&self) -> impl Iterator<Item = Id> + '_ {
+        self.topics.keys().copied()
+    }
+
+    /// Number of entries stored across every topic.
+    pub fn entry_count(&self) -> usize {
+        self.entry_count
+    }
+
+    /// Lists the stored topics whose original name starts with prefix.
+    ///
+    /// Only topics inserted with a name are listed (and only if the
+    /// topic index is enabled), the DHT only passes names that match the topic id.
+    pub fn list_topics(&self, prefix: &str) -> Vec<(String, Id)> {
+        self.topic_names
+            .range(prefix.to_owned()..)
+            .take_while(|(name, _)| name.starts_with(prefix))
+            .map(|(name, id)| (name.clone(), *id))
+            .collect()
+    }
+
+    /// Returns the n stored topics closest to the given id (by XOR distance).
+    pub fn closest_topics(&self, to: Id, n: usize) -> Vec<Id> {
+        let mut topics: Vec<Id> = self.topics.keys().copied().collect();
+        topics.sort_unstable_by_key(|x| to ^ *x);
+        topics.truncate(n);
+        topics
+    }
+
+    fn entry_key(&self, topic: Id, entry: &TopicEntry) -> EntryKey {
+        (topic, entry.publisher, sub_key(&entry.data))
+    }
+
+    /// Returns the seconds left before the publisher's most recent entry expires
+    pub fn remaining_lifetime(&self, topic: Id, publisher: Id) -> Option<u32> {
+        let entry = self.topics.get(&topic)?
+            .iter()
+            .rev()
+            .find(|x| x.publisher == publisher)?;
+        self.deadlines
+            .get_priority(&self.entry_key(topic, entry))
+            .map(|x| x.0.saturating_duration_since(Instant::now()).as_secs() as u32)
+    }
+
+    pub fn periodic_run(&mut self) -> Vec<(Id, Id)> {
         let now = Instant::now();
+        let mut evicted = Vec::new();
         // Remove old entries
-        while let Some(((topic, user), deadline)) = self.deadlines.peek() {
+        while let Some(((topic, user, _), Reverse(deadline))) = self.deadlines.peek() {
             if *deadline > now {
                 break;
             }
 
             info!("Removing topic: {topic:?} user: {user:?}");
 
-            let id = self.deadlines.pop().unwrap().0;
-            self.remove(id.0, id.1);
+            let key = self.deadlines.pop().unwrap().0;
+            evicted.push((key.0, key.1));
+            self.remove_entry(key);
         }
+        evicted
     }
 
+    /// Checks the limits of an entry, the topic name counts towards its size
     pub fn check_entry(
         config: &StorageConfig,
         _topic: Id,
+        name: Option<&str>,
         _sender: Id,
         lifetime: u32,
         data: &[u8],
     ) -> Result<(), Error> {
-        if data.len() > config.max_size {
+        let name_len = name.map_or(0, |x| x.len());
+        let size = data.len() + name_len;
+        if name_len > config.max_name_len || size > config.max_size {
             Err(Error::InvalidData)
-        } else if lifetime > config.max_lifetime {
+        } else if matches!(config.max_publisher_bytes, Some(x) if size > x) {
+            Err(Error::PublisherQuotaExceeded)
+        } else if lifetime > config.max_lifetime || lifetime < config.min_lifetime {
             Err(Error::InvalidLifetime)
         } else {
             Ok(())
@@ -78,49 +252,670 @@ impl Storage {
     }
 
     pub fn insert(&mut self, topic: Id, publisher: Id, lifetime: u32, data: Vec<u8>) -> Result<(), Error> {
-        // TODO: check distance?
-        Self::check_entry(&self.config, topic, publisher, lifetime, &data)?;
+        self.insert_named(topic, None, publisher, lifetime, data)
+    }
 
-        self.remove(topic, publisher);
+    pub fn insert_named(
+        &mut self,
+        topic: Id,
+        name: Option<String>,
+        publisher: Id,
+        lifetime: u32,
+        data: Vec<u8>,
+    ) -> Result<(), Error> {
+        self.insert_with_mode(topic, name, publisher, lifetime, data, self.config.publisher_mode, None)
+    }
 
-        if self.entry_count >= self.config.max_entries {
-            info!("Error inserting new value, too many entries");
-            return Err(Error::TooManyEntries);
-        }
-        info!("Inserting {topic:?}:{publisher:?} for {lifetime}s");
+    /// Inserts a record signed by the publisher of the proof (already verified).
+    ///
+    /// The proof is kept until the entry is removed or replaced, so that
+    /// the record can be replicated to other nodes.
+    pub fn insert_signed(&mut self, topic: Id, lifetime: u32, data: Vec<u8>, proof: RecordProof) -> Result<(), Error> {
+        let key = (topic, proof.signature.publisher, sub_key(&data));
+        self.insert_with_mode(topic, None, key.1, lifetime, data, self.config.publisher_mode, None)?;
+        self.proofs.insert(key, proof);
+        Ok(())
+    }
+
+    /// Proof of a stored entry, None if it was not inserted as a signed record
+    pub fn proof(&self, topic: Id, entry: &TopicEntry) -> Option<&RecordProof> {
+        self.proofs.get(&self.entry_key(topic, entry))
+    }
 
+    /// Adds an entry after the previous entries of the publisher (as in
+    /// [`PublisherMode::Append`], whatever the configured mode is).
+    ///
+    /// Appending the same data again only refreshes it, when the publisher
+    /// has max_entries_per_publisher entries in the topic its oldest one is dropped.
+    pub fn append(&mut self, topic: Id, publisher: Id, lifetime: u32, data: Vec<u8>) -> Result<(), Error> {
+        self.insert_with_mode(topic, None, publisher, lifetime, data, PublisherMode::Append, None)
+    }
+
+    // Inserts an entry replacing the previous ones (as in mode) and the replacing entry, if any.
+    // Nothing is changed if the entry can't be inserted
+    #[allow(clippy::too_many_arguments)]
+    fn insert_with_mode(
+        &mut self,
+        topic: Id,
+        name: Option<String>,
+        publisher: Id,
+        lifetime: u32,
+        data: Vec<u8>,
+        mode: PublisherMode,
+        replacing: Option<EntryKey>,
+    ) -> Result<(), Error> {
+        // TODO: check distance?
+        Self::check_entry(&self.config, topic, name.as_deref(), publisher, lifetime, &data)?;
+
+        // The name can't be taken from another topic
+        let name = name.filter(|x| self.config.topic_index && self.can_name(topic, x));
+        // Names that will be indexed count against the quota
+        let name_len = name.as_ref().map_or(0, |x| x.len());
+        let size = data.len() + name_len;
         let deadline = Instant::now().checked_add(Duration::from_secs(lifetime as u64));
         let deadline = match deadline {
             Some(x) => x,
             None => return Err(Error::InvalidLifetime),
         };
+        let mut replaced = self.replaced_entries(topic, publisher, &data, mode);
+        if let Some(key) = replacing.filter(|x| !replaced.contains(x)) {
+            replaced.push(key);
+        }
+        self.check_limits(publisher, size, &replaced)?;
+        info!("Inserting {topic:?}:{publisher:?} for {lifetime}s");
+
+        // Removing the old entry might also remove the topic name
+        let name = name.or_else(|| self.names.get(&topic).cloned());
+        for key in replaced {
+            self.remove_entry(key);
+        }
+        self.push_entry(topic, name, name_len, publisher, deadline, data);
+        Ok(())
+    }
+
+    // Checks that the publisher can store another entry of the given size,
+    // once the replaced entries (of the same publisher) are removed
+    fn check_limits(&self, publisher: Id, size: usize, replaced: &[EntryKey]) -> Result<(), Error> {
+        let replaced_bytes: usize = replaced.iter()
+            .filter_map(|key| {
+                let entry = self.topics.get(&key.0)?.iter().find(|x| self.entry_key(key.0, x) == *key)?;
+                Some(entry.data.len() + self.name_bytes.get(key).copied().unwrap_or(0))
+            })
+            .sum();
+        let (entries, bytes) = self.publisher_usage.get(&publisher).copied().unwrap_or_default();
+        let (entries, bytes) = (entries - replaced.len(), bytes - replaced_bytes);
+
+        if self.entry_count - replaced.len() >= self.config.max_entries {
+            info!("Error inserting new value, too many entries");
+            return Err(Error::TooManyEntries);
+        }
+        let over = matches!(self.config.max_publisher_entries, Some(x) if entries >= x)
+            || matches!(self.config.max_publisher_bytes, Some(x) if bytes + size > x);
+        if over {
+            info!("Error inserting new value, quota of {publisher:?} exceeded");
+            return Err(Error::PublisherQuotaExceeded);
+        }
+        Ok(())
+    }
+
+    // Entries that a new entry with data would replace: in append mode the
+    // same data and the oldest entries over max_entries_per_publisher
+    fn replaced_entries(&self, topic: Id, publisher: Id, data: &[u8], mode: PublisherMode) -> Vec<EntryKey> {
+        let keys = match self.topics.get(&topic) {
+            Some(entries) => entries.iter()
+                .filter(|x| x.publisher == publisher)
+                .map(|x| self.entry_key(topic, x)),
+            None => return Vec::new(),
+        };
+        match mode {
+            PublisherMode::Replace => keys.collect(),
+            PublisherMode::Append => {
+                let same = (topic, publisher, sub_key(data));
+                let (mut replaced, others): (Vec<_>, Vec<_>) = keys.partition(|x| *x == same);
+                let max = self.config.max_entries_per_publisher.max(1) - 1;
+                replaced.extend(others.iter().take(others.len().saturating_sub(max)));
+                replaced
+            }
+        }
+    }
 
+    // Adds an entry without checks, the entry must not be present in the topic.
+    // name_len bytes of the name are charged to the publisher along with the data
+    fn push_entry(&mut self, topic: Id, name: Option<String>, name_len: usize, publisher: Id, deadline: Instant, data: Vec<u8>) {
         let entry = TopicEntry {
             publisher,
             data,
         };
+        let key = self.entry_key(topic, &entry);
+        let usage = self.publisher_usage.entry(publisher).or_default();
+        usage.0 += 1;
+        usage.1 += entry.data.len() + name_len;
+        if name_len > 0 {
+            self.name_bytes.insert(key, name_len);
+        }
         self.topics.entry(topic).or_default().push(entry);
-        self.deadlines.push((topic, publisher), deadline);
+        self.deadlines.push(key, Reverse(deadline));
         self.entry_count += 1;
 
-        Ok(())
+        if let (Some(name), true) = (name, self.config.topic_index) {
+            self.set_topic_name(topic, name);
+        }
+    }
+
+    /// Exports every stored entry with its remaining lifetime
+    pub fn export(&self) -> SerializableStore {
+        let now = Instant::now();
+        let mut entries: Vec<StoredEntry> = self.topics
+            .iter()
+            .flat_map(|(topic, entries)| entries.iter().map(move |x| (*topic, x)))
+            .filter_map(|(topic, entry)| {
+                let key = self.entry_key(topic, entry);
+                let deadline = self.deadlines.get_priority(&key)?;
+                Some(StoredEntry {
+                    topic,
+                    name: self.names.get(&topic).cloned(),
+                    publisher: entry.publisher,
+                    remaining_ms: deadline.0.saturating_duration_since(now).as_millis() as u64,
+                    data: entry.data.clone(),
+                    proof: self.proofs.get(&key).cloned(),
+                })
+            })
+            .filter(|x| x.remaining_ms > 0)
+            .collect();
+        // Stable sort, entries keep their order inside of each topic
+        entries.sort_by_key(|x| x.topic);
+        SerializableStore { entries }
+    }
+
+    /// Imports the entries of an exported store, deadlines are recomputed
+    /// from the current time.
+    ///
+    /// Existing entries are replaced as in inserts, lifetimes are
+    /// capped to max_lifetime and the entries that don't fit the storage
+    /// limits are skipped. Returns the number of imported entries.
+    pub fn import(&mut self, store: SerializableStore) -> usize {
+        let now = Instant::now();
+        let max_lifetime = Duration::from_secs(self.config.max_lifetime as u64);
+        let mut count = 0;
+        for entry in store.entries {
+            let lifetime = Duration::from_millis(entry.remaining_ms).min(max_lifetime);
+            if lifetime.is_zero() || entry.data.len() > self.config.max_size {
+                continue;
+            }
+            let replaced = self.replaced_entries(entry.topic, entry.publisher, &entry.data, self.config.publisher_mode);
+            if self.check_limits(entry.publisher, entry.data.len(), &replaced).is_err() {
+                continue;
+            }
+            let name = entry.name.or_else(|| self.names.get(&entry.topic).cloned());
+            for key in replaced {
+                self.remove_entry(key);
+            }
+            let key = (entry.topic, entry.publisher, sub_key(&entry.data));
+            self.push_entry(entry.topic, name, 0, entry.publisher, now + lifetime, entry.data);
+            if let Some(proof) = entry.proof {
+                self.proofs.insert(key, proof);
+            }
+            count += 1;
+        }
+        count
+    }
+
+    /// Replaces the publisher's entry only if its current data matches expected
+    /// (None meaning that the publisher has no entry in the topic).
+    /// In append mode the most recent entry of the publisher is compared and replaced.
+    /// Returns false if the current data does not match.
+    pub fn compare_and_swap(
+        &mut self,
+        topic: Id,
+        publisher: Id,
+        lifetime: u32,
+        expected: Option<&[u8]>,
+        data: Vec<u8>,
+    ) -> Result<bool, Error> {
+        let current = self.topics.get(&topic)
+            .and_then(|x| x.iter().rev().find(|x| x.publisher == publisher));
+        if current.map(|x| x.data.as_slice()) != expected {
+            return Ok(false);
+        }
+        let current = current.map(|x| self.entry_key(topic, x));
+        self.insert_with_mode(topic, None, publisher, lifetime, data, self.config.publisher_mode, current)?;
+        Ok(true)
+    }
+
+    // False if the name is already used by another stored topic
+    fn can_name(&self, topic: Id, name: &str) -> bool {
+        match self.topic_names.get(name) {
+            Some(x) if *x != topic => {
+                info!("Topic name {name:?} already used by {x:?}");
+                false
+            }
+            _ => true,
+        }
+    }
+
+    // Names used by other topics are never rebound
+    fn set_topic_name(&mut self, topic: Id, name: String) {
+        if !self.can_name(topic, &name) {
+            return;
+        }
+        if let Some(old) = self.names.remove(&topic) {
+            self.topic_names.remove(&old);
+        }
+        self.topic_names.insert(name.clone(), topic);
+        self.names.insert(topic, name);
     }
 
+    /// Removes every entry of the publisher from the topic
     pub fn remove(&mut self, topic: Id, user: Id) {
-        if let Entry::Occupied(mut o) = self.topics.entry(topic) {
-            // Search for position of publisher
-            let pos = o.get_mut().iter().position(|x| x.publisher == user);
-            // if the element is found
-            if let Some(pos) = pos {
-                // remove the element
-                o.get_mut().remove(pos);
-                self.entry_count -= 1;
-                self.deadlines.remove(&(topic, user));
-                // if the topic is empty, remove it from the map
-                if o.get().is_empty() {
-                    o.remove_entry();
+        let keys: Vec<_> = match self.topics.get(&topic) {
+            Some(entries) => entries.iter()
+                .filter(|x| x.publisher == user)
+                .map(|x| self.entry_key(topic, x))
+                .collect(),
+            None => return,
+        };
+        for key in keys {
+            self.remove_entry(key);
+        }
+    }
+
+    fn remove_entry(&mut self, key: EntryKey) {
+        let topic = key.0;
+        let entries = match self.topics.get(&topic) {
+            Some(x) => x,
+            None => return,
+        };
+        // Search for position of the entry
+        let pos = entries.iter().position(|x| self.entry_key(topic, x) == key);
+        // if the element is found
+        if let Some(pos) = pos {
+            let entries = self.topics.get_mut(&topic).unwrap();
+            // remove the element
+            let entry = entries.remove(pos);
+            self.entry_count -= 1;
+            let name_len = self.name_bytes.remove(&key).unwrap_or(0);
+            if let Some(usage) = self.publisher_usage.get_mut(&entry.publisher) {
+                usage.0 -= 1;
+                usage.1 -= entry.data.len() + name_len;
+                if usage.0 == 0 {
+                    self.publisher_usage.remove(&entry.publisher);
                 }
             }
+            self.deadlines.remove(&key);
+            self.proofs.remove(&key);
+            // if the topic is empty, remove it from the map
+            if entries.is_empty() {
+                self.topics.remove(&topic);
+                if let Some(name) = self.names.remove(&topic) {
+                    self.topic_names.remove(&name);
+                }
+            }
+        }
+    }
+}
+
+impl StorageBackend for Storage {
+    fn get(&self, topic: Id) -> Option<Vec<TopicEntry>> {
+        Storage::get(self, topic).cloned()
+    }
+
+    fn topics(&self) -> Vec<Id> {
+        Storage::topics(self).collect()
+    }
+
+    fn closest_topics(&self, to: Id, n: usize) -> Vec<Id> {
+        Storage::closest_topics(self, to, n)
+    }
+
+    fn remaining_lifetime(&self, topic: Id, publisher: Id) -> Option<u32> {
+        Storage::remaining_lifetime(self, topic, publisher)
+    }
+
+    fn check_entry(&self, topic: Id, name: Option<&str>, publisher: Id, lifetime: u32, data: &[u8]) -> Result<(), Error> {
+        Storage::check_entry(&self.config, topic, name, publisher, lifetime, data)
+    }
+
+    fn insert(&mut self, topic: Id, name: Option<String>, publisher: Id, lifetime: u32, data: Vec<u8>) -> Result<(), Error> {
+        self.insert_named(topic, name, publisher, lifetime, data)
+    }
+
+    fn insert_signed(&mut self, topic: Id, lifetime: u32, data: Vec<u8>, proof: RecordProof) -> Result<(), Error> {
+        Storage::insert_signed(self, topic, lifetime, data, proof)
+    }
+
+    fn proof(&self, topic: Id, entry: &TopicEntry) -> Option<RecordProof> {
+        Storage::proof(self, topic, entry).cloned()
+    }
+
+    fn append(&mut self, topic: Id, publisher: Id, lifetime: u32, data: Vec<u8>) -> Result<(), Error> {
+        Storage::append(self, topic, publisher, lifetime, data)
+    }
+
+    fn compare_and_swap(
+        &mut self,
+        topic: Id,
+        publisher: Id,
+        lifetime: u32,
+        expected: Option<&[u8]>,
+        data: Vec<u8>,
+    ) -> Result<bool, Error> {
+        Storage::compare_and_swap(self, topic, publisher, lifetime, expected, data)
+    }
+
+    fn remove(&mut self, topic: Id, publisher: Id) {
+        Storage::remove(self, topic, publisher)
+    }
+
+    fn periodic_run(&mut self) -> Vec<(Id, Id)> {
+        Storage::periodic_run(self)
+    }
+
+    fn export(&self) -> SerializableStore {
+        Storage::export(self)
+    }
+
+    fn import(&mut self, store: SerializableStore) -> usize {
+        Storage::import(self, store)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::transport::RecordSignature;
+
+    use super::*;
+
+    fn config() -> StorageConfig {
+        StorageConfig {
+            min_lifetime: 10,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn min_lifetime() {
+        let mut storage = Storage::new(config());
+        let topic = Id::ZERO;
+        let publisher = Id::ZERO;
+
+        assert!(matches!(
+            storage.insert(topic, publisher, 0, vec![1]),
+            Err(Error::InvalidLifetime)
+        ));
+        assert!(matches!(
+            storage.insert(topic, publisher, 9, vec![1]),
+            Err(Error::InvalidLifetime)
+        ));
+        assert!(storage.get(topic).is_none());
+
+        storage.insert(topic, publisher, 10, vec![1]).unwrap();
+        assert_eq!(storage.get(topic).map(|x| x.len()), Some(1));
+    }
+
+    #[test]
+    fn publisher_quota() {
+        let mut storage = Storage::new(StorageConfig {
+            max_publisher_entries: Some(3),
+            max_publisher_bytes: Some(10),
+            ..config()
+        });
+        let (abuser, other) = (Id::ZERO.set_bit(0), Id::ZERO.set_bit(1));
+        let topic = |x: u8| Id::ZERO.set_bit(10 + x);
+
+        for i in 0..3 {
+            storage.insert(topic(i), abuser, 60, vec![i]).unwrap();
+        }
+        // Every topic counts against the same quota
+        assert!(matches!(storage.insert(topic(3), abuser, 60, vec![3]), Err(Error::PublisherQuotaExceeded)));
+        assert!(storage.get(topic(3)).is_none());
+        // Replacing an entry doesn't need more space
+        storage.insert(topic(0), abuser, 60, vec![4, 4]).unwrap();
+
+        // Other publishers are not affected
+        for i in 0..3 {
+            storage.insert(topic(i), other, 60, vec![i]).unwrap();
+        }
+
+        // Removed entries free the quota
+        storage.remove(topic(1), abuser);
+        storage.insert(topic(3), abuser, 60, vec![3]).unwrap();
+
+        // Byte quota (entries are 2 + 1 + 1 bytes)
+        storage.remove(topic(3), abuser);
+        assert!(matches!(storage.insert(topic(3), abuser, 60, vec![0; 8]), Err(Error::PublisherQuotaExceeded)));
+        storage.insert(topic(3), abuser, 60, vec![0; 7]).unwrap();
+        assert!(matches!(storage.check_entry(topic(4), None, other, 60, &[0; 11]), Err(Error::PublisherQuotaExceeded)));
+
+        // Rejected inserts and swaps leave the entry they would replace in place
+        let data = |x: &Storage| x.get(topic(0)).unwrap().iter().find(|x| x.publisher == abuser).unwrap().data.clone();
+        assert!(matches!(storage.insert(topic(0), abuser, 60, vec![0; 4]), Err(Error::PublisherQuotaExceeded)));
+        assert_eq!(data(&storage), vec![4, 4]);
+        assert!(matches!(
+            storage.compare_and_swap(topic(0), abuser, 60, Some(&[4, 4]), vec![0; 4]),
+            Err(Error::PublisherQuotaExceeded)
+        ));
+        assert_eq!(data(&storage), vec![4, 4]);
+        assert_eq!(storage.entry_count(), 6);
+        assert!(storage.compare_and_swap(topic(0), abuser, 60, Some(&[4, 4]), vec![0; 2]).unwrap());
+        assert_eq!(data(&storage), vec![0; 2]);
+    }
+
+    #[test]
+    fn list_topics() {
+        let mut storage = Storage::new(StorageConfig {
+            topic_index: true,
+            ..Default::default()
+        });
+        let publisher = Id::ZERO;
+        let names = ["chat:general", "chat:random", "chatter", "files:readme"];
+        for (i, name) in names.iter().enumerate() {
+            let topic = Id::ZERO.set_bit(i as u8);
+            storage.insert_named(topic, Some(name.to_string()), publisher, 10, vec![1]).unwrap();
+        }
+        // Unnamed topics are never listed
+        storage.insert(Id::MAX, publisher, 10, vec![1]).unwrap();
+
+        let listed = storage.list_topics("chat:");
+        assert_eq!(listed, vec![
+            ("chat:general".to_owned(), Id::ZERO.set_bit(0)),
+            ("chat:random".to_owned(), Id::ZERO.set_bit(1)),
+        ]);
+        assert_eq!(storage.list_topics("").len(), 4);
+
+        // Removed topics are removed from the index too
+        storage.remove(Id::ZERO.set_bit(0), publisher);
+        assert_eq!(storage.list_topics("chat:").len(), 1);
+
+        // A name used by another topic is not taken over, the entry is still stored
+        let other = Id::ZERO.set_bit(10);
+        storage.insert_named(other, Some("chat:random".to_owned()), Id::MAX, 10, vec![2]).unwrap();
+        assert!(storage.get(other).is_some());
+        assert_eq!(storage.list_topics("chat:"), vec![("chat:random".to_owned(), Id::ZERO.set_bit(1))]);
+
+        // Names are not indexed if the index is disabled
+        let mut storage = Storage::new(StorageConfig::default());
+        storage.insert_named(Id::ZERO, Some("chat:general".to_owned()), publisher, 10, vec![1]).unwrap();
+        assert!(storage.list_topics("chat:").is_empty());
+    }
+
+    #[test]
+    fn topic_name_limits() {
+        let mut storage = Storage::new(StorageConfig {
+            topic_index: true,
+            max_size: 10,
+            max_name_len: 6,
+            max_publisher_bytes: Some(12),
+            ..Default::default()
+        });
+        let publisher = Id::ZERO;
+        let name = |x: &str| Some(x.to_owned());
+
+        assert!(matches!(
+            storage.insert_named(Id::ZERO, name("chat:general"), publisher, 10, vec![1]),
+            Err(Error::InvalidData)
+        ));
+        // The name counts towards the size of the entry
+        assert!(matches!(
+            storage.insert_named(Id::ZERO, name("chat"), publisher, 10, vec![1; 7]),
+            Err(Error::InvalidData)
+        ));
+        storage.insert_named(Id::ZERO, name("chat"), publisher, 10, vec![1; 4]).unwrap();
+        // And towards the quota of the publisher
+        assert!(matches!(
+            storage.insert_named(Id::MAX, name("files"), publisher, 10, vec![1; 4]),
+            Err(Error::PublisherQuotaExceeded)
+        ));
+        storage.insert(Id::MAX, publisher, 10, vec![1; 4]).unwrap();
+        assert!(storage.get(Id::ZERO).is_some());
+        // The quota is full: 4 bytes of name and 8 of data
+        let other = Id::ZERO.set_bit(1);
+        assert!(matches!(
+            storage.insert(other, publisher, 10, vec![1]),
+            Err(Error::PublisherQuotaExceeded)
+        ));
+        // Removing the entry releases the name bytes too
+        storage.remove(Id::ZERO, publisher);
+        storage.insert_named(other, name("files"), publisher, 10, vec![1; 3]).unwrap();
+    }
+
+    #[test]
+    fn export_import() {
+        let config = StorageConfig {
+            topic_index: true,
+            ..Default::default()
+        };
+        let mut storage = Storage::new(config.clone());
+        let (a, b) = (Id::ZERO.set_bit(1), Id::ZERO.set_bit(2));
+        storage.insert(Id::ZERO, a, 100, vec![1]).unwrap();
+        let signature = RecordSignature { publisher: b, public_key: vec![4], signature: vec![5] };
+        let proof = RecordProof { lifetime: 200, signature };
+        storage.insert_signed(Id::ZERO, 200, vec![2], proof.clone()).unwrap();
+        storage.insert_named(Id::MAX, Some("chat".to_owned()), a, 300, vec![3]).unwrap();
+
+        let exported = storage.export();
+        assert_eq!(exported.entries.len(), 3);
+
+        let mut restored = Storage::new(config);
+        assert_eq!(restored.import(exported.clone()), 3);
+        assert_eq!(restored.get(Id::ZERO), storage.get(Id::ZERO));
+        assert_eq!(restored.get(Id::MAX), storage.get(Id::MAX));
+        assert_eq!(restored.list_topics("chat"), vec![("chat".to_owned(), Id::MAX)]);
+        let signed = TopicEntry { publisher: b, data: vec![2] };
+        assert_eq!(restored.proof(Id::ZERO, &signed), Some(&proof));
+        for (topic, publisher, lifetime) in [(Id::ZERO, a, 100), (Id::ZERO, b, 200), (Id::MAX, a, 300)] {
+            let remaining = restored.remaining_lifetime(topic, publisher).unwrap();
+            assert!((lifetime - 1..=lifetime).contains(&remaining));
+        }
+
+        // Lifetimes are capped to the limits of the importing storage
+        let mut small = Storage::new(StorageConfig {
+            max_lifetime: 150,
+            max_entries: 2,
+            ..Default::default()
+        });
+        assert_eq!(small.import(exported), 2);
+        assert!(small.remaining_lifetime(Id::ZERO, b).unwrap() <= 150);
+    }
+
+    #[test]
+    fn closest_topics() {
+        let mut storage = Storage::new(config());
+        let publisher = Id::ZERO;
+        let topics: Vec<_> = (0..8).map(|x| Id::ZERO.set_bit(x * 3)).collect();
+        for topic in topics.iter() {
+            storage.insert(*topic, publisher, 10, vec![1]).unwrap();
         }
+
+        let target = Id::ZERO.set_bit(9).set_bit(10);
+        let mut expected = topics.clone();
+        expected.sort_by_key(|x| target ^ *x);
+        expected.truncate(3);
+        let closest = storage.closest_topics(target, 3);
+        assert_eq!(closest, expected);
+        assert_eq!(closest[0], Id::ZERO.set_bit(9));
+
+        assert_eq!(storage.closest_topics(target, 100).len(), topics.len());
+        assert!(Storage::new(config()).closest_topics(target, 3).is_empty());
+    }
+
+    #[test]
+    fn publisher_modes() {
+        let (topic, publisher) = (Id::ZERO, Id::ZERO.set_bit(1));
+        let data = |x: &Storage| -> Vec<Vec<u8>> {
+            x.get(topic).map_or(Vec::new(), |x| x.iter().map(|x| x.data.clone()).collect())
+        };
+
+        // Replace: the last insert wins
+        let mut storage = Storage::new(config());
+        storage.insert(topic, publisher, 10, vec![1]).unwrap();
+        storage.insert(topic, publisher, 20, vec![2]).unwrap();
+        assert_eq!(data(&storage), vec![vec![2]]);
+        assert!(storage.remaining_lifetime(topic, publisher).unwrap() > 10);
+
+        // Append: every distinct value is kept
+        let mut storage = Storage::new(StorageConfig {
+            publisher_mode: PublisherMode::Append,
+            ..config()
+        });
+        storage.insert(topic, publisher, 10, vec![1]).unwrap();
+        storage.insert(topic, publisher, 20, vec![2]).unwrap();
+        storage.insert(topic, Id::ZERO.set_bit(2), 10, vec![2]).unwrap();
+        // Re-inserting the same value only refreshes it
+        storage.insert(topic, publisher, 30, vec![1]).unwrap();
+        assert_eq!(data(&storage), vec![vec![2], vec![2], vec![1]]);
+        assert!(storage.remaining_lifetime(topic, publisher).unwrap() > 20);
+
+        // CAS replaces the most recent entry of the publisher
+        assert!(!storage.compare_and_swap(topic, publisher, 10, Some(&[2]), vec![3]).unwrap());
+        assert!(storage.compare_and_swap(topic, publisher, 10, Some(&[1]), vec![3]).unwrap());
+        assert_eq!(data(&storage), vec![vec![2], vec![2], vec![3]]);
+
+        let exported = storage.export();
+        let mut restored = Storage::new(storage.config.clone());
+        assert_eq!(restored.import(exported), 3);
+        assert_eq!(data(&restored), data(&storage));
+
+        // Removal drops every entry of the publisher
+        storage.remove(topic, publisher);
+        assert_eq!(data(&storage), vec![vec![2]]);
+        assert_eq!(storage.entry_count, 1);
+    }
+
+    #[test]
+    fn append() {
+        let (topic, a, b) = (Id::ZERO, Id::ZERO.set_bit(1), Id::ZERO.set_bit(2));
+        let data = |x: &Storage| -> Vec<(Id, Vec<u8>)> {
+            x.get(topic).map_or(Vec::new(), |x| x.iter().map(|x| (x.publisher, x.data.clone())).collect())
+        };
+        let mut storage = Storage::new(StorageConfig {
+            max_entries_per_publisher: 3,
+            ..config()
+        });
+
+        // Entries are kept in append order, even in replace mode
+        storage.append(topic, a, 10, vec![1]).unwrap();
+        storage.append(topic, b, 10, vec![1]).unwrap();
+        storage.append(topic, a, 10, vec![2]).unwrap();
+        storage.append(topic, a, 10, vec![3]).unwrap();
+        assert_eq!(data(&storage), vec![(a, vec![1]), (b, vec![1]), (a, vec![2]), (a, vec![3])]);
+
+        // Appending over the limit drops the oldest entries of the publisher only
+        storage.append(topic, a, 10, vec![4]).unwrap();
+        assert_eq!(data(&storage), vec![(b, vec![1]), (a, vec![2]), (a, vec![3]), (a, vec![4])]);
+
+        // Appending the same data moves it to the end
+        storage.append(topic, a, 30, vec![2]).unwrap();
+        assert_eq!(data(&storage), vec![(b, vec![1]), (a, vec![3]), (a, vec![4]), (a, vec![2])]);
+        assert!(storage.remaining_lifetime(topic, a).unwrap() > 10);
+        assert_eq!(storage.entry_count, 4);
+
+        // Inserts still replace every entry of the publisher
+        storage.insert(topic, a, 10, vec![5]).unwrap();
+        assert_eq!(data(&storage), vec![(b, vec![1]), (a, vec![5])]);
+
+        storage.remove(topic, b);
+        storage.remove(topic, a);
+        assert!(storage.get(topic).is_none());
+        assert_eq!(storage.entry_count, 0);
     }
 }
@@ -1,4 +1,12 @@
-use std::{borrow::Cow, fmt::Debug, future::Future};
+use std::{
+    borrow::Cow,
+    fmt::Debug,
+    future::Future,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+};
 
 #[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
@@ -7,6 +15,8 @@ use thiserror::Error;
 use crate::id::Id;
 
 pub mod simulate;
+#[cfg(test)]
+pub(crate) mod testing;
 
 /// An interface to deal with Transport-held contacts
 ///
@@ -33,6 +43,42 @@ impl Contact for Id {
     }
 }
 
+/// Number of new connections that can still be opened while resolving
+/// responses, shared between all the requests of a single search.
+///
+/// This prevents a single lookup from cascading into an unbounded
+/// number of connection establishments.
+#[derive(Clone, Debug)]
+pub struct ConnectionBudget(Option<Arc<AtomicU32>>);
+
+impl ConnectionBudget {
+    pub fn unlimited() -> Self {
+        ConnectionBudget(None)
+    }
+
+    pub fn new(max_connections: u32) -> Self {
+        ConnectionBudget(Some(Arc::new(AtomicU32::new(max_connections))))
+    }
+
+    /// Takes up to n connections from the budget, returns how many were granted
+    pub fn take(&self, n: usize) -> usize {
+        let budget = match &self.0 {
+            Some(x) => x,
+            None => return n,
+        };
+        let n = n.min(u32::MAX as usize) as u32;
+        let prev = budget
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |x| Some(x - x.min(n)))
+            .unwrap();
+        prev.min(n) as usize
+    }
+
+    /// Connections left in the budget (None if unlimited)
+    pub fn remaining(&self) -> Option<u32> {
+        self.0.as_ref().map(|x| x.load(Ordering::SeqCst))
+    }
+}
+
 /// Object able to send messages to an id
 // Should use some sort of interior mutability and Refcounting
 // You must be able to send a Transport copy between boundaries! (Send)
@@ -59,6 +105,16 @@ pub trait TransportSender: Clone {
     /// a [`TransportError::ConnectionLost`] error might be thrown
     fn send(&self, id: Id, msg: Request) -> Self::Fut;
 
+    /// Sends a message like [`TransportSender::send`], but the new
+    /// connections opened to resolve the response are taken from the budget.
+    ///
+    /// Once the budget is exhausted the nodes that would need a new
+    /// connection are left out of the response.
+    fn send_with_budget(&self, id: Id, msg: Request, budget: ConnectionBudget) -> Self::Fut {
+        let _ = budget;
+        self.send(id, msg)
+    }
+
     /// Wraps an Id in a Contact
     ///
     /// The passed Id must be used in the DHT's routing table,
@@ -69,6 +125,27 @@ pub trait TransportSender: Clone {
     /// probably be dropped at the transport level.
     fn wrap_contact(&self, id: Id) -> Self::Contact;
 
+    /// Closes every connection, called when the node leaves the network.
+    fn shutdown(&self) {}
+
+    /// Number of new connections that can still be opened (None if unlimited)
+    fn connection_headroom(&self) -> Option<u32> {
+        None
+    }
+
+    /// Future returned when checking the signature of a record
+    type VerifyFut: Future<Output = bool>;
+
+    /// Checks that the record was signed by the owner of `record.publisher`
+    /// (and that the publisher id is derived from its key).
+    ///
+    /// Only records that pass this check are stored as signed records.
+    fn verify_record(&self, topic: Id, lifetime: u32, data: &[u8], record: &RecordSignature) -> Self::VerifyFut;
+
+    /// Checks that topic is the id derived from the topic name,
+    /// only the names that pass this check are indexed.
+    fn verify_topic_name(&self, topic: Id, name: &str) -> Self::VerifyFut;
+
     /// The type of the smart pointer used by this transport
     type Contact: Contact;
 }
@@ -77,20 +154,170 @@ pub trait TransportListener {
     /// Returns true only if the id is used in the routing protocol
     fn on_connect(&self, id: Id) -> bool;
 
-    fn on_disconnect(&self, id: Id);
+    /// Returns true if the id is still wanted by the routing protocol,
+    /// the transport might try to reconnect to it
+    fn on_disconnect(&self, id: Id) -> bool;
 
     fn on_request(&self, sender: Id, request: Request) -> Response;
+
+    /// Requests that should be pushed to a peer right after it connected
+    /// (ex. stored entries it's now responsible for).
+    ///
+    /// Transports should call this once the contact registered in
+    /// [`TransportListener::on_connect`] can be used to send messages.
+    fn take_pending_requests(&self, _id: Id) -> Vec<Request> {
+        Vec::new()
+    }
 }
 
+/// Requests sent between DHT nodes.
+///
+/// Newer nodes might send kinds unknown to this version, they are decoded as
+/// [`Request::Unknown`] (answered with [`RawResponse::Error`]) instead of breaking the connection.
+/// Only self-describing formats (ex. JSON) can skip the payload of an unknown request.
 #[derive(Clone, Debug, PartialEq, Eq)]
-#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[non_exhaustive]
 pub enum Request {
     FindNodes(Id),
     // Id, max_entries
     FindData(Id, u32),
     // id, seconds, data
     Insert(Id, u32, Vec<u8>),
+    // id, seconds, data, original topic name
+    InsertNamed(Id, u32, Vec<u8>, String),
+    // id, seconds, data (kept with the previous entries of the publisher)
+    Append(Id, u32, Vec<u8>),
+    // id, seconds, data, signature of the record (checked by the transport)
+    InsertSigned(Id, u32, Vec<u8>, RecordSignature),
+    // id, seconds, expected data (None if not present), new data
+    Cas(Id, u32, Option<Vec<u8>>, Vec<u8>),
     Remove(Id),
+    // id, entries of every publisher (oldest first), sent to a node that should also store them
+    Replicate(Id, Vec<ReplicaEntry>),
+    // Request kind not supported by this node, can't be sent
+    #[cfg_attr(feature = "serde", serde(skip_serializing))]
+    Unknown,
+}
+
+#[cfg(feature = "serde")]
+mod request_serde {
+    use std::fmt;
+
+    use serde::{de::{EnumAccess, IgnoredAny, VariantAccess, Visitor}, Deserialize, Deserializer};
+
+    use super::Request;
+
+    // Names (or indexes) of the known requests, in declaration order
+    #[derive(Deserialize)]
+    #[serde(field_identifier)]
+    enum RequestKind {
+        FindNodes,
+        FindData,
+        Insert,
+        InsertNamed,
+        Append,
+        InsertSigned,
+        Cas,
+        Remove,
+        Replicate,
+        #[serde(other)]
+        Other,
+    }
+
+    const VARIANTS: &[&str] = &["FindNodes", "FindData", "Insert", "InsertNamed", "Append", "InsertSigned", "Cas", "Remove", "Replicate"];
+
+    struct RequestVisitor;
+
+    impl<'de> Visitor<'de> for RequestVisitor {
+        type Value = Request;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("a DHT request")
+        }
+
+        fn visit_enum<A: EnumAccess<'de>>(self, data: A) -> Result<Self::Value, A::Error> {
+            let (kind, variant) = data.variant()?;
+            // Tuple variants are read as tuples, the encoding is the same
+            Ok(match kind {
+                RequestKind::FindNodes => Request::FindNodes(variant.newtype_variant()?),
+                RequestKind::FindData => {
+                    let (id, limit) = variant.newtype_variant()?;
+                    Request::FindData(id, limit)
+                }
+                RequestKind::Insert => {
+                    let (id, lifetime, data) = variant.newtype_variant()?;
+                    Request::Insert(id, lifetime, data)
+                }
+                RequestKind::InsertNamed => {
+                    let (id, lifetime, data, name) = variant.newtype_variant()?;
+                    Request::InsertNamed(id, lifetime, data, name)
+                }
+                RequestKind::Append => {
+                    let (id, lifetime, data) = variant.newtype_variant()?;
+                    Request::Append(id, lifetime, data)
+                }
+                RequestKind::InsertSigned => {
+                    let (id, lifetime, data, signature) = variant.newtype_variant()?;
+                    Request::InsertSigned(id, lifetime, data, signature)
+                }
+                RequestKind::Cas => {
+                    let (id, lifetime, expected, data) = variant.newtype_variant()?;
+                    Request::Cas(id, lifetime, expected, data)
+                }
+                RequestKind::Remove => Request::Remove(variant.newtype_variant()?),
+                RequestKind::Replicate => {
+                    let (id, entries) = variant.newtype_variant()?;
+                    Request::Replicate(id, entries)
+                }
+                RequestKind::Other => {
+                    variant.newtype_variant::<IgnoredAny>()?;
+                    Request::Unknown
+                }
+            })
+        }
+    }
+
+    impl<'de> Deserialize<'de> for Request {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            deserializer.deserialize_enum("Request", VARIANTS, RequestVisitor)
+        }
+    }
+}
+
+/// Proof that a record was created by the owner of a key.
+///
+/// The record is stored with `publisher` as its publisher instead of the sender,
+/// so it can be inserted through any node. The DHT checks signatures with
+/// [`TransportSender::verify_record`] before storing the record.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct RecordSignature {
+    pub publisher: Id,
+    pub public_key: Vec<u8>,
+    // Signature of RecordSignature::payload
+    pub signature: Vec<u8>,
+}
+
+/// Signature kept with a stored signed record, so that it can be
+/// replicated to other nodes and checked again by them.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct RecordProof {
+    // Lifetime covered by the signature
+    pub lifetime: u32,
+    pub signature: RecordSignature,
+}
+
+impl RecordSignature {
+    /// Bytes covered by the signature: topic, lifetime (big endian) and data
+    pub fn payload(topic: Id, lifetime: u32, data: &[u8]) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(topic.as_bytes().len() + 4 + data.len());
+        payload.extend_from_slice(topic.as_bytes());
+        payload.extend_from_slice(&lifetime.to_be_bytes());
+        payload.extend_from_slice(data);
+        payload
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -100,11 +327,30 @@ pub struct TopicEntry {
     pub data: Vec<u8>,
 }
 
+/// Copy of a stored entry, with its original publisher.
+///
+/// The receiver only stores the entries sent by their own publisher and
+/// the signed records whose proof is still valid, and only for the publishers
+/// that have nothing stored in the topic yet.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ReplicaEntry {
+    pub publisher: Id,
+    // Seconds left before the entry expires
+    pub lifetime: u32,
+    pub data: Vec<u8>,
+    // Signature of the record (only for signed records)
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub proof: Option<RecordProof>,
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum RawResponse<T> {
     FoundNodes(Vec<T>),
     FoundData(Vec<TopicEntry>),
+    // Entries stored by the node, but other nodes are closer to the topic
+    FoundDataAndNodes(Vec<TopicEntry>, Vec<T>),
     Done,  // Generic response (ex: response to Insert)
     Error, // Generic bad response (should never be thrown with a correct client)
 }
@@ -123,6 +369,9 @@ pub enum TransportError {
     #[error("Error occurred while performing handshake")]
     Handshake,
 
+    #[error("Request refused by the peer")]
+    Refused,
+
     #[error("Unknown transport error {0}")]
     UnknownError(Cow<'static, str>),
 }
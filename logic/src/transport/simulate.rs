@@ -1,18 +1,21 @@
 use core::fmt;
 use std::{
-    collections::{hash_map::Entry, HashMap, HashSet},
+    collections::{hash_map::{DefaultHasher, Entry}, HashMap, HashSet},
     fmt::Write,
+    hash::{Hash, Hasher},
     sync::{Arc, Mutex, Weak},
 };
 
-use futures::Future;
+use futures::{future, Future};
 use tokio::sync::{broadcast, mpsc, oneshot, Barrier};
 use tracing::{debug, trace};
 
 use crate::{
     config::SystemConfig,
+    consts::ID_LEN_BITS,
     transport::{
-        Contact, RawResponse, Request, Response, TransportError, TransportListener, TransportSender,
+        ConnectionBudget, Contact, RawResponse, RecordSignature, Request, Response, TransportError,
+        TransportListener, TransportSender,
     },
     Id, KademliaDht,
 };
@@ -27,6 +30,9 @@ struct SimulatedResponse {
 #[derive(Clone, Debug)]
 pub struct IntrospectionData {
     pub connection_count: usize,
+    pub failed_request_count: usize,
+    pub sent_request_count: usize,
+    pub opened_connection_count: usize,
 }
 
 #[derive(Debug)]
@@ -43,10 +49,14 @@ enum TransportMessage {
     ConnectTo {
         // Sent from transport to the actor when a new node is contacted
         ids: Vec<(Id, mpsc::Sender<TransportMessage>)>,
+        // New connections are only opened while the budget allows it
+        budget: ConnectionBudget,
         res: oneshot::Sender<Vec<SearchContact>>,
     },
     // Used in testing
     Barrier(Arc<Barrier>),
+    // Stops the receiver, as if the node went offline
+    Shutdown,
 }
 
 #[derive(Clone, Debug)]
@@ -98,6 +108,11 @@ impl AsyncSimulatedTransport {
             id,
             data: Arc::new(Mutex::new(TransportData {
                 contacts: HashMap::new(),
+                unreachable: HashSet::new(),
+                eclipsing: None,
+                failed_request_count: 0,
+                sent_request_count: 0,
+                opened_connection_count: 0,
             })),
             receiver: tx,
         };
@@ -115,7 +130,7 @@ impl AsyncSimulatedTransport {
         shutdown: broadcast::Receiver<()>,
     ) -> Arc<KademliaDht<Sender>> {
         let (sender, receiver) = Self::create(id, shutdown);
-        let kad = Arc::new(KademliaDht::new(config, id, sender));
+        let kad = Arc::new(KademliaDht::new(config, id, sender).unwrap());
         tokio::spawn(receiver.run(kad.clone()));
         kad
     }
@@ -128,7 +143,22 @@ pub struct Receiver {
 }
 
 impl Receiver {
-    async fn run<L: TransportListener, R: AsRef<L>>(mut self, listener: R) {
+    // Sends the requests the listener wants to push to a newly connected peer
+    fn send_pending<L: TransportListener>(&self, listener: &L, id: Id) {
+        let requests = listener.take_pending_requests(id);
+        if requests.is_empty() {
+            return;
+        }
+        let sender = self.sender.clone();
+        tokio::spawn(async move {
+            for req in requests {
+                // Best effort, ignore errors
+                let _ = sender.send(id, req).await;
+            }
+        });
+    }
+
+    async fn run(mut self, listener: Arc<KademliaDht<Sender>>) {
         loop {
             let mail = tokio::select! {
                 x = self.mailbox.recv() => x,
@@ -149,12 +179,25 @@ impl Receiver {
                             .unwrap()
                             .contacts
                             .insert(id, (mex, ContactLifetime::Routing));
+                        self.send_pending(listener.as_ref(), id);
                     }
                 }
                 Request { id, msg, res: wait } => {
-                    let res = listener.as_ref().on_request(id, msg);
+                    let eclipsing = self.sender.data.lock().unwrap().eclipsing;
+                    if let (Some(target), super::Request::FindNodes(_) | super::Request::FindData(..)) = (eclipsing, &msg) {
+                        let fakes: Vec<_> = (0..8)
+                            .map(|i| target ^ Id::ZERO.set_bit(ID_LEN_BITS as u8 - 1 - i))
+                            .collect();
+                        let res = SimulatedResponse {
+                            contacts: vec![self.sender.receiver.clone(); fakes.len()],
+                            payload: Response::FoundNodes(fakes),
+                        };
+                        let _ = wait.send(res);
+                        continue;
+                    }
+                    let res = listener.handle_request(id, msg).await;
                     let contacts = match &res {
-                        Response::FoundNodes(ids) => {
+                        Response::FoundNodes(ids) | Response::FoundDataAndNodes(_, ids) => {
                             // We're sending node ids, also send contact data!
                             // (in a WebRTC-like implementation this would be a tad more complex)
                             let routes = self.sender.data.lock().unwrap();
@@ -171,17 +214,28 @@ impl Receiver {
                     // Ignore error, if the other half ignores the response we don't care
                     let _ = wait.send(res);
                 }
-                ConnectTo { ids, res } => {
-                    for (_, mailbox) in ids.iter() {
-                        mailbox
+                ConnectTo { ids, budget, res } => {
+                    let ids: Vec<_> = {
+                        let transport = self.sender.data.lock().unwrap();
+                        ids.into_iter()
+                            .filter(|(id, _)| transport.contacts.contains_key(id) || budget.take(1) == 1)
+                            .collect()
+                    };
+                    let mut alive = Vec::with_capacity(ids.len());
+                    for (id, mailbox) in ids {
+                        let hello = mailbox
                             .send(TransportMessage::Hello {
                                 id: self.sender.id,
                                 // Own mailbox
                                 mex: self.sender.receiver.clone(),
                             })
-                            .await
-                            .expect("Failed to send hello");
+                            .await;
+                        // Nodes that went offline cannot be connected
+                        if hello.is_ok() {
+                            alive.push((id, mailbox));
+                        }
                     }
+                    let ids = alive;
                     let contacts = ids
                         .iter()
                         .map(|(id, mailbox)| {
@@ -194,7 +248,11 @@ impl Receiver {
 
                             let routed = listener.as_ref().on_connect(*id);
 
-                            transport.insert(*id, mailbox.clone(), &self.sender.data, routed)
+                            transport.opened_connection_count += 1;
+                            let contact = transport.insert(*id, mailbox.clone(), &self.sender.data, routed);
+                            drop(transport);
+                            self.send_pending(listener.as_ref(), *id);
+                            contact
                         })
                         .collect();
 
@@ -203,6 +261,7 @@ impl Receiver {
                 Barrier(b) => {
                     b.wait().await;
                 }
+                Shutdown => break,
             }
         }
     }
@@ -215,6 +274,15 @@ enum ContactLifetime {
 
 struct TransportData {
     contacts: HashMap<Id, (mpsc::Sender<TransportMessage>, ContactLifetime)>,
+    // Simulates a network partition, requests to these ids always fail
+    unreachable: HashSet<Id>,
+    // Simulates a malicious node, searches are answered with fake nodes
+    // close to this id (all of them served by this node)
+    eclipsing: Option<Id>,
+    failed_request_count: usize,
+    sent_request_count: usize,
+    // Connections opened by this node (not counting incoming ones)
+    opened_connection_count: usize,
 }
 
 impl TransportData {
@@ -291,10 +359,16 @@ impl Sender {
         self,
         id: Id,
         msg: Request,
+        budget: ConnectionBudget,
     ) -> Result<RawResponse<SearchContact>, TransportError> {
         trace!("send_req({:?} to {:?}, {:?})", self.id, id, msg);
         let sender = {
-            let data = self.data.lock().unwrap();
+            let mut data = self.data.lock().unwrap();
+            data.sent_request_count += 1;
+            if data.unreachable.contains(&id) {
+                data.failed_request_count += 1;
+                return Err(TransportError::ConnectionLost);
+            }
             data.contacts
                 .get(&id)
                 .ok_or(TransportError::ContactLost)?
@@ -310,29 +384,33 @@ impl Sender {
                 res: tx,
             })
             .await
-            .expect("Failed to send request");
-        let SimulatedResponse { payload, contacts } = rx.await.expect("Error receiving response");
+            .map_err(|_| TransportError::ConnectionLost)?;
+        let SimulatedResponse { payload, contacts } = rx.await.map_err(|_| TransportError::ConnectionLost)?;
 
         debug!("{:?} -> {:?} = {:?}? {:?}", self.id, id, msg, payload);
 
         use RawResponse::*;
         let payload = match payload {
-            FoundNodes(nodes) => {
-                let x = nodes.into_iter().zip(contacts.into_iter()).collect();
-                let (tx, rx) = oneshot::channel();
-                self.receiver
-                    .send(TransportMessage::ConnectTo { ids: x, res: tx })
-                    .await
-                    .unwrap();
-                FoundNodes(rx.await.unwrap())
-            }
+            FoundNodes(nodes) => FoundNodes(self.connect_found(nodes, contacts, budget).await?),
             FoundData(x) => FoundData(x),
+            FoundDataAndNodes(x, nodes) => FoundDataAndNodes(x, self.connect_found(nodes, contacts, budget).await?),
             Done => Done,
             Error => Error,
         };
         Ok(payload)
     }
 
+    // Connects to the nodes returned by a peer (with the contacts it sent)
+    async fn connect_found(&self, nodes: Vec<Id>, contacts: Vec<mpsc::Sender<TransportMessage>>, budget: ConnectionBudget) -> Result<Vec<SearchContact>, TransportError> {
+        let x = nodes.into_iter().zip(contacts.into_iter()).collect();
+        let (tx, rx) = oneshot::channel();
+        self.receiver
+            .send(TransportMessage::ConnectTo { ids: x, budget, res: tx })
+            .await
+            .map_err(|_| TransportError::ConnectionLost)?;
+        rx.await.map_err(|_| TransportError::ConnectionLost)
+    }
+
     pub async fn connect_to(&self, ids: Vec<(Id, &Sender)>) {
         let (tx, rx) = oneshot::channel();
 
@@ -341,7 +419,7 @@ impl Sender {
             .map(|(id, sender)| (*id, sender.receiver.clone()))
             .collect();
         self.receiver
-            .send(TransportMessage::ConnectTo { ids, res: tx })
+            .send(TransportMessage::ConnectTo { ids, budget: ConnectionBudget::unlimited(), res: tx })
             .await
             .unwrap();
         rx.await.unwrap();
@@ -354,10 +432,22 @@ impl Sender {
             .unwrap();
     }
 
+    pub fn set_unreachable(&self, ids: impl IntoIterator<Item = Id>) {
+        self.data.lock().unwrap().unreachable = ids.into_iter().collect();
+    }
+
+    /// Makes this node answer every search with fake nodes close to target
+    pub fn set_eclipsing(&self, target: Option<Id>) {
+        self.data.lock().unwrap().eclipsing = target;
+    }
+
     pub fn introspect(&self) -> IntrospectionData {
         let data = self.data.lock().unwrap();
         IntrospectionData {
             connection_count: data.contacts.len(),
+            failed_request_count: data.failed_request_count,
+            sent_request_count: data.sent_request_count,
+            opened_connection_count: data.opened_connection_count,
         }
     }
 }
@@ -369,17 +459,72 @@ impl TransportSender for Sender {
 
     type Fut = impl Future<Output = Result<RawResponse<Self::Contact>, TransportError>>;
     fn send(&self, id: Id, msg: Request) -> Self::Fut {
+        self.send_with_budget(id, msg, ConnectionBudget::unlimited())
+    }
+
+    fn send_with_budget(&self, id: Id, msg: Request, budget: ConnectionBudget) -> Self::Fut {
         let s = self.clone();
-        s.send_req(id, msg)
+        s.send_req(id, msg, budget)
     }
 
     fn wrap_contact(&self, id: Id) -> Self::Contact {
         SearchContact::Routed(id)
     }
 
+    fn shutdown(&self) {
+        // Ignore errors, the receiver might be already stopped
+        let _ = self.receiver.try_send(TransportMessage::Shutdown);
+    }
+
+    type VerifyFut = future::Ready<bool>;
+    fn verify_record(&self, topic: Id, lifetime: u32, data: &[u8], record: &RecordSignature) -> Self::VerifyFut {
+        future::ready(verify_simulated(topic, lifetime, data, record))
+    }
+
+    fn verify_topic_name(&self, topic: Id, name: &str) -> Self::VerifyFut {
+        future::ready(simulated_topic_id(name) == topic)
+    }
+
     type Contact = SearchContact;
 }
 
+// Hash of the publisher and of the signed payload
+fn simulated_signature(publisher: Id, topic: Id, lifetime: u32, data: &[u8]) -> Vec<u8> {
+    let mut hasher = DefaultHasher::new();
+    publisher.hash(&mut hasher);
+    RecordSignature::payload(topic, lifetime, data).hash(&mut hasher);
+    hasher.finish().to_be_bytes().to_vec()
+}
+
+/// Signs a record for the simulated transport.
+///
+/// Anyone can compute these signatures, they only let tests
+/// tell signed records apart from forged ones.
+pub fn sign_simulated(publisher: Id, topic: Id, lifetime: u32, data: &[u8]) -> RecordSignature {
+    RecordSignature {
+        publisher,
+        public_key: Vec::new(),
+        signature: simulated_signature(publisher, topic, lifetime, data),
+    }
+}
+
+/// Checks a signature made with [`sign_simulated`]
+pub fn verify_simulated(topic: Id, lifetime: u32, data: &[u8], record: &RecordSignature) -> bool {
+    record.signature == simulated_signature(record.publisher, topic, lifetime, data)
+}
+
+/// Id of a named topic in the simulated transport
+pub fn simulated_topic_id(name: &str) -> Id {
+    let hash: Vec<u8> = (0..3u8)
+        .flat_map(|i| {
+            let mut hasher = DefaultHasher::new();
+            (i, name).hash(&mut hasher);
+            hasher.finish().to_be_bytes()
+        })
+        .collect();
+    Id::from_hash_truncated(&hash)
+}
+
 pub trait IntoDot {
     fn to_dot_string(self) -> String;
 }
@@ -418,8 +563,9 @@ where
 
 #[cfg(test)]
 mod tests {
-    use std::{cmp::Reverse, time::Duration};
+    use std::{cmp::Reverse, iter, num::NonZeroU64, time::{Duration, Instant}};
 
+    use futures::{stream::FuturesUnordered, StreamExt};
     use itertools::Itertools;
     use log::info;
     use rand::{
@@ -428,7 +574,7 @@ mod tests {
     };
     use test_log;
 
-    use crate::{search::BasicSearchOptions, transport::TopicEntry};
+    use crate::{config::PlacementStrategy, search::BasicSearchOptions, transport::TopicEntry};
 
     use super::*;
 
@@ -460,7 +606,7 @@ mod tests {
         // a will ask b for any other nodes, but there won't be any, so the search
         // will terminate with [b]
         let res = a
-            .query_nodes(bid, BasicSearchOptions { parallelism: 1 })
+            .query_nodes(bid, BasicSearchOptions { parallelism: 1, ..Default::default() })
             .await;
         assert_eq!(
             res.iter().map(|x| x.id()).collect::<Vec<_>>(),
@@ -476,7 +622,7 @@ mod tests {
         let (killswitch, _shutdown) = broadcast::channel(1);
 
         let config: SystemConfig = Default::default();
-        let search_options = BasicSearchOptions { parallelism: 2 };
+        let search_options = BasicSearchOptions { parallelism: 2, ..Default::default() };
 
         let ids = [
             "aaaaaaaa", "aaaabbbb", "aaaa0000", "aaaa4444", "4444aaaa", "44441234", "cafebabe",
@@ -508,14 +654,17 @@ mod tests {
         // Node-querying test
         let target = Id::from_hex("123456ff"); // Note: this node does not exist
         let found = dhts[4]
-            .query_nodes(target.clone(), search_options.clone())
+            .query_nodes_with_distance(target.clone(), search_options.clone())
             .await;
+        // The distances match the contacts, closest first
+        assert!(found.iter().all(|(d, x)| *d == (x.id() ^ target).leading_zeros()));
+        assert!(found.windows(2).all(|x| x[0].0 >= x[1].0));
         // How can we check that node orderings are equivalent?
         // We should check that the ordering has the best XOR distance from the target node
         assert_eq!(
             found
                 .iter()
-                .map(|x| (x.id() ^ target).leading_zeros())
+                .map(|x| x.0)
                 .collect::<Vec<_>>(),
             ids.iter()
                 .map(|x| (*x ^ target).leading_zeros())
@@ -546,6 +695,769 @@ mod tests {
         killswitch.send(()).unwrap();
     }
 
+    const TEST_IDS: [&str; 10] = [
+        "aaaaaaaa", "aaaabbbb", "aaaa0000", "aaaa4444", "4444aaaa", "44441234", "cafebabe",
+        "89abcdef", "12345678", "31415fab",
+    ];
+
+    /// Spawns a DHT for each id and bootstraps them through the first one
+    async fn spawn_network(
+        ids: &[Id],
+        config: &SystemConfig,
+        killswitch: &broadcast::Sender<()>,
+        search_options: &BasicSearchOptions,
+    ) -> Vec<Arc<KademliaDht<Sender>>> {
+        let dhts = ids
+            .iter()
+            .cloned()
+            .map(|id| AsyncSimulatedTransport::spawn(config.clone(), id, killswitch.subscribe()))
+            .collect::<Vec<_>>();
+
+        for i in 1..ids.len() {
+            dhts[i]
+                .transport()
+                .connect_to(vec![(ids[0], &dhts[0].transport)])
+                .await;
+            dhts[i].query_nodes(ids[i], search_options.clone()).await;
+        }
+        dhts
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn simulate_partition() {
+        let (killswitch, _shutdown) = broadcast::channel(1);
+
+        let config: SystemConfig = Default::default();
+        let search_options = BasicSearchOptions {
+            parallelism: 2,
+            connect_cooldown: Duration::from_secs(60),
+            max_connect_failures: 2,
+            ..Default::default()
+        };
+
+        let ids = TEST_IDS.into_iter().map(Id::from_hex).collect::<Vec<_>>();
+        let dhts = spawn_network(&ids, &config, &killswitch, &search_options).await;
+
+        // Partition: the searcher cannot reach half of the network
+        let searcher = &dhts[4];
+        searcher.transport().set_unreachable(ids[5..].iter().cloned());
+
+        for target in ["12345600", "cafeba00", "89abcd00"] {
+            let before = searcher.transport().introspect().failed_request_count;
+            searcher.query_nodes(Id::from_hex(target), search_options.clone()).await;
+            let failed = searcher.transport().introspect().failed_request_count - before;
+            // Queries already in flight might fail after the limit is reached
+            assert!(failed <= (search_options.max_connect_failures + search_options.parallelism) as usize);
+        }
+
+        // Every unreachable node has failed at least once by now, no more attempts are made
+        let before = searcher.transport().introspect().failed_request_count;
+        searcher.query_nodes(Id::from_hex("12345600"), search_options.clone()).await;
+        assert_eq!(searcher.transport().introspect().failed_request_count, before);
+
+        killswitch.send(()).unwrap();
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn simulate_region_backoff() {
+        let (killswitch, _shutdown) = broadcast::channel(1);
+
+        let config: SystemConfig = Default::default();
+        let search_options = BasicSearchOptions {
+            parallelism: 1,
+            connect_cooldown: Duration::ZERO,
+            region_backoff: Duration::ZERO,
+            ..Default::default()
+        };
+
+        let ids = TEST_IDS.into_iter().map(Id::from_hex).collect::<Vec<_>>();
+        let dhts = spawn_network(&ids, &config, &killswitch, &search_options).await;
+
+        // aaaa0000 and aaaa4444 share the same 16 bit prefix with the target
+        let target = Id::from_hex("aaaa8000");
+        let searcher = &dhts[9];
+        searcher.transport().set_unreachable([ids[2], ids[3]]);
+
+        let backoff = Duration::from_millis(500);
+        for region_backoff in [Duration::ZERO, backoff] {
+            let options = BasicSearchOptions { region_backoff, ..search_options.clone() };
+            let before = searcher.transport().introspect().failed_request_count;
+            let start = Instant::now();
+            searcher.query_nodes(target, options).await;
+            let elapsed = start.elapsed();
+            assert_eq!(searcher.transport().introspect().failed_request_count - before, 2);
+            // The second node of the region is contacted only after the backoff
+            if region_backoff.is_zero() {
+                assert!(elapsed < backoff);
+            } else {
+                assert!(elapsed >= backoff);
+            }
+        }
+
+        killswitch.send(()).unwrap();
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn simulate_cas() {
+        let (killswitch, _shutdown) = broadcast::channel(1);
+
+        let config: SystemConfig = Default::default();
+        let search_options = BasicSearchOptions { parallelism: 2, ..Default::default() };
+        let ids = TEST_IDS.into_iter().map(Id::from_hex).collect::<Vec<_>>();
+        let dhts = spawn_network(&ids, &config, &killswitch, &search_options).await;
+
+        let target = Id::from_hex("123456ff");
+        let lifetime = Duration::from_secs(60);
+        let publisher = &dhts[4];
+
+        // Nothing stored yet, swapping from "None" succeeds
+        assert!(publisher.compare_and_swap(target, None, vec![1], lifetime).await.unwrap());
+        // Successful swap
+        assert!(publisher.compare_and_swap(target, Some(vec![1]), vec![2], lifetime).await.unwrap());
+        // Conflicting swap (the value is now [2])
+        assert!(!publisher.compare_and_swap(target, Some(vec![1]), vec![3], lifetime).await.unwrap());
+        assert!(!publisher.compare_and_swap(target, None, vec![3], lifetime).await.unwrap());
+
+        let found = dhts[9].query_value(target, 10, search_options.clone()).await;
+        assert_eq!(found, vec![TopicEntry {
+            publisher: publisher.id(),
+            data: vec![2],
+        }]);
+
+        killswitch.send(()).unwrap();
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn simulate_append() {
+        let (killswitch, _shutdown) = broadcast::channel(1);
+
+        let config: SystemConfig = Default::default();
+        let search_options = BasicSearchOptions { parallelism: 2, ..Default::default() };
+        let ids = TEST_IDS.into_iter().map(Id::from_hex).collect::<Vec<_>>();
+        let dhts = spawn_network(&ids, &config, &killswitch, &search_options).await;
+
+        let target = Id::from_hex("123456ff");
+        let lifetime = Duration::from_secs(60);
+        let (a, b) = (&dhts[4], &dhts[7]);
+
+        assert!(a.insert_append(target, lifetime, vec![1]).await.unwrap() > 0);
+        assert!(b.insert_append(target, lifetime, vec![2]).await.unwrap() > 0);
+        assert!(a.insert_append(target, lifetime, vec![3]).await.unwrap() > 0);
+
+        // Every message is returned, in append order
+        let found = dhts[9].query_value(target, 10, search_options.clone()).await;
+        let entry = |publisher, data| TopicEntry { publisher, data };
+        assert_eq!(found, vec![entry(a.id(), vec![1]), entry(b.id(), vec![2]), entry(a.id(), vec![3])]);
+
+        killswitch.send(()).unwrap();
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn simulate_entry_limit() {
+        let (killswitch, _shutdown) = broadcast::channel(1);
+
+        let config: SystemConfig = Default::default();
+        let search_options = BasicSearchOptions { parallelism: 2, ..Default::default() };
+        let ids = TEST_IDS.into_iter().map(Id::from_hex).collect::<Vec<_>>();
+        let dhts = spawn_network(&ids, &config, &killswitch, &search_options).await;
+
+        let target = Id::from_hex("aaaa1234");
+        let lifetime = Duration::from_secs(60);
+        for publisher in &dhts[..6] {
+            assert!(publisher.insert(target, lifetime, vec![1]).await.unwrap() > 0);
+        }
+
+        // Only the entries of the publishers closest to the target are kept
+        let found = dhts[9].query_value(target, 3, search_options.clone()).await;
+        let publishers: Vec<_> = found.iter().map(|x| x.publisher).sorted().collect();
+        let closest: Vec<_> = ids[..6].iter()
+            .cloned()
+            .sorted_by_key(|x| *x ^ target)
+            .take(3)
+            .sorted()
+            .collect();
+        assert_eq!(publishers, closest);
+
+        // The newest entries of a publisher are kept
+        let topic = Id::from_hex("12345600");
+        for i in 0..4 {
+            assert!(dhts[4].insert_append(topic, lifetime, vec![i]).await.unwrap() > 0);
+        }
+        let found = dhts[9].query_value(topic, 2, search_options.clone()).await;
+        assert_eq!(found.into_iter().map(|x| x.data).collect::<Vec<_>>(), vec![vec![2], vec![3]]);
+
+        killswitch.send(()).unwrap();
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn simulate_insert_stream() {
+        let (killswitch, _shutdown) = broadcast::channel(1);
+
+        let config: SystemConfig = Default::default();
+        let search_options = BasicSearchOptions { parallelism: 2, ..Default::default() };
+        let ids = TEST_IDS.into_iter().map(Id::from_hex).collect::<Vec<_>>();
+        let dhts = spawn_network(&ids, &config, &killswitch, &search_options).await;
+
+        let topic = Id::from_hex("aaaa1111");
+        let acks: Vec<_> = dhts[8]
+            .insert_stream(topic, Duration::from_secs(60), vec![1])
+            .unwrap()
+            .collect()
+            .await;
+        assert_eq!(acks.len(), config.routing.bucket_size);
+        assert!(acks.iter().all(|(_, x)| x.is_ok()));
+
+        // One answer for each node storing the value
+        let acked: Vec<_> = acks.iter().map(|x| x.0).sorted().collect();
+        let holders: Vec<_> = dhts.iter()
+            .filter(|x| x.storage.read().unwrap().get(topic).is_some())
+            .map(|x| x.id())
+            .sorted()
+            .collect();
+        assert_eq!(acked, holders);
+
+        assert!(dhts[8].insert_stream(topic, Duration::ZERO, vec![1]).is_err());
+
+        killswitch.send(()).unwrap();
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn simulate_local_store_failure() {
+        let (killswitch, _shutdown) = broadcast::channel(1);
+
+        let mut config: SystemConfig = Default::default();
+        config.storage.max_entries = 1;
+        let search_options = BasicSearchOptions { parallelism: 2, ..Default::default() };
+        let ids = TEST_IDS.into_iter().map(Id::from_hex).collect::<Vec<_>>();
+        let lifetime = Duration::from_secs(60);
+
+        // The key is the id of the inserting node, so it's always one of the closest
+        let fill = |dht: &KademliaDht<Sender>| {
+            dht.storage.write().unwrap().insert(Id::from_hex("ffff"), dht.id(), 60, vec![0]).unwrap();
+        };
+        let dhts = spawn_network(&ids, &config, &killswitch, &search_options).await;
+        fill(&dhts[8]);
+        let topic = dhts[8].id();
+
+        // The full local storage is skipped, the other nodes still store the value
+        let count = dhts[8].insert(topic, lifetime, vec![1]).await.unwrap();
+        assert_eq!(count, config.routing.bucket_size - 1);
+        assert!(dhts[8].storage.read().unwrap().get(topic).is_none());
+
+        let acks: Vec<_> = dhts[8].insert_stream(topic, lifetime, vec![2]).unwrap().collect().await;
+        assert!(acks.iter().any(|x| matches!(x, (id, Err(TransportError::Refused)) if *id == topic)));
+        assert_eq!(acks.iter().filter(|(_, x)| x.is_ok()).count(), config.routing.bucket_size - 1);
+        assert!(dhts[8].compare_and_swap(topic, Some(vec![2]), vec![3], lifetime).await.unwrap());
+
+        // Without replication the insert is abandoned
+        config.storage.replicate_on_local_failure = false;
+        let dhts = spawn_network(&ids, &config, &killswitch, &search_options).await;
+        fill(&dhts[8]);
+        assert_eq!(dhts[8].insert(topic, lifetime, vec![1]).await.unwrap(), 0);
+        assert!(dhts.iter().all(|x| x.storage.read().unwrap().get(topic).is_none()));
+        assert!(dhts[8].compare_and_swap(topic, None, vec![1], lifetime).await.is_err());
+
+        killswitch.send(()).unwrap();
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn simulate_partial_data() {
+        let (killswitch, _shutdown) = broadcast::channel(1);
+
+        let config: SystemConfig = Default::default();
+        let search_options = BasicSearchOptions { parallelism: 2, ..Default::default() };
+        let ids = TEST_IDS.into_iter().map(Id::from_hex).collect::<Vec<_>>();
+        let dhts = spawn_network(&ids, &config, &killswitch, &search_options).await;
+
+        // aaaabbbb only knows one publisher, the closer aaaaaaaa knows another one
+        let topic = Id::from_hex("aaaaaaab");
+        let (first, second) = (Id::from_hex("1111"), Id::from_hex("2222"));
+        dhts[1].storage.write().unwrap().insert(topic, first, 60, vec![1]).unwrap();
+        dhts[0].storage.write().unwrap().insert(topic, second, 60, vec![2]).unwrap();
+
+        let res = dhts[1].on_request(ids[8], Request::FindData(topic, 10));
+        assert!(matches!(res, Response::FoundDataAndNodes(ref data, ref nodes) if data.len() == 1 && nodes == &[ids[0]]));
+        let res = dhts[0].on_request(ids[8], Request::FindData(topic, 10));
+        assert!(matches!(res, Response::FoundData(ref data) if data.len() == 1));
+
+        let mut found: Vec<_> = dhts[8].query_value(topic, 10, search_options.clone()).await
+            .into_iter()
+            .map(|x| (x.publisher, x.data))
+            .collect();
+        found.sort();
+        assert_eq!(found, vec![(first, vec![1]), (second, vec![2])]);
+
+        killswitch.send(()).unwrap();
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn simulate_query_stream() {
+        let (killswitch, _shutdown) = broadcast::channel(1);
+
+        let config: SystemConfig = Default::default();
+        let search_options = BasicSearchOptions { parallelism: 2, ..Default::default() };
+        let ids = TEST_IDS.into_iter().map(Id::from_hex).collect::<Vec<_>>();
+        let dhts = spawn_network(&ids, &config, &killswitch, &search_options).await;
+
+        // aaaabbbb answers first, the closer aaaaaaaa is only known through it
+        let topic = Id::from_hex("aaaaaaab");
+        let (first, second) = (Id::from_hex("1111"), Id::from_hex("2222"));
+        dhts[1].storage.write().unwrap().insert(topic, first, 60, vec![1]).unwrap();
+        dhts[0].storage.write().unwrap().insert(topic, second, 60, vec![2]).unwrap();
+
+        let searcher = &dhts[8];
+        let stream = searcher.query_value_stream(topic, 10, search_options.clone());
+        tokio::pin!(stream);
+        // The first entry is received while the search is still running
+        let entry = stream.next().await.unwrap();
+        assert_eq!(searcher.active_searches(), 1);
+
+        let mut found: Vec<_> = iter::once(entry)
+            .chain(stream.collect::<Vec<_>>().await)
+            .map(|x| (x.publisher, x.data))
+            .collect();
+        found.sort();
+        assert_eq!(found, vec![(first, vec![1]), (second, vec![2])]);
+        assert_eq!(searcher.active_searches(), 0);
+
+        killswitch.send(()).unwrap();
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn simulate_handoff() {
+        let (killswitch, _shutdown) = broadcast::channel(1);
+
+        let config: SystemConfig = Default::default();
+        let search_options = BasicSearchOptions { parallelism: 2, ..Default::default() };
+        let ids = TEST_IDS.into_iter().map(Id::from_hex).collect::<Vec<_>>();
+        let dhts = spawn_network(&ids, &config, &killswitch, &search_options).await;
+
+        // No node is close to the topic (yet)
+        let topic = Id::from_hex("ffff0000");
+        let lifetime = Duration::from_secs(60);
+        // Only signed entries can be handed off by the nodes storing them
+        for (dht, data) in [(&dhts[4], vec![1, 2, 3]), (&dhts[5], vec![4, 5])] {
+            let signature = sign_simulated(dht.id(), topic, 60, &data);
+            dht.insert_signed(topic, lifetime, data, signature).await.unwrap();
+        }
+        assert!(dhts.iter().any(|x| x.storage.read().unwrap().get(topic).is_some()));
+
+        // A node closer than anyone else joins
+        let newcomer_id = Id::from_hex("ffff0001");
+        let newcomer = AsyncSimulatedTransport::spawn(config.clone(), newcomer_id, killswitch.subscribe());
+        newcomer.transport().connect_to(vec![(ids[0], &dhts[0].transport)]).await;
+        newcomer.query_nodes(newcomer_id, search_options.clone()).await;
+
+        // Hand-offs are sent in the background
+        let mut received = None;
+        for _ in 0..1000 {
+            received = newcomer.storage.read().unwrap().get(topic).cloned();
+            if received.is_some() {
+                break;
+            }
+            tokio::task::yield_now().await;
+        }
+        let mut received = received.expect("Entry not handed off");
+        // Every entry is handed off with its original publisher
+        received.sort_by_key(|x| x.publisher);
+        let mut expected = vec![
+            TopicEntry { publisher: dhts[4].id(), data: vec![1, 2, 3] },
+            TopicEntry { publisher: dhts[5].id(), data: vec![4, 5] },
+        ];
+        expected.sort_by_key(|x| x.publisher);
+        assert_eq!(received, expected);
+
+        // Unrelated topics are not handed off
+        assert_eq!(newcomer.storage.read().unwrap().closest_topics(Id::ZERO, 10), vec![topic]);
+
+        killswitch.send(()).unwrap();
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn simulate_republish() {
+        let (killswitch, _shutdown) = broadcast::channel(1);
+
+        let mut config: SystemConfig = Default::default();
+        config.storage.republish = true;
+        let search_options = BasicSearchOptions { parallelism: 2, ..Default::default() };
+        let ids = TEST_IDS.into_iter().map(Id::from_hex).collect::<Vec<_>>();
+        let dhts = spawn_network(&ids, &config, &killswitch, &search_options).await;
+
+        let lifetime = Duration::from_secs(2);
+        let (republished, forgotten) = (Id::from_hex("aaaa1111"), Id::from_hex("aaaa2222"));
+        dhts[8].insert(republished, lifetime, vec![1]).await.unwrap();
+        dhts[3].insert(forgotten, lifetime, vec![2]).await.unwrap();
+
+        // Nothing to republish yet
+        assert_eq!(dhts[8].republish().await, 0);
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+
+        // The closest nodes leave, a new closest node joins
+        let mut leaving: Vec<_> = dhts.iter().filter(|x| x.id() != dhts[8].id()).collect();
+        leaving.sort_by_key(|x| x.id() ^ republished);
+        for dht in leaving.iter().take(2) {
+            dht.leave().await;
+        }
+        let newcomer_id = Id::from_hex("aaaa1110");
+        let newcomer = AsyncSimulatedTransport::spawn(config.clone(), newcomer_id, killswitch.subscribe());
+        newcomer.transport().connect_to(vec![(ids[0], &dhts[0].transport)]).await;
+        newcomer.query_nodes(newcomer_id, search_options.clone()).await;
+
+        // Half of the lifetime has elapsed
+        assert_eq!(dhts[8].republish().await, 1);
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        for dht in dhts.iter().chain(iter::once(&newcomer)) {
+            dht.periodic_run();
+        }
+
+        // Only the republished value survived its original lifetime
+        let found = newcomer.query_value(republished, 10, search_options.clone()).await;
+        assert_eq!(found, vec![TopicEntry { publisher: dhts[8].id(), data: vec![1] }]);
+        assert!(newcomer.storage.read().unwrap().get(republished).is_some());
+        assert!(newcomer.query_value(forgotten, 10, search_options.clone()).await.is_empty());
+
+        killswitch.send(()).unwrap();
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn simulate_connection_budget() {
+        let (killswitch, _shutdown) = broadcast::channel(1);
+
+        let config: SystemConfig = Default::default();
+        let search_options = BasicSearchOptions { parallelism: 2, ..Default::default() };
+        let mut rng = StdRng::seed_from_u64(0x5eed);
+        let ids = (0..30).map(|_| rng.gen::<Id>()).collect::<Vec<_>>();
+        let dhts = spawn_network(&ids, &config, &killswitch, &search_options).await;
+
+        let budget = 3;
+        let limited_options = BasicSearchOptions {
+            max_new_connections: Some(budget),
+            ..search_options.clone()
+        };
+
+        // Fresh nodes only know the first node, every other node needs a new connection
+        let spawn_fresh = |id: Id| {
+            let dht = AsyncSimulatedTransport::spawn(config.clone(), id, killswitch.subscribe());
+            let first = dhts[0].clone();
+            async move {
+                dht.transport().connect_to(vec![(first.id(), &first.transport)]).await;
+                dht
+            }
+        };
+
+        for i in 0..5 {
+            let searcher = spawn_fresh(rng.gen()).await;
+            let before = searcher.transport().introspect().opened_connection_count;
+            let target = ids[(i * 7) % ids.len()];
+            searcher.query_nodes(target, limited_options.clone()).await;
+            let opened = searcher.transport().introspect().opened_connection_count - before;
+            assert!(opened <= budget as usize, "opened {opened} connections");
+        }
+
+        // Without a budget the same kind of search opens more connections
+        let searcher = spawn_fresh(rng.gen()).await;
+        let before = searcher.transport().introspect().opened_connection_count;
+        searcher.query_nodes(ids[7], search_options.clone()).await;
+        let opened = searcher.transport().introspect().opened_connection_count - before;
+        assert!(opened > budget as usize);
+
+        killswitch.send(()).unwrap();
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn simulate_adaptive_parallelism() {
+        let (killswitch, _shutdown) = broadcast::channel(1);
+
+        let config: SystemConfig = Default::default();
+        let search_options = BasicSearchOptions { parallelism: 4, ..Default::default() };
+        let mut rng = StdRng::seed_from_u64(0xa1fa);
+        let ids = (0..30).map(|_| rng.gen::<Id>()).collect::<Vec<_>>();
+        let dhts = spawn_network(&ids, &config, &killswitch, &search_options).await;
+        let targets = (0..10).map(|_| rng.gen::<Id>()).collect::<Vec<_>>();
+
+        // Both searchers start by knowing the first node only
+        let count_requests = |options: BasicSearchOptions, id: Id| {
+            let dht = AsyncSimulatedTransport::spawn(config.clone(), id, killswitch.subscribe());
+            let first = dhts[0].clone();
+            let targets = targets.clone();
+            async move {
+                dht.transport().connect_to(vec![(first.id(), &first.transport)]).await;
+                for target in targets {
+                    assert!(!dht.query_nodes(target, options.clone()).await.is_empty());
+                }
+                dht.transport().introspect().sent_request_count
+            }
+        };
+
+        let fixed = count_requests(search_options.clone(), rng.gen()).await;
+        let adaptive_options = BasicSearchOptions {
+            min_parallelism: Some(1),
+            max_parallelism: Some(4),
+            ..search_options.clone()
+        };
+        let adaptive = count_requests(adaptive_options, rng.gen()).await;
+        info!("Requests sent: fixed {fixed}, adaptive {adaptive}");
+        assert!(adaptive < fixed, "adaptive {adaptive} >= fixed {fixed}");
+
+        killswitch.send(()).unwrap();
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn simulate_disjoint_paths() {
+        let (killswitch, _shutdown) = broadcast::channel(1);
+
+        let config: SystemConfig = Default::default();
+        let spawn = |id| AsyncSimulatedTransport::spawn(config.clone(), Id::from_hex(id), killswitch.subscribe());
+        let target = spawn("12345678");
+        let honest = spawn("80000000");
+        // Closer to the target, it's the first node queried
+        let adversary = spawn("12000000");
+        honest.transport().connect_to(vec![(target.id(), &target.transport)]).await;
+        adversary.transport().set_eclipsing(Some(target.id()));
+
+        // The fake nodes end up in the routing table, every search needs a new searcher
+        let spawn_searcher = |id| {
+            let searcher = spawn(id);
+            let (adversary, honest) = (adversary.clone(), honest.clone());
+            async move {
+                searcher.transport().connect_to(vec![
+                    (adversary.id(), &adversary.transport),
+                    (honest.id(), &honest.transport),
+                ]).await;
+                searcher
+            }
+        };
+
+        // The fake nodes push the honest one out of the window
+        let options = BasicSearchOptions { parallelism: 1, ..Default::default() };
+        let searcher = spawn_searcher("fedcba98").await;
+        let found = searcher.query_nodes(target.id(), options.clone()).await;
+        assert!(!found.iter().any(|x| x.id() == target.id()));
+        assert!(!found.iter().any(|x| x.id() == honest.id()));
+
+        // The path through the honest node is not affected
+        let options = BasicSearchOptions { disjoint_paths: 2, ..options };
+        let searcher = spawn_searcher("fedcba99").await;
+        let found = searcher.query_nodes(target.id(), options).await;
+        assert_eq!(found[0].id(), target.id());
+
+        killswitch.send(()).unwrap();
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn simulate_leave() {
+        let (killswitch, _shutdown) = broadcast::channel(1);
+
+        let config: SystemConfig = Default::default();
+        let search_options = BasicSearchOptions { parallelism: 2, ..Default::default() };
+        let ids = TEST_IDS.into_iter().map(Id::from_hex).collect::<Vec<_>>();
+        let dhts = spawn_network(&ids, &config, &killswitch, &search_options).await;
+
+        let topic = Id::from_hex("aaaa1111");
+        // Only signed entries can be handed off by the nodes storing them
+        for (dht, data) in [(&dhts[8], vec![4, 2]), (&dhts[2], vec![7])] {
+            let signature = sign_simulated(dht.id(), topic, 60, &data);
+            dht.insert_signed(topic, Duration::from_secs(60), data, signature).await.unwrap();
+        }
+        let holder_count = |dhts: &[&Arc<KademliaDht<Sender>>]| {
+            dhts.iter()
+                .filter(|x| x.storage.read().unwrap().get(topic).is_some())
+                .count()
+        };
+        let before = holder_count(&dhts.iter().collect::<Vec<_>>());
+
+        // The closest node leaves
+        let leaving = dhts.iter()
+            .min_by_key(|x| x.id() ^ topic)
+            .unwrap();
+        assert!(leaving.storage.read().unwrap().get(topic).is_some());
+        assert_eq!(leaving.leave().await, 1);
+
+        // The remaining network still holds as many replicas
+        let remaining: Vec<_> = dhts.iter().filter(|x| x.id() != leaving.id()).collect();
+        assert!(holder_count(&remaining) >= before);
+        // With the entries of both publishers
+        let mut publishers = vec![dhts[2].id(), dhts[8].id()];
+        publishers.sort();
+        for dht in remaining.iter() {
+            if let Some(entries) = dht.storage.read().unwrap().get(topic) {
+                let stored: Vec<_> = entries.iter().map(|x| x.publisher).sorted().collect();
+                assert_eq!(stored, publishers);
+            }
+        }
+
+        // Every publisher's entry is still retrievable
+        let mut found: Vec<_> = dhts[8].query_value(topic, 10, search_options.clone()).await
+            .into_iter()
+            .map(|x| (x.publisher, x.data))
+            .collect();
+        found.sort();
+        let mut expected = vec![(dhts[8].id(), vec![4, 2]), (dhts[2].id(), vec![7])];
+        expected.sort();
+        assert_eq!(found, expected);
+
+        // The node that left does not answer anymore
+        assert!(dhts[8].transport().send(leaving.id(), Request::FindNodes(topic)).await.is_err());
+
+        killswitch.send(()).unwrap();
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn simulate_random_placement() {
+        let (killswitch, _shutdown) = broadcast::channel(1);
+
+        let config = SystemConfig {
+            placement: PlacementStrategy::ClosestKPlusRandom { extra: 2 },
+            ..Default::default()
+        };
+        let k = config.routing.bucket_size;
+        let search_options = BasicSearchOptions { parallelism: 2, ..Default::default() };
+        let ids = TEST_IDS.into_iter().map(Id::from_hex).collect::<Vec<_>>();
+        let dhts = spawn_network(&ids, &config, &killswitch, &search_options).await;
+
+        let topic = Id::from_hex("aaaa1111");
+        let stored = dhts[8].insert(topic, Duration::from_secs(60), vec![4, 2]).await.unwrap();
+        assert_eq!(stored, k + 2);
+
+        // Two copies are placed outside of the closest k nodes
+        let mut closest = ids.clone();
+        closest.sort_by_key(|x| *x ^ topic);
+        closest.truncate(k);
+        let holders: Vec<_> = dhts.iter()
+            .filter(|x| x.storage.read().unwrap().get(topic).is_some())
+            .map(|x| x.id())
+            .collect();
+        assert_eq!(holders.len(), k + 2);
+        assert_eq!(holders.iter().filter(|x| !closest.contains(x)).count(), 2);
+
+        // The value is still found by searches (through the closest nodes)
+        let found = dhts[3].query_value(topic, 10, search_options.clone()).await;
+        assert!(!found.is_empty());
+        assert!(found.iter().all(|x| x.data == vec![4, 2]));
+
+        killswitch.send(()).unwrap();
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn simulate_search_limit() {
+        let (killswitch, _shutdown) = broadcast::channel(1);
+
+        let config = SystemConfig {
+            max_concurrent_searches: Some(NonZeroU64::new(2).unwrap()),
+            ..Default::default()
+        };
+        let search_options = BasicSearchOptions { parallelism: 2, ..Default::default() };
+        let ids = TEST_IDS.into_iter().map(Id::from_hex).collect::<Vec<_>>();
+        let dhts = spawn_network(&ids, &config, &killswitch, &search_options).await;
+        let searcher = &dhts[3];
+
+        let searches = ids.iter()
+            .map(|x| searcher.query_nodes(*x, search_options.clone()))
+            .collect::<FuturesUnordered<_>>();
+        let mut searches = searches.collect::<Vec<_>>();
+
+        // Keep track of the searches running while they complete
+        let mut max_active = 0;
+        let results = loop {
+            tokio::select! {
+                biased;
+                res = &mut searches => break res,
+                _ = tokio::task::yield_now() => {
+                    max_active = max_active.max(searcher.active_searches());
+                }
+            }
+        };
+        assert_eq!(results.len(), ids.len());
+        assert!(results.iter().all(|x| !x.is_empty()));
+        assert_eq!(max_active, 2);
+        assert_eq!(searcher.active_searches(), 0);
+
+        killswitch.send(()).unwrap();
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn simulate_first_match() {
+        let (killswitch, _shutdown) = broadcast::channel(1);
+
+        let config: SystemConfig = Default::default();
+        let search_options = BasicSearchOptions { parallelism: 1, ..Default::default() };
+        let ids = TEST_IDS.into_iter().map(Id::from_hex).collect::<Vec<_>>();
+        let dhts = spawn_network(&ids, &config, &killswitch, &search_options).await;
+
+        // aaaaaaaa stores the value but it knows the closer aaaabbbb
+        let target = Id::from_hex("aaaabbba");
+        assert!(dhts[4].insert(target, Duration::from_secs(60), vec![1]).await.unwrap() > 0);
+        assert!(dhts[0].storage.read().unwrap().get(target).is_some());
+
+        // Fresh nodes only know the first node and store nothing
+        let spawn_fresh = |id: &str| {
+            let dht = AsyncSimulatedTransport::spawn(config.clone(), Id::from_hex(id), killswitch.subscribe());
+            let first = dhts[0].clone();
+            async move {
+                dht.transport().connect_to(vec![(first.id(), &first.transport)]).await;
+                dht
+            }
+        };
+
+        let searcher = spawn_fresh("fedcba98").await;
+        searcher.query_value(target, 10, search_options.clone()).await;
+        let full_requests = searcher.transport().introspect().sent_request_count;
+
+        let searcher = spawn_fresh("fedcba99").await;
+        let found = searcher.query_value_any(target, search_options.clone(), |x| x.data == [1]).await;
+        assert_eq!(found.map(|x| x.data), Some(vec![1]));
+        // The search stops at the first responder storing the value
+        let any_requests = searcher.transport().introspect().sent_request_count;
+        assert!(any_requests < full_requests, "{any_requests} >= {full_requests}");
+
+        // Entries not accepted don't stop the search
+        assert_eq!(searcher.query_value_any(target, search_options.clone(), |x| x.data == [2]).await, None);
+
+        killswitch.send(()).unwrap();
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn simulate_prefetch() {
+        let (killswitch, _shutdown) = broadcast::channel(1);
+
+        // Disable hand-offs, entries should only be fetched by the newcomer
+        let mut config: SystemConfig = Default::default();
+        config.storage.handoff_max_topics = 0;
+        let search_options = BasicSearchOptions { parallelism: 2, ..Default::default() };
+        let ids = TEST_IDS.into_iter().map(Id::from_hex).collect::<Vec<_>>();
+        let dhts = spawn_network(&ids, &config, &killswitch, &search_options).await;
+
+        let topics = ["cafe0001", "cafe0002", "12340000"].map(Id::from_hex);
+        for topic in topics {
+            dhts[2].insert(topic, Duration::from_secs(600), vec![7]).await.unwrap();
+        }
+        // The closest node to the "cafe" region
+        let peer = dhts.iter().find(|x| x.id() == Id::from_hex("cafebabe")).unwrap();
+
+        let newcomer_id = Id::from_hex("cafe0000");
+        let newcomer = AsyncSimulatedTransport::spawn(config.clone(), newcomer_id, killswitch.subscribe());
+        newcomer.transport().connect_to(vec![(peer.id(), &peer.transport)]).await;
+
+        // Only the topics stored by the peer are fetched
+        let stored = newcomer.prefetch_region(peer.id(), &topics).await;
+        assert_eq!(stored, 2);
+        // Prefetching again does not duplicate the entries
+        assert_eq!(newcomer.prefetch_region(peer.id(), &topics).await, 0);
+
+        // The newcomer can serve the entries right away
+        let res = peer.transport().send(newcomer_id, Request::FindData(topics[0], 10)).await.unwrap();
+        let entries = match res {
+            RawResponse::FoundData(x) => x,
+            x => panic!("Unexpected response {x:?}"),
+        };
+        assert_eq!(entries, vec![TopicEntry {
+            publisher: dhts[2].id(),
+            data: vec![7],
+        }]);
+
+        killswitch.send(()).unwrap();
+    }
+
     /// Very expensive test that simulates 100k nodes
     /// takes around 3GiB and (in my crappy laptop) ~5 minutes.
     /// It'd be better to use somewhat parallel bootstrapping.
@@ -557,7 +1469,7 @@ mod tests {
         let (killswitch, _shutdown) = broadcast::channel(1);
 
         let config: SystemConfig = Default::default();
-        let search_options = BasicSearchOptions { parallelism: 4 };
+        let search_options = BasicSearchOptions { parallelism: 4, ..Default::default() };
 
         let n_max = 100_000usize;
         let ids: Vec<Id> = (0..n_max).map(|_| rng.gen()).collect();
@@ -0,0 +1,144 @@
+//! Programmable transport for unit tests.
+//!
+//! Ping outcomes and request responses can be configured per id,
+//! failed pings are collected as disconnections that the test can
+//! feed back into the routing table (like a real transport would do
+//! through [`super::TransportListener::on_disconnect`]).
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use crate::Id;
+
+use super::{simulate::{simulated_topic_id, verify_simulated}, RecordSignature, Request, Response, TransportError, TransportSender};
+
+#[derive(Default)]
+struct Inner {
+    pings: HashMap<Id, usize>,
+    requests: HashMap<Id, usize>,
+    failing_pings: HashSet<Id>,
+    disconnected: Vec<Id>,
+    responses: HashMap<Id, Result<Response, TransportError>>,
+    stalled: HashSet<Id>,
+    headroom: Option<u32>,
+    in_flight: usize,
+    max_in_flight: usize,
+}
+
+#[derive(Clone, Default)]
+pub struct ConfigurableTestTransport(Arc<Mutex<Inner>>);
+
+impl ConfigurableTestTransport {
+    fn inner(&self) -> MutexGuard<'_, Inner> {
+        self.0.lock().unwrap()
+    }
+
+    /// Makes every following ping to id fail (or succeed again)
+    pub fn set_ping_fails(&self, id: Id, fails: bool) {
+        let mut inner = self.inner();
+        if fails {
+            inner.failing_pings.insert(id);
+        } else {
+            inner.failing_pings.remove(&id);
+        }
+    }
+
+    /// Sets the response to every request sent to id,
+    /// requests to ids without a response fail with ContactLost
+    pub fn set_response(&self, id: Id, res: Result<Response, TransportError>) {
+        let mut inner = self.inner();
+        inner.stalled.remove(&id);
+        inner.responses.insert(id, res);
+    }
+
+    /// Requests sent to id are never answered (until a response is set)
+    pub fn set_stalled(&self, id: Id) {
+        self.inner().stalled.insert(id);
+    }
+
+    /// Sets the connection headroom reported to the searches
+    pub fn set_headroom(&self, headroom: Option<u32>) {
+        self.inner().headroom = headroom;
+    }
+
+    /// Max number of requests waiting for an answer at the same time
+    pub fn max_in_flight(&self) -> usize {
+        self.inner().max_in_flight
+    }
+
+    /// Number of pings received by each id
+    pub fn pings(&self) -> HashMap<Id, usize> {
+        self.inner().pings.clone()
+    }
+
+    /// Number of requests received by each id
+    pub fn requests(&self) -> HashMap<Id, usize> {
+        self.inner().requests.clone()
+    }
+
+    pub fn clear_pings(&self) {
+        self.inner().pings.clear();
+    }
+
+    /// Takes the ids disconnected because of failed pings
+    pub fn take_disconnected(&self) -> Vec<Id> {
+        std::mem::take(&mut self.inner().disconnected)
+    }
+}
+
+impl TransportSender for ConfigurableTestTransport {
+    fn ping(&self, id: Id) {
+        let mut inner = self.inner();
+        *inner.pings.entry(id).or_insert(0) += 1;
+        if inner.failing_pings.contains(&id) && !inner.disconnected.contains(&id) {
+            inner.disconnected.push(id);
+        }
+    }
+
+    type Fut = impl Future<Output = Result<Response, TransportError>>;
+
+    fn send(&self, id: Id, _msg: Request) -> Self::Fut {
+        let mut inner = self.inner();
+        *inner.requests.entry(id).or_insert(0) += 1;
+        inner.in_flight += 1;
+        inner.max_in_flight = inner.max_in_flight.max(inner.in_flight);
+        let res = inner
+            .responses
+            .get(&id)
+            .cloned()
+            .unwrap_or(Err(TransportError::ContactLost));
+        let stalled = inner.stalled.contains(&id);
+        let this = self.clone();
+        async move {
+            if stalled {
+                futures::future::pending::<()>().await;
+            }
+            // The request is answered when first polled
+            this.inner().in_flight -= 1;
+            res
+        }
+    }
+
+    fn connection_headroom(&self) -> Option<u32> {
+        self.inner().headroom
+    }
+
+    // Signatures made with simulate::sign_simulated
+    type VerifyFut = futures::future::Ready<bool>;
+
+    fn verify_record(&self, topic: Id, lifetime: u32, data: &[u8], record: &RecordSignature) -> Self::VerifyFut {
+        futures::future::ready(verify_simulated(topic, lifetime, data, record))
+    }
+
+    fn verify_topic_name(&self, topic: Id, name: &str) -> Self::VerifyFut {
+        futures::future::ready(simulated_topic_id(name) == topic)
+    }
+
+    type Contact = Id;
+
+    fn wrap_contact(&self, id: Id) -> Self::Contact {
+        id
+    }
+}
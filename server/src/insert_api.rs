@@ -0,0 +1,136 @@
+use std::{convert::Infallible, sync::Arc, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+use warp::{
+    body::{bytes, content_length_limit},
+    hyper::body::Bytes,
+    http::StatusCode,
+    reply::{json, with_status},
+    Filter, Reply,
+};
+use wdht::{
+    logic::{config::StorageConfig, Id, KademliaDht},
+    wrtc::WrtcSender,
+};
+
+#[derive(Deserialize)]
+pub struct InsertQuery {
+    pub lifetime: Option<u32>,
+}
+
+#[derive(Serialize)]
+pub struct InsertReply {
+    // Number of nodes that stored the value
+    pub stored: usize,
+    // Lifetime actually used (in seconds)
+    pub lifetime: u32,
+}
+
+/// Lifetime used for an insert: the default one when the client doesn't specify it,
+/// always clamped into the storage limits instead of rejecting the request.
+/// If the limits are inverted (min > max) max wins and the storage refuses the insert.
+pub fn effective_lifetime(requested: Option<u32>, default: u32, config: &StorageConfig) -> u32 {
+    requested
+        .unwrap_or(default)
+        .max(config.min_lifetime)
+        .min(config.max_lifetime)
+}
+
+#[instrument(level = "error", name = "http_kademlia_insert", skip_all, fields(kad_id = %dht.id()))]
+async fn dht_insert_handle(
+    key: String,
+    query: InsertQuery,
+    data: Bytes,
+    dht: Arc<KademliaDht<WrtcSender>>,
+    default_lifetime: u32,
+) -> Result<warp::reply::Response, Infallible> {
+    let key: Id = match key.parse() {
+        Ok(x) => x,
+        Err(e) => return Ok(with_status(format!("Invalid key: {e}"), StatusCode::BAD_REQUEST).into_response()),
+    };
+    let lifetime = effective_lifetime(query.lifetime, default_lifetime, &dht.config().storage);
+
+    Ok(match dht.insert(key, Duration::from_secs(lifetime.into()), data.to_vec()).await {
+        Ok(stored) => json(&InsertReply { stored, lifetime }).into_response(),
+        Err(e) => with_status(e.to_string(), StatusCode::BAD_REQUEST).into_response(),
+    })
+}
+
+/// `POST /insert/<key>?lifetime=<seconds>` with the value as body.
+///
+/// Lifetimes outside of the storage limits are clamped,
+/// when the lifetime is missing default_lifetime is used.
+pub fn dht_insert(
+    dht: Arc<KademliaDht<WrtcSender>>,
+    default_lifetime: u32,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let max_size = dht.config().storage.max_size as u64;
+    warp::path!("insert" / String)
+        .and(warp::post())
+        .and(warp::query::<InsertQuery>())
+        .and(content_length_limit(max_size))
+        .and(bytes())
+        .and(warp::any().map(move || dht.clone()))
+        .and(warp::any().map(move || default_lifetime))
+        .and_then(dht_insert_handle)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+    use wdht::{create_dht, logic::{config::SystemConfig, search::BasicSearchOptions}, TransportConfig};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn insert_lifetimes() {
+        let mut config = SystemConfig::default();
+        config.storage.max_lifetime = 120;
+        let (dht, _events) = create_dht(config, TransportConfig::default(), vec![] as Vec<&str>).await.unwrap();
+        let filter = dht_insert(dht.clone(), 30);
+        let key = Id::ZERO.set_bit(7);
+
+        // Missing lifetime, the default is used
+        let res = warp::test::request()
+            .method("POST")
+            .path(&format!("/insert/{key}"))
+            .body("hello")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["lifetime"], 30);
+        assert_eq!(body["stored"], 1);
+
+        // Lifetime over the maximum, it gets clamped
+        let res = warp::test::request()
+            .method("POST")
+            .path(&format!("/insert/{key}?lifetime=100000"))
+            .body("world")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["lifetime"], 120);
+
+        let found = dht.query_value(key, 10, BasicSearchOptions::default()).await;
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].data, b"world");
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/insert/not_an_id")
+            .body("hello")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn inverted_lifetime_limits() {
+        let config = StorageConfig { min_lifetime: 100, max_lifetime: 50, ..Default::default() };
+        assert_eq!(effective_lifetime(Some(10), 30, &config), 50);
+        assert_eq!(effective_lifetime(None, 30, &config), 50);
+    }
+}
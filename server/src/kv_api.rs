@@ -0,0 +1,193 @@
+use std::{convert::Infallible, sync::Arc, time::Duration};
+
+use percent_encoding::percent_decode_str;
+use serde::Deserialize;
+use tracing::instrument;
+use warp::{
+    body::{bytes, content_length_limit},
+    hyper::body::Bytes,
+    http::StatusCode,
+    reply::{json, with_status},
+    Filter, Reply,
+};
+use wdht::{
+    logic::{search::BasicSearchOptions, KademliaDht},
+    wrtc::WrtcSender,
+    TopicKey,
+};
+
+use crate::insert_api::{effective_lifetime, InsertQuery, InsertReply};
+
+// Max number of entries returned by a GET without a limit
+const DEFAULT_QUERY_LIMIT: u32 = 16;
+
+#[derive(Deserialize)]
+struct GetQuery {
+    limit: Option<u32>,
+}
+
+// Same hashing as the topics of the web client, topic is the raw path segment
+async fn parse_topic(topic: &str) -> Result<TopicKey, warp::reply::Response> {
+    let invalid = |e: String| with_status(format!("Invalid topic: {e}"), StatusCode::BAD_REQUEST).into_response();
+    let topic = percent_decode_str(topic).decode_utf8().map_err(|e| invalid(e.to_string()))?;
+    TopicKey::hash(&topic).await.map_err(|e| invalid(e.to_string()))
+}
+
+#[instrument(level = "error", name = "http_kademlia_put", skip_all, fields(kad_id = %dht.id()))]
+async fn dht_put_handle(
+    topic: String,
+    query: InsertQuery,
+    data: Bytes,
+    dht: Arc<KademliaDht<WrtcSender>>,
+    default_lifetime: u32,
+) -> Result<warp::reply::Response, Infallible> {
+    let key = match parse_topic(&topic).await {
+        Ok(x) => x,
+        Err(e) => return Ok(e),
+    };
+    let lifetime = effective_lifetime(query.lifetime, default_lifetime, &dht.config().storage);
+
+    Ok(match dht.insert(key, Duration::from_secs(lifetime.into()), data.to_vec()).await {
+        Ok(stored) => json(&InsertReply { stored, lifetime }).into_response(),
+        Err(e) => with_status(e.to_string(), StatusCode::BAD_REQUEST).into_response(),
+    })
+}
+
+#[instrument(level = "error", name = "http_kademlia_get", skip_all, fields(kad_id = %dht.id()))]
+async fn dht_get_handle(
+    topic: String,
+    query: GetQuery,
+    dht: Arc<KademliaDht<WrtcSender>>,
+) -> Result<warp::reply::Response, Infallible> {
+    let key = match parse_topic(&topic).await {
+        Ok(x) => x,
+        Err(e) => return Ok(e),
+    };
+    let limit = query.limit.unwrap_or(DEFAULT_QUERY_LIMIT);
+
+    let entries = dht.query_value(key, limit, BasicSearchOptions::default()).await;
+    Ok(json(&entries).into_response())
+}
+
+/// Key-value API over plain HTTP, topics are hashed like the ones of the web client.
+///
+/// `PUT /kv/<topic>?lifetime=<seconds>` inserts the body (like `POST /insert`),
+/// `GET /kv/<topic>?limit=<count>` returns the entries as `[{publisher, data}]`.
+/// The topic is the percent-decoded path segment.
+pub fn dht_kv(
+    dht: Arc<KademliaDht<WrtcSender>>,
+    default_lifetime: u32,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let max_size = dht.config().storage.max_size as u64;
+    let with_dht = warp::any().map(move || dht.clone());
+
+    let put = warp::path!("kv" / String)
+        .and(warp::put())
+        .and(warp::query::<InsertQuery>())
+        .and(content_length_limit(max_size))
+        .and(bytes())
+        .and(with_dht.clone())
+        .and(warp::any().map(move || default_lifetime))
+        .and_then(dht_put_handle);
+
+    let get = warp::path!("kv" / String)
+        .and(warp::get())
+        .and(warp::query::<GetQuery>())
+        .and(with_dht)
+        .and_then(dht_get_handle);
+
+    put.or(get)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+    use wdht::{create_dht, logic::config::SystemConfig, warp_filter::dht_connect, TransportConfig};
+
+    use super::*;
+
+    async fn put<F>(filter: &F, path: &str, body: &str) -> Value
+    where
+        F: Filter + 'static,
+        F::Extract: Reply + Send,
+    {
+        let res = warp::test::request()
+            .method("PUT")
+            .path(path)
+            .body(body.to_owned())
+            .reply(filter)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        serde_json::from_slice(res.body()).unwrap()
+    }
+
+    async fn get<F>(filter: &F, path: &str) -> Value
+    where
+        F: Filter + 'static,
+        F::Extract: Reply + Send,
+    {
+        let res = warp::test::request()
+            .path(path)
+            .reply(filter)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        serde_json::from_slice(res.body()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn kv_local() {
+        let (dht, _events) = create_dht(SystemConfig::default(), TransportConfig::default(), vec![] as Vec<&str>).await.unwrap();
+        let filter = dht_kv(dht.clone(), 30);
+
+        let body = put(&filter, "/kv/greetings?lifetime=60", "hello").await;
+        assert_eq!(body["stored"], 1);
+        assert_eq!(body["lifetime"], 60);
+
+        // Stored under the hashed topic
+        let key = TopicKey::hash("greetings").await.unwrap();
+        assert_eq!(dht.query_local(key.into(), 10)[0].data, b"hello");
+
+        let body = get(&filter, "/kv/greetings?limit=1").await;
+        assert_eq!(body.as_array().unwrap().len(), 1);
+        assert_eq!(body[0]["publisher"], serde_json::json!(dht.id()));
+        assert_eq!(body[0]["data"], serde_json::json!(b"hello"));
+
+        let body = get(&filter, "/kv/nothing").await;
+        assert_eq!(body, serde_json::json!([]));
+    }
+
+    #[tokio::test]
+    async fn kv_encoded_topic() {
+        let (dht, _events) = create_dht(SystemConfig::default(), TransportConfig::default(), vec![] as Vec<&str>).await.unwrap();
+        let filter = dht_kv(dht.clone(), 30);
+
+        put(&filter, "/kv/my%20topic", "spaced").await;
+
+        // Hashed as the decoded topic
+        let key = parse_topic("my%20topic").await.unwrap();
+        assert_eq!(key, TopicKey::hash("my topic").await.unwrap());
+        assert_eq!(dht.query_local(key.into(), 10)[0].data, b"spaced");
+
+        let body = get(&filter, "/kv/my%20topic").await;
+        assert_eq!(body[0]["data"], serde_json::json!(b"spaced"));
+    }
+
+    #[tokio::test]
+    async fn kv_two_nodes() {
+        let (srv, _srv_events) = create_dht(SystemConfig::default(), TransportConfig::default(), vec![] as Vec<&str>).await.unwrap();
+        let (addr, server) = warp::serve(dht_connect(srv.clone())).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let url = format!("http://localhost:{}", addr.port());
+        let (client, _client_events) = create_dht(SystemConfig::default(), TransportConfig::default(), vec![url.as_str()]).await.unwrap();
+
+        // Inserted through one node, found through the other
+        let body = put(&dht_kv(client.clone(), 30), "/kv/shared", "value").await;
+        assert_eq!(body["stored"], 2);
+
+        let body = get(&dht_kv(srv, 30), "/kv/shared").await;
+        let entries = body.as_array().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0]["publisher"], serde_json::json!(client.id()));
+        assert_eq!(entries[0]["data"], serde_json::json!(b"value"));
+    }
+}
@@ -1,15 +1,17 @@
-use std::{net::SocketAddr, num::NonZeroU64, sync::Arc, time::Duration};
+use std::{io, net::SocketAddr, num::NonZeroU64, path::PathBuf, sync::Arc, time::Duration};
 
 use reqwest::Url;
-use tracing::{info, span, Instrument, Level};
+use tracing::{info, span, warn, Instrument, Level};
 use tracing_subscriber::{prelude::*, EnvFilter};
 use warp::Filter;
-use wdht::{create_dht, warp_filter::dht_connect, TransportConfig, Dht, logic::config::SystemConfig};
+use wdht::{create_dht, create_dht_with_identity, warp_filter::dht_connect, TransportConfig, Dht, EventReceiver, Identity, SEED_SIZE, logic::config::SystemConfig, metrics::MetricsCollector};
 
 use clap::{Args, Parser, Subcommand};
 
-use crate::server_stats::dht_query;
+use crate::{insert_api::dht_insert, kv_api::dht_kv, server_stats::{dht_debug_state, dht_metrics, dht_query}};
 
+mod insert_api;
+mod kv_api;
 mod server_stats;
 
 /// Web-dht server (and tester client)
@@ -47,6 +49,38 @@ struct ServerArgs {
     /// Bind address
     #[clap(long, default_value = "127.0.0.1:3141")]
     bind: SocketAddr,
+
+    /// Lifetime (in seconds) of the values inserted through HTTP without one,
+    /// every lifetime is clamped into the storage limits
+    #[clap(long, default_value = "600")]
+    default_lifetime: u32,
+
+    /// Expose Prometheus metrics at /metrics
+    #[clap(long)]
+    metrics: bool,
+
+    /// Expose the internal state of the node at /debug/state
+    #[clap(long)]
+    debug_state: bool,
+
+    /// Seed of the server identity (64 hex digits or a 24 words mnemonic),
+    /// keeps the node id stable across restarts. A random identity is used if missing
+    #[clap(long, parse(try_from_str = parse_identity_seed))]
+    identity_seed: Option<[u8; SEED_SIZE]>,
+
+    /// File containing the server identity, generated on the first run
+    /// [default: ~/.wdht/identity]
+    #[clap(long)]
+    identity_file: Option<PathBuf>,
+}
+
+fn parse_identity_seed(s: &str) -> Result<[u8; SEED_SIZE], String> {
+    if s.contains(' ') {
+        return wdht::seed_from_mnemonic(s).map_err(|x| x.to_string());
+    }
+    let mut seed = [0u8; SEED_SIZE];
+    hex::decode_to_slice(s, &mut seed).map_err(|x| x.to_string())?;
+    Ok(seed)
 }
 
 #[derive(Parser, Debug)]
@@ -79,7 +113,7 @@ async fn main() {
     }
 }
 
-async fn start_kademlia(args: &CommonArgs) -> Arc<Dht> {
+async fn start_kademlia(args: &CommonArgs, identity: Option<Identity>) -> (Arc<Dht>, EventReceiver) {
     let mut config: SystemConfig = Default::default();
     config.routing.max_routing_count = args.max_routing_count;
     let mut tconfig: TransportConfig = Default::default();
@@ -87,11 +121,15 @@ async fn start_kademlia(args: &CommonArgs) -> Arc<Dht> {
     tconfig.stun_servers = args.stun_servers.iter().map(|x| x.to_string()).collect();
 
     let span = span!(Level::INFO, "create_dht");
-    let t = create_dht(config, tconfig, args.bootstrap.clone())
-        .instrument(span)
-        .await;
-
-    t.0
+    let res = match identity {
+        Some(identity) => create_dht_with_identity(config, tconfig, identity, args.bootstrap.clone())
+            .instrument(span)
+            .await,
+        None => create_dht(config, tconfig, args.bootstrap.clone())
+            .instrument(span)
+            .await,
+    };
+    res.unwrap_or_else(|x| panic!("Invalid configuration: {x}"))
 }
 
 async fn start_client(args: &ClientArgs) {
@@ -106,7 +144,7 @@ async fn start_client(args: &ClientArgs) {
             .map(|i| async move {
                 tokio::time::sleep(Duration::from_secs(5 * i as u64)).await;
                 info!("Starting client {i}");
-                start_kademlia(&args.common).await
+                start_kademlia(&args.common, None).await.0
         }),
     )
     .await;
@@ -117,12 +155,54 @@ async fn start_client(args: &ClientArgs) {
         .expect("Failed to listen to ctrl-c");
 }
 
+async fn load_identity(args: &ServerArgs) -> Option<Identity> {
+    if let Some(seed) = &args.identity_seed {
+        return Some(Identity::from_seed(seed));
+    }
+    let path = args.identity_file.clone()
+        .or_else(|| std::env::var_os("HOME").map(|x| PathBuf::from(x).join(".wdht").join("identity")))?;
+    match Identity::load(&path).await {
+        Ok(x) => Some(x),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            info!("Generating a new identity in {}", path.display());
+            let identity = Identity::generate().await;
+            if let Err(e) = identity.save(&path).await {
+                warn!("Failed to save the identity in {}: {e}", path.display());
+            }
+            Some(identity)
+        }
+        Err(e) => panic!("Failed to load the identity from {}: {e}", path.display()),
+    }
+}
+
+// Passes the requests only if the endpoint is enabled
+fn enabled(flag: bool) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    warp::any()
+        .and_then(move || async move {
+            if flag {
+                Ok(())
+            } else {
+                Err(warp::reject::not_found())
+            }
+        })
+        .untuple_one()
+}
+
 async fn start_server(args: &ServerArgs) {
-    let kad = start_kademlia(&args.common).await;
+    let identity = load_identity(args).await;
+    let (kad, events) = start_kademlia(&args.common, identity).await;
+    let metrics = MetricsCollector::spawn(events);
     info!("Starting up server");
 
     let routes = dht_connect(kad.clone())
-        .or(dht_query(kad));
-
-    warp::serve(routes).run(args.bind).await;
+        .or(dht_insert(kad.clone(), args.default_lifetime))
+        .or(dht_kv(kad.clone(), args.default_lifetime))
+        .or(enabled(args.debug_state).and(dht_debug_state(kad.clone())))
+        .or(dht_query(kad.clone(), metrics.clone()));
+
+    if args.metrics {
+        warp::serve(routes.or(dht_metrics(kad, metrics))).run(args.bind).await;
+    } else {
+        warp::serve(routes).run(args.bind).await;
+    }
 }
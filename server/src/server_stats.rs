@@ -1,23 +1,45 @@
-use std::sync::Arc;
+use std::{fmt::Write, sync::Arc};
 
 use either::Either;
 use tracing::instrument;
 use warp::{Filter, Reply, reply::html, cors};
-use wdht::{logic::KademliaDht, wrtc::WrtcSender};
+use wdht::{debug::debug_state, logic::KademliaDht, metrics::MetricsCollector, wrtc::WrtcSender};
 
 #[instrument(level = "error", name = "http_kademlia_query", skip_all, fields(kad_id = %dht.id()))]
-fn dht_query_handle(dht: Arc<KademliaDht<WrtcSender>>) -> impl Reply {
+fn dht_query_handle(dht: Arc<KademliaDht<WrtcSender>>, metrics: MetricsCollector) -> impl Reply {
     let transport = dht.transport();
 
-    let id = dht.id().as_short_hex();
+    let id = dht.id().to_hex();
     let config = transport.config();
-    let connections = transport.connection_count();
+    let transport_metrics = transport.metrics();
+    let connections = transport_metrics.connection_count;
     let connections_limit = match config.max_connections {
       Some(x) => Either::Left(x.get()),
       None => Either::Right("inf"),
     };
-    let connected = transport.connected_count();
-    let half_closed = transport.half_closed_count();
+    let connected = transport_metrics.connected_count;
+    let half_closed = transport_metrics.half_closed_count;
+    let connecting = transport_metrics.connecting_count;
+    let bytes_sent = transport_metrics.bytes_sent;
+    let bytes_recv = transport_metrics.bytes_recv;
+    let bootstrap_state = transport.bootstrap_state();
+    let effective = dht.effective_config();
+    let max_searches = match effective.system.max_concurrent_searches {
+      Some(x) => Either::Left(x.get()),
+      None => Either::Right("inf"),
+    };
+    let overrides = effective.overrides.join(", ");
+    let mut routing: Vec<_> = dht.routing_snapshot().iter().map(|x| x.to_hex()).collect();
+    routing.sort();
+    let routing_size = routing.len();
+    let routing = routing.join("\n");
+    let metrics = metrics.snapshot();
+    let connects = metrics.connects;
+    let mut disconnects: Vec<_> = metrics.disconnects.iter()
+        .map(|(reason, count)| format!("{reason}: {count}"))
+        .collect();
+    disconnects.sort();
+    let disconnects = disconnects.join(", ");
 
     let body = format!(r#"
     <html>
@@ -29,10 +51,21 @@ fn dht_query_handle(dht: Arc<KademliaDht<WrtcSender>>) -> impl Reply {
       <h3><a href="https://github.com/SnowyCoder/wdht">Visit us</a> to find out more</h3>
       <h4>
         Id: {id}<br>
+        State: {bootstrap_state}<br>
         Connections: {connections}/{connections_limit}<br>
         Connected: {connected}<br>
-        Half closed: {half_closed}
+        Half closed: {half_closed}<br>
+        Connecting: {connecting}<br>
+        Traffic: {bytes_sent} bytes sent, {bytes_recv} bytes received<br>
+        Max searches: {max_searches}<br>
+        Runtime overrides: {overrides}<br>
+        Connects: {connects}<br>
+        Disconnects: {disconnects}
       </h4>
+      <h4>Routing table ({routing_size} nodes)</h4>
+      <pre>
+{routing}
+      </pre>
     </body>
     </html>
     "#);
@@ -42,10 +75,12 @@ fn dht_query_handle(dht: Arc<KademliaDht<WrtcSender>>) -> impl Reply {
 
 pub fn dht_query(
     dht: Arc<KademliaDht<WrtcSender>>,
+    metrics: MetricsCollector,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::path::end()
         .and(warp::get())
         .and(warp::any().map(move || dht.clone()))
+        .and(warp::any().map(move || metrics.clone()))
         .map(dht_query_handle)
         .with(
             cors()
@@ -54,3 +89,116 @@ pub fn dht_query(
                 .build(),
         )
 }
+
+pub fn dht_debug_state(
+    dht: Arc<KademliaDht<WrtcSender>>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("debug" / "state")
+        .and(warp::get())
+        .and(warp::any().map(move || dht.clone()))
+        .map(|dht: Arc<KademliaDht<WrtcSender>>| warp::reply::json(&debug_state(&dht)))
+}
+
+// Appends a metric in the Prometheus text format, samples are (labels, value)
+fn write_metric<V: std::fmt::Display>(out: &mut String, name: &str, kind: &str, help: &str, samples: &[(String, V)]) {
+    writeln!(out, "# HELP {name} {help}").unwrap();
+    writeln!(out, "# TYPE {name} {kind}").unwrap();
+    for (labels, value) in samples {
+        writeln!(out, "{name}{labels} {value}").unwrap();
+    }
+}
+
+fn render_metrics(dht: &KademliaDht<WrtcSender>, metrics: &MetricsCollector) -> String {
+    let transport = dht.transport().metrics();
+    let events = metrics.snapshot();
+    let storage_entries = dht.storage.read().unwrap().entry_count();
+    let one = |x: u64| [(String::new(), x)];
+
+    let mut out = String::new();
+    write_metric(&mut out, "wdht_connections", "gauge", "Open transport connections.", &one(transport.connection_count));
+    write_metric(&mut out, "wdht_connected", "gauge", "Connections with an open data channel.", &one(transport.connected_count));
+    write_metric(&mut out, "wdht_half_closed", "gauge", "Half closed connections.", &one(transport.half_closed_count));
+    write_metric(&mut out, "wdht_connecting", "gauge", "Connections still being negotiated.", &one(transport.connecting_count));
+    write_metric(&mut out, "wdht_sent_bytes_total", "counter", "Bytes sent to the peers.", &one(transport.bytes_sent));
+    write_metric(&mut out, "wdht_received_bytes_total", "counter", "Bytes received from the peers.", &one(transport.bytes_recv));
+    write_metric(&mut out, "wdht_connects_total", "counter", "Connections established.", &one(events.connects));
+    let mut disconnects: Vec<_> = events.disconnects.iter()
+        .map(|(reason, count)| (format!("{{reason=\"{reason:?}\"}}"), *count))
+        .collect();
+    disconnects.sort();
+    write_metric(&mut out, "wdht_disconnects_total", "counter", "Connections closed, by reason.", &disconnects);
+    write_metric(&mut out, "wdht_channel_opens_total", "counter", "Data channels opened.", &one(events.channel_opens));
+    write_metric(&mut out, "wdht_lost_events_total", "counter", "Transport events skipped by the metrics collector.", &one(events.lost_events));
+    write_metric(&mut out, "wdht_storage_entries", "gauge", "Entries held in the local storage.", &one(storage_entries as u64));
+    write_metric(&mut out, "wdht_routing_size", "gauge", "Nodes in the routing table.", &one(dht.routing_size()));
+    out
+}
+
+/// Prometheus metrics of the transport, storage and routing table at `GET /metrics`.
+pub fn dht_metrics(
+    dht: Arc<KademliaDht<WrtcSender>>,
+    metrics: MetricsCollector,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("metrics")
+        .and(warp::get())
+        .map(move || {
+            warp::reply::with_header(
+                render_metrics(&dht, &metrics),
+                "content-type",
+                "text/plain; version=0.0.4",
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+    use warp::http::StatusCode;
+    use wdht::{create_dht, logic::config::SystemConfig, TransportConfig};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn debug_state_endpoint() {
+        let (dht, _events) = create_dht(SystemConfig::default(), TransportConfig::default(), vec![] as Vec<&str>).await.unwrap();
+        let filter = dht_debug_state(dht.clone());
+
+        let res = warp::test::request()
+            .path("/debug/state")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["dht"]["tree_locked"], false);
+        assert_eq!(body["dht"]["active_searches"], 0);
+        assert_eq!(body["transport"]["connections"], Value::Array(vec![]));
+    }
+
+    #[tokio::test]
+    async fn metrics_endpoint() {
+        let (dht, events) = create_dht(SystemConfig::default(), TransportConfig::default(), vec![] as Vec<&str>).await.unwrap();
+        let filter = dht_metrics(dht.clone(), MetricsCollector::spawn(events));
+
+        let res = warp::test::request()
+            .path("/metrics")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["content-type"], "text/plain; version=0.0.4");
+        let body = std::str::from_utf8(res.body()).unwrap();
+
+        let lines: Vec<_> = body.lines().collect();
+        for (i, line) in lines.iter().enumerate() {
+            if let Some(help) = line.strip_prefix("# HELP ") {
+                let name = help.split(' ').next().unwrap();
+                let kind = lines[i + 1].strip_prefix(&format!("# TYPE {name} ")).unwrap();
+                assert!(kind == "gauge" || kind == "counter", "{kind}");
+            }
+        }
+        let connections = dht.transport().metrics().connection_count;
+        assert!(lines.contains(&format!("wdht_connections {connections}").as_str()));
+        assert!(lines.contains(&"# TYPE wdht_connections gauge"));
+        assert!(lines.contains(&"wdht_routing_size 0"));
+        assert!(lines.contains(&"wdht_storage_entries 0"));
+    }
+}
@@ -6,7 +6,14 @@ mod inner {
     pub use std::rc::Rc as Orc;
     pub use std::rc::Weak;
 
-    pub use wasm_bindgen_futures::spawn_local as spawn;
+    use std::future::Future;
+
+    pub fn spawn<F>(future: F)
+    where
+        F: Future<Output = ()> + 'static,
+    {
+        wasm_bindgen_futures::spawn_local(crate::tasks::track(future))
+    }
 
     pub fn sleep(duration: Duration) -> TimeoutFuture {
         TimeoutFuture::new(duration.as_millis() as u32)
@@ -15,6 +22,10 @@ mod inner {
     pub trait MaybeSend {}
 
     impl<T> MaybeSend for T {}
+
+    pub trait MaybeSync {}
+
+    impl<T> MaybeSync for T {}
 }
 
 #[cfg(not(target_arch = "wasm32"))]
@@ -22,14 +33,27 @@ mod inner {
     pub use std::sync::Arc as Orc;
     pub use std::sync::Weak;
 
-    pub use tokio::spawn;
+    use std::future::Future;
+    use tokio::task::JoinHandle;
+
+    pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        tokio::spawn(crate::tasks::track(future))
+    }
 
     pub use tokio::time::sleep;
 
     pub use core::marker::Send as MaybeSend;
+    pub use core::marker::Sync as MaybeSync;
 }
 
-pub use inner::{sleep, spawn, Orc, Weak, MaybeSend};
+mod tasks;
+
+pub use inner::{sleep, spawn, Orc, Weak, MaybeSend, MaybeSync};
+pub use tasks::live_tasks;
 
 pub trait SenderExt<T> {
     fn maybe_spawn_send(&self, mex: T);
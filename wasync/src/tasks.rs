@@ -0,0 +1,101 @@
+//! Accounting of the tasks started with [`crate::spawn`].
+//!
+//! wasm has no join handles, so leaked tasks (e.g. listeners kept alive by
+//! an `Rc` cycle) would go unnoticed. With the `task-count` feature every
+//! spawned task is counted until its future completes or is dropped.
+use std::future::Future;
+
+#[cfg(any(test, feature = "task-count"))]
+mod counter {
+    use std::{
+        future::Future,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    static LIVE_TASKS: AtomicUsize = AtomicUsize::new(0);
+
+    struct TaskGuard;
+
+    impl Drop for TaskGuard {
+        fn drop(&mut self) {
+            LIVE_TASKS.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    pub fn track<F: Future>(future: F) -> impl Future<Output = F::Output> {
+        LIVE_TASKS.fetch_add(1, Ordering::SeqCst);
+        let guard = TaskGuard;
+        async move {
+            let _guard = guard;
+            future.await
+        }
+    }
+
+    pub fn live_tasks() -> Option<usize> {
+        Some(LIVE_TASKS.load(Ordering::SeqCst))
+    }
+}
+
+#[cfg(not(any(test, feature = "task-count")))]
+mod counter {
+    use std::future::Future;
+
+    pub fn track<F: Future>(future: F) -> F {
+        future
+    }
+
+    pub fn live_tasks() -> Option<usize> {
+        None
+    }
+}
+
+pub(crate) fn track<F: Future>(future: F) -> impl Future<Output = F::Output> {
+    counter::track(future)
+}
+
+/// Number of spawned tasks that are still alive,
+/// None if the `task-count` feature is disabled
+pub fn live_tasks() -> Option<usize> {
+    counter::live_tasks()
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use tokio::sync::{mpsc, oneshot};
+
+    use crate::{live_tasks, spawn};
+
+    #[tokio::test]
+    async fn count_connection_lifecycle() {
+        let base = live_tasks().unwrap();
+
+        // A connection spawns a listener that lives until its channel is closed
+        let (tx, mut rx) = mpsc::channel::<u32>(4);
+        let (done_tx, done_rx) = oneshot::channel();
+        let listener = spawn(async move {
+            let mut received = 0;
+            while let Some(x) = rx.recv().await {
+                received += x;
+            }
+            let _ = done_tx.send(received);
+        });
+        assert_eq!(live_tasks(), Some(base + 1));
+
+        tx.send(1).await.unwrap();
+        tx.send(2).await.unwrap();
+        assert_eq!(live_tasks(), Some(base + 1));
+
+        // Closing the connection ends the listener
+        drop(tx);
+        assert_eq!(done_rx.await.unwrap(), 3);
+        listener.await.unwrap();
+        assert_eq!(live_tasks(), Some(base));
+
+        // Aborted tasks are dropped and not counted as leaked
+        let pending = spawn(std::future::pending::<()>());
+        assert_eq!(live_tasks(), Some(base + 1));
+        pending.abort();
+        assert!(pending.await.unwrap_err().is_cancelled());
+        assert_eq!(live_tasks(), Some(base));
+    }
+}
@@ -1,13 +1,410 @@
-use std::num::NonZeroU64;
+use std::{collections::HashSet, fmt, num::NonZeroU64, sync::Arc, time::Duration};
 
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use wdht_logic::{config::ConfigError as RoutingConfigError, Id};
+use wdht_wrtc::{ChannelPriority, IceServer, IceTransportPolicy, IpPreference};
 
+use crate::wrtc::{record::HandshakeRecord, HandshakeInfo};
 
-#[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize, Default)]
+pub type AuthorizeHook = Arc<dyn Fn(&Id, &HandshakeInfo) -> bool + Send + Sync>;
+pub type HandshakeRecorder = Arc<dyn Fn(&HandshakeRecord) + Send + Sync>;
+
+/// Behavior of the event channel (returned by `create_dht`) when it's full.
+///
+/// Receivers cloned by the application share the same buffer, so a slow
+/// receiver affects everyone.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventOverflow {
+    // Events are kept until every receiver reads them, senders wait for space.
+    // Events that can't wait (ex. disconnections) are dropped when it's full
+    #[default]
+    Block,
+    // The oldest events are dropped to make space, every event is sent
+    // but slow receivers skip events (receiving RecvError::Overflowed)
+    DropOldest,
+}
+
+/// Handling of channel open events that don't fit in the event channel.
+///
+/// Events are never awaited by the connection listener, so a slow consumer
+/// can't stall the DHT messages of the peer.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChannelOpenPolicy {
+    // Wait for space in a background task, events are never lost
+    #[default]
+    Queue,
+    // Drop the event (and so the channel)
+    Drop,
+}
+
+/// Side that keeps its connection attempt when two peers connect to each other
+/// at the same time (the other attempt is dropped).
+///
+/// Both peers must agree on the winner, so every node of the network must use the
+/// same value: with mixed values both attempts can be dropped.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionOrder {
+    // The peer with the lower id is the offerer (active side)
+    #[default]
+    LowerIdOffers,
+    // The peer with the higher id is the offerer (active side)
+    HigherIdOffers,
+}
+
+impl ConnectionOrder {
+    /// True if the attempt made by offerer wins against the one made by other
+    pub fn wins(self, offerer: Id, other: Id) -> bool {
+        match self {
+            ConnectionOrder::LowerIdOffers => offerer < other,
+            ConnectionOrder::HigherIdOffers => offerer > other,
+        }
+    }
+}
+
+/// Encoding of the messages exchanged with the peers.
+///
+/// Each side proposes its format during the handshake, the binary format is used
+/// only if both peers propose it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WireFormat {
+    // Human readable, binary data is encoded as base64 or arrays of numbers
+    #[default]
+    Json,
+    // Compact binary encoding, binary data is sent raw
+    Bincode,
+}
+
+/// Offers that a peer can ask this node to forward to its connections.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ForwardPolicy {
+    // Offers to every connected node are forwarded
+    #[default]
+    Open,
+    // Only offers to the nodes recently returned to the peer (in answer to its
+    // lookups) are forwarded, this node can't be used as an open relay
+    SearchContext,
+}
+
+/// Delays between the attempts of a reconnection (to bootstrap or routing nodes).
+///
+/// Every failed attempt multiplies the delay, up to max, a random
+/// jitter (up to jitter) is added so that peers don't retry in sync.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct BackoffConfig {
+    // Delay after the first failed attempt
+    pub initial: Duration,
+    pub max: Duration,
+    // Must be at least 1
+    pub multiplier: f64,
+    pub jitter: Duration,
+}
+
+impl Default for BackoffConfig {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(5 * 60),
+            multiplier: 2.0,
+            jitter: Duration::from_secs(1),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
 #[serde(default)]
 pub struct TransportConfig {
     pub stun_servers: Vec<String>,
 
+    // Other ICE servers, with their credentials (ex. TURN servers
+    // for peers behind symmetric NATs)
+    pub ice_servers: Vec<IceServer>,
+
     // Max number of connected nodes
     pub max_connections: Option<NonZeroU64>,
+
+    // Max number of connections being opened at the same time,
+    // new connection attempts are rejected when it is reached
+    pub max_connecting: usize,
+
+    // Connection attempts older than this are dropped to make room for new ones
+    // (their result would never be reported anyway)
+    pub connecting_timeout: Duration,
+
+    // Max time spent opening the WebRTC channel of a connection (signaling and ICE),
+    // must be non-zero
+    pub signaling_timeout: Duration,
+
+    // Max time waited for the answer to a request, after that the connection
+    // is considered dead and closed. Must be non-zero
+    pub request_timeout: Duration,
+
+    // Which attempt survives when two peers connect to each other at the same time
+    pub connection_order: ConnectionOrder,
+
+    // Keep a connection whose control channel closed while the peer connection is
+    // still up, so that the app channels opened on it keep working. The connection
+    // is dropped only when the peer connection itself fails
+    pub keep_connection_on_channel_close: bool,
+
+    // Which offers received from a peer are forwarded to our connections
+    pub forward_policy: ForwardPolicy,
+
+    // Max number of offers forwarded for each peer per minute
+    pub max_forwards_per_minute: Option<u32>,
+
+    // Number of events buffered for the slowest receiver
+    pub event_capacity: usize,
+
+    // What to do when the event channel is full
+    pub event_overflow: EventOverflow,
+
+    // What to do with channel open events when the event channel is full
+    // (only used with EventOverflow::Block)
+    pub channel_open_policy: ChannelOpenPolicy,
+
+    // Messages larger than this are split in fragments (in bytes), it must be
+    // smaller than the max data channel message size of every peer.
+    // At least 2 (a marker byte and a data byte)
+    pub max_fragment_size: usize,
+
+    // Max time between the first and the last fragment of a received message,
+    // the connection is closed if a fragmented message takes longer
+    pub fragment_timeout: Duration,
+
+    // Max size of a received message after reassembly (in bytes, JSON encoded),
+    // stored bytes are encoded as numbers so it should be ~4x StorageConfig::max_size
+    pub max_message_size: usize,
+
+    // Data is queued (instead of being handed to the data channel) while the channel
+    // buffers more than this (in bytes), avoids overflowing the SCTP send buffer
+    pub send_buffer_high_watermark: usize,
+
+    // Max size of the data queued on a single connection (in bytes), sending fails
+    // when the queue is full. It must be at least max_message_size
+    pub max_send_queue: usize,
+
+    // Max size of a received SDP offer (in bytes, JSON encoded)
+    pub max_sdp_size: usize,
+
+    // Message encoding proposed to the peers
+    pub wire_format: WireFormat,
+
+    // Priority of the DHT control channel over the app channels opened on the same
+    // connection, so that bulk app transfers don't starve the DHT messages.
+    // Only applied where supported (browsers)
+    pub control_channel_priority: ChannelPriority,
+
+    // Address family to prefer for ICE candidates
+    pub ip_preference: IpPreference,
+
+    // Relay forces every connection through the TURN servers of ice_servers
+    // (that must have credentials), used to test NAT traversal
+    pub ice_transport_policy: IceTransportPolicy,
+
+    // Max time waited for a bootstrap server to answer the connection request,
+    // after that the attempt fails and is retried with backoff
+    pub bootstrap_http_timeout: Duration,
+
+    // If present, bootstrap nodes must have one of these ids,
+    // the others are disconnected before being used for routing
+    pub trusted_bootstrap_ids: Option<HashSet<Id>>,
+
+    // Never half-close (or reap) the connections to the bootstrap nodes,
+    // avoiding reconnections to the seeds under connection pressure
+    pub pin_bootstrap: bool,
+
+    // Max number of bootstrap nodes being connected at the same time,
+    // the other bootstrap urls wait for a free slot (in order)
+    pub max_bootstrap_connecting: usize,
+
+    // Max time waited for a peer to answer a ping (sent when its routing bucket
+    // is full), after that the connection is closed as dead
+    pub ping_timeout: Duration,
+
+    // Max time a half-closed connection is kept open waiting for the peer to
+    // half-close it too (or for it to be reused), after that it's closed
+    pub half_close_grace: Duration,
+
+    // Reconnect (with backoff) to the lost routing peers that left their bucket
+    // with a free place, the connection is signaled through the other peers
+    pub auto_reconnect_routing: bool,
+
+    // Delays between the reconnection attempts
+    pub reconnect_backoff: BackoffConfig,
+
+    // Called after the handshake of every connection, returning false
+    // disconnects the peer
+    #[serde(skip)]
+    pub authorize: Option<AuthorizeHook>,
+
+    // Called with the signaling descriptions and handshake messages of every
+    // connection opened through signaling, to replay them later
+    #[serde(skip)]
+    pub handshake_recorder: Option<HandshakeRecorder>,
+}
+
+impl Default for TransportConfig {
+    fn default() -> Self {
+        Self {
+            stun_servers: Vec::new(),
+            ice_servers: Vec::new(),
+            max_connections: None,
+            max_connecting: 256,
+            connecting_timeout: Duration::from_secs(2 * 60),
+            signaling_timeout: Duration::from_secs(60),
+            request_timeout: Duration::from_secs(10 * 60),
+            connection_order: ConnectionOrder::LowerIdOffers,
+            keep_connection_on_channel_close: false,
+            forward_policy: ForwardPolicy::Open,
+            max_forwards_per_minute: None,
+            event_capacity: 64,
+            event_overflow: EventOverflow::Block,
+            channel_open_policy: ChannelOpenPolicy::Queue,
+            max_fragment_size: 16 * 1024,
+            fragment_timeout: Duration::from_secs(60),
+            max_message_size: 1024 * 1024,
+            send_buffer_high_watermark: 256 * 1024,
+            max_send_queue: 4 * 1024 * 1024,
+            max_sdp_size: 4 * 1024,
+            wire_format: WireFormat::Json,
+            control_channel_priority: ChannelPriority::High,
+            ip_preference: IpPreference::None,
+            ice_transport_policy: IceTransportPolicy::All,
+            bootstrap_http_timeout: Duration::from_secs(30),
+            trusted_bootstrap_ids: None,
+            pin_bootstrap: true,
+            max_bootstrap_connecting: 8,
+            ping_timeout: Duration::from_secs(30),
+            half_close_grace: Duration::from_secs(5 * 60),
+            auto_reconnect_routing: false,
+            reconnect_backoff: BackoffConfig::default(),
+            authorize: None,
+            handshake_recorder: None,
+        }
+    }
+}
+
+/// Invalid configuration, rejected when the DHT is created
+#[derive(Clone, Debug, Error, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ConfigError {
+    #[error("signaling_timeout must be non-zero")]
+    ZeroSignalingTimeout,
+    #[error("request_timeout must be non-zero")]
+    ZeroRequestTimeout,
+    #[error("max_send_queue ({0}) must fit a whole message of max_message_size ({1})")]
+    SendQueueTooSmall(usize, usize),
+    #[error("reconnect_backoff.multiplier must be at least 1")]
+    InvalidBackoffMultiplier,
+    #[error("reconnect_backoff.max must not be less than initial")]
+    InvalidBackoffMax,
+    #[error("max_fragment_size must be at least 2 (found {0})")]
+    FragmentSizeTooSmall(usize),
+    #[error("Invalid routing config: {0}")]
+    Routing(#[from] RoutingConfigError),
+}
+
+impl TransportConfig {
+    /// Checks that the values can be used by the transport
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.signaling_timeout.is_zero() {
+            return Err(ConfigError::ZeroSignalingTimeout);
+        }
+        if self.request_timeout.is_zero() {
+            return Err(ConfigError::ZeroRequestTimeout);
+        }
+        if self.max_send_queue < self.max_message_size {
+            return Err(ConfigError::SendQueueTooSmall(self.max_send_queue, self.max_message_size));
+        }
+        let multiplier = self.reconnect_backoff.multiplier;
+        if multiplier.is_nan() || multiplier < 1.0 {
+            return Err(ConfigError::InvalidBackoffMultiplier);
+        }
+        if self.reconnect_backoff.max < self.reconnect_backoff.initial {
+            return Err(ConfigError::InvalidBackoffMax);
+        }
+        if self.max_fragment_size < 2 {
+            return Err(ConfigError::FragmentSizeTooSmall(self.max_fragment_size));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for TransportConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TransportConfig")
+            .field("stun_servers", &self.stun_servers)
+            .field("ice_servers", &self.ice_servers)
+            .field("max_connections", &self.max_connections)
+            .field("max_connecting", &self.max_connecting)
+            .field("connecting_timeout", &self.connecting_timeout)
+            .field("signaling_timeout", &self.signaling_timeout)
+            .field("request_timeout", &self.request_timeout)
+            .field("connection_order", &self.connection_order)
+            .field("keep_connection_on_channel_close", &self.keep_connection_on_channel_close)
+            .field("forward_policy", &self.forward_policy)
+            .field("max_forwards_per_minute", &self.max_forwards_per_minute)
+            .field("event_capacity", &self.event_capacity)
+            .field("event_overflow", &self.event_overflow)
+            .field("channel_open_policy", &self.channel_open_policy)
+            .field("max_fragment_size", &self.max_fragment_size)
+            .field("fragment_timeout", &self.fragment_timeout)
+            .field("max_message_size", &self.max_message_size)
+            .field("send_buffer_high_watermark", &self.send_buffer_high_watermark)
+            .field("max_send_queue", &self.max_send_queue)
+            .field("max_sdp_size", &self.max_sdp_size)
+            .field("wire_format", &self.wire_format)
+            .field("control_channel_priority", &self.control_channel_priority)
+            .field("ip_preference", &self.ip_preference)
+            .field("ice_transport_policy", &self.ice_transport_policy)
+            .field("bootstrap_http_timeout", &self.bootstrap_http_timeout)
+            .field("trusted_bootstrap_ids", &self.trusted_bootstrap_ids)
+            .field("pin_bootstrap", &self.pin_bootstrap)
+            .field("max_bootstrap_connecting", &self.max_bootstrap_connecting)
+            .field("ping_timeout", &self.ping_timeout)
+            .field("half_close_grace", &self.half_close_grace)
+            .field("auto_reconnect_routing", &self.auto_reconnect_routing)
+            .field("reconnect_backoff", &self.reconnect_backoff)
+            .field("authorize", &self.authorize.as_ref().map(|_| "<hook>"))
+            .field("handshake_recorder", &self.handshake_recorder.as_ref().map(|_| "<hook>"))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate() {
+        assert_eq!(TransportConfig::default().validate(), Ok(()));
+        let config = TransportConfig { signaling_timeout: Duration::ZERO, ..Default::default() };
+        assert_eq!(config.validate(), Err(ConfigError::ZeroSignalingTimeout));
+        let config = TransportConfig { request_timeout: Duration::ZERO, ..Default::default() };
+        assert_eq!(config.validate(), Err(ConfigError::ZeroRequestTimeout));
+        let config = TransportConfig { max_send_queue: 10, max_message_size: 11, ..Default::default() };
+        assert_eq!(config.validate(), Err(ConfigError::SendQueueTooSmall(10, 11)));
+        for multiplier in [0.5, f64::NAN] {
+            let reconnect_backoff = BackoffConfig { multiplier, ..Default::default() };
+            let config = TransportConfig { reconnect_backoff, ..Default::default() };
+            assert_eq!(config.validate(), Err(ConfigError::InvalidBackoffMultiplier));
+        }
+        let reconnect_backoff = BackoffConfig { max: Duration::ZERO, ..Default::default() };
+        let config = TransportConfig { reconnect_backoff, ..Default::default() };
+        assert_eq!(config.validate(), Err(ConfigError::InvalidBackoffMax));
+        // Fragments need room for the marker and some data
+        for max_fragment_size in [0, 1] {
+            let config = TransportConfig { max_fragment_size, ..Default::default() };
+            assert_eq!(config.validate(), Err(ConfigError::FragmentSizeTooSmall(max_fragment_size)));
+        }
+        let config = TransportConfig { max_fragment_size: 2, ..Default::default() };
+        assert_eq!(config.validate(), Ok(()));
+    }
 }
@@ -0,0 +1,35 @@
+//! Best-effort introspection of a running node, used to diagnose hangs.
+//!
+//! Nothing here waits on a lock: locks that are currently held are reported
+//! as such and the data they protect is left out of the snapshot.
+use serde::Serialize;
+use wdht_logic::DhtDebugState;
+use wdht_wasync::live_tasks;
+
+use crate::{wrtc::ConnectionDebugState, Dht};
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct TransportDebugState {
+    // True if the connection map was locked (connections is empty)
+    pub connections_locked: bool,
+    pub connections: Vec<ConnectionDebugState>,
+    // Connections still being negotiated, None if the connector was locked
+    pub connecting: Option<usize>,
+    pub half_closed_locked: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct DebugState {
+    pub dht: DhtDebugState,
+    pub transport: TransportDebugState,
+    // Spawned tasks still alive, None if the task-count feature is disabled
+    pub live_tasks: Option<usize>,
+}
+
+pub fn debug_state(dht: &Dht) -> DebugState {
+    DebugState {
+        dht: dht.debug_state(),
+        transport: dht.transport().debug_state(),
+        live_tasks: live_tasks(),
+    }
+}
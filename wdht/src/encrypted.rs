@@ -0,0 +1,102 @@
+//! Opt-in end-to-end encryption of stored values.
+//!
+//! Values are encrypted with a caller-managed symmetric key before being
+//! inserted, so the nodes storing them only ever see opaque bytes.
+//! Entries that cannot be decrypted (e.g. inserted by someone without the
+//! key) are skipped when querying. The topic is authenticated with the value,
+//! so a ciphertext copied to another topic can't be decrypted.
+use std::time::Duration;
+
+use thiserror::Error;
+use tracing::debug;
+use wdht_crypto::{self as crypto, CryptoError};
+use wdht_logic::{
+    search::BasicSearchOptions,
+    transport::{TopicEntry, TransportSender},
+    Id, KademliaDht, Storage, StorageBackend, StorageError,
+};
+
+pub use wdht_crypto::{import_symmetric_key, SymmetricKey, SYMMETRIC_KEY_SIZE};
+
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum EncryptedError {
+    #[error("Encryption error: {0}")]
+    Crypto(#[from] CryptoError),
+    #[error("Storage error: {0}")]
+    Storage(#[from] StorageError),
+}
+
+pub struct EncryptedDht<'a, T: TransportSender, S: StorageBackend = Storage> {
+    dht: &'a KademliaDht<T, S>,
+    key: SymmetricKey,
+}
+
+impl<'a, T: TransportSender, S: StorageBackend> EncryptedDht<'a, T, S> {
+    pub fn new(dht: &'a KademliaDht<T, S>, key: SymmetricKey) -> Self {
+        EncryptedDht { dht, key }
+    }
+
+    pub async fn insert(&self, key: Id, lifetime: Duration, value: &[u8]) -> Result<usize, EncryptedError> {
+        let encrypted = crypto::encrypt(&self.key, value, key.as_bytes()).await?;
+        Ok(self.dht.insert(key, lifetime, encrypted).await?)
+    }
+
+    pub async fn query_value(&self, key: Id, max_entry_count: u32, options: BasicSearchOptions) -> Vec<TopicEntry> {
+        let entries = self.dht.query_value(key, max_entry_count, options).await;
+        let mut res = Vec::with_capacity(entries.len());
+        for entry in entries {
+            match crypto::decrypt(&self.key, &entry.data, key.as_bytes()).await {
+                Ok(data) => res.push(TopicEntry { publisher: entry.publisher, data }),
+                Err(_) => debug!("Skipping undecryptable entry from {}", entry.publisher),
+            }
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::broadcast;
+    use wdht_logic::{config::SystemConfig, transport::simulate::AsyncSimulatedTransport};
+
+    use super::*;
+
+    #[test_log::test(tokio::test)]
+    async fn encrypted_roundtrip() {
+        let (killswitch, _shutdown) = broadcast::channel(1);
+        let config = SystemConfig::default();
+        let ids = [Id::ZERO.set_bit(1), Id::ZERO.set_bit(2)];
+        let dhts: Vec<_> = ids.iter()
+            .map(|id| AsyncSimulatedTransport::spawn(config.clone(), *id, killswitch.subscribe()))
+            .collect();
+        dhts[1].transport().connect_to(vec![(ids[0], &dhts[0].transport)]).await;
+        dhts[1].query_nodes(ids[1], BasicSearchOptions::default()).await;
+
+        let key = import_symmetric_key(&[42u8; SYMMETRIC_KEY_SIZE]).await.unwrap();
+        let writer = EncryptedDht::new(&*dhts[1], key.clone());
+        let topic = Id::ZERO.set_bit(10);
+        let secret = b"Very secret data";
+        assert!(writer.insert(topic, Duration::from_secs(60), secret).await.unwrap() > 0);
+
+        // Plain queries only see opaque bytes
+        let raw = dhts[0].query_value(topic, 10, BasicSearchOptions::default()).await;
+        assert_eq!(raw.len(), 1);
+        assert!(!raw[0].data.windows(secret.len()).any(|x| x == secret));
+
+        let reader = EncryptedDht::new(&*dhts[0], key);
+        let found = reader.query_value(topic, 10, BasicSearchOptions::default()).await;
+        assert_eq!(found, vec![TopicEntry { publisher: ids[1], data: secret.to_vec() }]);
+
+        // Entries are skipped without the right key
+        let other = import_symmetric_key(&[7u8; SYMMETRIC_KEY_SIZE]).await.unwrap();
+        let other = EncryptedDht::new(&*dhts[0], other);
+        assert!(other.query_value(topic, 10, BasicSearchOptions::default()).await.is_empty());
+
+        // Or if copied to another topic
+        let copied = Id::ZERO.set_bit(11);
+        dhts[0].insert(copied, Duration::from_secs(60), raw[0].data.clone()).await.unwrap();
+        assert_eq!(dhts[1].query_value(copied, 10, BasicSearchOptions::default()).await.len(), 1);
+        assert!(reader.query_value(copied, 10, BasicSearchOptions::default()).await.is_empty());
+    }
+}
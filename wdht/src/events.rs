@@ -1,10 +1,13 @@
 use core::fmt;
+use std::time::Duration;
 
-use async_broadcast::RecvError;
+use async_broadcast::{RecvError, TrySendError};
+use tracing::warn;
 use wdht_logic::Id;
+use wdht_wasync::spawn;
 use wdht_wrtc::{RawConnection, RawChannel};
 
-use crate::wrtc::WrtcContact;
+use crate::{wrtc::WrtcContact, ChannelOpenPolicy};
 
 #[derive(Clone, Debug)]
 pub struct ChannelOpenEvent {
@@ -14,15 +17,39 @@ pub struct ChannelOpenEvent {
 }
 
 
+/// Side of the signaling taken by the local node
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum PeerRole {
+    Active,// We sent the offer
+    Passive,// We answered the peer's offer
+    Adopted,// Channel opened outside of the built-in signaling
+}
+
+#[derive(Clone, Debug)]
+pub struct PeerConnectedEvent {
+    pub id: Id,
+    pub role: PeerRole,
+    // The connection was opened to a bootstrap node
+    pub was_bootstrap: bool,
+    // Duration of the handshake (about one round trip), None if it was skipped
+    pub rtt_estimate: Option<Duration>,
+}
+
 #[derive(Clone, Debug)]
 pub enum TransportEvent {
     Connect(WrtcContact),
+    // Sent right after Connect, describes how the connection was formed
+    PeerConnected(PeerConnectedEvent),
     Disconnect(Id, DisconnectReason),
     ChannelOpen(ChannelOpenEvent),
+    // A stored entry expired and was removed from the local storage
+    StorageEvicted { topic: Id, publisher: Id },
+    // The node finished joining the network (sent once, before create_dht returns)
+    BootstrapComplete { peers: u64, routing_size: u64 },
     Shutdown,
 }
 
-#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
 pub enum DisconnectReason {
     ConnectionLost,
     HalfCloseReplace,// Connection was half closen and we needed space to open new connections
@@ -30,7 +57,7 @@ pub enum DisconnectReason {
     BadBehavior,
     TimeoutExpired,
     SendFail,
-    ProtocolVersionMismatch,// TODO: implement some kind of protocol version matching
+    ProtocolVersionMismatch,// The peer speaks an incompatible protocol version
     ShuttingDown,// DHT is shutting down
 }
 
@@ -66,6 +93,26 @@ pub async fn wait_for_event(listener: &mut async_broadcast::Receiver<TransportEv
     }
 }
 
+// Sends the event without waiting, even if the channel is full
+pub(crate) fn send_channel_open(
+    events_tx: &async_broadcast::Sender<TransportEvent>,
+    event: ChannelOpenEvent,
+    policy: ChannelOpenPolicy,
+) {
+    // Closed or inactive channels are not errors
+    if let Err(TrySendError::Full(event)) = events_tx.try_broadcast(TransportEvent::ChannelOpen(event)) {
+        match policy {
+            ChannelOpenPolicy::Queue => {
+                let events_tx = events_tx.clone();
+                spawn(async move {
+                    let _ = events_tx.broadcast(event).await;
+                });
+            }
+            ChannelOpenPolicy::Drop => warn!("Event channel is full, dropping channel open event"),
+        }
+    }
+}
+
 pub async fn wait_for_shutdown(listener: &mut async_broadcast::Receiver<TransportEvent>) {
     wait_for_event(listener, |ev| match ev {
         Ok(TransportEvent::Shutdown) |
@@ -73,3 +120,48 @@ pub async fn wait_for_shutdown(listener: &mut async_broadcast::Receiver<Transpor
         _ => false,
     }).await;
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn event(x: u8) -> ChannelOpenEvent {
+        ChannelOpenEvent {
+            id: Id::ZERO.set_bit(x),
+            connection: Default::default(),
+            channel: Default::default(),
+        }
+    }
+
+    fn received_ids(rx: &mut async_broadcast::Receiver<TransportEvent>) -> Vec<Id> {
+        let mut res = Vec::new();
+        while let Ok(TransportEvent::ChannelOpen(x)) = rx.try_recv() {
+            res.push(x.id);
+        }
+        res
+    }
+
+    #[tokio::test]
+    async fn slow_channel_open_consumer() {
+        for policy in [ChannelOpenPolicy::Queue, ChannelOpenPolicy::Drop] {
+            let (tx, mut rx) = async_broadcast::broadcast(1);
+            // Nobody is reading, the listener must not block anyway
+            tokio::time::timeout(Duration::from_secs(1), async {
+                for i in 0..3 {
+                    send_channel_open(&tx, event(i), policy);
+                }
+            }).await.unwrap();
+
+            assert_eq!(received_ids(&mut rx), vec![Id::ZERO.set_bit(0)]);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            let late = received_ids(&mut rx);
+            match policy {
+                // The queued events arrive once there's space
+                ChannelOpenPolicy::Queue => assert_eq!(late.len(), 1),
+                ChannelOpenPolicy::Drop => assert!(late.is_empty()),
+            }
+        }
+    }
+}
@@ -1,19 +1,83 @@
-use wdht_crypto::{self as crypto, SigningKey}
+use std::{fs::{self, OpenOptions}, io::{self, Write}, path::Path};
+
+use thiserror::Error;
+use wdht_crypto::{self as crypto, KeyAlgorithm, SigningKey}
 ;
-use wdht_logic::{Id, consts::ID_LEN};
+use wdht_logic::Id;
+
+pub use wdht_crypto::SEED_SIZE;
 
 const KEY_HASH_CONTEXT: &'static [u8] = b"wdht.transport.identity";
 
+#[derive(Clone, Debug, Error)]
+#[non_exhaustive]
+pub enum SeedError {
+    #[error("Invalid mnemonic: {0}")]
+    InvalidMnemonic(String),
+
+    #[error("The mnemonic doesn't encode a {SEED_SIZE} bytes seed")]
+    WrongLength,
+}
+
+/// Key pair of a node, its id is derived from the public key.
+#[derive(Clone)]
 pub struct Identity {
     key: SigningKey,
 }
 
 impl Identity {
     pub async fn generate() -> Self {
-        let key = crypto::generate_pair().await.expect("Failed to generate crypto key");
+        Self::generate_with(KeyAlgorithm::default()).await
+    }
+
+    /// Generates a key pair of the given algorithm, peers accept any supported algorithm
+    pub async fn generate_with(algorithm: KeyAlgorithm) -> Self {
+        let key = crypto::generate_pair_with(algorithm).await.expect("Failed to generate crypto key");
         Identity { key, }
     }
 
+    /// Uses a caller-provided key, so the node id stays the same across runs
+    pub fn from_key(key: SigningKey) -> Self {
+        Identity { key }
+    }
+
+    /// Derives the key from a seed, persisting the seed keeps the node id stable
+    pub fn from_seed(seed: &[u8; SEED_SIZE]) -> Self {
+        Identity { key: crypto::signing_key_from_seed(seed) }
+    }
+
+    /// Same as from_seed, with the seed encoded as a 24 words BIP39 mnemonic (english)
+    pub fn from_mnemonic(words: &str) -> Result<Self, SeedError> {
+        seed_from_mnemonic(words).map(|x| Self::from_seed(&x))
+    }
+
+    /// Saves the private key in path (readable only by its owner), creating the missing directories
+    pub async fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let data = crypto::export_private_key(&self.key).await
+            .map_err(|x| io::Error::new(io::ErrorKind::Other, x))?;
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(path)?;
+        // The mode is only applied to new files
+        #[cfg(unix)]
+        file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+        file.write_all(&data)
+    }
+
+    /// Loads a private key saved by save
+    pub async fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let data = fs::read(path)?;
+        let key = crypto::import_private_key(&data).await
+            .map_err(|x| io::Error::new(io::ErrorKind::InvalidData, x))?;
+        Ok(Identity { key })
+    }
+
     pub fn export_key(&self) -> &[u8] {
         crypto::export_public_key(&self.key)
     }
@@ -26,15 +90,14 @@ impl Identity {
     async fn compute_identity(&self, key: &[u8]) -> Id {
         let hash_data = crypto::sha2_hash(&KEY_HASH_CONTEXT, key).await.expect("Failed to generate crypto ID");
         // Truncate hashed bytes into ID (hash is 256 bitsm ID should be 160 bits)
-        let mut id = Id::ZERO;
-        id.0[..ID_LEN].copy_from_slice(&hash_data[..ID_LEN]);
-        id
+        Id::from_hash_truncated(&hash_data)
     }
 
     pub async fn create_proof(&self, fingerprint: &[u8]) -> Vec<u8> {
         crypto::sign(&self.key, fingerprint).await.expect("Failed to generate proof")
     }
 
+    /// Checks that the signature of the fingerprint was made by key, returning the id derived from it
     pub async fn check_identity_proof(&self, key: &[u8], fingerprint: &[u8], signature: &[u8]) -> Result<Id, ()> {
         let raw_key = key;
         let key = crypto::import_pub_key(key).await
@@ -44,4 +107,81 @@ impl Identity {
         }
         Ok(self.compute_identity(raw_key).await)
     }
+
+    /// Same as check_identity_proof, but also checks that the key belongs to expected.
+    /// The ids are compared in constant time, so the timing doesn't leak how close a forged key got
+    pub async fn check_identity_proof_of(&self, expected: Id, key: &[u8], fingerprint: &[u8], signature: &[u8]) -> bool {
+        self.check_identity_proof(key, fingerprint, signature).await
+            .map_or(false, |id| crypto::constant_time_eq(id.as_bytes(), expected.as_bytes()))
+    }
+}
+
+/// Encodes a seed as a 24 words BIP39 mnemonic, easier to write down than the raw bytes
+pub fn seed_to_mnemonic(seed: &[u8; SEED_SIZE]) -> String {
+    bip39::Mnemonic::from_entropy(seed)
+        .expect("Seed has a valid entropy length")
+        .to_string()
+}
+
+pub fn seed_from_mnemonic(words: &str) -> Result<[u8; SEED_SIZE], SeedError> {
+    let mnemonic = bip39::Mnemonic::parse(words)
+        .map_err(|x| SeedError::InvalidMnemonic(x.to_string()))?;
+    mnemonic.to_entropy().try_into()
+        .map_err(|_| SeedError::WrongLength)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn seed_test() {
+        let seed = [3u8; SEED_SIZE];
+        let id = Identity::from_seed(&seed).generate_id().await;
+        assert_eq!(Identity::from_seed(&seed).generate_id().await, id);
+        assert_ne!(Identity::from_seed(&[4u8; SEED_SIZE]).generate_id().await, id);
+
+        let words = seed_to_mnemonic(&seed);
+        assert_eq!(words.split(' ').count(), 24);
+        assert_eq!(Identity::from_mnemonic(&words).unwrap().generate_id().await, id);
+
+        // Valid mnemonic of a shorter seed
+        let short = bip39::Mnemonic::from_entropy(&[3u8; 16]).unwrap().to_string();
+        assert!(matches!(Identity::from_mnemonic(&short), Err(SeedError::WrongLength)));
+        assert!(matches!(Identity::from_mnemonic("not a mnemonic"), Err(SeedError::InvalidMnemonic(_))));
+    }
+
+    #[tokio::test]
+    async fn save_load_test() {
+        let dir = std::env::temp_dir().join(format!("wdht-identity-{}", rand::random::<u64>()));
+        let path = dir.join("nested").join("identity");
+        for identity in [Identity::generate().await, Identity::from_seed(&[5u8; SEED_SIZE])] {
+            identity.save(&path).await.unwrap();
+            let loaded = Identity::load(&path).await.unwrap();
+            assert_eq!(loaded.export_key(), identity.export_key());
+            assert_eq!(loaded.generate_id().await, identity.generate_id().await);
+        }
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+
+        fs::write(&path, b"garbage").unwrap();
+        assert_eq!(Identity::load(&path).await.err().map(|x| x.kind()), Some(io::ErrorKind::InvalidData));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn proof_of_test() {
+        let (owner, other) = tokio::join!(Identity::generate(), Identity::generate_with(KeyAlgorithm::Ed25519));
+        let (owner_id, other_id) = tokio::join!(owner.generate_id(), other.generate_id());
+        let proof = owner.create_proof(b"fingerprint").await;
+
+        assert!(other.check_identity_proof_of(owner_id, owner.export_key(), b"fingerprint", &proof).await);
+        assert!(!other.check_identity_proof_of(other_id, owner.export_key(), b"fingerprint", &proof).await);
+        assert!(!other.check_identity_proof_of(owner_id, other.export_key(), b"fingerprint", &proof).await);
+        assert!(!other.check_identity_proof_of(owner_id, owner.export_key(), b"other", &proof).await);
+    }
 }
@@ -12,15 +12,27 @@ use wrtc::WrtcSender;
 
 mod identity;
 mod config;
+pub mod debug;
+pub mod encrypted;
 pub mod events;
 mod http_api;
+pub mod messages;
+pub mod metrics;
 mod reconnect;
+pub mod revalidate;
 mod serde;
+pub mod signed;
+pub mod topic;
 #[cfg(feature = "warp")]
 pub mod warp_filter;
 pub mod wrtc;
 
-pub use config::TransportConfig;
+pub use config::{AuthorizeHook, BackoffConfig, ChannelOpenPolicy, ConfigError, ConnectionOrder, EventOverflow, ForwardPolicy, HandshakeRecorder, WireFormat, TransportConfig};
+pub use identity::{seed_from_mnemonic, seed_to_mnemonic, Identity, SeedError, SEED_SIZE};
+pub use reconnect::BootstrapState;
+pub use topic::{RawId, TopicKey};
+pub use wdht_crypto::KeyAlgorithm;
+pub use wdht_wrtc::{ChannelPriority, IceServer, IceTransportPolicy, IpPreference};
 
 use crate::events::wait_for_shutdown;
 
@@ -28,19 +40,53 @@ pub type Dht = KademliaDht<WrtcSender>;
 pub type EventReceiver = async_broadcast::Receiver<TransportEvent>;
 // Reexport
 pub use wdht_logic as logic;
+pub use wdht_wasync as wasync;
 
+/// Creates a new DHT node connected to the bootstrap nodes.
+///
+/// The returned receiver gets the [`TransportEvent`]s of the node, every clone shares
+/// the same buffer of [`TransportConfig::event_capacity`] events.
+/// With [`EventOverflow::Block`] no event is lost but slow receivers stall the transport
+/// (disconnection events are dropped instead of waiting), with [`EventOverflow::DropOldest`]
+/// the transport never waits and slow receivers get [`async_broadcast::RecvError::Overflowed`].
+/// Unused receivers should be dropped or deactivated.
+///
+/// Fails if the configuration is invalid (see [`TransportConfig::validate`]
+/// and [`wdht_logic::config::RoutingConfig::validate`]).
 pub async fn create_dht<T, I>(
     config: SystemConfig,
     transport_config: TransportConfig,
     bootstrap: T,
-) -> (Orc<Dht>, EventReceiver)
+) -> Result<(Orc<Dht>, EventReceiver), ConfigError>
 where
     T: IntoIterator<Item = I>,
     I: TryInto<Url>,
     <I as TryInto<Url>>::Error: Display,
 {
-    let (events_tx, events_rx) = broadcast(64);
-    let dht = wrtc::Connections::create(config, transport_config, events_tx).await;
+    let identity = Identity::generate().await;
+    create_dht_with_identity(config, transport_config, identity, bootstrap).await
+}
+
+fn event_channel(config: &TransportConfig) -> (async_broadcast::Sender<TransportEvent>, EventReceiver) {
+    let (mut events_tx, events_rx) = broadcast(config.event_capacity);
+    events_tx.set_overflow(config.event_overflow == EventOverflow::DropOldest);
+    (events_tx, events_rx)
+}
+
+/// Like [`create_dht`] but with a fixed identity (and so a fixed id)
+pub async fn create_dht_with_identity<T, I>(
+    config: SystemConfig,
+    transport_config: TransportConfig,
+    identity: Identity,
+    bootstrap: T,
+) -> Result<(Orc<Dht>, EventReceiver), ConfigError>
+where
+    T: IntoIterator<Item = I>,
+    I: TryInto<Url>,
+    <I as TryInto<Url>>::Error: Display,
+{
+    let (events_tx, events_rx) = event_channel(&transport_config);
+    let dht = wrtc::Connections::create(config, transport_config, identity, events_tx).await?;
     // Run periodic cleaner
     let task = run_periodic_clean(Orc::downgrade(&dht), events_rx.clone());
     spawn(task.instrument(tracing::info_span!("Periodic cleaner")));
@@ -58,7 +104,14 @@ where
             }
         })
         .collect();
+    join_network(&dht, urls, &events_rx).await;
 
+    Ok((dht, events_rx))
+}
+
+// Connects to the bootstrap nodes and looks up our neighbours,
+// the progress is tracked in Connections::bootstrap_state
+async fn join_network(dht: &Orc<Dht>, urls: Vec<Url>, events_rx: &EventReceiver) {
     let connector = &dht.transport.0;
     let (bootstrap_connect_tx, bootstrap_connect_rx) = oneshot::channel();
     let reconnector = reconnect::bootstrap_reconnector(urls, events_rx.clone(), Orc::downgrade(connector), bootstrap_connect_tx);
@@ -66,12 +119,12 @@ where
     bootstrap_connect_rx.await.expect("Major failure while connecting to bootstrap nodes");
 
     info!("Finished connecting to bootstrap nodes");
-    let search_config = BasicSearchOptions { parallelism: 4 };
+    connector.set_bootstrap_state(BootstrapState::Bootstrapping);
+    let search_config = BasicSearchOptions { parallelism: 4, ..Default::default() };
     let mut rng = rand::thread_rng();
     dht.bootstrap(search_config, &mut rng).await;
     info!("Bootstrap finished correctly");
-
-    (dht, events_rx)
+    connector.on_bootstrap_complete(dht.routing_size());
 }
 
 async fn run_periodic_clean(kad: Weak<KademliaDht<WrtcSender>>, mut events: async_broadcast::Receiver<TransportEvent>) {
@@ -84,22 +137,561 @@ async fn run_periodic_clean(kad: Weak<KademliaDht<WrtcSender>>, mut events: asyn
             Some(x) => x,
             None => break,// Program exited
         };
-        k.periodic_run();
+        periodic_clean(&k).await;
     }
 }
 
+async fn periodic_clean(kad: &KademliaDht<WrtcSender>) {
+    let evicted = kad.periodic_run();
+    kad.transport().0.on_storage_evicted(evicted).await;
+    kad.republish().await;
+}
+
 #[cfg(test)]
 mod tests {
-    use wdht_logic::config::SystemConfig;
+    use std::sync::{Arc, Mutex};
+
+    use reqwest::Url;
+    use tokio::sync::oneshot;
+    use wdht_logic::{
+        config::SystemConfig,
+        search::BasicSearchOptions,
+        transport::{Contact, Request, TransportError, TransportSender},
+        Id,
+    };
+    use wdht_wrtc::{create_channel, ConnectionRole, RtcConfig, SessionDescription, WrtcChannel, WrtcError};
+
+    use crate::{create_dht, create_dht_with_identity, Identity, TransportConfig, events::{DisconnectReason, PeerRole, TransportEvent}, warp_filter::dht_connect, wrtc::{ConnectionPriority, WrtcTransportError}};
+
+    // Opens a channel pair without using wdht's signaling
+    pub(crate) async fn open_channel_pair() -> (WrtcChannel, WrtcChannel) {
+        let rtc_config = RtcConfig::new(&[] as &[&str]);
+        let (offer_tx, offer_rx) = oneshot::channel();
+        let (answer_tx, answer_rx) = oneshot::channel();
+        let (remote_answer_tx, remote_answer_rx) = oneshot::channel::<Result<SessionDescription, WrtcError>>();
+        let (a_chan, b_chan, _) = tokio::join!(
+            create_channel(&rtc_config, ConnectionRole::Active(remote_answer_rx), offer_tx),
+            async {
+                let offer = offer_rx.await.unwrap();
+                create_channel::<WrtcError>(&rtc_config, ConnectionRole::Passive(offer), answer_tx).await
+            },
+            async { remote_answer_tx.send(Ok(answer_rx.await.unwrap())) },
+        );
+        (a_chan.unwrap(), b_chan.unwrap())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn event_overflow() {
+        use async_broadcast::{RecvError, TryRecvError, TrySendError};
+        use crate::{event_channel, EventOverflow};
+
+        let ev = |x: u8| TransportEvent::Disconnect(Id::ZERO.set_bit(x), DisconnectReason::ConnectionLost);
+        let disconnected = |ev: TransportEvent| match ev {
+            TransportEvent::Disconnect(id, _) => id,
+            _ => panic!("Unexpected event"),
+        };
+        let event_config = |event_overflow| TransportConfig { event_capacity: 2, event_overflow, ..Default::default() };
 
-    use crate::{create_dht, TransportConfig, events::TransportEvent};
+        // Block: new events are refused until the receivers catch up
+        let (tx, mut rx) = event_channel(&event_config(EventOverflow::Block));
+        tx.try_broadcast(ev(0)).unwrap();
+        tx.try_broadcast(ev(1)).unwrap();
+        assert!(matches!(tx.try_broadcast(ev(2)), Err(TrySendError::Full(_))));
+        assert_eq!(disconnected(rx.try_recv().unwrap()), Id::ZERO.set_bit(0));
+        assert_eq!(disconnected(rx.try_recv().unwrap()), Id::ZERO.set_bit(1));
+        assert!(matches!(rx.try_recv(), Err(TryRecvError::Empty)));
+
+        // DropOldest: new events are always sent, slow receivers are notified of the lost ones
+        let (tx, mut rx) = event_channel(&event_config(EventOverflow::DropOldest));
+        for i in 0..3 {
+            tx.try_broadcast(ev(i)).unwrap();
+        }
+        assert!(matches!(rx.recv().await, Err(RecvError::Overflowed(1))));
+        assert_eq!(disconnected(rx.recv().await.unwrap()), Id::ZERO.set_bit(1));
+        assert_eq!(disconnected(rx.recv().await.unwrap()), Id::ZERO.set_bit(2));
+    }
 
     #[test_log::test(tokio::test)]
     async fn drop_test() {
         let config = SystemConfig::default();
         let tconfig = TransportConfig::default();
-        let (dht, mut events) = create_dht(config, tconfig, vec![] as Vec<&'static str>).await;
+        let (dht, mut events) = create_dht(config, tconfig, vec![] as Vec<&'static str>).await.unwrap();
+        assert!(matches!(events.try_recv(), Ok(TransportEvent::BootstrapComplete { .. })));
         drop(dht);
         assert!(matches!(events.recv().await, Ok(TransportEvent::Shutdown)));
     }
+
+    #[test_log::test(tokio::test)]
+    async fn storage_evicted_test() {
+        let (dht, mut events) = create_dht(SystemConfig::default(), TransportConfig::default(), vec![] as Vec<Url>).await.unwrap();
+        assert!(matches!(events.try_recv(), Ok(TransportEvent::BootstrapComplete { .. })));
+        let key = Id::ZERO.set_bit(1);
+        dht.insert(key, std::time::Duration::from_secs(1), vec![1, 2, 3]).await.unwrap();
+
+        // Nothing expired yet
+        crate::periodic_clean(&dht).await;
+        assert!(events.try_recv().is_err());
+
+        tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+        crate::periodic_clean(&dht).await;
+        assert!(matches!(
+            events.try_recv(),
+            Ok(TransportEvent::StorageEvicted { topic, publisher }) if topic == key && publisher == dht.id()
+        ));
+        assert!(dht.storage.read().unwrap().get(key).is_none());
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn fixed_identity_test() {
+        let key = wdht_crypto::generate_pair().await.unwrap();
+        let create = || create_dht_with_identity(
+            SystemConfig::default(),
+            TransportConfig::default(),
+            Identity::from_key(key.clone()),
+            vec![] as Vec<Url>,
+        );
+        let (a, _a_events) = create().await.unwrap();
+        let (b, _b_events) = create().await.unwrap();
+        assert_eq!(a.id(), b.id());
+
+        let (c, _c_events) = create_dht(SystemConfig::default(), TransportConfig::default(), vec![] as Vec<Url>).await.unwrap();
+        assert_ne!(a.id(), c.id());
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn bootstrap_complete_test() {
+        let bootstrap_events = |events: &mut crate::EventReceiver| {
+            let mut complete = Vec::new();
+            while let Ok(ev) = events.try_recv() {
+                if let TransportEvent::BootstrapComplete { peers, routing_size } = ev {
+                    complete.push((peers, routing_size));
+                }
+            }
+            complete
+        };
+
+        let (srv, mut srv_events) = create_dht(SystemConfig::default(), TransportConfig::default(), vec![] as Vec<Url>).await.unwrap();
+        assert_eq!(bootstrap_events(&mut srv_events), vec![(0, 0)]);
+        let (addr, srv) = warp::serve(dht_connect(srv)).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(srv);
+        let url: Url = format!("http://localhost:{}", addr.port()).parse().unwrap();
+
+        let (_dht, mut events) = create_dht(SystemConfig::default(), TransportConfig::default(), vec![url]).await.unwrap();
+        let complete = bootstrap_events(&mut events);
+        assert_eq!(complete.len(), 1);
+        assert_eq!(complete[0].0, 1);
+        assert!(complete[0].1 > 0);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn peer_connected_test() {
+        let (srv, mut srv_events) = create_dht(SystemConfig::default(), TransportConfig::default(), vec![] as Vec<Url>).await.unwrap();
+        let (addr, srv) = warp::serve(dht_connect(srv)).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(srv);
+        let url: Url = format!("http://localhost:{}", addr.port()).parse().unwrap();
+
+        let (dht, mut events) = create_dht(SystemConfig::default(), TransportConfig::default(), vec![url]).await.unwrap();
+        let connected = |events: &mut crate::EventReceiver| loop {
+            if let TransportEvent::PeerConnected(x) = events.try_recv().unwrap() {
+                break x;
+            }
+        };
+        let ev = connected(&mut events);
+        assert_eq!(ev.role, PeerRole::Active);
+        assert!(ev.was_bootstrap);
+        assert!(ev.rtt_estimate.is_some());
+
+        // The server answered an offer that it didn't ask for
+        let ev = connected(&mut srv_events);
+        assert_eq!(ev.id, dht.id());
+        assert_eq!(ev.role, PeerRole::Passive);
+        assert!(!ev.was_bootstrap);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn peer_quality_test() {
+        let config = SystemConfig::default();
+        let tconfig = TransportConfig::default();
+
+        let (srv, _srv_events) = create_dht(config.clone(), tconfig.clone(), vec![] as Vec<Url>).await.unwrap();
+        let srv_id = srv.id();
+        let (addr, srv) = warp::serve(dht_connect(srv)).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(srv);
+
+        let bootstrap = vec![format!("http://localhost:{}", addr.port()).parse().unwrap()] as Vec<Url>;
+        let (dht, _events) = create_dht(config, tconfig, bootstrap).await.unwrap();
+
+        assert!(dht.transport().peer_quality(Id::ZERO).is_none());
+
+        let quality = dht.transport().peer_quality(srv_id).unwrap();
+        // Bootstrap searches go through the server connection
+        assert!(quality.messages_exchanged > 0);
+        assert!(quality.rtt.is_some());
+        assert!(quality.connected_since.elapsed() < std::time::Duration::from_secs(60));
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn adopt_connection_test() {
+        let config = SystemConfig::default();
+        let tconfig = TransportConfig::default();
+        let (a, _a_events) = create_dht(config.clone(), tconfig.clone(), vec![] as Vec<Url>).await.unwrap();
+        let (b, _b_events) = create_dht(config, tconfig, vec![] as Vec<Url>).await.unwrap();
+
+        let (a_chan, b_chan) = open_channel_pair().await;
+        let (a_contact, b_contact) = tokio::join!(
+            a.transport().adopt_connection(a_chan, None),
+            b.transport().adopt_connection(b_chan, None),
+        );
+        assert_eq!(a_contact.unwrap().id(), b.id());
+        assert_eq!(b_contact.unwrap().id(), a.id());
+
+        // The adopted connection is used for routing
+        let found = a.query_nodes(b.id(), BasicSearchOptions::default()).await;
+        assert_eq!(found[0].id(), b.id());
+        assert_eq!(a.transport().connected_count(), 1);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn traffic_metrics_test() {
+        let config = SystemConfig::default();
+        let tconfig = TransportConfig::default();
+        let (a, _a_events) = create_dht(config.clone(), tconfig.clone(), vec![] as Vec<Url>).await.unwrap();
+        let (b, _b_events) = create_dht(config, tconfig, vec![] as Vec<Url>).await.unwrap();
+
+        let (a_chan, b_chan) = open_channel_pair().await;
+        let (a_contact, b_contact) = tokio::join!(
+            a.transport().adopt_connection(a_chan, None),
+            b.transport().adopt_connection(b_chan, None),
+        );
+        let (_a_contact, _b_contact) = (a_contact.unwrap(), b_contact.unwrap());
+
+        let before = a.transport().metrics();
+        assert_eq!(before.connected_count, 1);
+        assert_eq!(before.connecting_count, 0);
+        let res = a.transport().send(b.id(), Request::FindNodes(a.id())).await;
+        assert!(res.is_ok());
+
+        // The request went out and its response came back
+        let after = a.transport().metrics();
+        assert!(after.bytes_sent > before.bytes_sent);
+        assert!(after.bytes_recv > before.bytes_recv);
+        let peer = b.transport().metrics();
+        assert!(peer.bytes_sent > 0 && peer.bytes_recv > 0);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn channel_close_test() {
+        let config = SystemConfig::default();
+        let tconfig = TransportConfig {
+            keep_connection_on_channel_close: true,
+            ..Default::default()
+        };
+        let (a, _a_events) = create_dht(config.clone(), tconfig.clone(), vec![] as Vec<Url>).await.unwrap();
+        let (b, _b_events) = create_dht(config, tconfig, vec![] as Vec<Url>).await.unwrap();
+
+        let (a_chan, b_chan) = open_channel_pair().await;
+        let (a_contact, b_contact) = tokio::join!(
+            a.transport().adopt_connection(a_chan, None),
+            b.transport().adopt_connection(b_chan, None),
+        );
+        let (a_contact, _b_contact) = (a_contact.unwrap(), b_contact.unwrap());
+
+        let conn = a.transport().0.connections.lock().unwrap()[&b.id()].clone();
+        conn.close_channel();
+        // Let the listener process the close
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+        // The peer connection is still there for the app channels
+        assert_eq!(a.transport().connected_count(), 1);
+        assert!(a.transport().0.connections.lock().unwrap().contains_key(&b.id()));
+        assert!(a_contact.raw_connection().is_some());
+        // but it can't be used for DHT requests anymore
+        let res = a.transport().send(b.id(), Request::FindNodes(a.id())).await;
+        assert!(res.is_err());
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn large_payload_test() {
+        let config = SystemConfig::default();
+        let tconfig = TransportConfig::default();
+        let (a, _a_events) = create_dht(config.clone(), tconfig.clone(), vec![] as Vec<Url>).await.unwrap();
+        let (b, _b_events) = create_dht(config, tconfig, vec![] as Vec<Url>).await.unwrap();
+
+        let (a_chan, b_chan) = open_channel_pair().await;
+        let (a_contact, b_contact) = tokio::join!(
+            a.transport().adopt_connection(a_chan, None),
+            b.transport().adopt_connection(b_chan, None),
+        );
+        let _contacts = (a_contact.unwrap(), b_contact.unwrap());
+
+        for (i, size) in [1024, 64 * 1024, 100 * 1024, 128 * 1024].into_iter().enumerate() {
+            let key = Id::ZERO.set_bit(i as u8);
+            // Worst case for JSON: every byte is encoded with 3 digits
+            let value: Vec<_> = (0..size).map(|x| 100 + (x % 156) as u8).collect();
+
+            let start = std::time::Instant::now();
+            let count = a.insert(key, std::time::Duration::from_secs(60), value.clone()).await.unwrap();
+            assert!(count >= 1);
+            // The copy of the other node went through the transport
+            let stored = b.storage.read().unwrap().get(key).unwrap().clone();
+            assert_eq!(stored[0].data, value);
+
+            let found = b.query_value(key, 1, BasicSearchOptions::default()).await;
+            assert_eq!(found.len(), 1);
+            assert!(found[0].data == value);
+            let elapsed = start.elapsed();
+            tracing::info!(
+                "{} KiB round-trip in {elapsed:?} ({:.1} KiB/s)",
+                size / 1024,
+                2.0 * size as f64 / 1024.0 / elapsed.as_secs_f64(),
+            );
+        }
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn backpressure_test() {
+        let config = SystemConfig::default();
+        // Small watermark: most messages wait in the outbound queue
+        let tconfig = TransportConfig {
+            send_buffer_high_watermark: 32 * 1024,
+            ..Default::default()
+        };
+        let (a, _a_events) = create_dht(config.clone(), tconfig.clone(), vec![] as Vec<Url>).await.unwrap();
+        let (b, _b_events) = create_dht(config, tconfig, vec![] as Vec<Url>).await.unwrap();
+
+        let (a_chan, b_chan) = open_channel_pair().await;
+        let (a_contact, b_contact) = tokio::join!(
+            a.transport().adopt_connection(a_chan, None),
+            b.transport().adopt_connection(b_chan, None),
+        );
+        let _contacts = (a_contact.unwrap(), b_contact.unwrap());
+
+        let value = vec![200u8; 32 * 1024];
+        let keys: Vec<_> = (0..16).map(|x| Id::ZERO.set_bit(x)).collect();
+        let results = futures::future::join_all(keys.iter().map(|key| {
+            a.transport().send(b.id(), Request::Insert(*key, 60, value.clone()))
+        })).await;
+
+        // Every insert is either answered or reported as failed, none is lost
+        let mut inserted = 0;
+        for (key, res) in keys.iter().zip(results) {
+            if res.is_ok() {
+                inserted += 1;
+                assert!(b.storage.read().unwrap().get(*key).is_some());
+            }
+        }
+        assert_eq!(inserted, keys.len());
+        assert_eq!(a.transport().connected_count(), 1);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn authorize_test() {
+        let banned = Id::ZERO.set_bit(3);
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let seen_hook = seen.clone();
+        let tconfig = TransportConfig {
+            authorize: Some(Arc::new(move |id, info| {
+                seen_hook.lock().unwrap().push((*id, info.public_key.is_some()));
+                *id != banned
+            })),
+            ..Default::default()
+        };
+        let (dht, mut events) = create_dht(SystemConfig::default(), tconfig, vec![] as Vec<Url>).await.unwrap();
+        assert!(matches!(events.try_recv(), Ok(TransportEvent::BootstrapComplete { .. })));
+
+        let (chan, _other) = open_channel_pair().await;
+        let res = dht.transport().adopt_connection(chan, Some(banned)).await;
+        assert!(matches!(res, Err(WrtcTransportError::Unauthorized(x)) if x == banned));
+        assert!(matches!(events.recv().await, Ok(TransportEvent::Disconnect(x, DisconnectReason::BadBehavior)) if x == banned));
+        assert_eq!(dht.transport().connection_count(), 0);
+
+        let allowed = Id::ZERO.set_bit(4);
+        let (chan, _other) = open_channel_pair().await;
+        let contact = dht.transport().adopt_connection(chan, Some(allowed)).await.unwrap();
+        assert_eq!(contact.id(), allowed);
+        assert_eq!(dht.transport().connected_count(), 1);
+
+        // The handshake was skipped, so no public key was available to the hook
+        assert_eq!(*seen.lock().unwrap(), vec![(banned, false), (allowed, false)]);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn ping_timeout_test() {
+        let tconfig = TransportConfig {
+            ping_timeout: std::time::Duration::from_millis(200),
+            ..Default::default()
+        };
+        let (dht, mut events) = create_dht(SystemConfig::default(), tconfig, vec![] as Vec<Url>).await.unwrap();
+        assert!(matches!(events.try_recv(), Ok(TransportEvent::BootstrapComplete { .. })));
+
+        // Nobody listens on the other end, so the peer never answers
+        let stalled = Id::ZERO.set_bit(5);
+        let (chan, _other) = open_channel_pair().await;
+        let _contact = dht.transport().adopt_connection(chan, Some(stalled)).await.unwrap();
+        assert_eq!(dht.transport().connected_count(), 1);
+
+        dht.transport().ping(stalled);
+        assert!(matches!(
+            events.recv().await,
+            Ok(TransportEvent::Disconnect(x, DisconnectReason::TimeoutExpired)) if x == stalled
+        ));
+        assert!(!dht.transport().0.connections.lock().unwrap().contains_key(&stalled));
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn half_close_grace_test() {
+        let tconfig = TransportConfig {
+            half_close_grace: std::time::Duration::from_millis(200),
+            ..Default::default()
+        };
+        let (dht, mut events) = create_dht(SystemConfig::default(), tconfig, vec![] as Vec<Url>).await.unwrap();
+        assert!(matches!(events.try_recv(), Ok(TransportEvent::BootstrapComplete { .. })));
+
+        // Nobody listens on the other end, so the peer never half-closes
+        let stalled = Id::ZERO.set_bit(10);
+        let (chan, _other) = open_channel_pair().await;
+        let _contact = dht.transport().adopt_connection(chan, Some(stalled)).await.unwrap();
+        let conn = dht.transport().0.connections.lock().unwrap()[&stalled].clone();
+        conn.set_priority(ConnectionPriority::Transient);
+        conn.on_contact_lost();
+        drop(conn);
+        assert_eq!(dht.transport().metrics().half_closed_count, 1);
+
+        assert!(matches!(
+            events.recv().await,
+            Ok(TransportEvent::Disconnect(x, DisconnectReason::HalfCloseBoth)) if x == stalled
+        ));
+        assert_eq!(dht.transport().connected_count(), 0);
+        assert_eq!(dht.transport().metrics().half_closed_count, 0);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn fragment_timeout_test() {
+        let tconfig = TransportConfig {
+            fragment_timeout: std::time::Duration::from_millis(200),
+            ..Default::default()
+        };
+        let (dht, mut events) = create_dht(SystemConfig::default(), tconfig, vec![] as Vec<Url>).await.unwrap();
+        assert!(matches!(events.try_recv(), Ok(TransportEvent::BootstrapComplete { .. })));
+
+        let stalled = Id::ZERO.set_bit(7);
+        let (chan, mut other) = open_channel_pair().await;
+        let _contact = dht.transport().adopt_connection(chan, Some(stalled)).await.unwrap();
+
+        // First fragment of a message that is never completed
+        other.sender.send(&[0x01, b'{']).unwrap();
+        assert!(matches!(
+            events.recv().await,
+            Ok(TransportEvent::Disconnect(x, DisconnectReason::TimeoutExpired)) if x == stalled
+        ));
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn request_timeout_test() {
+        let tconfig = TransportConfig {
+            request_timeout: std::time::Duration::from_millis(100),
+            ..Default::default()
+        };
+        let (dht, _events) = create_dht(SystemConfig::default(), tconfig, vec![] as Vec<Url>).await.unwrap();
+
+        // Nobody listens on the other end, so the request is never answered
+        let stalled = Id::ZERO.set_bit(6);
+        let (chan, _other) = open_channel_pair().await;
+        let _contact = dht.transport().adopt_connection(chan, Some(stalled)).await.unwrap();
+
+        let start = std::time::Instant::now();
+        let res = dht.transport().send(stalled, Request::FindNodes(dht.id())).await;
+        assert!(matches!(res, Err(TransportError::ConnectionLost)));
+        assert!(start.elapsed() < std::time::Duration::from_secs(1));
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn connecting_count_test() {
+        let tconfig = TransportConfig {
+            request_timeout: std::time::Duration::from_millis(200),
+            ..Default::default()
+        };
+        let (dht, _events) = create_dht(SystemConfig::default(), tconfig, vec![] as Vec<Url>).await.unwrap();
+
+        let stalled = Id::ZERO.set_bit(8);
+        let (chan, _other) = open_channel_pair().await;
+        let _contact = dht.transport().adopt_connection(chan, Some(stalled)).await.unwrap();
+        let referrer = dht.transport().0.connections.lock().unwrap()[&stalled].clone();
+        assert_eq!(dht.transport().connecting_count(), 0);
+
+        // The offer is forwarded through the stalled peer, so the connection never completes
+        let connections = dht.transport().0.clone();
+        let attempt = connections.connector.connect_all(connections.clone(), referrer, vec![Id::ZERO.set_bit(9)]);
+        let check = async {
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            assert_eq!(dht.transport().connecting_count(), 1);
+            assert_eq!(dht.transport().metrics().connecting_count, 1);
+        };
+        let (res, ()) = tokio::join!(attempt, check);
+        assert!(res[0].is_err());
+        assert_eq!(dht.transport().connecting_count(), 0);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn debug_state_test() {
+        let (dht, _events) = create_dht(SystemConfig::default(), TransportConfig::default(), vec![] as Vec<Url>).await.unwrap();
+
+        // Nobody listens on the other end, so the request stays outstanding
+        let stalled = Id::ZERO.set_bit(6);
+        let (chan, _other) = open_channel_pair().await;
+        let _contact = dht.transport().adopt_connection(chan, Some(stalled)).await.unwrap();
+        let request = {
+            let dht = dht.clone();
+            tokio::spawn(async move { dht.transport().send(stalled, Request::FindNodes(dht.id())).await })
+        };
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+        let state = crate::debug::debug_state(&dht);
+        assert!(!state.dht.tree_locked && !state.dht.storage_locked);
+        assert!(!state.transport.connections_locked);
+        assert_eq!(state.transport.connections.len(), 1);
+        let conn = &state.transport.connections[0];
+        assert_eq!(conn.id, stalled);
+        assert_eq!(conn.outstanding_requests, Some(1));
+        // The snapshot can be served as JSON
+        let json = serde_json::to_value(&state).unwrap();
+        assert_eq!(json["transport"]["connections"][0]["outstanding_requests"], 1);
+
+        request.abort();
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn control_under_load_test() {
+        let config = SystemConfig::default();
+        let tconfig = TransportConfig::default();
+        let (a, _a_events) = create_dht(config.clone(), tconfig.clone(), vec![] as Vec<Url>).await.unwrap();
+        let (b, _b_events) = create_dht(config, tconfig, vec![] as Vec<Url>).await.unwrap();
+
+        let (a_chan, b_chan) = open_channel_pair().await;
+        let (a_contact, b_contact) = tokio::join!(
+            a.transport().adopt_connection(a_chan, None),
+            b.transport().adopt_connection(b_chan, None),
+        );
+        let _contacts = (a_contact.unwrap(), b_contact.unwrap());
+
+        // Bulk transfer on the same connection
+        let bulk = {
+            let a = a.clone();
+            tokio::spawn(async move {
+                let value = vec![0xaa; 256 * 1024];
+                for i in 0..16 {
+                    let _ = a.insert(Id::ZERO.set_bit(i), std::time::Duration::from_secs(60), value.clone()).await;
+                }
+            })
+        };
+
+        // Small control messages keep making progress
+        for _ in 0..10 {
+            let start = std::time::Instant::now();
+            let res = b.transport().send(a.id(), Request::FindNodes(b.id())).await;
+            assert!(res.is_ok());
+            assert!(start.elapsed() < std::time::Duration::from_secs(2), "slow request: {:?}", start.elapsed());
+        }
+        bulk.await.unwrap();
+    }
 }
@@ -0,0 +1,174 @@
+//! Canonical JSON encoding of DHT messages.
+//!
+//! Requests and responses are encoded exactly as they travel inside wdht's
+//! WebRTC messages, so external tools can craft or inspect them.
+//! Enum variants are externally tagged, ids are base64 strings and data is
+//! an array of bytes, for example:
+//!
+//! ```json
+//! {"FindNodes":"AAAAAAAAAAAAAAAAAAAAAAAAAKo="}
+//! {"Insert":["AAAAAAAAAAAAAAAAAAAAAAAAAKo=",60,[1,2,3]]}
+//! {"FoundData":[{"publisher":"AAAAAAAAAAAAAAAAAAAAAAAAALs=","data":[1,2]}]}
+//! "Done"
+//! ```
+use std::time::Duration;
+
+pub use wdht_logic::transport::{RecordProof, RecordSignature, ReplicaEntry, Request, Response, TopicEntry};
+use wdht_logic::Id;
+
+pub fn encode_request(req: &Request) -> String {
+    serde_json::to_string(req).expect("Requests are always serializable")
+}
+
+pub fn decode_request(data: &str) -> Result<Request, serde_json::Error> {
+    serde_json::from_str(data)
+}
+
+pub fn encode_response(res: &Response) -> String {
+    serde_json::to_string(res).expect("Responses are always serializable")
+}
+
+pub fn decode_response(data: &str) -> Result<Response, serde_json::Error> {
+    serde_json::from_str(data)
+}
+
+pub fn find_nodes(id: Id) -> Request {
+    Request::FindNodes(id)
+}
+
+pub fn find_data(id: Id, max_entries: u32) -> Request {
+    Request::FindData(id, max_entries)
+}
+
+/// Lifetimes are sent with a precision of one second
+pub fn insert(id: Id, lifetime: Duration, data: Vec<u8>) -> Request {
+    Request::Insert(id, lifetime.as_secs() as u32, data)
+}
+
+pub fn insert_named(id: Id, lifetime: Duration, data: Vec<u8>, name: String) -> Request {
+    Request::InsertNamed(id, lifetime.as_secs() as u32, data, name)
+}
+
+pub fn append(id: Id, lifetime: Duration, data: Vec<u8>) -> Request {
+    Request::Append(id, lifetime.as_secs() as u32, data)
+}
+
+pub fn insert_signed(id: Id, lifetime: Duration, data: Vec<u8>, signature: RecordSignature) -> Request {
+    Request::InsertSigned(id, lifetime.as_secs() as u32, data, signature)
+}
+
+pub fn compare_and_swap(id: Id, lifetime: Duration, expected: Option<Vec<u8>>, data: Vec<u8>) -> Request {
+    Request::Cas(id, lifetime.as_secs() as u32, expected, data)
+}
+
+pub fn remove(id: Id) -> Request {
+    Request::Remove(id)
+}
+
+pub fn replicate(id: Id, entries: Vec<ReplicaEntry>) -> Request {
+    Request::Replicate(id, entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Base64 of the ids aa and bb
+    const A: &str = "AAAAAAAAAAAAAAAAAAAAAAAAAKo=";
+    const B: &str = "AAAAAAAAAAAAAAAAAAAAAAAAALs=";
+
+    fn check_request(req: Request, golden: &str) {
+        assert_eq!(encode_request(&req), golden);
+        assert_eq!(decode_request(golden).unwrap(), req);
+    }
+
+    fn check_response(res: Response, golden: &str) {
+        assert_eq!(encode_response(&res), golden);
+        assert_eq!(decode_response(golden).unwrap(), res);
+    }
+
+    #[test]
+    fn request_golden() {
+        let a = Id::from_hex("aa");
+        let minute = Duration::from_secs(60);
+
+        check_request(find_nodes(a), &format!(r#"{{"FindNodes":"{A}"}}"#));
+        check_request(find_data(a, 10), &format!(r#"{{"FindData":["{A}",10]}}"#));
+        check_request(insert(a, minute, vec![1, 2, 3]), &format!(r#"{{"Insert":["{A}",60,[1,2,3]]}}"#));
+        check_request(
+            insert_named(a, minute, vec![1], "topic".to_owned()),
+            &format!(r#"{{"InsertNamed":["{A}",60,[1],"topic"]}}"#),
+        );
+        check_request(append(a, minute, vec![1, 2]), &format!(r#"{{"Append":["{A}",60,[1,2]]}}"#));
+        let signature = RecordSignature { publisher: Id::from_hex("bb"), public_key: vec![4, 5], signature: vec![6] };
+        check_request(
+            insert_signed(a, minute, vec![1], signature),
+            &format!(r#"{{"InsertSigned":["{A}",60,[1],{{"publisher":"{B}","public_key":[4,5],"signature":[6]}}]}}"#),
+        );
+        check_request(
+            compare_and_swap(a, minute, Some(vec![1]), vec![2]),
+            &format!(r#"{{"Cas":["{A}",60,[1],[2]]}}"#),
+        );
+        check_request(
+            compare_and_swap(a, minute, None, vec![2]),
+            &format!(r#"{{"Cas":["{A}",60,null,[2]]}}"#),
+        );
+        check_request(remove(a), &format!(r#"{{"Remove":"{A}"}}"#));
+        let entry = ReplicaEntry { publisher: Id::from_hex("bb"), lifetime: 60, data: vec![1], proof: None };
+        check_request(
+            replicate(a, vec![entry.clone()]),
+            &format!(r#"{{"Replicate":["{A}",[{{"publisher":"{B}","lifetime":60,"data":[1],"proof":null}}]]}}"#),
+        );
+        // Sent by older nodes
+        assert_eq!(
+            decode_request(&format!(r#"{{"Replicate":["{A}",[{{"publisher":"{B}","lifetime":60,"data":[1]}}]]}}"#)).unwrap(),
+            replicate(a, vec![entry]),
+        );
+        let signature = RecordSignature { publisher: Id::from_hex("bb"), public_key: vec![4], signature: vec![5] };
+        let entry = ReplicaEntry {
+            publisher: Id::from_hex("bb"),
+            lifetime: 50,
+            data: vec![1],
+            proof: Some(RecordProof { lifetime: 60, signature }),
+        };
+        check_request(
+            replicate(a, vec![entry]),
+            &format!(
+                r#"{{"Replicate":["{A}",[{{"publisher":"{B}","lifetime":50,"data":[1],"proof":{{"lifetime":60,"signature":{{"publisher":"{B}","public_key":[4],"signature":[5]}}}}}}]]}}"#
+            ),
+        );
+    }
+
+    #[test]
+    fn response_golden() {
+        let a = Id::from_hex("aa");
+        let b = Id::from_hex("bb");
+
+        check_response(Response::FoundNodes(vec![a, b]), &format!(r#"{{"FoundNodes":["{A}","{B}"]}}"#));
+        check_response(
+            Response::FoundData(vec![TopicEntry { publisher: b, data: vec![1, 2] }]),
+            &format!(r#"{{"FoundData":[{{"publisher":"{B}","data":[1,2]}}]}}"#),
+        );
+        check_response(
+            Response::FoundDataAndNodes(vec![TopicEntry { publisher: b, data: vec![1] }], vec![a]),
+            &format!(r#"{{"FoundDataAndNodes":[[{{"publisher":"{B}","data":[1]}}],["{A}"]]}}"#),
+        );
+        check_response(Response::Done, r#""Done""#);
+        check_response(Response::Error, r#""Error""#);
+    }
+
+    #[test]
+    fn invalid_messages() {
+        assert!(decode_request(r#"{"FindNodes":"zz"}"#).is_err());
+        assert!(decode_request(r#"{"FindData":["00"]}"#).is_err());
+        assert!(decode_response(r#""Maybe""#).is_err());
+    }
+
+    #[test]
+    fn unknown_request() {
+        // Requests added by newer versions are not errors
+        assert_eq!(decode_request(r#"{"Future":1}"#).unwrap(), Request::Unknown);
+        assert_eq!(decode_request(&format!(r#"{{"Future":["{A}",{{"x":[1,2]}}]}}"#)).unwrap(), Request::Unknown);
+        assert!(serde_json::to_string(&Request::Unknown).is_err());
+    }
+}
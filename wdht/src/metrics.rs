@@ -0,0 +1,130 @@
+//! Aggregate metrics derived from the [`TransportEvent`] stream.
+//!
+//! The collector only reads the events returned by `create_dht`, so it never
+//! touches the transport hot paths. Events lost by the channel (see
+//! [`crate::EventOverflow`]) are counted but their effect can't be recovered,
+//! so the gauges are best-effort.
+use std::{collections::HashMap, sync::Mutex};
+
+use async_broadcast::RecvError;
+use tracing::Instrument;
+use wdht_wasync::{spawn, Orc};
+
+use crate::{events::{DisconnectReason, TransportEvent}, EventReceiver};
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EventMetrics {
+    pub connects: u64,
+    pub disconnects: HashMap<DisconnectReason, u64>,
+    // Connects not yet followed by a disconnect
+    pub current_connections: u64,
+    pub channel_opens: u64,
+    // Events skipped because the collector was too slow
+    pub lost_events: u64,
+}
+
+impl EventMetrics {
+    pub fn total_disconnects(&self) -> u64 {
+        self.disconnects.values().sum()
+    }
+
+    pub fn record(&mut self, event: &TransportEvent) {
+        match event {
+            TransportEvent::Connect(_) => {
+                self.connects += 1;
+                self.current_connections += 1;
+            },
+            TransportEvent::Disconnect(_, reason) => {
+                *self.disconnects.entry(*reason).or_insert(0) += 1;
+                self.current_connections = self.current_connections.saturating_sub(1);
+            },
+            TransportEvent::ChannelOpen(_) => self.channel_opens += 1,
+            TransportEvent::PeerConnected(_) |
+            TransportEvent::StorageEvicted { .. } |
+            TransportEvent::BootstrapComplete { .. } |
+            TransportEvent::Shutdown => {},
+        }
+    }
+}
+
+/// Keeps [`EventMetrics`] updated in the background, cheap to clone.
+#[derive(Clone, Default)]
+pub struct MetricsCollector {
+    metrics: Orc<Mutex<EventMetrics>>,
+}
+
+impl MetricsCollector {
+    /// Starts collecting events until the DHT shuts down,
+    /// pass a clone of the receiver returned by `create_dht` to also count
+    /// the events sent before the call.
+    pub fn spawn(mut events: EventReceiver) -> Self {
+        let collector = Self::default();
+        let metrics = collector.metrics.clone();
+        spawn(async move {
+            loop {
+                let event = match events.recv().await {
+                    Ok(x) => x,
+                    Err(RecvError::Overflowed(lost)) => {
+                        metrics.lock().unwrap().lost_events += lost;
+                        continue;
+                    },
+                    Err(RecvError::Closed) => break,
+                };
+                metrics.lock().unwrap().record(&event);
+                if let TransportEvent::Shutdown = event {
+                    break;
+                }
+            }
+        }.instrument(tracing::info_span!("Metrics collector")));
+        collector
+    }
+
+    pub fn snapshot(&self) -> EventMetrics {
+        self.metrics.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use wdht_logic::Id;
+
+    use crate::wrtc::WrtcContact;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn aggregates() {
+        let (mut tx, rx) = async_broadcast::broadcast(8);
+        tx.set_overflow(true);
+        let collector = MetricsCollector::spawn(rx);
+        let ids: Vec<_> = (0..3).map(|x| Id::ZERO.set_bit(x)).collect();
+
+        for id in ids.iter() {
+            tx.broadcast(TransportEvent::Connect(WrtcContact::SelfId(*id))).await.unwrap();
+        }
+        tx.broadcast(TransportEvent::Disconnect(ids[0], DisconnectReason::ConnectionLost)).await.unwrap();
+        tx.broadcast(TransportEvent::Disconnect(ids[1], DisconnectReason::TimeoutExpired)).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        let metrics = collector.snapshot();
+        assert_eq!(metrics.connects, 3);
+        assert_eq!(metrics.current_connections, 1);
+        assert_eq!(metrics.total_disconnects(), 2);
+        assert_eq!(metrics.disconnects[&DisconnectReason::ConnectionLost], 1);
+        assert_eq!(metrics.disconnects[&DisconnectReason::TimeoutExpired], 1);
+        assert_eq!(metrics.lost_events, 0);
+
+        // Flood the collector, the lost events are counted
+        for _ in 0..100 {
+            tx.try_broadcast(TransportEvent::Disconnect(ids[2], DisconnectReason::SendFail)).unwrap();
+        }
+        tx.broadcast(TransportEvent::Shutdown).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        let metrics = collector.snapshot();
+        assert_eq!(metrics.current_connections, 0);
+        assert_eq!(metrics.total_disconnects() + metrics.lost_events, 102);
+    }
+}
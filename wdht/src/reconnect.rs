@@ -1,33 +1,105 @@
-use std::{error::Error, time::Duration, sync::Mutex, collections::HashMap};
+use std::{error::Error, fmt, time::Duration, sync::Mutex, collections::HashMap};
 
 use async_broadcast::RecvError;
 use futures::future::join_all;
 use rand::Rng;
-use tokio::sync::oneshot;
-use tracing::{info, instrument};
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::sync::{oneshot, Semaphore};
+use tracing::{info, instrument, warn};
 use reqwest::Url;
 use wdht_logic::{transport::Contact, Id};
 use wdht_wasync::{Orc, Weak, sleep, spawn};
 
-use crate::{events::{TransportEvent, DisconnectReason, wait_for_shutdown}, wrtc::{Connections, WrtcTransportError, HandshakeError}, http_api::{ConnectRequest, ConnectResponse}};
+use crate::{BackoffConfig, events::{TransportEvent, DisconnectReason, wait_for_shutdown}, wrtc::{Connections, WrtcTransportError, HandshakeError}, http_api::{ConnectRequest, ConnectResponse}};
 
-const NANOS_PER_SEC: u32 = 1_000_000_000;
-const MAX_EXPONENTIAL_BACKOFF_SECS: u64 = 5 * 60;// 5 minutes
+const MAX_ROUTING_RECONNECT_ATTEMPTS: u32 = 8;
+// Peers asked to forward the offer at each attempt
+const ROUTING_RECONNECT_REFERRERS: usize = 3;
 
 
+/// Exponential backoff, computes the delays between the attempts
+struct Backoff {
+    config: BackoffConfig,
+    next: Duration,
+}
+
+impl Backoff {
+    fn new(config: BackoffConfig) -> Self {
+        let next = config.initial;
+        Backoff { config, next }
+    }
+
+    /// Delay to wait after a failed attempt
+    fn next_delay(&mut self, rng: &mut impl Rng) -> Duration {
+        let delay = self.next;
+        let next = delay.as_secs_f64() * self.config.multiplier;
+        self.next = if next >= self.config.max.as_secs_f64() {
+            self.config.max
+        } else {
+            Duration::from_secs_f64(next)
+        };
+
+        let jitter = match self.config.jitter {
+            x if x.is_zero() => Duration::ZERO,
+            x => rng.gen_range(Duration::ZERO..x),
+        };
+        delay + jitter
+    }
+}
+
+/// Progress of the node joining the network, see [`Connections::bootstrap_state`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BootstrapState {
+    /// Connecting to the bootstrap nodes (first attempt only, retries are not counted)
+    Connecting { attempted: usize, connected: usize },
+    /// Looking up the nodes closest to our id
+    Bootstrapping,
+    /// Joined the network
+    Ready { peers: u64 },
+    /// Joined the network, but the routing table is below its low watermark
+    Degraded,
+}
+
+impl fmt::Display for BootstrapState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BootstrapState::Connecting { attempted, connected } => write!(f, "connecting ({connected}/{attempted})"),
+            BootstrapState::Bootstrapping => f.write_str("bootstrapping"),
+            BootstrapState::Ready { peers } => write!(f, "ready ({peers} peers)"),
+            BootstrapState::Degraded => f.write_str("degraded"),
+        }
+    }
+}
+
+// reqwest has no client timeout on wasm, so the request is raced against a timer instead
+async fn post_json<B: Serialize, R: DeserializeOwned>(
+    url: Url,
+    body: &B,
+    timeout: Duration,
+) -> Result<R, Box<dyn Error + Send + Sync>> {
+    let client = reqwest::Client::new();
+    let request = async {
+        client.post(url)
+            .json(body)
+            .send()
+            .await?
+            .json()
+            .await
+    };
+    tokio::select! {
+        res = request => Ok(res?),
+        _ = sleep(timeout) => Err(format!("HTTP request timed out after {:?}", timeout).into()),
+    }
+}
+
 async fn bootstrap_connect(url: Url, connector: Orc<Connections>) -> Result<Id, Box<dyn Error + Send + Sync>> {
     let self_id = connector.self_id;
-    let (offer, answer_tx, mut connection_rx) = connector.create_active(None).await?;
+    let timeout = connector.config.bootstrap_http_timeout;
+    let (offer, answer_tx, mut connection_rx) = connector.create_bootstrap().await?;
 
-    let client = reqwest::Client::new();
     let offer = ConnectRequest { id: self_id, offer };
 
-    let r: ConnectResponse = client.post(url)
-        .json(&offer)
-        .send()
-        .await?
-        .json()
-        .await?;
+    let r: ConnectResponse = post_json(url, &offer, timeout).await?;
 
     let ans = match r {
         ConnectResponse::Ok { answer } => answer,
@@ -54,17 +126,30 @@ async fn bootstrap_connect(url: Url, connector: Orc<Connections>) -> Result<Id,
 async fn bootstrap_exponential_backoff_connect(
     url: &Url,
     connector: Weak<Connections>,
+    limiter: &Semaphore,
+    backoff: BackoffConfig,
     mut initial_connection_report: Option<oneshot::Sender<Result<(), Box<dyn Error + Send + Sync>>>>,
 ) -> Result<Id, ()> {
-    let mut wait_secs = 1u64;
+    let mut backoff = Backoff::new(backoff);
 
-    while let Some(connector) = connector.upgrade() {
+    loop {
+        // Only a slot is waited for, the connector itself is not kept alive
+        let permit = limiter.acquire().await.map_err(|_| ())?;
+        let connector = match connector.upgrade() {
+            Some(x) => x,
+            None => break,
+        };
         let res = bootstrap_connect(url.clone(), connector).await;
+        drop(permit);
 
         let id = match &res {
             Ok(id) => Some(*id),
             Err(_) => None
         };
+        let untrusted = matches!(
+            res.as_ref().map_err(|e| e.downcast_ref::<WrtcTransportError>()),
+            Err(Some(WrtcTransportError::Unauthorized(_)))
+        );
 
         if let Some(reporter) = initial_connection_report.take() {
             // Ignore sending error if present
@@ -76,14 +161,65 @@ async fn bootstrap_exponential_backoff_connect(
         if let Some(id) = id {
             return Ok(id);
         }
-        wait_secs = (wait_secs * 2).min(MAX_EXPONENTIAL_BACKOFF_SECS);
-        let wait_nanos = rand::thread_rng().gen_range(0..NANOS_PER_SEC);
-        info!("Sleeping for {wait_secs}s before next attempt");
-        sleep(Duration::new(wait_secs, wait_nanos)).await;
+        if untrusted {
+            // Retrying won't change the node id
+            return Err(());
+        }
+        let delay = backoff.next_delay(&mut rand::thread_rng());
+        info!("Sleeping for {delay:?} before next attempt");
+        sleep(delay).await;
     }
     Err(())
 }
 
+// The peers closest to the lost one are the most likely to still be connected to it
+async fn routing_connect(id: Id, connector: Orc<Connections>) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut referrers: Vec<_> = connector.connections.lock().unwrap().values().cloned().collect();
+    referrers.sort_by_key(|x| x.peer_id ^ id);
+    referrers.truncate(ROUTING_RECONNECT_REFERRERS);
+
+    let mut res: Result<(), Box<dyn Error + Send + Sync>> = Err("No peer to forward the offer".into());
+    for referrer in referrers {
+        let contacts = connector.connector.clone().connect_all(connector.clone(), referrer, vec![id]).await;
+        res = match contacts.into_iter().next() {
+            Some(Ok(_)) => return Ok(()),
+            Some(Err(e)) => Err(e.into()),
+            None => Err("No contact returned".into()),
+        };
+    }
+    res
+}
+
+/// Reconnects to a routing peer lost by the DHT, until its place is taken by another node
+#[instrument(name = "routing_reconnector", skip(backoff, connector))]
+pub(crate) async fn routing_reconnector(id: Id, backoff: BackoffConfig, connector: Weak<Connections>) {
+    let mut backoff = Backoff::new(backoff);
+
+    for _ in 0..MAX_ROUTING_RECONNECT_ATTEMPTS {
+        let delay = backoff.next_delay(&mut rand::thread_rng());
+        sleep(delay).await;
+
+        let connector = match connector.upgrade() {
+            Some(x) if !x.is_shutting_down() => x,
+            _ => return,
+        };
+        // The peer might have connected back to us in the meantime
+        let wanted = !connector.connections.lock().unwrap().contains_key(&id) &&
+            connector.dht.upgrade().map_or(false, |x| x.is_wanted_for_routing(id));
+        if !wanted {
+            return;
+        }
+        match routing_connect(id, connector).await {
+            Ok(()) => {
+                info!("Reconnected to {id}");
+                return;
+            },
+            Err(err) => info!("Error reconnecting to {id}: {err}"),
+        }
+    }
+    info!("Giving up reconnecting to {id}");
+}
+
 pub async fn bootstrap_reconnector(
     urls: Vec<Url>,
     mut events: async_broadcast::Receiver<TransportEvent>,
@@ -91,17 +227,27 @@ pub async fn bootstrap_reconnector(
     initial_connected: oneshot::Sender<()>
 ) {
     let id_to_index = Orc::new(Mutex::new(HashMap::new()));
+    let (max_connecting, backoff) = match connector.upgrade() {
+        Some(x) => {
+            x.set_bootstrap_state(BootstrapState::Connecting { attempted: urls.len(), connected: 0 });
+            (x.config.max_bootstrap_connecting.max(1), x.config.reconnect_backoff.clone())
+        },
+        None => return,
+    };
+    let limiter = Orc::new(Semaphore::new(max_connecting));
 
     let inactive_recv = events.clone().deactivate();
 
     let spawn_connector = |url: Url, index: usize, conn_tx: Option<oneshot::Sender<Result<(), Box<dyn Error + Send + Sync>>>>| {
         let connector = connector.clone();
         let id_to_index = id_to_index.clone();
+        let limiter = limiter.clone();
+        let backoff = backoff.clone();
         let mut events = inactive_recv.activate_cloned();
         spawn(async move {
             let url = url;
             let id = tokio::select! {
-                x = bootstrap_exponential_backoff_connect(&url, connector, conn_tx) => x,
+                x = bootstrap_exponential_backoff_connect(&url, connector, &limiter, backoff, conn_tx) => x,
                 _ = wait_for_shutdown(&mut events) => return,
             };
             if let Ok(id) = id {
@@ -117,11 +263,16 @@ pub async fn bootstrap_reconnector(
         .map(|(index, url)| {
             let (conn_tx, conn_rx) = oneshot::channel();
             spawn_connector(url.clone(), index, Some(conn_tx));
+            let connector = connector.clone();
             async move {
                 match conn_rx.await {
                     Ok(Err(x)) => info!("Error connecting to '{url}': {x}"),
                     Err(_) => info!("Major failure in bootstrap connection"),
-                    Ok(Ok(())) => {},
+                    Ok(Ok(())) => {
+                        if let Some(x) = connector.upgrade() {
+                            x.on_bootstrap_connected();
+                        }
+                    },
                 };
             }
         })
@@ -132,7 +283,8 @@ pub async fn bootstrap_reconnector(
         match events.recv().await {
             Ok(TransportEvent::Disconnect(id, DisconnectReason::ConnectionLost | DisconnectReason::SendFail | DisconnectReason::TimeoutExpired)) => {
                 // If the disconencted ID previously was a bootstrap node, try to reconnect.
-                if let Some(index) = id_to_index.lock().unwrap().get(&id).copied() {
+                // (the connector will insert it again once reconnected)
+                if let Some(index) = id_to_index.lock().unwrap().remove(&id) {
                     info!("Connection to bootstrap node closed, retrying {}", urls[index]);
                     spawn_connector(urls[index].clone(), index, None);
                 }
@@ -140,7 +292,29 @@ pub async fn bootstrap_reconnector(
             Ok(TransportEvent::Shutdown) |
             Err(RecvError::Closed) => break,// Closed
             Ok(_) => {}, // Ignore other events
-            Err(RecvError::Overflowed(_)) => {},// Should never happen
+            Err(RecvError::Overflowed(lost)) => {
+                // With EventOverflow::DropOldest some disconnections might have been lost,
+                // check the bootstrap connections directly instead
+                let conns = match connector.upgrade() {
+                    Some(x) => x,
+                    None => break,
+                };
+                let mut disconnected = Vec::new();
+                {
+                    let connections = conns.connections.lock().unwrap();
+                    id_to_index.lock().unwrap().retain(|id, index| {
+                        let connected = connections.contains_key(id);
+                        if !connected {
+                            disconnected.push(*index);
+                        }
+                        connected
+                    });
+                }
+                warn!("Lost {lost} events, {} bootstrap nodes to reconnect", disconnected.len());
+                for index in disconnected {
+                    spawn_connector(urls[index].clone(), index, None);
+                }
+            },
         }
     }
 
@@ -148,12 +322,61 @@ pub async fn bootstrap_reconnector(
 
 #[cfg(test)]
 mod tests {
+    use warp::Filter;
     use wdht_logic::config::SystemConfig;
 
-    use crate::{TransportConfig, create_dht, warp_filter::dht_connect, events::wait_for_event};
+    use crate::{TransportConfig, Identity, create_dht, event_channel, join_network, warp_filter::dht_connect, events::wait_for_event};
 
     use super::*;
 
+    #[test_log::test(tokio::test)]
+    async fn bootstrap_http_timeout_test() {
+        // The server accepts connections but never answers
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url: Url = format!("http://{}", listener.local_addr().unwrap()).parse().unwrap();
+        tokio::spawn(async move {
+            let mut accepted = Vec::new();
+            while let Ok((socket, _)) = listener.accept().await {
+                accepted.push(socket);
+            }
+        });
+
+        let timeout = Duration::from_millis(200);
+        let start = std::time::Instant::now();
+        let res: Result<serde_json::Value, _> = post_json(url, &"offer", timeout).await;
+        let elapsed = start.elapsed();
+
+        assert!(res.unwrap_err().to_string().contains("timed out"));
+        assert!(elapsed >= timeout);
+        assert!(elapsed < timeout * 5);
+    }
+
+    #[test]
+    fn backoff_test() {
+        let mut rng = rand::rngs::mock::StepRng::new(0, 0);
+        let mut backoff = Backoff::new(BackoffConfig {
+            initial: Duration::from_millis(500),
+            max: Duration::from_secs(5),
+            multiplier: 3.0,
+            jitter: Duration::ZERO,
+        });
+        let delays: Vec<_> = (0..5).map(|_| backoff.next_delay(&mut rng)).collect();
+        assert_eq!(delays, [500, 1500, 4500, 5000, 5000].map(Duration::from_millis));
+
+        // The jitter is only added to the delay, it doesn't accumulate
+        let config = BackoffConfig {
+            jitter: Duration::from_millis(100),
+            multiplier: 1.0,
+            ..Default::default()
+        };
+        let mut backoff = Backoff::new(config.clone());
+        let mut rng = rand::thread_rng();
+        for _ in 0..20 {
+            let delay = backoff.next_delay(&mut rng);
+            assert!(delay >= config.initial && delay < config.initial + config.jitter);
+        }
+    }
+
     #[test_log::test(tokio::test)]
     async fn server_reconnect_test() {
         let config = SystemConfig::default();
@@ -171,7 +394,7 @@ mod tests {
         };
 
         // Spawn server on random port
-        let (srv, srv_events) = create_dht(config.clone(), transport_config.clone(), vec![] as Vec<Url>).await;
+        let (srv, srv_events) = create_dht(config.clone(), transport_config.clone(), vec![] as Vec<Url>).await.unwrap();
         let (srv_shutdown_tx, srv_shutdown_rx) = oneshot::channel();
         let (addr, srv) = warp::serve(dht_connect(srv)).bind_with_graceful_shutdown(([127, 0, 0, 1], 0), async {
             let _ = srv_shutdown_rx.await;
@@ -179,7 +402,7 @@ mod tests {
         tokio::spawn(srv);
         print_server_events(srv_events);
 
-        let (dht, mut events) = create_dht(config.clone(), transport_config.clone(), vec![format!("http://localhost:{}", addr.port()).parse().unwrap()] as Vec<Url>).await;
+        let (dht, mut events) = create_dht(config.clone(), transport_config.clone(), vec![format!("http://localhost:{}", addr.port()).parse().unwrap()] as Vec<Url>).await.unwrap();
         assert!(dht.transport().connection_count() == 1);
 
         // Shutdown server
@@ -190,7 +413,7 @@ mod tests {
         assert!(dht.transport().connected_count() == 0);
 
         // Reopen server
-        let (srv, srv_events) = create_dht(config.clone(), transport_config.clone(), vec![] as Vec<Url>).await;
+        let (srv, srv_events) = create_dht(config.clone(), transport_config.clone(), vec![] as Vec<Url>).await.unwrap();
         let (srv_shutdown_tx, srv_shutdown_rx) = oneshot::channel();
         let (_addr, srv) = warp::serve(dht_connect(srv)).bind_with_graceful_shutdown(addr, async {
             let _ = srv_shutdown_rx.await;
@@ -206,4 +429,121 @@ mod tests {
         drop(dht);
         wait_for_shutdown(&mut events).await;
     }
+
+    #[test_log::test(tokio::test)]
+    async fn routing_reconnect_test() {
+        let config = SystemConfig::default();
+
+        let (srv, _srv_events) = create_dht(config.clone(), TransportConfig::default(), vec![] as Vec<Url>).await.unwrap();
+        let (addr, srv) = warp::serve(dht_connect(srv)).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(srv);
+        let url: Url = format!("http://localhost:{}", addr.port()).parse().unwrap();
+
+        let (peer, _peer_events) = create_dht(config.clone(), TransportConfig::default(), vec![url.clone()]).await.unwrap();
+        let transport_config = TransportConfig {
+            auto_reconnect_routing: true,
+            ..Default::default()
+        };
+        let (dht, mut events) = create_dht(config, transport_config, vec![url]).await.unwrap();
+        assert!(dht.tree.lock().unwrap().has(peer.id()));
+
+        // Drop the connection to the other peer, the server is still connected to it
+        let conn = dht.transport().0.connections.lock().unwrap()[&peer.id()].clone();
+        conn.shutdown(DisconnectReason::ConnectionLost);
+        drop(conn);
+        wait_for_event(&mut events, |e| matches!(e, Ok(TransportEvent::Disconnect(x, _)) if x == peer.id())).await;
+
+        wait_for_event(&mut events, |e| matches!(e, Ok(TransportEvent::Connect(x)) if x.id() == peer.id())).await;
+        assert!(dht.tree.lock().unwrap().has(peer.id()));
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn trusted_bootstrap_test() {
+        let config = SystemConfig::default();
+
+        let mut urls = Vec::new();
+        let mut ids = Vec::new();
+        for _ in 0..2 {
+            let (srv, _srv_events) = create_dht(config.clone(), TransportConfig::default(), vec![] as Vec<Url>).await.unwrap();
+            ids.push(srv.id());
+            let (addr, srv) = warp::serve(dht_connect(srv)).bind_ephemeral(([127, 0, 0, 1], 0));
+            tokio::spawn(srv);
+            urls.push(format!("http://localhost:{}", addr.port()).parse().unwrap());
+        }
+        let (trusted, untrusted) = (ids[0], ids[1]);
+
+        let transport_config = TransportConfig {
+            trusted_bootstrap_ids: Some([trusted].into_iter().collect()),
+            ..Default::default()
+        };
+        let (dht, _events) = create_dht(config, transport_config, urls as Vec<Url>).await.unwrap();
+
+        // Only the trusted seed is used
+        assert_eq!(dht.transport().connected_count(), 1);
+        assert!(dht.tree.lock().unwrap().has(trusted));
+        assert!(!dht.tree.lock().unwrap().has(untrusted));
+        assert!(dht.transport().peer_quality(untrusted).is_none());
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn bootstrap_state_test() {
+        let mut config = SystemConfig::default();
+        // A single peer is enough to leave the degraded state
+        config.routing.high_watermark = 1;
+
+        let (srv, _srv_events) = create_dht(config.clone(), TransportConfig::default(), vec![] as Vec<Url>).await.unwrap();
+        let (addr, srv) = warp::serve(dht_connect(srv)).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(srv);
+        let url: Url = format!("http://localhost:{}", addr.port()).parse().unwrap();
+
+        let transport_config = TransportConfig::default();
+        let (events_tx, events_rx) = event_channel(&transport_config);
+        let dht = Connections::create(config, transport_config, Identity::generate().await, events_tx).await.unwrap();
+        let transport = dht.transport().clone();
+        assert_eq!(transport.bootstrap_state(), BootstrapState::Connecting { attempted: 0, connected: 0 });
+
+        join_network(&dht, vec![url], &events_rx).await;
+        assert_eq!(transport.bootstrap_state(), BootstrapState::Ready { peers: 1 });
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn bootstrap_limit_test() {
+        let config = SystemConfig::default();
+        // Time span of every connection request answered by the servers
+        let requests = Orc::new(Mutex::new(Vec::new()));
+
+        let mut urls = Vec::new();
+        for _ in 0..8 {
+            let (srv, _srv_events) = create_dht(config.clone(), TransportConfig::default(), vec![] as Vec<Url>).await.unwrap();
+            let requests = requests.clone();
+            let log = warp::log::custom(move |info| {
+                let end = std::time::Instant::now();
+                requests.lock().unwrap().push((end - info.elapsed(), end));
+            });
+            let (addr, srv) = warp::serve(dht_connect(srv).with(log)).bind_ephemeral(([127, 0, 0, 1], 0));
+            tokio::spawn(srv);
+            urls.push(format!("http://localhost:{}", addr.port()).parse().unwrap());
+        }
+
+        let transport_config = TransportConfig {
+            max_bootstrap_connecting: 2,
+            ..Default::default()
+        };
+        let (dht, _events) = create_dht(config, transport_config, urls as Vec<Url>).await.unwrap();
+
+        // Every seed is connected, but never more than 2 at a time
+        assert_eq!(dht.transport().connected_count(), 8);
+        let mut edges: Vec<_> = requests.lock().unwrap().iter()
+            .flat_map(|(start, end)| [(*start, 1), (*end, -1)])
+            .collect();
+        edges.sort();
+        let max_concurrent = edges.iter()
+            .scan(0, |current, (_, x)| {
+                *current += x;
+                Some(*current)
+            })
+            .max();
+        assert_eq!(edges.len(), 16);
+        assert!(max_concurrent.unwrap() <= 2);
+    }
 }
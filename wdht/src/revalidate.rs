@@ -0,0 +1,85 @@
+//! Stale-while-revalidate queries.
+//!
+//! Read-heavy applications can show the locally stored (possibly stale)
+//! entries right away while a network search runs in the background,
+//! the fresher entries are cached locally and reported once found.
+use tokio::sync::oneshot;
+use wdht_logic::{
+    search::BasicSearchOptions,
+    transport::{TopicEntry, TransportSender},
+    Id, KademliaDht,
+};
+use wdht_wasync::{spawn, MaybeSend, MaybeSync, Orc};
+
+pub struct StaleQuery {
+    /// Entries stored locally when the query was started
+    pub entries: Vec<TopicEntry>,
+    /// Receives the entries found on the network if they differ from the
+    /// stale ones, it's closed without a value if nothing new was found
+    pub refreshed: oneshot::Receiver<Vec<TopicEntry>>,
+}
+
+pub fn query_value_stale_while_revalidate<T>(
+    dht: &Orc<KademliaDht<T>>,
+    key: Id,
+    max_entry_count: u32,
+    options: BasicSearchOptions,
+) -> StaleQuery
+where
+    T: TransportSender + MaybeSend + MaybeSync + 'static,
+    T::Fut: MaybeSend,
+    T::Contact: MaybeSend + MaybeSync,
+{
+    let entries = dht.query_local(key, max_entry_count);
+    let (refreshed_tx, refreshed) = oneshot::channel();
+
+    let stale = entries.clone();
+    let dht = dht.clone();
+    spawn(async move {
+        let fresh = dht.query_value(key, max_entry_count, options).await;
+        if fresh.is_empty() || fresh == stale {
+            return;
+        }
+        dht.cache_entries(key, fresh.clone());
+        // The caller might not be interested anymore
+        let _ = refreshed_tx.send(fresh);
+    });
+
+    StaleQuery { entries, refreshed }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::broadcast;
+    use wdht_logic::{config::SystemConfig, transport::simulate::AsyncSimulatedTransport};
+
+    use super::*;
+
+    #[test_log::test(tokio::test)]
+    async fn stale_then_refreshed() {
+        let (killswitch, _shutdown) = broadcast::channel(1);
+        let config = SystemConfig::default();
+        let ids = [Id::ZERO.set_bit(1), Id::ZERO.set_bit(2)];
+        let dhts: Vec<_> = ids.iter()
+            .map(|id| AsyncSimulatedTransport::spawn(config.clone(), *id, killswitch.subscribe()))
+            .collect();
+        dhts[1].transport().connect_to(vec![(ids[0], &dhts[0].transport)]).await;
+        dhts[1].query_nodes(ids[1], BasicSearchOptions::default()).await;
+
+        let topic = Id::ZERO.set_bit(10);
+        let publisher = Id::ZERO.set_bit(20);
+        dhts[1].storage.write().unwrap().insert(topic, publisher, 60, b"old".to_vec()).unwrap();
+        dhts[0].storage.write().unwrap().insert(topic, publisher, 60, b"new".to_vec()).unwrap();
+
+        let query = query_value_stale_while_revalidate(&dhts[1], topic, 10, BasicSearchOptions::default());
+        let fresh = TopicEntry { publisher, data: b"new".to_vec() };
+        assert_eq!(query.entries, vec![TopicEntry { publisher, data: b"old".to_vec() }]);
+        assert_eq!(query.refreshed.await.unwrap(), vec![fresh.clone()]);
+        // The local copy has been updated
+        assert_eq!(dhts[1].query_local(topic, 10), vec![fresh]);
+
+        // Nothing changed, no update
+        let query = query_value_stale_while_revalidate(&dhts[1], topic, 10, BasicSearchOptions::default());
+        assert!(query.refreshed.await.is_err());
+    }
+}
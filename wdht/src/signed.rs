@@ -0,0 +1,59 @@
+//! Signed, self-authenticating storage records.
+//!
+//! A signed record is stored with the id derived from the signing key as its
+//! publisher (derived like node ids), so its slot can only be written by the
+//! owner of the key, even when the record is inserted through other nodes.
+//! Signatures are checked by the DHT (through the transport) when the request
+//! is received, records with an invalid signature are refused.
+use std::time::Duration;
+
+use wdht_logic::{transport::RecordSignature, Id};
+
+use crate::Identity;
+
+/// Signs a record to be inserted with [`wdht_logic::KademliaDht::insert_signed`],
+/// the lifetime is signed with a precision of one second.
+pub async fn sign_record(identity: &Identity, topic: Id, lifetime: Duration, data: &[u8]) -> RecordSignature {
+    let payload = RecordSignature::payload(topic, lifetime.as_secs() as u32, data);
+    RecordSignature {
+        publisher: identity.generate_id().await,
+        public_key: identity.export_key().to_vec(),
+        signature: identity.create_proof(&payload).await,
+    }
+}
+
+/// Returns true if the record was signed by the owner of its publisher id
+pub async fn verify_record(identity: &Identity, topic: Id, lifetime: u32, data: &[u8], record: &RecordSignature) -> bool {
+    let payload = RecordSignature::payload(topic, lifetime, data);
+    identity.check_identity_proof_of(record.publisher, &record.public_key, &payload, &record.signature).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn signatures() {
+        let owner = Identity::generate().await;
+        let verifier = Identity::generate().await;
+        let topic = Id::ZERO.set_bit(3);
+        let minute = Duration::from_secs(60);
+
+        let record = sign_record(&owner, topic, minute, &[1, 2, 3]).await;
+        assert_eq!(record.publisher, owner.generate_id().await);
+        assert!(verify_record(&verifier, topic, 60, &[1, 2, 3], &record).await);
+
+        // Tampered records
+        assert!(!verify_record(&verifier, topic, 60, &[1, 2, 4], &record).await);
+        assert!(!verify_record(&verifier, topic, 3600, &[1, 2, 3], &record).await);
+        assert!(!verify_record(&verifier, Id::ZERO.set_bit(4), 60, &[1, 2, 3], &record).await);
+        let mut forged = record.clone();
+        forged.signature[10] ^= 1;
+        assert!(!verify_record(&verifier, topic, 60, &[1, 2, 3], &forged).await);
+
+        // A valid signature can't be used to write in someone else's slot
+        let mut stolen = record.clone();
+        stolen.publisher = verifier.generate_id().await;
+        assert!(!verify_record(&verifier, topic, 60, &[1, 2, 3], &stolen).await);
+    }
+}
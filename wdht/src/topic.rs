@@ -0,0 +1,97 @@
+//! Typed DHT keys.
+//!
+//! Every DHT operation works on an [`Id`], but the same string can be used
+//! either as a topic name (hashed into an id) or as a hex encoded raw id.
+//! [`TopicKey`] and [`RawId`] keep the two apart so a string is never
+//! silently used the wrong way, both can be passed wherever an `Id` is expected.
+use std::{fmt::Display, str::FromStr};
+
+use thiserror::Error;
+use wdht_crypto::{sha2_hash, CryptoError};
+use wdht_logic::{Id, IdParseError};
+
+pub const TOPIC_HASH_CONTEXT: &[u8] = b"wdht.topic";
+
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum TopicError {
+    #[error("Key is empty")]
+    Empty,
+    #[error("Cryptographic error: {0}")]
+    Crypto(#[from] CryptoError),
+}
+
+/// Key of a named topic, the first `ID_LEN` bytes of the SHA2 hash
+/// of the name (with [`TOPIC_HASH_CONTEXT`] as context).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct TopicKey(Id);
+
+impl TopicKey {
+    pub async fn hash(name: &str) -> Result<Self, TopicError> {
+        if name.is_empty() {
+            return Err(TopicError::Empty);
+        }
+        let hash_data = sha2_hash(TOPIC_HASH_CONTEXT, name.as_bytes()).await?;
+        Ok(TopicKey(Id::from_hash_truncated(&hash_data)))
+    }
+
+    pub fn id(&self) -> Id {
+        self.0
+    }
+}
+
+impl From<TopicKey> for Id {
+    fn from(x: TopicKey) -> Self {
+        x.0
+    }
+}
+
+/// Key used as-is, without any hashing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct RawId(pub Id);
+
+impl FromStr for RawId {
+    type Err = IdParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(RawId(s.parse()?))
+    }
+}
+
+impl Display for RawId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl From<RawId> for Id {
+    fn from(x: RawId) -> Self {
+        x.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wdht_logic::consts::ID_LEN;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn construction() {
+        let name = "00000000000000000000000000000000000000aa";
+
+        let hash = sha2_hash(b"wdht.topic", name.as_bytes()).await.unwrap();
+        let topic = TopicKey::hash(name).await.unwrap();
+        assert_eq!(&topic.id().as_bytes()[..], &hash[..ID_LEN]);
+
+        let raw: RawId = name.parse().unwrap();
+        assert_eq!(Id::from(raw), Id::from_hex(name));
+        assert_eq!(raw.to_string(), name);
+
+        // The same string targets different keys
+        assert_ne!(Id::from(topic), Id::from(raw));
+
+        assert!(matches!(TopicKey::hash("").await, Err(TopicError::Empty)));
+        assert!("xyz".parse::<RawId>().is_err());
+    }
+}
@@ -1,7 +1,7 @@
 use std::sync::Arc;
 
 use tracing::instrument;
-use warp::{cors, Filter};
+use warp::{cors, http::StatusCode, reject::PayloadTooLarge, Filter, Rejection};
 use wdht_logic::KademliaDht;
 
 use crate::{
@@ -25,22 +25,38 @@ async fn dht_connect_handle(
         Err(WrtcTransportError::ConnectionLimitReached) => ConnectResponse::Error {
             description: "Connection limit reached".into(),
         },
+        Err(WrtcTransportError::SdpTooLarge) => ConnectResponse::Error {
+            description: "Offer too large".into(),
+        },
         Err(_) => ConnectResponse::Error {
             description: "Error creating Wrtc connection".into(),
         },
     }
 }
 
+async fn handle_too_large(err: Rejection) -> Result<impl warp::Reply, Rejection> {
+    if err.find::<PayloadTooLarge>().is_none() {
+        return Err(err);
+    }
+    let res = ConnectResponse::Error {
+        description: "Offer too large".into(),
+    };
+    Ok(warp::reply::with_status(warp::reply::json(&res), StatusCode::PAYLOAD_TOO_LARGE))
+}
+
 pub fn dht_connect(
     dht: Arc<KademliaDht<WrtcSender>>,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    // Leave some space for the id and the JSON structure
+    let max_body_size = dht.transport().config().max_sdp_size as u64 + 256;
     warp::path::end()
         .and(warp::post())
         .and(warp::any().map(move || dht.clone()))
-        .and(warp::body::content_length_limit(1024 * 4))
+        .and(warp::body::content_length_limit(max_body_size))
         .and(warp::body::json())
         .then(dht_connect_handle)
         .map(|x| warp::reply::json(&x))
+        .recover(handle_too_large)
         .with(
             cors()
                 .allow_any_origin()
@@ -49,3 +65,37 @@ pub fn dht_connect(
                 .build(),
         )
 }
+
+#[cfg(test)]
+mod tests {
+    use reqwest::Url;
+    use wdht_logic::config::SystemConfig;
+
+    use crate::{create_dht, TransportConfig};
+
+    use super::*;
+
+    #[test_log::test(tokio::test)]
+    async fn oversized_offer_test() {
+        let tconfig = TransportConfig {
+            max_sdp_size: 1024,
+            ..Default::default()
+        };
+        let (dht, _events) = create_dht(SystemConfig::default(), tconfig, vec![] as Vec<Url>).await.unwrap();
+        let filter = dht_connect(dht);
+
+        let offer = format!(r#"{{"type":"offer","sdp":"{}"}}"#, "a".repeat(2048));
+        let body = format!(r#"{{"id":"00","offer":{offer}}}"#);
+        let res = warp::test::request()
+            .method("POST")
+            .path("/")
+            .header("content-type", "application/json")
+            .body(body)
+            .reply(&filter)
+            .await;
+
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let res: ConnectResponse = serde_json::from_slice(res.body()).unwrap();
+        assert!(matches!(res, ConnectResponse::Error { .. }));
+    }
+}
@@ -1,19 +1,25 @@
-use std::{collections::HashMap, fmt::Debug, sync::Mutex, time::Duration};
+use std::{collections::HashMap, fmt::Debug, sync::{atomic::{AtomicU64, Ordering}, Mutex}, time::Duration};
 
 use futures::future::join_all;
+use instant::Instant;
+use serde::Serialize;
 use thiserror::Error;
 use tokio::sync::{mpsc, oneshot};
 use tracing::{debug, span, warn, Instrument, Level};
 use wdht_logic::{
-    transport::{TransportError, TransportListener},
+    transport::{RawResponse, Request, TransportError, TransportListener},
     Id,
 };
 use wdht_wrtc::{WrtcChannel, WrtcDataChannel, WrtcError, RawConnection, WrtcEvent, RawChannel};
 use wdht_wasync::{sleep, spawn, Orc, Weak};
 
-use crate::events::{TransportEvent, ChannelOpenEvent, DisconnectReason};
+use crate::config::{ForwardPolicy, WireFormat};
+use crate::events::{send_channel_open, ChannelOpenEvent, DisconnectReason};
 
 use super::{
+    forward::ForwardGuard,
+    framing::{FramingError, Reassembler},
+    outbound::OutboundQueue,
     protocol::{
         WrtcMessage, WrtcPayload, WrtcRequest, WrtcResponse,
     },
@@ -27,6 +33,10 @@ pub enum PeerMessageError {
     TransportError(WrtcTransportError),
     #[error("Wrong message format: {0}")]
     WrongFormat(serde_json::Error),
+    #[error("Wrong binary message format: {0}")]
+    WrongBinaryFormat(bincode::Error),
+    #[error("Wrong message framing: {0}")]
+    WrongFraming(FramingError),
     #[error("Unknown answer id")]
     UnknownAnswerId,
     #[error("Unknown internal error: {0}")]
@@ -51,15 +61,107 @@ impl From<serde_json::Error> for PeerMessageError {
     }
 }
 
+impl From<bincode::Error> for PeerMessageError {
+    fn from(x: bincode::Error) -> Self {
+        PeerMessageError::WrongBinaryFormat(x)
+    }
+}
+
+impl From<FramingError> for PeerMessageError {
+    fn from(x: FramingError) -> Self {
+        PeerMessageError::WrongFraming(x)
+    }
+}
+
+fn encode_message(message: &WrtcMessage, format: WireFormat) -> Vec<u8> {
+    match format {
+        WireFormat::Json => serde_json::to_vec(message).expect("Failed to serialize"),
+        WireFormat::Bincode => bincode::serialize(message).expect("Failed to serialize"),
+    }
+}
+
+fn decode_message(data: &[u8], format: WireFormat) -> Result<WrtcMessage, PeerMessageError> {
+    Ok(match format {
+        WireFormat::Json => serde_json::from_slice(data)?,
+        WireFormat::Bincode => bincode::deserialize(data)?,
+    })
+}
+
 struct InnerWrtcConnection {
     next_id: u32,
     responses: HashMap<u32, oneshot::Sender<Result<WrtcResponse, TransportError>>>,
     channel: WrtcDataChannel,
-    /// True when the connection is also used in routing tables (so we can't drop the connection)
-    dont_cleanup: bool,
+    // Fragments waiting for the channel to drain its buffer
+    outbound: OutboundQueue,
+    max_fragment_size: usize,
+    wire_format: WireFormat,
+    priority: ConnectionPriority,
     /// If true the peer won't be issuing other requests but will still answer requests
     other_half_closed: bool,
     this_half_closed: bool,
+    /// Number of messages sent and received on this connection
+    messages_exchanged: u64,
+    /// Round-trip time of the last answered request
+    rtt: Option<Duration>,
+    /// Decides which offers sent by the peer are forwarded
+    forward_guard: ForwardGuard,
+    /// True while a ping is waiting for its answer
+    pinging: bool,
+    traffic: Orc<TrafficCounters>,
+}
+
+/// Bytes of the messages exchanged by all the connections (after reassembly)
+#[derive(Debug, Default)]
+pub(crate) struct TrafficCounters {
+    pub sent: AtomicU64,
+    pub received: AtomicU64,
+}
+
+/// How important a connection is when connections are under pressure.
+///
+/// When the connection limit is reached half-closed connections are reaped
+/// from the lowest priority up, bootstrap and routing connections are never half-closed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionPriority {
+    /// Only used by searches, can be closed as soon as it is not needed
+    Transient,
+    /// Kept open by the application, reaped only after transient connections
+    Pinned,
+    /// Connection to a bootstrap node (with TransportConfig::pin_bootstrap), can't be closed
+    Bootstrap,
+    /// Used in the routing table, can't be closed
+    Routing,
+}
+
+impl ConnectionPriority {
+    /// True if the connection can be half-closed (and then reaped)
+    pub fn is_reapable(self) -> bool {
+        self < ConnectionPriority::Bootstrap
+    }
+}
+
+/// Snapshot of a connection taken without blocking,
+/// the fields are None if the connection was locked
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct ConnectionDebugState {
+    pub id: Id,
+    pub locked: bool,
+    pub priority: Option<ConnectionPriority>,
+    // Requests sent and not yet answered
+    pub outstanding_requests: Option<usize>,
+    // Bytes waiting for the data channel to drain
+    pub queued_bytes: Option<usize>,
+}
+
+/// Transport's view of the quality of a single peer connection
+#[derive(Clone, Debug)]
+pub struct PeerQuality {
+    pub rtt: Option<Duration>,
+    /// Type of the selected local ICE candidate (host, srflx, prflx, relay), if known
+    pub candidate_type: Option<String>,
+    pub connected_since: Instant,
+    pub messages_exchanged: u64,
 }
 
 impl InnerWrtcConnection {
@@ -74,13 +176,19 @@ impl InnerWrtcConnection {
 
     fn send_raw(&mut self, mex: WrtcRequest) -> Result<(), WrtcError> {
         let message = self.wrap_message(mex);
-        let data = serde_json::to_vec(&message).expect("Failed to serialize");
+        let data = encode_message(&message, self.wire_format);
+        self.messages_exchanged += 1;
 
-        self.channel
-            .send(&data)
+        self.send_data(&data)
             .map_err(|_| WrtcError::DataChannelError("Failed to send message".into()))
     }
 
+    fn send_data(&mut self, data: &[u8]) -> Result<(), WrtcError> {
+        self.outbound.send_message(&mut self.channel, data, self.max_fragment_size)?;
+        self.traffic.sent.fetch_add(data.len() as u64, Ordering::Relaxed);
+        Ok(())
+    }
+
     pub fn send_request(
         &mut self,
         mex: WrtcRequest,
@@ -91,8 +199,9 @@ impl InnerWrtcConnection {
         let (send, recv) = oneshot::channel();
         self.responses.insert(message.id, send);
 
-        let data = serde_json::to_vec(&message).expect("Failed to serialize");
-        if let Err(_err) = self.channel.send(&data) {
+        let data = encode_message(&message, self.wire_format);
+        self.messages_exchanged += 1;
+        if let Err(_err) = self.send_data(&data) {
             self.responses
                 .remove(&message.id)
                 .map(|x| x.send(Err("Failed to send message".into())));
@@ -108,8 +217,9 @@ impl InnerWrtcConnection {
         };
 
         debug!("Send: {:?}", message);
-        let data = serde_json::to_vec(&message).expect("Failed to serialize");
-        match self.channel.send(&data) {
+        let data = encode_message(&message, self.wire_format);
+        self.messages_exchanged += 1;
+        match self.send_data(&data) {
             Err(x) => {
                 warn!("Failed to send message: {}", x);
                 Err(())
@@ -121,28 +231,46 @@ impl InnerWrtcConnection {
 pub struct WrtcConnection {
     pub(crate) peer_id: Id,
     inner: Mutex<InnerWrtcConnection>,
-    parent: Weak<Connections>,
+    pub(super) parent: Weak<Connections>,
+    connected_since: Instant,
+    request_timeout: Duration,
 }
 
 impl WrtcConnection {
-    pub fn new(peer_id: Id, channel: WrtcChannel, parent: Weak<Connections>) -> Orc<Self> {
-        let kad_id = parent.upgrade().unwrap().dht.upgrade().unwrap().id();
-        let WrtcChannel { sender, listener } = channel;
+    pub fn new(peer_id: Id, channel: WrtcChannel, wire_format: WireFormat, parent: Weak<Connections>) -> Orc<Self> {
+        let root = parent.upgrade().unwrap();
+        let kad_id = root.dht.upgrade().unwrap().id();
+        let reassembler = Reassembler::new(root.config.max_message_size);
+        let WrtcChannel { mut sender, listener } = channel;
+        let outbound = OutboundQueue::new(root.config.send_buffer_high_watermark, root.config.max_send_queue);
+        if let Err(x) = sender.set_buffered_amount_low_threshold(outbound.low_watermark()) {
+            warn!("Failed to set buffered amount threshold: {}", x);
+        }
         let res = Orc::new(Self {
             peer_id,
             inner: Mutex::new(InnerWrtcConnection {
                 next_id: 0,
                 responses: HashMap::new(),
                 channel: sender,
-                dont_cleanup: false,
+                outbound,
+                max_fragment_size: root.config.max_fragment_size,
+                wire_format,
+                priority: ConnectionPriority::Transient,
                 other_half_closed: false,
                 this_half_closed: false,
+                messages_exchanged: 0,
+                rtt: None,
+                forward_guard: ForwardGuard::new(Instant::now()),
+                pinging: false,
+                traffic: root.traffic.clone(),
             }),
             parent,
+            connected_since: Instant::now(),
+            request_timeout: root.config.request_timeout,
         });
 
         spawn(
-            connection_listen(listener, Orc::downgrade(&res)).instrument(
+            connection_listen(listener, reassembler, root.config.fragment_timeout, root.traffic.clone(), Orc::downgrade(&res)).instrument(
                 span!(parent: None, Level::INFO, "kad_listener_wrtc", %kad_id, peer_id=%peer_id),
             ),
         );
@@ -154,12 +282,14 @@ impl WrtcConnection {
         mex: WrtcRequest,
     ) -> Result<WrtcResponse, TransportError> {
         let reply = self.inner.lock().unwrap().send_request(mex);
+        let sent_at = Instant::now();
+        let timeout = self.request_timeout;
 
         let weak = Orc::downgrade(&self);
         drop(self);
 
         tokio::select! {
-            _ = sleep(Duration::from_secs(10 * 60)) => {
+            _ = sleep(timeout) => {
                 // Timeout expired, connection is not alive
                 let this = match weak.upgrade() {
                     Some(x) => x,
@@ -170,32 +300,71 @@ impl WrtcConnection {
             }
             x = reply => {
                 match x {
-                    Ok(x) => x,
+                    Ok(x) => {
+                        if let Some(this) = weak.upgrade() {
+                            this.inner.lock().unwrap().rtt = Some(sent_at.elapsed());
+                        }
+                        x
+                    },
                     Err(_) => Err(TransportError::ConnectionLost),
                 }
             }
         }
     }
 
+    /// Checks that the peer still answers, the connection is closed if the
+    /// answer doesn't arrive in time
+    pub async fn ping(self: Orc<Self>, timeout: Duration) {
+        {
+            let mut inner = self.inner.lock().unwrap();
+            if inner.pinging {
+                return;
+            }
+            inner.pinging = true;
+        }
+        let weak = Orc::downgrade(&self);
+
+        let res = tokio::select! {
+            _ = sleep(timeout) => None,
+            x = self.send_request(WrtcRequest::Ping) => Some(x),
+        };
+
+        let this = match weak.upgrade() {
+            Some(x) => x,
+            None => return,
+        };
+        this.inner.lock().unwrap().pinging = false;
+        match res {
+            Some(Ok(WrtcResponse::Pong)) => {}
+            Some(Ok(x)) => {
+                warn!("Invalid ping response from {}: {:?}", this.peer_id, x);
+                this.shutdown(DisconnectReason::BadBehavior);
+            }
+            // The connection was already closed
+            Some(Err(TransportError::ConnectionLost)) => {}
+            Some(Err(_)) => this.shutdown(DisconnectReason::SendFail),
+            None => {
+                debug!("Ping to {} timed out", this.peer_id);
+                this.shutdown(DisconnectReason::TimeoutExpired);
+            }
+        }
+    }
+
     fn send_response(&self, id: u32, res: WrtcResponse) {
         if self.inner.lock().unwrap().send_response(id, res).is_err() {
             self.shutdown(DisconnectReason::SendFail);
         }
     }
 
-    fn shutdown(&self, reason: DisconnectReason) {
+    pub(crate) fn shutdown(&self, reason: DisconnectReason) {
         debug!("Shutting down connection: {reason}");
         let parent = match self.parent.upgrade() {
             Some(x) => x,
             None => return,
         };
-        parent.connections.lock().unwrap().remove(&self.peer_id);
-        parent.on_disconnect(
-            self.peer_id,
-            reason,
-            true,
-            self.inner.lock().unwrap().this_half_closed,
-        );
+        // A concurrent alloc_connection might have taken (and accounted for) this connection
+        let removed = parent.connections.lock().unwrap().remove(&self.peer_id).is_some();
+        parent.on_disconnect(self.peer_id, reason, removed);
 
         self.shutdown_local();
     }
@@ -213,22 +382,22 @@ impl WrtcConnection {
 
     /// Called when the last usable contact is lost, will try to close (or half-close) the connection
     pub fn on_contact_lost(self: &Orc<Self>) {
-        let other_half_closed = {
+        let (other_half_closed, priority) = {
             let mut inner = self.inner.lock().unwrap();
-            if inner.dont_cleanup {
-                return; // Can't close this half, it's used in the routing table
+            if !inner.priority.is_reapable() {
+                return; // Can't close this half, it's used in the routing table (or it's a seed)
             }
             if !inner.other_half_closed {
                 // Don't set this half closed, we're closing the connection instantly
                 inner.this_half_closed = true;
             }
-            inner.other_half_closed
+            (inner.other_half_closed, inner.priority)
         };
         if other_half_closed {
             self.shutdown(DisconnectReason::HalfCloseBoth);
         } else {
             if let Some(x) = self.parent.upgrade() {
-                x.on_half_closed(self.peer_id);
+                x.on_half_closed(self, priority);
             }
             if let Err(x) = self.send_half_close() {
                 warn!("Failed to send half-close: {}", x);
@@ -236,18 +405,77 @@ impl WrtcConnection {
         }
     }
 
-    pub fn set_dont_cleanup(self: &Orc<Self>, dont_cleanup: bool) {
-        self.inner.lock().unwrap().dont_cleanup = dont_cleanup;
+    pub fn priority(&self) -> ConnectionPriority {
+        self.inner.lock().unwrap().priority
+    }
+
+    pub fn set_priority(&self, priority: ConnectionPriority) {
+        self.inner.lock().unwrap().priority = priority;
+        if let Some(x) = self.parent.upgrade() {
+            x.half_closed_connections.lock().unwrap().update(self.peer_id, priority);
+        }
     }
 
     pub fn raw_connection(&self) -> RawConnection {
         self.inner.lock().unwrap().channel.raw_connection()
     }
+
+    // Called when the channel has room for the queued data
+    fn flush_outbound(&self) {
+        let res = {
+            let inner = &mut *self.inner.lock().unwrap();
+            inner.outbound.flush(&mut inner.channel)
+        };
+        if let Err(x) = res {
+            warn!("Failed to send queued data: {}", x);
+            self.shutdown(DisconnectReason::SendFail);
+        }
+    }
+
+    /// Closes the control channel, app channels on the same peer connection are not affected
+    pub fn close_channel(&self) {
+        self.inner.lock().unwrap().channel.close();
+    }
+
+    // Returns true if the connection should be kept for its app channels
+    fn on_channel_closed(&self) -> bool {
+        let keep = self.parent
+            .upgrade()
+            .map_or(false, |x| x.config.keep_connection_on_channel_close);
+        if keep {
+            // Requests can't be answered anymore
+            self.shutdown_local();
+        }
+        keep
+    }
+
+    pub fn debug_state(&self) -> ConnectionDebugState {
+        let inner = self.inner.try_lock().ok();
+        ConnectionDebugState {
+            id: self.peer_id,
+            locked: inner.is_none(),
+            priority: inner.as_ref().map(|x| x.priority),
+            outstanding_requests: inner.as_ref().map(|x| x.responses.len()),
+            queued_bytes: inner.as_ref().map(|x| x.outbound.queued),
+        }
+    }
+
+    pub fn quality(&self) -> PeerQuality {
+        let inner = self.inner.lock().unwrap();
+        PeerQuality {
+            rtt: inner.rtt,
+            candidate_type: inner.channel.selected_candidate_type(),
+            connected_since: self.connected_since,
+            messages_exchanged: inner.messages_exchanged,
+        }
+    }
 }
 
 fn process_message(msg: &[u8], conn: Orc<WrtcConnection>) -> Result<(), PeerMessageError> {
-    let msg: WrtcMessage = serde_json::from_slice(msg)?;
+    let wire_format = conn.inner.lock().unwrap().wire_format;
+    let msg = decode_message(msg, wire_format)?;
     debug!("Received message: {:?}", msg);
+    conn.inner.lock().unwrap().messages_exchanged += 1;
     let req = match msg.payload {
         WrtcPayload::Req(x) => x,
         WrtcPayload::Res(x) => {
@@ -268,20 +496,52 @@ fn process_message(msg: &[u8], conn: Orc<WrtcConnection>) -> Result<(), PeerMess
         .ok_or(PeerMessageError::UnknownInternalError("Shutting down"))?;
 
     match req {
+        WrtcRequest::Req(x @ (Request::InsertSigned(..) | Request::InsertNamed(..) | Request::Replicate(..))) => {
+            // The DHT waits for the signatures (and topic names) to be checked
+            spawn(async move {
+                let dht = match root.dht.upgrade() {
+                    Some(x) => x,
+                    None => return, // Shutting down
+                };
+                let ans = dht.handle_request(conn.peer_id, x).await;
+                conn.send_response(msg.id, WrtcResponse::Ans(ans));
+            });
+        }
         WrtcRequest::Req(x) => {
             let dht = match root.dht.upgrade() {
                 Some(x) => x,
                 None => return Ok(()), // Shutting down
             };
             let ans = dht.on_request(conn.peer_id, x);
+            if let RawResponse::FoundNodes(ids) | RawResponse::FoundDataAndNodes(_, ids) = &ans {
+                if root.config.forward_policy == ForwardPolicy::SearchContext {
+                    conn.inner.lock().unwrap().forward_guard.record_found(ids, Instant::now());
+                }
+            }
             conn.send_response(msg.id, WrtcResponse::Ans(ans));
         }
         WrtcRequest::ForwardOffer(offers) => {
+            let offers: Vec<_> = {
+                let mut inner = conn.inner.lock().unwrap();
+                let now = Instant::now();
+                offers.into_iter()
+                    .map(|(id, offer)| {
+                        let allowed = inner.forward_guard.allow(id, now, &root.config);
+                        if !allowed {
+                            debug!("Refusing to forward offer from {} to {}", conn.peer_id, id);
+                        }
+                        (id, offer, allowed)
+                    })
+                    .collect()
+            };
             let connections = root.connections.lock().unwrap();
-            let fut = join_all(offers.into_iter().map(|(id, offer)| {
+            let fut = join_all(offers.into_iter().map(|(id, offer, allowed)| {
                 let oconn = connections.get(&id).cloned();
                 let peer_id = conn.peer_id;
                 async move {
+                    if !allowed {
+                        return Err("not_allowed".into());
+                    }
                     match oconn {
                         Some(x) => {
                             match x.send_request(WrtcRequest::TryOffer(peer_id, offer)).await {
@@ -324,6 +584,7 @@ fn process_message(msg: &[u8], conn: Orc<WrtcConnection>) -> Result<(), PeerMess
                 }
             });
         }
+        WrtcRequest::Ping => conn.send_response(msg.id, WrtcResponse::Pong),
         WrtcRequest::HalfClose => {
             let mut inner = conn.inner.lock().unwrap();
             inner.other_half_closed = true;
@@ -337,41 +598,74 @@ fn process_message(msg: &[u8], conn: Orc<WrtcConnection>) -> Result<(), PeerMess
     Ok(())
 }
 
-async fn process_channel(channel: RawChannel, conn: Orc<WrtcConnection>) -> Result<(), PeerMessageError> {
+fn process_channel(channel: RawChannel, conn: Orc<WrtcConnection>) -> Result<(), PeerMessageError> {
     let root = conn
         .parent
         .upgrade()
         .ok_or(PeerMessageError::UnknownInternalError("Shutting down"))?;
 
-    let connection = conn.inner.lock().unwrap().channel.raw_connection().clone();
-    let _ = root.events_tx.broadcast(TransportEvent::ChannelOpen(ChannelOpenEvent {
+    let connection = conn.inner.lock().unwrap().channel.raw_connection();
+    let event = ChannelOpenEvent {
         id: conn.peer_id,
         connection,
         channel,
-    })).await;
+    };
+    send_channel_open(&root.events_tx, event, root.config.channel_open_policy);
     Ok(())
 }
 
 async fn connection_listen(
     mut mex_rx: mpsc::Receiver<Result<WrtcEvent, WrtcError>>,
+    mut reassembler: Reassembler,
+    fragment_timeout: Duration,
+    traffic: Orc<TrafficCounters>,
     conn: Weak<WrtcConnection>,
 ) {
     // TODO: add proper shutdown reason
-    while let Some(msg) = mex_rx.recv().await {
+    let mut reason = DisconnectReason::ConnectionLost;
+    loop {
+        let msg = match reassembler.pending_since() {
+            // The rest of the message must arrive before the deadline
+            Some(started) => tokio::select! {
+                x = mex_rx.recv() => x,
+                _ = sleep(fragment_timeout.saturating_sub(started.elapsed())) => {
+                    warn!("Fragmented message not completed in time");
+                    reason = DisconnectReason::TimeoutExpired;
+                    break;
+                }
+            },
+            None => mex_rx.recv().await,
+        };
+        let msg = match msg {
+            Some(x) => x,
+            None => break,
+        };
         match (msg, conn.upgrade()) {
             (Ok(WrtcEvent::OpenChannel(x)), Some(conn)) => {
-                if let Err(x) = process_channel(x, conn).await {
+                if let Err(x) = process_channel(x, conn) {
                     warn!("Error while processing open channel: {}", x);
                     break;
                 }
             }
+            (Ok(WrtcEvent::BufferedAmountLow), Some(conn)) => conn.flush_outbound(),
             (Ok(WrtcEvent::Data(x)), Some(conn)) => {
-                if let Err(x) = process_message(&x, conn) {
+                let res = match reassembler.push(x) {
+                    Ok(Some(x)) => {
+                        traffic.received.fetch_add(x.len() as u64, Ordering::Relaxed);
+                        process_message(&x, conn)
+                    }
+                    Ok(None) => Ok(()), // Wait for the next fragments
+                    Err(x) => Err(x.into()),
+                };
+                if let Err(x) = res {
                     warn!("Error while processing message: {}", x);
                     break;
                 }
             }
-            (Err(WrtcError::ConnectionLost), _) | (_, None) => {
+            (Err(WrtcError::ChannelClosed), Some(conn)) if conn.on_channel_closed() => {
+                debug!("Control channel closed, keeping the peer connection");
+            }
+            (Err(WrtcError::ConnectionLost | WrtcError::ChannelClosed), _) | (_, None) => {
                 debug!("Connection lost");
                 break;
             }
@@ -382,6 +676,68 @@ async fn connection_listen(
         }
     }
     if let Some(x) = conn.upgrade() {
-        x.shutdown(DisconnectReason::ConnectionLost);
+        x.shutdown(reason);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wdht_logic::transport::Request;
+
+    use super::*;
+    use crate::wrtc::framing::send_fragmented;
+
+    #[test_log::test]
+    fn wire_format_size() {
+        let data: Vec<u8> = (0..64 * 1024).map(|x| x as u8).collect();
+        let message = WrtcMessage {
+            id: 1,
+            payload: WrtcPayload::Req(WrtcRequest::Req(Request::Insert(Id::MAX, 60, data.clone()))),
+        };
+
+        let json = encode_message(&message, WireFormat::Json);
+        let binary = encode_message(&message, WireFormat::Bincode);
+        tracing::info!("Insert of {} bytes: json {} bytes, bincode {} bytes", data.len(), json.len(), binary.len());
+        // Every byte is sent raw, plus a few bytes of framing
+        assert!(binary.len() < data.len() + 64);
+        assert!(json.len() > 3 * data.len());
+
+        let decoded = decode_message(&binary, WireFormat::Bincode).unwrap();
+        assert_eq!(decoded.id, 1);
+        assert!(matches!(
+            decoded.payload,
+            WrtcPayload::Req(WrtcRequest::Req(Request::Insert(id, 60, x))) if id == Id::MAX && x == data
+        ));
+        // The formats are not interchangeable
+        assert!(decode_message(&binary, WireFormat::Json).is_err());
+    }
+
+    #[test]
+    fn bincode_framing() {
+        // Binary messages may start with a byte that is also a fragment marker
+        let mut reassembler = Reassembler::new(64 * 1024);
+        for id in 0..1024u32 {
+            let payload = match id % 3 {
+                0 => WrtcPayload::Res(WrtcResponse::Pong),
+                x => WrtcPayload::Req(WrtcRequest::Req(Request::Insert(Id::ZERO, 60, vec![id as u8; x as usize * 100]))),
+            };
+            let data = encode_message(&WrtcMessage { id, payload }, WireFormat::Bincode);
+            let mut received = Vec::new();
+            send_fragmented::<()>(&data, 128, |frame| {
+                received.extend(reassembler.push(frame.to_vec()).unwrap());
+                Ok(())
+            }).unwrap();
+            assert_eq!(received.len(), 1);
+            assert_eq!(decode_message(&received[0], WireFormat::Bincode).unwrap().id, id);
+        }
+    }
+
+    #[test]
+    fn unknown_request_message() {
+        // Requests added in newer versions are decoded (and answered with an error) instead of dropping the peer
+        let msg = br#"{"id":3,"payload":{"Req":{"Req":{"Subscribe":["00ff",{"ttl":60}]}}}}"#;
+        let decoded = decode_message(msg, WireFormat::Json).unwrap();
+        assert_eq!(decoded.id, 3);
+        assert!(matches!(decoded.payload, WrtcPayload::Req(WrtcRequest::Req(Request::Unknown))));
     }
 }
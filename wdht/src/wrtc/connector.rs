@@ -2,16 +2,20 @@ use std::{
     collections::{hash_map::Entry, HashMap},
     iter,
     sync::Mutex,
+    time::Duration,
 };
 
 use async_broadcast as broadcast;
 use futures::future::join_all;
+use instant::Instant;
 use tokio::sync::oneshot;
-use tracing::{error, event, Level};
+use tracing::{error, event, warn, Level};
 use wdht_logic::Id;
 use wdht_wrtc::SessionDescription;
 use wdht_wasync::Orc;
 
+use crate::ConnectionOrder;
+
 use super::{
     conn::WrtcConnection,
     protocol::{WrtcRequest, WrtcResponse},
@@ -27,6 +31,8 @@ pub struct CreatingConnectionSender {
     message_sent: bool,
     channel: broadcast::Sender<ContactResult>,
     owner: Option<Orc<WrtcConnector>>,
+    // Connection to a bootstrap node (the id is checked against the trusted ones)
+    bootstrap: bool,
 }
 
 impl CreatingConnectionSender {
@@ -35,6 +41,10 @@ impl CreatingConnectionSender {
         // drop!
     }
 
+    pub fn is_bootstrap(&self) -> bool {
+        self.bootstrap
+    }
+
     pub fn is_last(&self) -> bool {
         match (self.owner.as_ref(), self.peer_id.as_ref()) {
             (Some(parent), Some(id)) => parent
@@ -77,9 +87,13 @@ impl Drop for CreatingConnectionSender {
 
 type WrtcChannelCreationData = oneshot::Sender<Result<SessionDescription, WrtcTransportError>>;
 
+/// Sender (None if another connection is already being created) and receiver of the connection
+pub type CreatingConnection = (Option<CreatingConnectionSender>, broadcast::Receiver<ContactResult>);
+
 #[derive(Default)]
 struct WrtcConnectorInner {
-    connecting: HashMap<Id, (broadcast::Receiver<ContactResult>, usize)>,
+    // Receiver, id of the last sender, creation time
+    connecting: HashMap<Id, (broadcast::Receiver<ContactResult>, usize, Instant)>,
     sender_id: usize,
 }
 
@@ -88,31 +102,45 @@ impl WrtcConnectorInner {
         &mut self,
         parent: &Orc<WrtcConnector>,
         id: Id,
-    ) -> (
-        Option<CreatingConnectionSender>,
-        broadcast::Receiver<ContactResult>,
-    ) {
+    ) -> Result<CreatingConnection, WrtcTransportError> {
         if let Some(x) = self.connecting.get(&id) {
-            return (None, x.clone().0);
+            return Ok((None, x.0.clone()));
         }
         self.create_passive(parent, id)
     }
 
+    // Removes the entries older than timeout (their senders have probably been leaked)
+    fn evict_expired(&mut self, timeout: Duration) {
+        let now = Instant::now();
+        self.connecting.retain(|id, (receiver, _, created)| {
+            if now.duration_since(*created) < timeout {
+                return true;
+            }
+            warn!("Connection attempt to {id} expired");
+            let _ = receiver.new_sender().try_broadcast(Err("Connection attempt expired".into()));
+            false
+        });
+    }
+
     pub fn create_passive(
         &mut self,
         parent: &Orc<WrtcConnector>,
         id: Id,
-    ) -> (
-        Option<CreatingConnectionSender>,
-        broadcast::Receiver<ContactResult>,
-    ) {
+    ) -> Result<CreatingConnection, WrtcTransportError> {
+        if !self.connecting.contains_key(&id) && self.connecting.len() >= parent.max_connecting {
+            self.evict_expired(parent.connecting_timeout);
+            if self.connecting.len() >= parent.max_connecting {
+                event!(Level::INFO, kad_id=%parent.dht_id, %id, "Too many connections being opened");
+                return Err(WrtcTransportError::TooManyConnecting);
+            }
+        }
         let entry = self.connecting.entry(id);
         match entry {
             Entry::Occupied(mut entry) => {
-                // Rule: only the one with the lowest connection can open!
+                // Rule: only one of the two peers can open (see ConnectionOrder)!
                 // We already have an active connection present, and we're creating a passive connection
-                // Only use the passive connection if other_id < self_id
-                let sender = if id < parent.dht_id {
+                // Only use the passive connection if the other peer wins the conflict
+                let sender = if parent.order.wins(id, parent.dht_id) {
                     // Use this connection
                     let sender_id = self.sender_id;
                     self.sender_id += 1;
@@ -124,13 +152,14 @@ impl WrtcConnectorInner {
                         message_sent: false,
                         channel: entry.get().0.new_sender(),
                         owner: Some(parent.clone()),
+                        bootstrap: false,
                     };
                     Some(sender)
                 } else {
                     event!(Level::INFO, kad_id=%parent.dht_id, %id, "Dropping passive connection to prevent conflict");
                     None
                 };
-                (sender, entry.get().0.new_receiver())
+                Ok((sender, entry.get().0.new_receiver()))
             }
             Entry::Vacant(entry) => {
                 let sender_id = self.sender_id;
@@ -143,9 +172,10 @@ impl WrtcConnectorInner {
                     message_sent: false,
                     channel: sender,
                     owner: Some(parent.clone()),
+                    bootstrap: false,
                 };
-                entry.insert((receiver.clone(), sender_id));
-                (Some(sender), receiver)
+                entry.insert((receiver.clone(), sender_id, Instant::now()));
+                Ok((Some(sender), receiver))
             }
         }
     }
@@ -153,13 +183,19 @@ impl WrtcConnectorInner {
 
 pub struct WrtcConnector {
     dht_id: Id,
+    max_connecting: usize,
+    connecting_timeout: Duration,
+    order: ConnectionOrder,
     inner: Mutex<WrtcConnectorInner>,
 }
 
 impl WrtcConnector {
-    pub fn new(id: Id) -> Self {
+    pub fn new(id: Id, max_connecting: usize, connecting_timeout: Duration, order: ConnectionOrder) -> Self {
         WrtcConnector {
             dht_id: id,
+            max_connecting,
+            connecting_timeout,
+            order,
             inner: Default::default(),
         }
     }
@@ -257,12 +293,19 @@ impl WrtcConnector {
             let mut inner = self.inner.lock().unwrap();
 
             join_all(ids.iter().map(|id| {
-                let (sender, mut recv) = inner.create_active(self, *id);
-                if let Some(sender) = sender {
-                    to_send.push((*id, sender));
-                }
+                let recv = inner.create_active(self, *id).map(|(sender, recv)| {
+                    if let Some(sender) = sender {
+                        to_send.push((*id, sender));
+                    }
+                    recv
+                });
 
-                async move { recv.recv().await.expect("Error receiving contact") }
+                async move {
+                    match recv {
+                        Ok(mut recv) => recv.recv().await.expect("Error receiving contact"),
+                        Err(e) => Err(e),
+                    }
+                }
             }))
         };
 
@@ -273,6 +316,16 @@ impl WrtcConnector {
         contacts.await
     }
 
+    /// Number of pending connections
+    pub fn connecting_len(&self) -> usize {
+        self.inner.lock().unwrap().connecting.len()
+    }
+
+    /// Number of pending connections, None if the state is locked
+    pub fn connecting_count(&self) -> Option<usize> {
+        self.inner.try_lock().ok().map(|x| x.connecting.len())
+    }
+
     pub fn is_connecting(&self, id: Id) -> bool {
         self.inner.lock().unwrap().connecting.contains_key(&id)
     }
@@ -280,6 +333,17 @@ impl WrtcConnector {
     pub fn create_unknown(
         self: &Orc<Self>,
     ) -> (CreatingConnectionSender, broadcast::Receiver<ContactResult>) {
+        Self::create_unknown0(false)
+    }
+
+    /// Like create_unknown, but the connection is to a bootstrap node
+    pub fn create_bootstrap(
+        self: &Orc<Self>,
+    ) -> (CreatingConnectionSender, broadcast::Receiver<ContactResult>) {
+        Self::create_unknown0(true)
+    }
+
+    fn create_unknown0(bootstrap: bool) -> (CreatingConnectionSender, broadcast::Receiver<ContactResult>) {
         let (sender, receiver) = broadcast::broadcast(1);
         (
             CreatingConnectionSender {
@@ -288,6 +352,7 @@ impl WrtcConnector {
                 message_sent: false,
                 channel: sender,
                 owner: None,
+                bootstrap,
             },
             receiver,
         )
@@ -296,10 +361,7 @@ impl WrtcConnector {
     pub fn create_active(
         self: &Orc<Self>,
         id: Id,
-    ) -> (
-        Option<CreatingConnectionSender>,
-        broadcast::Receiver<ContactResult>,
-    ) {
+    ) -> Result<CreatingConnection, WrtcTransportError> {
         let mut conns = self.inner.lock().unwrap();
         conns.create_active(self, id)
     }
@@ -307,11 +369,82 @@ impl WrtcConnector {
     pub fn create_passive(
         self: &Orc<Self>,
         id: Id,
-    ) -> (
-        Option<CreatingConnectionSender>,
-        broadcast::Receiver<ContactResult>,
-    ) {
+    ) -> Result<CreatingConnection, WrtcTransportError> {
         let mut conns = self.inner.lock().unwrap();
         conns.create_passive(self, id)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn connector(max_connecting: usize, connecting_timeout: Duration) -> Orc<WrtcConnector> {
+        Orc::new(WrtcConnector::new(Id::ZERO, max_connecting, connecting_timeout, ConnectionOrder::default()))
+    }
+
+    #[tokio::test]
+    async fn simultaneous_connect() {
+        let (a, b) = (Id::ZERO.set_bit(1), Id::ZERO.set_bit(0));
+        assert!(a < b);
+        for order in [ConnectionOrder::LowerIdOffers, ConnectionOrder::HigherIdOffers] {
+            let new = |id| Orc::new(WrtcConnector::new(id, 16, Duration::from_secs(60), order));
+            let (conn_a, conn_b) = (new(a), new(b));
+
+            // Both peers start connecting to each other
+            let (active_a, _) = conn_a.create_active(b).unwrap();
+            let (active_b, _) = conn_b.create_active(a).unwrap();
+            assert!(active_a.is_some() && active_b.is_some());
+
+            // Then they receive the offer of the other one
+            let (passive_a, _) = conn_a.create_passive(b).unwrap();
+            let (passive_b, _) = conn_b.create_passive(a).unwrap();
+            let (a_offers, b_offers) = (passive_b.is_some(), passive_a.is_some());
+            assert!(a_offers != b_offers, "Exactly one offer must be accepted");
+            assert_eq!(a_offers, order == ConnectionOrder::LowerIdOffers);
+        }
+    }
+
+    #[tokio::test]
+    async fn connecting_cap() {
+        let connector = connector(2, Duration::from_secs(60));
+        let ids: Vec<_> = (0..3).map(|x| Id::ZERO.set_bit(x)).collect();
+
+        let (first, mut first_rx) = connector.create_active(ids[0]).unwrap();
+        let (second, _second_rx) = connector.create_active(ids[1]).unwrap();
+        assert!(matches!(
+            connector.create_active(ids[2]),
+            Err(WrtcTransportError::TooManyConnecting)
+        ));
+        // Joining a connection already being created is still allowed
+        let (joined, mut joined_rx) = connector.create_active(ids[0]).unwrap();
+        assert!(joined.is_none());
+
+        // The existing connection completes normally
+        assert!(first.unwrap().send(Err("done".into())));
+        assert!(first_rx.recv().await.unwrap().is_err());
+        assert!(joined_rx.recv().await.unwrap().is_err());
+        assert!(!connector.is_connecting(ids[0]));
+
+        // There is room for a new one
+        assert!(connector.create_active(ids[2]).unwrap().0.is_some());
+        drop(second);
+    }
+
+    #[tokio::test]
+    async fn connecting_eviction() {
+        let connector = connector(1, Duration::from_millis(50));
+        let (leaked, mut leaked_rx) = connector.create_active(Id::ZERO.set_bit(1)).unwrap();
+        assert!(connector.create_active(Id::ZERO.set_bit(2)).is_err());
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        // The expired entry is evicted to make room, its waiters are notified
+        let (fresh, _fresh_rx) = connector.create_active(Id::ZERO.set_bit(2)).unwrap();
+        assert!(fresh.is_some());
+        assert!(leaked_rx.recv().await.unwrap().is_err());
+        assert!(!connector.is_connecting(Id::ZERO.set_bit(1)));
+        // The late sender does not touch the new entries
+        assert!(!leaked.unwrap().send(Err("late".into())));
+        assert!(connector.is_connecting(Id::ZERO.set_bit(2)));
+    }
+}
@@ -22,6 +22,9 @@ pub enum HandshakeError {
     #[error("A channel with the same ID was already open")]
     IdConflict(Id),
 
+    #[error("Peer {0} speaks the incompatible protocol version {1}")]
+    ProtocolVersionMismatch(Id, u16),
+
     #[error("WebRTC error: {0}")]
     Wrtc(wdht_wrtc::WrtcError),
 
@@ -51,6 +54,12 @@ pub enum WrtcTransportError {
     ConnectionLimitReached,
     #[error("Already connecting to that id")]
     AlreadyConnecting,
+    #[error("Too many connections being opened")]
+    TooManyConnecting,
+    #[error("SDP description too large")]
+    SdpTooLarge,
+    #[error("Peer {0} rejected by authorization hook")]
+    Unauthorized(Id),
     #[error("Error occurred during handshake: {0}")]
     Handshake(HandshakeError),
     #[error("Transport error: {0}")]
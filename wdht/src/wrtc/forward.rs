@@ -0,0 +1,104 @@
+use std::{collections::HashMap, time::Duration};
+
+use instant::Instant;
+use wdht_logic::Id;
+
+use crate::{config::ForwardPolicy, TransportConfig};
+
+/// How long an id returned to a peer can be the target of its forwarded offers
+pub const FOUND_NODES_EXPIRY: Duration = Duration::from_secs(60);
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Decides which of the offers a peer asks us to forward are accepted.
+///
+/// A peer doing a lookup only asks to forward offers to the nodes we just
+/// returned to it, so with [`ForwardPolicy::SearchContext`] every other id is
+/// refused. The rate limit is applied to the accepted offers only.
+pub struct ForwardGuard {
+    // Ids returned to the peer in FoundNodes responses, with the time they were sent
+    found: HashMap<Id, Instant>,
+    window_start: Instant,
+    window_count: u32,
+}
+
+impl ForwardGuard {
+    pub fn new(now: Instant) -> Self {
+        ForwardGuard {
+            found: HashMap::new(),
+            window_start: now,
+            window_count: 0,
+        }
+    }
+
+    /// Records the ids returned to the peer, expired ids are dropped
+    pub fn record_found(&mut self, ids: &[Id], now: Instant) {
+        self.found.retain(|_, sent| now.duration_since(*sent) < FOUND_NODES_EXPIRY);
+        for id in ids {
+            self.found.insert(*id, now);
+        }
+    }
+
+    /// Returns true if an offer to id should be forwarded, counting it against the rate limit
+    pub fn allow(&mut self, id: Id, now: Instant, config: &TransportConfig) -> bool {
+        if config.forward_policy == ForwardPolicy::SearchContext {
+            let in_context = self.found.get(&id)
+                .map_or(false, |sent| now.duration_since(*sent) < FOUND_NODES_EXPIRY);
+            if !in_context {
+                return false;
+            }
+        }
+
+        if let Some(max) = config.max_forwards_per_minute {
+            if now.duration_since(self.window_start) >= RATE_WINDOW {
+                self.window_start = now;
+                self.window_count = 0;
+            }
+            if self.window_count >= max {
+                return false;
+            }
+            self.window_count += 1;
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn throttling() {
+        let config = TransportConfig {
+            forward_policy: ForwardPolicy::SearchContext,
+            max_forwards_per_minute: Some(10),
+            ..Default::default()
+        };
+        let start = Instant::now();
+        let mut guard = ForwardGuard::new(start);
+        let found: Vec<_> = (0..5).map(|x| Id::ZERO.set_bit(x)).collect();
+
+        // Nothing was returned to the peer yet, it's using us as an open relay
+        assert!(!guard.allow(found[0], start, &config));
+
+        // A normal lookup forwards to the nodes we returned
+        guard.record_found(&found, start);
+        assert!(found.iter().all(|id| guard.allow(*id, start, &config)));
+        assert!(!guard.allow(Id::ZERO.set_bit(100), start, &config));
+
+        // Repeated forwards are throttled
+        let now = start + Duration::from_secs(1);
+        let allowed = (0..20).filter(|_| guard.allow(found[0], now, &config)).count();
+        assert_eq!(allowed, 5);
+
+        // Old lookups can't be reused
+        let now = start + FOUND_NODES_EXPIRY;
+        assert!(!guard.allow(found[0], now, &config));
+        guard.record_found(&found[1..2], now);
+        assert!(guard.allow(found[1], now, &config));
+
+        // The default configuration forwards everything
+        let config = TransportConfig::default();
+        let mut guard = ForwardGuard::new(start);
+        assert!((0..100).all(|x| guard.allow(Id::ZERO.set_bit(x), start, &config)));
+    }
+}
@@ -0,0 +1,203 @@
+//! Splitting of large messages into data channel sized fragments.
+//!
+//! SCTP implementations limit the size of a single data channel message
+//! (libdatachannel accepts 64 KiB by default, browsers usually 256 KiB),
+//! while JSON encodes every stored byte as a number of up to 4 characters:
+//! an insert at the default 128 KiB storage limit weighs up to ~512 KiB.
+//!
+//! Messages that fit in a fragment are sent as they are (JSON messages always
+//! start with `{`), larger ones are split into fragments starting with a
+//! marker byte. Whole messages that start with a marker byte themselves (as
+//! binary messages might) are prefixed with one more marker. Data channels are reliable and ordered, and fragments of a
+//! message are sent together, so the receiver only needs to concatenate them.
+//! A peer that stops in the middle of a message would hold the reassembly
+//! buffer forever, so partial messages have a deadline.
+use std::mem;
+
+use instant::Instant;
+
+use thiserror::Error;
+
+// First byte of a whole message that would otherwise be taken for a fragment
+const WHOLE_MESSAGE: u8 = 0x00;
+// First byte of a fragment followed by other fragments of the same message
+const MORE_FRAGMENTS: u8 = 0x01;
+// First byte of the last fragment of a message
+const LAST_FRAGMENT: u8 = 0x02;
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum FramingError {
+    #[error("Message too large")]
+    TooLarge,
+    #[error("Message interleaved with a fragmented message")]
+    Interleaved,
+}
+
+// True if data can be sent without any marker
+fn is_unmarked(data: &[u8]) -> bool {
+    matches!(data.first(), Some(&x) if x > LAST_FRAGMENT)
+}
+
+/// Bytes sent by [`send_fragmented`] for data (markers included)
+pub fn framed_size(data: &[u8], max_fragment_size: usize) -> usize {
+    match data.len() {
+        x if x <= max_fragment_size && is_unmarked(data) => x,
+        x if x < max_fragment_size => x + 1,
+        // Every fragment starts with a marker byte
+        x => x + (x + max_fragment_size - 2) / (max_fragment_size - 1),
+    }
+}
+
+/// Sends data in fragments of at most max_fragment_size bytes (markers included)
+pub fn send_fragmented<E>(
+    data: &[u8],
+    max_fragment_size: usize,
+    mut send: impl FnMut(&[u8]) -> Result<(), E>,
+) -> Result<(), E> {
+    if data.len() <= max_fragment_size && is_unmarked(data) {
+        return send(data);
+    }
+    if data.len() < max_fragment_size {
+        return send(&[&[WHOLE_MESSAGE], data].concat());
+    }
+    let mut chunks = data.chunks(max_fragment_size - 1).peekable();
+    let mut fragment = Vec::with_capacity(max_fragment_size);
+    while let Some(chunk) = chunks.next() {
+        fragment.clear();
+        fragment.push(if chunks.peek().is_some() { MORE_FRAGMENTS } else { LAST_FRAGMENT });
+        fragment.extend_from_slice(chunk);
+        send(&fragment)?;
+    }
+    Ok(())
+}
+
+/// Rebuilds the messages split by [`send_fragmented`]
+pub struct Reassembler {
+    buffer: Vec<u8>,
+    max_message_size: usize,
+    // Arrival of the first fragment of the partial message
+    started: Option<Instant>,
+}
+
+impl Reassembler {
+    pub fn new(max_message_size: usize) -> Self {
+        Reassembler {
+            buffer: Vec::new(),
+            max_message_size,
+            started: None,
+        }
+    }
+
+    /// Arrival time of the first fragment of the message being reassembled, if any
+    pub fn pending_since(&self) -> Option<Instant> {
+        self.started
+    }
+
+    /// Returns the message completed by frame, if any
+    pub fn push(&mut self, mut frame: Vec<u8>) -> Result<Option<Vec<u8>>, FramingError> {
+        let marker = match (frame.first(), self.buffer.is_empty()) {
+            (Some(x @ (&MORE_FRAGMENTS | &LAST_FRAGMENT)), _) => *x,
+            (Some(&WHOLE_MESSAGE), true) => {
+                frame.remove(0);
+                return Ok(Some(frame));
+            }
+            (_, true) => return Ok(Some(frame)),
+            (_, false) => return Err(FramingError::Interleaved),
+        };
+        if self.buffer.len() + frame.len() - 1 > self.max_message_size {
+            self.buffer = Vec::new();
+            self.started = None;
+            return Err(FramingError::TooLarge);
+        }
+        self.buffer.extend_from_slice(&frame[1..]);
+        Ok(match marker {
+            LAST_FRAGMENT => {
+                self.started = None;
+                Some(mem::take(&mut self.buffer))
+            }
+            _ => {
+                self.started.get_or_insert_with(Instant::now);
+                None
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fragments(data: &[u8], max_fragment_size: usize) -> Vec<Vec<u8>> {
+        let mut res = Vec::new();
+        send_fragmented::<()>(data, max_fragment_size, |x| {
+            res.push(x.to_vec());
+            Ok(())
+        }).unwrap();
+        res
+    }
+
+    #[test]
+    fn roundtrip() {
+        let mut reassembler = Reassembler::new(1024);
+        let small = br#"{"id":1}"#.to_vec();
+        assert_eq!(fragments(&small, 8), vec![small.clone()]);
+        assert_eq!(reassembler.push(small.clone()).unwrap(), Some(small));
+
+        for len in [9, 14, 15, 100, 1024] {
+            let data: Vec<u8> = (0..len).map(|x| x as u8).collect();
+            let frames = fragments(&data, 8);
+            assert_eq!(frames.len(), (len + 6) / 7);
+            assert!(frames.iter().all(|x| x.len() <= 8));
+
+            let (last, rest) = frames.split_last().unwrap();
+            for frame in rest {
+                assert_eq!(reassembler.push(frame.clone()).unwrap(), None);
+            }
+            assert_eq!(reassembler.pending_since().is_some(), !rest.is_empty());
+            assert_eq!(reassembler.push(last.clone()).unwrap(), Some(data));
+            assert!(reassembler.pending_since().is_none());
+        }
+    }
+
+    #[test]
+    fn smallest_fragments() {
+        // The smallest valid max_fragment_size (see TransportConfig::validate)
+        let data = vec![7; 3];
+        let frames = fragments(&data, 2);
+        assert_eq!(frames, vec![vec![MORE_FRAGMENTS, 7], vec![MORE_FRAGMENTS, 7], vec![LAST_FRAGMENT, 7]]);
+        assert_eq!(framed_size(&data, 2), 6);
+    }
+
+    #[test]
+    fn marked_whole_messages() {
+        let mut reassembler = Reassembler::new(1024);
+        for first in [WHOLE_MESSAGE, MORE_FRAGMENTS, LAST_FRAGMENT] {
+            let data = vec![first, 7, 7];
+            let frames = fragments(&data, 8);
+            assert_eq!(frames, vec![[&[WHOLE_MESSAGE], &data[..]].concat()]);
+            assert_eq!(framed_size(&data, 8), 4);
+            assert_eq!(reassembler.push(frames[0].clone()).unwrap(), Some(data));
+        }
+        // A marked message that doesn't fit with its marker is fragmented
+        let data = vec![MORE_FRAGMENTS; 8];
+        let frames = fragments(&data, 8);
+        assert_eq!(frames.len(), 2);
+        assert_eq!(framed_size(&data, 8), 10);
+        assert_eq!(reassembler.push(frames[0].clone()).unwrap(), None);
+        assert_eq!(reassembler.push(frames[1].clone()).unwrap(), Some(data));
+        assert_eq!(reassembler.push(Vec::new()).unwrap(), Some(Vec::new()));
+    }
+
+    #[test]
+    fn invalid_frames() {
+        let mut reassembler = Reassembler::new(10);
+        let frames = fragments(&[7; 11], 8);
+        assert!(reassembler.push(frames[0].clone()).unwrap().is_none());
+        assert!(matches!(reassembler.push(frames[1].clone()), Err(FramingError::TooLarge)));
+
+        let mut reassembler = Reassembler::new(10);
+        assert!(reassembler.push(frames[0].clone()).unwrap().is_none());
+        assert!(matches!(reassembler.push(b"{}".to_vec()), Err(FramingError::Interleaved)));
+    }
+}
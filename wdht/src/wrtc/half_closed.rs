@@ -0,0 +1,198 @@
+use std::collections::{BTreeMap, HashMap};
+
+use wdht_logic::Id;
+
+use super::conn::ConnectionPriority;
+
+/// Half-closed connections ordered by priority, then by the time they were half-closed.
+///
+/// Bootstrap and routing connections are never candidates for reaping and are not tracked.
+///
+/// Membership and ordering are kept in the same structure (under the same lock)
+/// so the count can never go out of sync with the contents.
+#[derive(Default)]
+pub struct HalfClosedSet {
+    next_seq: u64,
+    order: BTreeMap<(ConnectionPriority, u64), Id>,
+    members: HashMap<Id, (ConnectionPriority, u64)>,
+}
+
+impl HalfClosedSet {
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    pub fn contains(&self, id: &Id) -> bool {
+        self.members.contains_key(id)
+    }
+
+    /// Inserts id as the newest half-closed connection,
+    /// returns false if it was already present or if it can't be reaped
+    pub fn push(&mut self, id: Id, priority: ConnectionPriority) -> bool {
+        if !priority.is_reapable() || self.members.contains_key(&id) {
+            return false;
+        }
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.order.insert((priority, seq), id);
+        self.members.insert(id, (priority, seq));
+        true
+    }
+
+    /// Removes and returns the oldest half-closed connection with the lowest priority
+    pub fn pop_lowest(&mut self) -> Option<Id> {
+        let key = *self.order.keys().next()?;
+        let id = self.order.remove(&key)?;
+        self.members.remove(&id);
+        Some(id)
+    }
+
+    /// Changes the priority of a half-closed connection (keeping its age),
+    /// connections promoted to bootstrap or routing are removed
+    pub fn update(&mut self, id: Id, priority: ConnectionPriority) {
+        let seq = match self.members.remove(&id) {
+            Some(key) => {
+                self.order.remove(&key);
+                key.1
+            }
+            None => return,
+        };
+        if priority.is_reapable() {
+            self.order.insert((priority, seq), id);
+            self.members.insert(id, (priority, seq));
+        }
+    }
+
+    /// Removes id, returns false if it was not present
+    pub fn remove(&mut self, id: &Id) -> bool {
+        match self.members.remove(id) {
+            Some(key) => {
+                self.order.remove(&key);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        thread,
+    };
+
+    use super::*;
+
+    #[test]
+    fn ordering() {
+        let mut set = HalfClosedSet::default();
+        let ids: Vec<_> = (0..4).map(|x| Id::ZERO.set_bit(x)).collect();
+        for id in ids.iter() {
+            assert!(set.push(*id, ConnectionPriority::Transient));
+        }
+        assert!(!set.push(ids[0], ConnectionPriority::Transient));
+        assert!(set.remove(&ids[1]));
+        assert!(!set.remove(&ids[1]));
+        assert_eq!(set.len(), 3);
+        assert_eq!(set.pop_lowest(), Some(ids[0]));
+        assert_eq!(set.pop_lowest(), Some(ids[2]));
+        assert_eq!(set.pop_lowest(), Some(ids[3]));
+        assert_eq!(set.pop_lowest(), None);
+        assert_eq!(set.len(), 0);
+    }
+
+    #[test]
+    fn routing_survives_pressure() {
+        use ConnectionPriority::*;
+        let mut set = HalfClosedSet::default();
+        let ids: Vec<_> = (0..5).map(|x| Id::ZERO.set_bit(x)).collect();
+
+        // Fill the connections, the oldest ones have the highest priority
+        assert!(!set.push(ids[0], Routing));
+        assert!(set.push(ids[1], Pinned));
+        assert!(set.push(ids[2], Transient));
+        assert!(set.push(ids[3], Transient));
+        assert!(set.push(ids[4], Pinned));
+
+        // A transient connection becomes a routing one while half-closed
+        set.update(ids[3], Routing);
+        assert_eq!(set.len(), 3);
+
+        // Transient connections are reaped first, then pinned ones by age
+        assert_eq!(set.pop_lowest(), Some(ids[2]));
+        assert_eq!(set.pop_lowest(), Some(ids[1]));
+        assert_eq!(set.pop_lowest(), Some(ids[4]));
+        // Routing connections are never reaped
+        assert_eq!(set.pop_lowest(), None);
+    }
+
+    #[test]
+    fn bootstrap_survives_pressure() {
+        use ConnectionPriority::*;
+        let mut set = HalfClosedSet::default();
+        let seed = Id::ZERO.set_bit(0);
+        let others: Vec<_> = (1..4).map(|x| Id::ZERO.set_bit(x)).collect();
+
+        // The seed is the oldest connection, but it is never tracked
+        assert!(!set.push(seed, Bootstrap));
+        for id in others.iter() {
+            assert!(set.push(*id, Pinned));
+        }
+        set.update(others[0], Bootstrap);
+
+        let mut reaped = Vec::new();
+        while let Some(x) = set.pop_lowest() {
+            reaped.push(x);
+        }
+        assert_eq!(reaped, &others[1..]);
+    }
+
+    #[test]
+    fn concurrent_alloc_and_disconnect() {
+        let set = Arc::new(Mutex::new(HalfClosedSet::default()));
+        let ids: Vec<_> = (0..160u8).map(|x| Id::ZERO.set_bit(x)).collect();
+        for id in ids.iter() {
+            set.lock().unwrap().push(*id, ConnectionPriority::Transient);
+        }
+
+        // Some threads reap the oldest connections (alloc_connection),
+        // others remove arbitrary connections (on_disconnect)
+        let mut handles = Vec::new();
+        for _ in 0..4 {
+            let set = set.clone();
+            handles.push(thread::spawn(move || {
+                let mut taken = Vec::new();
+                while let Some(x) = set.lock().unwrap().pop_lowest() {
+                    taken.push(x);
+                }
+                taken
+            }));
+        }
+        for i in 0..4 {
+            let set = set.clone();
+            let ids = ids.clone();
+            handles.push(thread::spawn(move || {
+                ids.iter()
+                    .skip(i)
+                    .step_by(4)
+                    .filter(|x| set.lock().unwrap().remove(x))
+                    .cloned()
+                    .collect::<Vec<_>>()
+            }));
+        }
+
+        let mut removed: Vec<Id> = handles.into_iter()
+            .flat_map(|x| x.join().unwrap())
+            .collect();
+
+        // Every connection has been removed exactly once
+        let set = set.lock().unwrap();
+        assert_eq!(set.len(), 0);
+        assert!(set.order.is_empty());
+        removed.sort();
+        let mut expected = ids;
+        expected.sort();
+        assert_eq!(removed, expected);
+    }
+}
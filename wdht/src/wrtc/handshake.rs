@@ -1,26 +1,61 @@
 use serde::Serialize;
 use wdht_logic::Id;
-use wdht_wrtc::{WrtcChannel, WrtcError};
+use wdht_wrtc::{WrtcChannel, WrtcError, WrtcEvent};
 
-use crate::identity::Identity;
+use crate::{config::WireFormat, identity::Identity};
 
-use super::{protocol::{HandshakeRequest}, error::HandshakeError};
+use super::{protocol::{HandshakeRequest, PROTOCOL_FEATURES, PROTOCOL_VERSION}, error::HandshakeError, record::HandshakeRecord};
+
+/// Information gathered about the remote peer while connecting.
+#[derive(Clone, Debug)]
+pub struct HandshakeInfo {
+    /// Exported public key of the peer, None if the handshake was skipped
+    pub public_key: Option<Vec<u8>>,
+    /// Fingerprint of the peer's DTLS certificate
+    pub certificate_fingerprint: Vec<u8>,
+    /// Encoding of the messages exchanged with the peer
+    pub wire_format: WireFormat,
+    /// Feature flags announced by the peer
+    pub features: u32,
+}
 
 fn encode_data<T: Serialize>(data: &T) -> Result<Vec<u8>, HandshakeError> {
     serde_json::to_vec(data).map_err(|_| HandshakeError::Internal("Error encoding data"))
 }
 
 async fn receive_message(chan: &mut WrtcChannel) -> Result<Vec<u8>, HandshakeError> {
-    chan
-        .listener
-        .recv()
-        .await
-        .ok_or(HandshakeError::ConnectionLost)??
-        .data()
-        .ok_or(HandshakeError::OpenedChannel)
+    loop {
+        match chan.listener.recv().await.ok_or(HandshakeError::ConnectionLost)?? {
+            WrtcEvent::Data(x) => return Ok(x),
+            // The handshake message was sent
+            WrtcEvent::BufferedAmountLow => continue,
+            WrtcEvent::OpenChannel(_) => return Err(HandshakeError::OpenedChannel),
+        }
+    }
+}
+
+/// Runs the identity handshake on a freshly opened channel.
+///
+/// The binary wire format is used only if both peers propose it.
+/// The handshake fails if the peer speaks a different protocol version.
+/// If record is provided the exchanged messages and certificate fingerprints are saved in it
+/// (even when the handshake fails).
+pub async fn handshake(
+    conn: &mut WrtcChannel,
+    identity: &Identity,
+    wire_format: WireFormat,
+    record: Option<&mut HandshakeRecord>,
+) -> Result<(Id, HandshakeInfo), HandshakeError> {
+    handshake_with_version(conn, identity, wire_format, PROTOCOL_VERSION, record).await
 }
 
-pub async fn handshake(conn: &mut WrtcChannel, identity: &Identity) -> Result<Id, HandshakeError> {
+async fn handshake_with_version(
+    conn: &mut WrtcChannel,
+    identity: &Identity,
+    wire_format: WireFormat,
+    protocol_version: u16,
+    mut record: Option<&mut HandshakeRecord>,
+) -> Result<(Id, HandshakeInfo), HandshakeError> {
     // Compute local proof
     let fp = conn.sender.local_certificate_fingerprint()?;
     let proof = identity.create_proof(&fp).await;
@@ -28,20 +63,146 @@ pub async fn handshake(conn: &mut WrtcChannel, identity: &Identity) -> Result<Id
     let msg = HandshakeRequest {
         identity: identity.export_key().into(),
         proof: proof.into(),
+        wire_format,
+        protocol_version,
+        features: PROTOCOL_FEATURES,
     };
+    let msg = encode_data(&msg)?;
+    if let Some(record) = record.as_deref_mut() {
+        record.local_fingerprint = fp;
+        record.sent = msg.clone();
+    }
 
     // Send local proof
-    conn.sender.send(&encode_data(&msg)?)
+    conn.sender.send(&msg)
         .map_err(|_| WrtcError::ConnectionLost)?;
 
     // Receive remote proof
     let msg = receive_message(conn).await?;
-    let req = serde_json::from_slice::<HandshakeRequest>(&msg)?;
-
-    // Check remote proof and derive ID
     let other_fingerprint = conn.sender.remote_certificate_fingerprint()?;
+    if let Some(record) = record {
+        record.received = msg.clone();
+        record.remote_fingerprint = other_fingerprint.clone();
+    }
+
+    verify_handshake(identity, &msg, other_fingerprint, wire_format, protocol_version).await
+}
+
+/// Checks the remote proof and protocol version and derives the peer id
+pub(crate) async fn verify_handshake(
+    identity: &Identity,
+    msg: &[u8],
+    other_fingerprint: Vec<u8>,
+    wire_format: WireFormat,
+    protocol_version: u16,
+) -> Result<(Id, HandshakeInfo), HandshakeError> {
+    let req = serde_json::from_slice::<HandshakeRequest>(msg)?;
+
     let peer_id = identity.check_identity_proof(&req.identity, &other_fingerprint, &req.proof).await
         .map_err(|_| HandshakeError::InvalidIdentity)?;
+    // Checked after the proof so that the peer can be reported
+    if req.protocol_version != protocol_version {
+        return Err(HandshakeError::ProtocolVersionMismatch(peer_id, req.protocol_version));
+    }
+
+    let info = HandshakeInfo {
+        public_key: Some(req.identity.to_vec()),
+        certificate_fingerprint: other_fingerprint,
+        wire_format: if req.wire_format == wire_format { wire_format } else { WireFormat::Json },
+        features: req.features,
+    };
+    Ok((peer_id, info))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wdht_crypto::KeyAlgorithm;
+    use crate::tests::open_channel_pair;
+
+    #[tokio::test]
+    async fn native_handshake_test() {
+        let (a_identity, b_identity) = tokio::join!(Identity::generate(), Identity::generate());
+        let (a_id, b_id) = tokio::join!(a_identity.generate_id(), b_identity.generate_id());
+        let (mut a_chan, mut b_chan) = open_channel_pair().await;
+
+        // The raw connections expose the same certificates seen from the two sides
+        let (a_raw, b_raw) = (a_chan.sender.raw_connection(), b_chan.sender.raw_connection());
+        let a_fp = a_raw.local_certificate_fingerprint().unwrap();
+        let b_fp = b_raw.local_certificate_fingerprint().unwrap();
+        assert_ne!(a_fp, b_fp);
+        assert_eq!(b_raw.remote_certificate_fingerprint().unwrap(), a_fp);
+        assert_eq!(a_raw.remote_certificate_fingerprint().unwrap(), b_fp);
+
+        let (a_res, b_res) = tokio::join!(
+            handshake(&mut a_chan, &a_identity, WireFormat::Json, None),
+            handshake(&mut b_chan, &b_identity, WireFormat::Json, None),
+        );
+        let (a_peer, a_info) = a_res.unwrap();
+        let (b_peer, b_info) = b_res.unwrap();
+        assert_eq!(a_peer, b_id);
+        assert_eq!(b_peer, a_id);
+        // Each proof is bound to the certificate of its sender
+        assert_eq!(a_info.certificate_fingerprint, b_fp);
+        assert_eq!(b_info.certificate_fingerprint, a_fp);
+
+        drop(a_chan);
+        assert!(a_raw.is_closed());
+    }
+
+    #[tokio::test]
+    async fn native_protocol_mismatch_test() {
+        let (a_identity, b_identity) = tokio::join!(Identity::generate(), Identity::generate());
+        let (a_id, b_id) = tokio::join!(a_identity.generate_id(), b_identity.generate_id());
+        let (mut a_chan, mut b_chan) = open_channel_pair().await;
+
+        let (a_res, b_res) = tokio::join!(
+            handshake(&mut a_chan, &a_identity, WireFormat::Json, None),
+            handshake_with_version(&mut b_chan, &b_identity, WireFormat::Json, PROTOCOL_VERSION + 1, None),
+        );
+        // Both sides refuse the connection and know who they refused
+        assert!(matches!(a_res, Err(HandshakeError::ProtocolVersionMismatch(id, v)) if id == b_id && v == PROTOCOL_VERSION + 1));
+        assert!(matches!(b_res, Err(HandshakeError::ProtocolVersionMismatch(id, v)) if id == a_id && v == PROTOCOL_VERSION));
+    }
+
+    #[tokio::test]
+    async fn protocol_version_check() {
+        let (local, remote) = tokio::join!(Identity::generate(), Identity::generate());
+        let remote_id = remote.generate_id().await;
+        let fingerprint = vec![7u8; 32];
+        let proof = remote.create_proof(&fingerprint).await;
+        let message = |protocol_version| encode_data(&HandshakeRequest {
+            identity: remote.export_key().into(),
+            proof: proof.clone().into(),
+            wire_format: WireFormat::Json,
+            protocol_version,
+            features: 0b10,
+        }).unwrap();
+
+        let (id, info) = verify_handshake(&local, &message(PROTOCOL_VERSION), fingerprint.clone(), WireFormat::Json, PROTOCOL_VERSION).await.unwrap();
+        assert_eq!(id, remote_id);
+        assert_eq!(info.features, 0b10);
+
+        // Older peers don't send their version, they're not compatible
+        let mut old: serde_json::Value = serde_json::from_slice(&message(PROTOCOL_VERSION)).unwrap();
+        old.as_object_mut().unwrap().remove("protocol_version");
+        let old = serde_json::to_vec(&old).unwrap();
+        let res = verify_handshake(&local, &old, fingerprint.clone(), WireFormat::Json, PROTOCOL_VERSION).await;
+        assert!(matches!(res, Err(HandshakeError::ProtocolVersionMismatch(id, 0)) if id == remote_id));
+
+        let res = verify_handshake(&local, &message(PROTOCOL_VERSION + 1), fingerprint, WireFormat::Json, PROTOCOL_VERSION).await;
+        assert!(matches!(res, Err(HandshakeError::ProtocolVersionMismatch(id, v)) if id == remote_id && v == PROTOCOL_VERSION + 1));
+    }
+
+    #[tokio::test]
+    async fn ed25519_proof_test() {
+        let (local, remote) = tokio::join!(Identity::generate(), Identity::generate_with(KeyAlgorithm::Ed25519));
+        let remote_id = remote.generate_id().await;
+        let fingerprint = vec![7u8; 32];
+        let proof = remote.create_proof(&fingerprint).await;
 
-    Ok(peer_id)
+        // A P-256 peer checks the proof of an Ed25519 peer
+        assert_eq!(local.check_identity_proof(remote.export_key(), &fingerprint, &proof).await, Ok(remote_id));
+        assert!(local.check_identity_proof(remote.export_key(), &[8u8; 32], &proof).await.is_err());
+    }
 }
@@ -1,15 +1,17 @@
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::HashMap,
     sync::{
         atomic::{AtomicU64, Ordering, AtomicBool},
-        Mutex,
-    }, time::Duration,
+        Arc, Mutex,
+    },
+    time::Duration,
 };
 
 use async_broadcast as broadcast;
+use instant::Instant;
 use broadcast::TrySendError;
 use tokio::sync::oneshot;
-use tracing::{debug, error, event, info, warn, Level};
+use tracing::{debug, event, info, warn, Level};
 use wdht_logic::{
     config::SystemConfig,
     transport::{TransportError, TransportListener},
@@ -17,24 +19,34 @@ use wdht_logic::{
 };
 use wdht_wasync::{spawn, Orc, Weak, sleep};
 use wdht_wrtc::{
-    create_channel, ConnectionRole, RtcConfig, SessionDescription, WrtcChannel, WrtcError,
+    create_channel, ConnectionRole, IceServer, RtcConfig, SessionDescription, WrtcChannel, WrtcError,
 };
 
-use crate::{TransportConfig, identity::Identity, events::{TransportEvent, DisconnectReason}};
+use crate::{ConfigError, TransportConfig, WireFormat, identity::Identity, events::{TransportEvent, DisconnectReason, PeerConnectedEvent, PeerRole}, reconnect::{routing_reconnector, BootstrapState}};
 
 use self::{
-    conn::WrtcConnection,
+    conn::{TrafficCounters, WrtcConnection},
     connector::{ContactResult, CreatingConnectionSender, WrtcConnector},
+    half_closed::HalfClosedSet,
+    protocol::WrtcRequest,
+    record::HandshakeRecord,
 };
 
 mod conn;
 mod connector;
 mod error;
+mod forward;
+mod framing;
+mod half_closed;
 mod handshake;
+mod outbound;
 mod protocol;
+pub mod record;
 mod sender;
 
+pub use conn::{ConnectionDebugState, ConnectionPriority, PeerQuality};
 pub use error::{WrtcTransportError, HandshakeError};
+pub use handshake::HandshakeInfo;
 pub use sender::{WrtcContact, WrtcSender};
 
 pub struct Connections {
@@ -48,18 +60,40 @@ pub struct Connections {
     connected_count: AtomicU64,
     // TODO: use some locking hashmap?
     pub connections: Mutex<HashMap<Id, Orc<WrtcConnection>>>,
-    half_closed_connections: Mutex<VecDeque<Id>>,
-    half_closed_count: AtomicU64,
+    pub(crate) half_closed_connections: Mutex<HalfClosedSet>,
     pub connector: Orc<WrtcConnector>,
     events_tx: broadcast::Sender<TransportEvent>,
+    bootstrap_state: Mutex<BootstrapState>,
+    pub(crate) traffic: Orc<TrafficCounters>,
+}
+
+/// Counters of the transport connections
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TransportMetrics {
+    // Allocated connections, connected or not
+    pub connection_count: u64,
+    pub connected_count: u64,
+    pub half_closed_count: u64,
+    // Connections still in the signaling or handshake phase
+    pub connecting_count: u64,
+    // Bytes of the messages sent and received by every connection
+    pub bytes_sent: u64,
+    pub bytes_recv: u64,
 }
 
 impl Connections {
-    pub async fn create(config: SystemConfig, tconfig: TransportConfig, events_tx: broadcast::Sender<TransportEvent>) -> Orc<KademliaDht<WrtcSender>> {
-        let identity = Identity::generate().await;
+    pub async fn create(
+        config: SystemConfig,
+        tconfig: TransportConfig,
+        identity: Identity,
+        events_tx: broadcast::Sender<TransportEvent>,
+    ) -> Result<Orc<KademliaDht<WrtcSender>>, ConfigError> {
+        tconfig.validate()?;
+        config.routing.validate()?;
         let id = identity.generate_id().await;
+        let connector = WrtcConnector::new(id, tconfig.max_connecting, tconfig.connecting_timeout, tconfig.connection_order);
 
-        Orc::new_cyclic(|weak_dht| {
+        Ok(Orc::new_cyclic(|weak_dht| {
             let connections = Orc::new(Connections {
                 dht: weak_dht.clone(),
                 self_id: id,
@@ -69,29 +103,35 @@ impl Connections {
                 connection_count: AtomicU64::new(0),
                 connected_count: AtomicU64::new(0),
                 connections: Mutex::new(HashMap::new()),
-                half_closed_connections: Mutex::new(VecDeque::new()),
-                half_closed_count: AtomicU64::new(0),
-                connector: Orc::new(WrtcConnector::new(id)),
-                events_tx
+                half_closed_connections: Mutex::new(HalfClosedSet::default()),
+                connector: Orc::new(connector),
+                events_tx,
+                bootstrap_state: Mutex::new(BootstrapState::Connecting { attempted: 0, connected: 0 }),
+                traffic: Orc::new(TrafficCounters::default()),
             });
             let sender = WrtcSender(connections);
 
-            KademliaDht::new(config, id, sender)
-        })
+            KademliaDht::new(config, id, sender).expect("Routing config already validated")
+        }))
     }
 
     async fn after_handshake(
         self: Orc<Self>,
         channel: WrtcChannel,
-        res: Result<Id, HandshakeError>,
+        res: Result<(Id, HandshakeInfo), HandshakeError>,
         conn_tx: CreatingConnectionSender,
+        role: PeerRole,
+        rtt_estimate: Option<Duration>,
     ) {
-        let id = match res {
+        let (id, info) = match res {
             Ok(x) => x,
             Err(e) => {
                 warn!("Handshake error {e}");
-                conn_tx.send(Err(WrtcTransportError::Handshake(e)));
+                conn_tx.send(Err(WrtcTransportError::Handshake(e.clone())));
                 self.connection_count.fetch_sub(1, Ordering::SeqCst);
+                if let HandshakeError::ProtocolVersionMismatch(id, _) = e {
+                    let _ = self.events_tx.broadcast(TransportEvent::Disconnect(id, DisconnectReason::ProtocolVersionMismatch)).await;
+                }
                 return;
             }
         };
@@ -100,9 +140,16 @@ impl Connections {
             self.connection_count.fetch_sub(1, Ordering::SeqCst);
             return;
         }
+        if !self.is_authorized(id, &info, conn_tx.is_bootstrap()) {
+            conn_tx.send(Err(WrtcTransportError::Unauthorized(id)));
+            self.connection_count.fetch_sub(1, Ordering::SeqCst);
+            // The channel is dropped on return, closing the connection
+            let _ = self.events_tx.broadcast(TransportEvent::Disconnect(id, DisconnectReason::BadBehavior)).await;
+            return;
+        }
         self.connected_count.fetch_add(1, Ordering::SeqCst);
         debug!("{} connected", id);
-        let connection = conn::WrtcConnection::new(id, channel, Orc::downgrade(&self));
+        let connection = conn::WrtcConnection::new(id, channel, info.wire_format, Orc::downgrade(&self));
 
         {
             let mut conns = self.connections.lock().unwrap();
@@ -116,16 +163,60 @@ impl Connections {
         }
         if let Some(x) = self.dht.upgrade() {
             // Inform the connection that it's used in the routing table
-            connection.set_dont_cleanup(x.on_connect(id));
+            if x.on_connect(id) {
+                connection.set_priority(ConnectionPriority::Routing);
+            } else if conn_tx.is_bootstrap() && self.config.pin_bootstrap {
+                connection.set_priority(ConnectionPriority::Bootstrap);
+            }
+            // Push the stored entries that the peer is now responsible for
+            let requests = x.take_pending_requests(id);
+            if !requests.is_empty() {
+                let connection = Orc::downgrade(&connection);
+                spawn(async move {
+                    for req in requests {
+                        let connection = match connection.upgrade() {
+                            Some(x) => x,
+                            None => break,
+                        };
+                        if let Err(e) = connection.send_request(WrtcRequest::Req(req)).await {
+                            debug!("Storage hand-off to {id} failed: {e}");
+                            break;
+                        }
+                    }
+                });
+            }
         }
         let connection = WrtcContact::Other(connection);
+        let was_bootstrap = conn_tx.is_bootstrap();
         conn_tx.send(Ok(connection.clone()));
         // Ignore channel closed errors
         let _ = self.events_tx.broadcast(TransportEvent::Connect(connection)).await;
+        let event = PeerConnectedEvent { id, role, was_bootstrap, rtt_estimate };
+        let _ = self.events_tx.broadcast(TransportEvent::PeerConnected(event)).await;
+    }
+
+    fn is_authorized(&self, id: Id, info: &HandshakeInfo, bootstrap: bool) -> bool {
+        if let (true, Some(trusted)) = (bootstrap, &self.config.trusted_bootstrap_ids) {
+            if !trusted.contains(&id) {
+                event!(Level::WARN, kad_id=%self.self_id, peer_id=%id, "Bootstrap node id is not trusted");
+                return false;
+            }
+        }
+        if let Some(authorize) = &self.config.authorize {
+            if !authorize(&id, info) {
+                event!(Level::INFO, kad_id=%self.self_id, peer_id=%id, "Peer rejected by authorization hook");
+                return false;
+            }
+        }
+        true
+    }
+
+    pub(crate) fn is_shutting_down(&self) -> bool {
+        self.is_shutting_down.load(Ordering::SeqCst)
     }
 
     fn alloc_connection(self: &Orc<Self>) -> bool {
-        if self.is_shutting_down.load(Ordering::SeqCst) {
+        if self.is_shutting_down() {
             return false;
         }
         let limit = match self.config.max_connections {
@@ -137,56 +228,46 @@ impl Connections {
         }
         .get();
 
-        let r = self
-            .connection_count
-            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |x| {
-                if x < limit {
-                    Some(x + 1)
-                } else {
-                    None
+        loop {
+            let r = self
+                .connection_count
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |x| {
+                    if x < limit {
+                        Some(x + 1)
+                    } else {
+                        None
+                    }
+                });
+
+            if r.is_ok() {
+                return true; // The connection permit is ours, wohoo!
+            }
+            // Connections are full, let's try to get an half-connection that
+            // we can close (the least important one).
+            let id = match self.half_closed_connections.lock().unwrap().pop_lowest() {
+                Some(x) => x,
+                None => {
+                    // We didn't get any permit even from the half-closed connections
+                    // In italian i might say "questa connessione non s'ha da fare"
+                    return false;
                 }
-            });
-
-        if r.is_ok() {
-            return true; // The connection permit is ours, wohoo!
-        }
-        // Connections are full, let's try to get an half-connection that
-        // we can close.
-        let r = self
-            .half_closed_count
-            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |x| {
-                if x > 0 {
-                    Some(x - 1)
-                } else {
-                    None
+            };
+            // We got an half-close connection that we can reuse
+            let conn = match self.connections.lock().unwrap().remove(&id) {
+                Some(x) => x,
+                None => {
+                    // A concurrent disconnection removed it first and released its permit
+                    debug!("Half-closed connection {id} already disconnected, retrying");
+                    continue;
                 }
-            });
-        if r.is_err() {
-            // We didn't get any permit even from the half-closed connections
-            // In italian i might say "questa connessione non s'ha da fare"
-            return false;
+            };
+            // Do not update the connection count, and don't even update the half-closed queue
+            // (we already took care of that)
+            self.connected_count.fetch_sub(1, Ordering::SeqCst);
+            self.on_disconnect(id, DisconnectReason::HalfCloseReplace, false);
+            conn.shutdown_local();
+            return true;
         }
-        // We got an half-close connection that we can reuse
-        let id = match self.half_closed_connections.lock().unwrap().pop_front() {
-            Some(x) => x,
-            None => {
-                error!("Half-closed connections and atomic counter out of sync");
-                return false;
-            }
-        };
-        let conn = match self.connections.lock().unwrap().remove(&id) {
-            Some(x) => x,
-            None => {
-                warn!("Half-closed connection was not present in connections!");
-                return true;
-            }
-        };
-        // Do not update the connection count, and don't even update the half-closed queue
-        // (we already took care of that)
-        self.connected_count.fetch_sub(1, Ordering::SeqCst);
-        self.on_disconnect(id, DisconnectReason::HalfCloseReplace, false, false);
-        conn.shutdown_local();
-        true
     }
 
     async fn create_channel_and_register(
@@ -195,15 +276,32 @@ impl Connections {
         answer_tx: oneshot::Sender<SessionDescription>,
         conn_tx: CreatingConnectionSender,
     ) {
-        let config = {
+        let (config, timeout, recorder) = {
             let this = match this.upgrade() {
                 Some(x) => x,
                 None => return,
             };
-            RtcConfig::new(&this.config.stun_servers)
+            let servers: Vec<_> = this.config.stun_servers.iter()
+                .map(IceServer::new)
+                .chain(this.config.ice_servers.iter().cloned())
+                .collect();
+            let config = RtcConfig::with_ice_servers(&servers)
+                .with_ip_preference(this.config.ip_preference)
+                .with_ice_transport_policy(this.config.ice_transport_policy)
+                .with_channel_priority(this.config.control_channel_priority);
+            (config, this.config.signaling_timeout, this.config.handshake_recorder.clone())
+        };
+        let record = recorder.as_ref().map(|_| Arc::new(Mutex::new(HandshakeRecord::default())));
+        let peer_role = match &role {
+            ConnectionRole::Active(_) => PeerRole::Active,
+            ConnectionRole::Passive(_) => PeerRole::Passive,
+        };
+        let (role, answer_tx) = match &record {
+            Some(x) => record::record_descriptions(role, answer_tx, x.clone()),
+            None => (role, answer_tx),
         };
         let channel = tokio::select! {
-            _ = sleep(Duration::from_secs(60)) => {
+            _ = sleep(timeout) => {
                 Err(TransportError::ConnectionLost.into())
             },
             channel = create_channel(&config, role, answer_tx) => channel,
@@ -216,8 +314,15 @@ impl Connections {
 
         match channel {
             Ok(mut channel) => {
-                let res = handshake::handshake(&mut channel, &this.identity).await;
-                this.after_handshake(channel, res, conn_tx).await;
+                let mut record = record.map(|x| x.lock().unwrap().clone());
+                // Both peers send their message at the same time, so the handshake takes about a round trip
+                let started = Instant::now();
+                let res = handshake::handshake(&mut channel, &this.identity, this.config.wire_format, record.as_mut()).await;
+                let rtt_estimate = started.elapsed();
+                if let (Some(recorder), Some(record)) = (recorder, record) {
+                    recorder(&record);
+                }
+                this.after_handshake(channel, res, conn_tx, peer_role, Some(rtt_estimate)).await;
             }
             Err(x) => {
                 this.connection_count.fetch_sub(1, Ordering::SeqCst);
@@ -232,7 +337,12 @@ impl Connections {
         id: Id,
         offer: SessionDescription,
     ) -> Result<(SessionDescription, broadcast::Receiver<ContactResult>), WrtcTransportError> {
-        let (conn_tx, conn_rx) = self.connector.create_passive(id);
+        let offer_size = serde_json::to_vec(&offer).map_or(usize::MAX, |x| x.len());
+        if offer_size > self.config.max_sdp_size {
+            info!("Cannot create passive connection: offer too large ({offer_size} bytes)");
+            return Err(WrtcTransportError::SdpTooLarge);
+        }
+        let (conn_tx, conn_rx) = self.connector.create_passive(id)?;
         let conn_tx = match conn_tx {
             Some(x) => x,
             None => return Err(WrtcTransportError::AlreadyConnecting),
@@ -309,7 +419,7 @@ impl Connections {
         WrtcTransportError,
     > {
         let (conn_tx, conn_rx) = match id {
-            Some(id) => match self.connector.create_active(id) {
+            Some(id) => match self.connector.create_active(id)? {
                 (Some(sender), chan) => (sender, chan),
                 (None, _) => return Err(WrtcTransportError::AlreadyConnecting),
             },
@@ -320,28 +430,73 @@ impl Connections {
         Ok((offer, answer_tx, conn_rx))
     }
 
-    fn on_disconnect(&self, peer_id: Id, reason: DisconnectReason, update_conn_count: bool, was_half_closed: bool) {
-        info!("{peer_id} disconnected (half_closed: {was_half_closed})");
-        self.connections.lock().unwrap().remove(&peer_id);
+    /// Like create_active with an unknown id, but the peer must be a trusted bootstrap node
+    pub async fn create_bootstrap(
+        self: Orc<Self>,
+    ) -> Result<
+        (
+            SessionDescription,
+            oneshot::Sender<Result<SessionDescription, WrtcTransportError>>,
+            broadcast::Receiver<ContactResult>,
+        ),
+        WrtcTransportError,
+    > {
+        let (conn_tx, conn_rx) = self.connector.create_bootstrap();
+        let (offer, answer_tx) = self.create_active_with_connector(conn_tx).await?;
+        Ok((offer, answer_tx, conn_rx))
+    }
+
+    /// Registers a channel opened outside of the built-in signaling.
+    ///
+    /// If trusted_id is provided the handshake is skipped and the peer is
+    /// trusted to be that id, otherwise a normal handshake is performed
+    /// (so the other side must also adopt the channel or run a handshake).
+    pub async fn adopt_connection(
+        self: Orc<Self>,
+        mut channel: WrtcChannel,
+        trusted_id: Option<Id>,
+    ) -> Result<WrtcContact, WrtcTransportError> {
+        if !self.alloc_connection() {
+            return Err(WrtcTransportError::ConnectionLimitReached);
+        }
+
+        let started = Instant::now();
+        let res = match trusted_id {
+            Some(id) => Ok((id, HandshakeInfo {
+                public_key: None,
+                certificate_fingerprint: channel.sender.remote_certificate_fingerprint().unwrap_or_default(),
+                // Nothing is negotiated without a handshake
+                wire_format: WireFormat::Json,
+                features: 0,
+            })),
+            None => handshake::handshake(&mut channel, &self.identity, self.config.wire_format, None).await,
+        };
+        let rtt_estimate = trusted_id.is_none().then(|| started.elapsed());
+        let (conn_tx, mut conn_rx) = self.connector.create_unknown();
+        self.after_handshake(channel, res, conn_tx, PeerRole::Adopted, rtt_estimate).await;
+
+        conn_rx.recv().await.map_err(|_| "Failed to receive adopted connection")?
+    }
+
+    /// Called after the connection has been removed from connections,
+    /// update_conn_count must only be set by whoever removed it
+    fn on_disconnect(&self, peer_id: Id, reason: DisconnectReason, update_conn_count: bool) {
+        info!("{peer_id} disconnected");
         if update_conn_count {
             self.connection_count.fetch_sub(1, Ordering::SeqCst);
             self.connected_count.fetch_sub(1, Ordering::SeqCst);
         }
 
-        if was_half_closed {
-            self.half_closed_count.fetch_sub(1, Ordering::SeqCst);
-            let mut half_closed_vec = self.half_closed_connections.lock().unwrap();
-            if let Some(index) = half_closed_vec.iter().position(|x| *x == peer_id) {
-                half_closed_vec.remove(index);
-            } else {
-                // Caller said that the closed connection was half_closed but it's not in the vec
-                // so it's a liar!!
-                warn!("webrtc connections, on_disconnect called but was_half_closed lied!");
-            }
-        }
+        // The connection might have already been removed by a concurrent alloc_connection
+        self.half_closed_connections.lock().unwrap().remove(&peer_id);
 
         if let Some(dht) = self.dht.upgrade() {
-            dht.on_disconnect(peer_id);
+            let wanted = dht.on_disconnect(peer_id);
+            let lost = matches!(reason, DisconnectReason::ConnectionLost | DisconnectReason::SendFail | DisconnectReason::TimeoutExpired);
+            if wanted && lost && self.config.auto_reconnect_routing && !self.is_shutting_down() {
+                debug!("Routing peer {peer_id} lost, reconnecting");
+                spawn(routing_reconnector(peer_id, self.config.reconnect_backoff.clone(), Orc::downgrade(&dht.transport().0)));
+            }
         }
         // Ignore channel closed errors
         if let Err(TrySendError::Full(_)) = self.events_tx.try_broadcast(TransportEvent::Disconnect(peer_id, reason)) {
@@ -349,10 +504,96 @@ impl Connections {
         }
     }
 
-    pub(crate) fn on_half_closed(&self, conn: Id) {
-        info!("{} half_closed", conn);
-        self.half_closed_connections.lock().unwrap().push_back(conn);
-        self.half_closed_count.fetch_add(1, Ordering::SeqCst);
+    pub(crate) async fn on_storage_evicted(&self, evicted: Vec<(Id, Id)>) {
+        for (topic, publisher) in evicted {
+            // Ignore channel closed errors
+            let _ = self.events_tx.broadcast(TransportEvent::StorageEvicted { topic, publisher }).await;
+        }
+    }
+
+    /// Current progress of the node joining the network,
+    /// once joined it reflects the current connections and routing table
+    pub fn bootstrap_state(&self) -> BootstrapState {
+        match *self.bootstrap_state.lock().unwrap() {
+            BootstrapState::Ready { .. } | BootstrapState::Degraded => {
+                let degraded = self.dht.upgrade().map_or(true, |x| x.is_routing_degraded());
+                if degraded {
+                    BootstrapState::Degraded
+                } else {
+                    BootstrapState::Ready { peers: self.connected_count.load(Ordering::SeqCst) }
+                }
+            },
+            x => x,
+        }
+    }
+
+    pub(crate) fn set_bootstrap_state(&self, state: BootstrapState) {
+        debug!("Bootstrap state: {state}");
+        *self.bootstrap_state.lock().unwrap() = state;
+    }
+
+    pub(crate) fn on_bootstrap_complete(&self, routing_size: u64) {
+        let peers = self.connected_count.load(Ordering::SeqCst);
+        self.set_bootstrap_state(BootstrapState::Ready { peers });
+        // Nobody reads the events before create_dht returns, don't wait for them
+        let event = TransportEvent::BootstrapComplete { peers, routing_size };
+        if let Err(TrySendError::Full(_)) = self.events_tx.try_broadcast(event) {
+            warn!("Event channel is full, dropping bootstrap event");
+        }
+    }
+
+    pub(crate) fn on_bootstrap_connected(&self) {
+        if let BootstrapState::Connecting { connected, .. } = &mut *self.bootstrap_state.lock().unwrap() {
+            *connected += 1;
+        }
+    }
+
+    /// Connections in the signaling or handshake phase (allocated but not yet connected)
+    pub fn connecting_count(&self) -> u64 {
+        self.connector.connecting_len() as u64
+    }
+
+    pub fn metrics(&self) -> TransportMetrics {
+        TransportMetrics {
+            connection_count: self.connection_count.load(Ordering::SeqCst),
+            connected_count: self.connected_count.load(Ordering::SeqCst),
+            half_closed_count: self.half_closed_connections.lock().unwrap().len() as u64,
+            connecting_count: self.connecting_count(),
+            bytes_sent: self.traffic.sent.load(Ordering::Relaxed),
+            bytes_recv: self.traffic.received.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn peer_quality(&self, id: Id) -> Option<PeerQuality> {
+        self.connections.lock().unwrap().get(&id).map(|x| x.quality())
+    }
+
+    pub(crate) fn on_half_closed(&self, conn: &Orc<conn::WrtcConnection>, priority: ConnectionPriority) {
+        let id = conn.peer_id;
+        info!("{} half_closed", id);
+        if !self.half_closed_connections.lock().unwrap().push(id, priority) {
+            return;
+        }
+        // Close the connection if the peer never half-closes it too
+        let grace = self.config.half_close_grace;
+        let conn = Orc::downgrade(conn);
+        spawn(async move {
+            sleep(grace).await;
+            let conn = match conn.upgrade() {
+                Some(x) => x,
+                None => return,
+            };
+            let parent = match conn.parent.upgrade() {
+                Some(x) => x,
+                None => return,
+            };
+            // The connection might have been reused or replaced in the meantime
+            let current = parent.connections.lock().unwrap().get(&id).map_or(false, |x| Orc::ptr_eq(x, &conn));
+            if current && parent.half_closed_connections.lock().unwrap().contains(&id) {
+                debug!("{id} did not half-close in time");
+                conn.shutdown(DisconnectReason::HalfCloseBoth);
+            }
+        });
     }
 
     pub fn shutdown(&self) {
@@ -361,7 +602,7 @@ impl Connections {
         }
         let drain: Vec<_> = self.connections.lock().unwrap().drain().map(|x| x.1).collect();
         for conn in drain {
-            self.on_disconnect(conn.peer_id, DisconnectReason::ShuttingDown, true, false);
+            self.on_disconnect(conn.peer_id, DisconnectReason::ShuttingDown, true);
             conn.shutdown_local();
         }
         let _ = self.events_tx.try_broadcast(TransportEvent::Shutdown);
@@ -373,3 +614,69 @@ impl Drop for Connections {
         self.shutdown();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{num::NonZeroU64, sync::Barrier, thread};
+
+    use reqwest::Url;
+    use wdht_logic::config::SystemConfig;
+
+    use crate::{create_dht, tests::open_channel_pair};
+
+    use super::*;
+
+    #[test_log::test(tokio::test(flavor = "multi_thread"))]
+    async fn alloc_disconnect_race() {
+        let config = SystemConfig::default();
+        let tconfig = TransportConfig {
+            max_connections: NonZeroU64::new(1),
+            ..Default::default()
+        };
+        let (a, _a_events) = create_dht(config.clone(), tconfig, vec![] as Vec<Url>).await.unwrap();
+        let conns = a.transport().0.clone();
+
+        for _ in 0..8 {
+            let (b, _b_events) = create_dht(config.clone(), TransportConfig::default(), vec![] as Vec<Url>).await.unwrap();
+            let (a_chan, b_chan) = open_channel_pair().await;
+            let (a_contact, b_contact) = tokio::join!(
+                a.transport().adopt_connection(a_chan, None),
+                b.transport().adopt_connection(b_chan, None),
+            );
+            let (_a_contact, _b_contact) = (a_contact.unwrap(), b_contact.unwrap());
+
+            // The only connection is half-closed, a new one can take its place
+            let conn = conns.connections.lock().unwrap()[&b.id()].clone();
+            conns.on_half_closed(&conn, ConnectionPriority::Transient);
+            assert_eq!(conns.metrics().half_closed_count, 1);
+
+            // The peer disconnects while the connection is being reused
+            let barrier = Barrier::new(2);
+            let runtime = tokio::runtime::Handle::current();
+            let allocated = thread::scope(|s| {
+                let alloc = s.spawn(|| {
+                    let _guard = runtime.enter();
+                    barrier.wait();
+                    conns.alloc_connection()
+                });
+                s.spawn(|| {
+                    let _guard = runtime.enter();
+                    barrier.wait();
+                    conn.shutdown(DisconnectReason::ConnectionLost);
+                });
+                alloc.join().unwrap()
+            });
+
+            // Whoever wins, the old connection is accounted for exactly once
+            assert!(allocated);
+            let metrics = conns.metrics();
+            assert_eq!(metrics.connection_count, 1);
+            assert_eq!(metrics.connected_count, 0);
+            assert_eq!(metrics.half_closed_count, 0);
+            assert!(conns.connections.lock().unwrap().is_empty());
+
+            // Release the permit taken by the test
+            conns.connection_count.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
@@ -0,0 +1,160 @@
+//! Flow control of the data sent on the control channel.
+//!
+//! Data channels accept any amount of data and buffer it until it's transmitted,
+//! if too much is buffered the SCTP send buffer overflows and the connection breaks.
+//! While the channel buffers more than the high watermark the fragments are kept
+//! in a bounded queue, it's flushed when the channel signals that its buffered
+//! amount is low again. Messages are either queued entirely or refused.
+use std::collections::VecDeque;
+
+use wdht_wrtc::{WrtcDataChannel, WrtcError};
+
+use super::framing::{framed_size, send_fragmented};
+
+/// Destination of the fragments (the data channel outside of tests)
+pub trait FrameSink {
+    fn buffered_amount(&self) -> usize;
+    fn send(&mut self, frame: &[u8]) -> Result<(), WrtcError>;
+}
+
+impl FrameSink for WrtcDataChannel {
+    fn buffered_amount(&self) -> usize {
+        WrtcDataChannel::buffered_amount(self)
+    }
+
+    fn send(&mut self, frame: &[u8]) -> Result<(), WrtcError> {
+        WrtcDataChannel::send(self, frame)
+    }
+}
+
+pub struct OutboundQueue {
+    frames: VecDeque<Vec<u8>>,
+    // Bytes in frames
+    pub queued: usize,
+    high_watermark: usize,
+    max_queued: usize,
+}
+
+impl OutboundQueue {
+    pub fn new(high_watermark: usize, max_queued: usize) -> Self {
+        OutboundQueue {
+            frames: VecDeque::new(),
+            queued: 0,
+            high_watermark,
+            max_queued,
+        }
+    }
+
+    /// Threshold under which the channel should signal that its buffered amount is low
+    pub fn low_watermark(&self) -> usize {
+        self.high_watermark / 2
+    }
+
+    fn is_congested(&self, sink: &impl FrameSink) -> bool {
+        sink.buffered_amount() > self.high_watermark
+    }
+
+    /// Sends data in fragments, the fragments that can't be sent yet are queued
+    pub fn send_message(
+        &mut self,
+        sink: &mut impl FrameSink,
+        data: &[u8],
+        max_fragment_size: usize,
+    ) -> Result<(), WrtcError> {
+        let size = framed_size(data, max_fragment_size);
+        if self.queued + size > self.max_queued {
+            return Err(WrtcError::DataChannelError("Send queue full".into()));
+        }
+        send_fragmented(data, max_fragment_size, |frame| {
+            if self.frames.is_empty() && !self.is_congested(sink) {
+                return sink.send(frame);
+            }
+            self.queued += frame.len();
+            self.frames.push_back(frame.to_vec());
+            Ok(())
+        })
+    }
+
+    /// Sends the queued fragments until the channel is congested again
+    pub fn flush(&mut self, sink: &mut impl FrameSink) -> Result<(), WrtcError> {
+        while !self.frames.is_empty() && !self.is_congested(sink) {
+            let frame = self.frames.pop_front().unwrap();
+            self.queued -= frame.len();
+            sink.send(&frame)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wrtc::framing::Reassembler;
+
+    // Channel that transmits only when asked to
+    #[derive(Default)]
+    struct TestSink {
+        buffered: VecDeque<Vec<u8>>,
+        transmitted: Vec<Vec<u8>>,
+    }
+
+    impl TestSink {
+        fn transmit(&mut self, count: usize) {
+            let count = count.min(self.buffered.len());
+            self.transmitted.extend(self.buffered.drain(..count));
+        }
+    }
+
+    impl FrameSink for TestSink {
+        fn buffered_amount(&self) -> usize {
+            self.buffered.iter().map(|x| x.len()).sum()
+        }
+
+        fn send(&mut self, frame: &[u8]) -> Result<(), WrtcError> {
+            self.buffered.push_back(frame.to_vec());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn no_message_lost() {
+        let mut sink = TestSink::default();
+        let mut queue = OutboundQueue::new(64, 256);
+        // Unfragmented messages start with '{' (as JSON messages do)
+        let messages: Vec<Vec<u8>> = (0..40u8)
+            .map(|x| [&[b'{'], &vec![x; 10 + x as usize][..]].concat())
+            .collect();
+
+        let mut refused = Vec::new();
+        for (i, msg) in messages.iter().enumerate() {
+            if queue.send_message(&mut sink, msg, 16).is_err() {
+                refused.push(i);
+            }
+            // The channel never buffers much more than the watermark
+            assert!(sink.buffered_amount() <= 64 + 16);
+            if i % 4 == 0 {
+                sink.transmit(3);
+                queue.flush(&mut sink).unwrap();
+            }
+        }
+        assert!(!refused.is_empty());
+        assert!(queue.queued <= 256);
+
+        while queue.queued > 0 || sink.buffered_amount() > 0 {
+            sink.transmit(2);
+            queue.flush(&mut sink).unwrap();
+        }
+
+        // Every accepted message arrives whole and in order
+        let mut reassembler = Reassembler::new(1024);
+        let received: Vec<_> = sink.transmitted.into_iter()
+            .filter_map(|x| reassembler.push(x).unwrap())
+            .collect();
+        let accepted: Vec<_> = messages.into_iter()
+            .enumerate()
+            .filter(|(i, _)| !refused.contains(i))
+            .map(|(_, x)| x)
+            .collect();
+        assert_eq!(received, accepted);
+    }
+}
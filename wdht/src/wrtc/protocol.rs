@@ -5,17 +5,32 @@ use wdht_logic::{
 };
 use wdht_wrtc::SessionDescription;
 
-use crate::serde::BytesOrB64;
+use crate::{config::WireFormat, serde::BytesOrB64};
 
 type WrtcOffer = SessionDescription;
 type WrtcAnswer = SessionDescription;
 
+/// Version of the protocol spoken on the control channel, peers only connect
+/// if their versions are equal (additive changes are announced with feature flags).
+/// Version 2 encodes the ids of JSON messages as base64 instead of hex
+pub const PROTOCOL_VERSION: u16 = 2;
+/// Optional features supported by this peer
+pub const PROTOCOL_FEATURES: u32 = 0;
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct HandshakeRequest<'a> {
     #[serde(borrow)]
     pub identity: BytesOrB64<'a>,
     #[serde(borrow)]
     pub proof: BytesOrB64<'a>,
+    // Format proposed for the following messages (missing in older peers)
+    #[serde(default)]
+    pub wire_format: WireFormat,
+    // Peers that don't send their version predate versioning (version 0)
+    #[serde(default)]
+    pub protocol_version: u16,
+    #[serde(default)]
+    pub features: u32,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -28,6 +43,8 @@ pub enum WrtcRequest {
     // without any consequences. The sender should still try to keep the connection open
     // to their best ability, but may still drop it (ex. to make space for new connections)
     HalfClose,
+    // Liveness check, the peer answers with Pong
+    Ping,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -35,6 +52,7 @@ pub enum WrtcResponse {
     Ans(RawResponse<Id>),
     ForwardAnswers(Vec<Result<WrtcAnswer, String>>),
     OkAnswer(Result<WrtcAnswer, String>),
+    Pong,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
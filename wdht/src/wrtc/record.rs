@@ -0,0 +1,115 @@
+//! Recording and replay of connection handshakes.
+//!
+//! With [`crate::TransportConfig::handshake_recorder`] set, every connection
+//! opened through signaling produces a [`HandshakeRecord`] with the exchanged
+//! descriptions and handshake messages. Records are plain serde data, so the
+//! ones captured in the field can be saved and replayed with [`replay_handshake`]
+//! to reproduce interop failures deterministically.
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+use wdht_logic::Id;
+use wdht_wasync::spawn;
+use wdht_wrtc::{ConnectionRole, SessionDescription};
+
+use crate::{config::WireFormat, identity::Identity};
+
+use super::{
+    handshake::{verify_handshake, HandshakeInfo},
+    protocol::{HandshakeRequest, PROTOCOL_VERSION},
+    HandshakeError, WrtcTransportError,
+};
+
+/// Everything exchanged while opening a connection, as seen by the local node
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct HandshakeRecord {
+    /// Session description sent to the peer (offer or answer)
+    pub local_description: Option<serde_json::Value>,
+    /// Session description received from the peer
+    pub remote_description: Option<serde_json::Value>,
+    /// Fingerprint of the local DTLS certificate
+    pub local_fingerprint: Vec<u8>,
+    /// Fingerprint of the peer's DTLS certificate
+    pub remote_fingerprint: Vec<u8>,
+    /// Handshake message sent to the peer
+    pub sent: Vec<u8>,
+    /// Handshake message received from the peer
+    pub received: Vec<u8>,
+}
+
+// Wraps the description channels of a connection, saving the descriptions that go through them
+pub(crate) fn record_descriptions(
+    role: ConnectionRole<WrtcTransportError>,
+    local_tx: oneshot::Sender<SessionDescription>,
+    record: Arc<Mutex<HandshakeRecord>>,
+) -> (ConnectionRole<WrtcTransportError>, oneshot::Sender<SessionDescription>) {
+    let role = match role {
+        ConnectionRole::Passive(offer) => {
+            record.lock().unwrap().remote_description = serde_json::to_value(&offer).ok();
+            ConnectionRole::Passive(offer)
+        }
+        ConnectionRole::Active(remote_rx) => {
+            let (tx, rx) = oneshot::channel();
+            let record = record.clone();
+            spawn(async move {
+                if let Ok(x) = remote_rx.await {
+                    if let Ok(desc) = &x {
+                        record.lock().unwrap().remote_description = serde_json::to_value(desc).ok();
+                    }
+                    let _ = tx.send(x);
+                }
+            });
+            ConnectionRole::Active(rx)
+        }
+    };
+
+    let (tx, rx) = oneshot::channel();
+    spawn(async move {
+        if let Ok(x) = rx.await {
+            record.lock().unwrap().local_description = serde_json::to_value(&x).ok();
+            let _ = local_tx.send(x);
+        }
+    });
+    (role, tx)
+}
+
+/// Replays the peer side of a recorded handshake.
+///
+/// The recorded descriptions must parse and the received handshake message
+/// must prove the peer identity, the derived peer id is returned.
+/// The identity is only used to run the checks, any identity gives the same result.
+pub async fn replay_handshake(record: &HandshakeRecord, identity: &Identity) -> Result<(Id, HandshakeInfo), HandshakeError> {
+    for desc in [&record.local_description, &record.remote_description].into_iter().flatten() {
+        serde_json::from_value::<SessionDescription>(desc.clone())?;
+    }
+    // The format and version proposed by the local node are in its handshake message
+    let (wire_format, protocol_version) = serde_json::from_slice::<HandshakeRequest>(&record.sent)
+        .map_or((WireFormat::Json, PROTOCOL_VERSION), |x| (x.wire_format, x.protocol_version));
+    verify_handshake(identity, &record.received, record.remote_fingerprint.clone(), wire_format, protocol_version).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn replay_fixture() {
+        let record: HandshakeRecord = serde_json::from_str(include_str!("fixtures/handshake.json")).unwrap();
+        let identity = Identity::generate().await;
+
+        let (id, info) = replay_handshake(&record, &identity).await.unwrap();
+        assert_eq!(id, Id::from_hex("394c28cd7846ad5853281c44e3f1a850ca4d7058"));
+        assert_eq!(info.certificate_fingerprint, record.remote_fingerprint);
+        // Recorded before the wire format negotiation
+        assert_eq!(info.wire_format, WireFormat::Json);
+
+        // The proof is bound to the peer's certificate
+        let mut tampered = record.clone();
+        tampered.remote_fingerprint = record.local_fingerprint.clone();
+        assert!(matches!(replay_handshake(&tampered, &identity).await, Err(HandshakeError::InvalidIdentity)));
+
+        tampered.received = b"{}".to_vec();
+        assert!(matches!(replay_handshake(&tampered, &identity).await, Err(HandshakeError::BadFormat)));
+    }
+}
@@ -1,25 +1,26 @@
 use core::future::Future;
 use std::{fmt::{Debug, Formatter}, sync::atomic::Ordering};
-use tracing::warn;
+use tracing::{debug, warn};
 use wdht_logic::{
-    transport::{Contact, RawResponse, Request, TransportError, TransportSender},
+    transport::{ConnectionBudget, Contact, RawResponse, RecordSignature, Request, TransportError, TransportSender},
     Id,
 };
-use wdht_wasync::Orc;
-use wdht_wrtc::RawConnection;
+use wdht_wasync::{spawn, Orc};
+use wdht_wrtc::{RawConnection, WrtcChannel};
 
-use crate::TransportConfig;
+use crate::{debug::TransportDebugState, signed, BootstrapState, Identity, TopicKey, TransportConfig};
 
 use super::{
-    conn::WrtcConnection,
+    conn::{ConnectionPriority, PeerQuality, WrtcConnection},
     protocol::{WrtcRequest, WrtcResponse},
-    Connections,
+    Connections, TransportMetrics, WrtcTransportError,
 };
 
 async fn resolve_nodes(
     referrer: Orc<WrtcConnection>,
     conn: Orc<Connections>,
     ids: Vec<Id>,
+    budget: ConnectionBudget,
 ) -> Result<Vec<WrtcContact>, TransportError> {
     // Collect old_contacts (contacts already known)
     // and contacts to query
@@ -44,8 +45,25 @@ async fn resolve_nodes(
         return Ok(old_contacts.into_iter().map(|x| x.unwrap()).collect());
     }
 
+    // Only connect to the nodes that fit in the budget, the placeholders
+    // left without a contact are removed below
+    let granted = budget.take(to_query.len());
+    if granted < to_query.len() {
+        debug!("Connection budget exhausted, skipping {} nodes", to_query.len() - granted);
+        to_query.truncate(granted);
+    }
+
+    let dht = conn.dht.clone();
     // Clone the connector Rc, so that we free the Connections Rc (and it gets GC correctly)
-    let new_contacts = conn.connector.clone().connect_all(conn, referrer, to_query).await;
+    let new_contacts = conn.connector.clone().connect_all(conn, referrer, to_query.clone()).await;
+
+    // Report failed connections, so that searches can avoid them for a while
+    if let Some(dht) = dht.upgrade() {
+        to_query.iter()
+            .zip(new_contacts.iter())
+            .filter(|(_, res)| res.is_err())
+            .for_each(|(id, _)| dht.report_failure(*id));
+    }
 
     // Piece back together old contacts and new contacts
     let mut new_contacts = new_contacts.into_iter();
@@ -71,11 +89,13 @@ async fn translate_response(
     contact: Orc<WrtcConnection>,
     conn: Orc<Connections>,
     res: RawResponse<Id>,
+    budget: ConnectionBudget,
 ) -> Result<RawResponse<WrtcContact>, TransportError> {
     use RawResponse::*;
     Ok(match res {
-        FoundNodes(nodes) => FoundNodes(resolve_nodes(contact, conn, nodes).await?),
+        FoundNodes(nodes) => FoundNodes(resolve_nodes(contact, conn, nodes, budget).await?),
         FoundData(x) => FoundData(x),
+        FoundDataAndNodes(x, nodes) => FoundDataAndNodes(x, resolve_nodes(contact, conn, nodes, budget).await?),
         Done => Done,
         Error => Error,
     })
@@ -90,7 +110,7 @@ impl WrtcSender {
     }
 
     pub fn half_closed_count(&self) -> u64 {
-        self.0.half_closed_count.load(Ordering::SeqCst)
+        self.0.half_closed_connections.lock().unwrap().len() as u64
     }
 
     pub fn connection_count(&self) -> u64 {
@@ -100,16 +120,84 @@ impl WrtcSender {
     pub fn connected_count(&self) -> u64 {
         self.0.connected_count.load(Ordering::SeqCst)
     }
+
+    pub fn connecting_count(&self) -> u64 {
+        self.0.connecting_count()
+    }
+
+    pub fn peer_quality(&self, id: Id) -> Option<PeerQuality> {
+        self.0.peer_quality(id)
+    }
+
+    pub fn metrics(&self) -> TransportMetrics {
+        self.0.metrics()
+    }
+
+    pub fn bootstrap_state(&self) -> BootstrapState {
+        self.0.bootstrap_state()
+    }
+
+    /// Snapshot of the connections, taken without blocking on their locks
+    pub fn debug_state(&self) -> TransportDebugState {
+        let connections = self.0.connections.try_lock()
+            .ok()
+            .map(|x| x.values().cloned().collect::<Vec<_>>());
+        let mut res = TransportDebugState {
+            connections_locked: connections.is_none(),
+            connections: connections.unwrap_or_default()
+                .iter()
+                .map(|x| x.debug_state())
+                .collect(),
+            connecting: self.0.connector.connecting_count(),
+            half_closed_locked: self.0.half_closed_connections.try_lock().is_err(),
+        };
+        res.connections.sort_by_key(|x| x.id);
+        res
+    }
+
+    /// Pins (or unpins) a connection so that it is reaped only after transient ones,
+    /// bootstrap and routing connections are left untouched. Returns false if id is not connected.
+    pub fn set_pinned(&self, id: Id, pinned: bool) -> bool {
+        let conn = match self.0.connections.lock().unwrap().get(&id) {
+            Some(x) => x.clone(),
+            None => return false,
+        };
+        if conn.priority().is_reapable() {
+            conn.set_priority(if pinned { ConnectionPriority::Pinned } else { ConnectionPriority::Transient });
+        }
+        true
+    }
+
+    pub fn connection_priority(&self, id: Id) -> Option<ConnectionPriority> {
+        self.0.connections.lock().unwrap().get(&id).map(|x| x.priority())
+    }
+
+    pub async fn adopt_connection(
+        &self,
+        channel: WrtcChannel,
+        trusted_id: Option<Id>,
+    ) -> Result<WrtcContact, WrtcTransportError> {
+        self.0.clone().adopt_connection(channel, trusted_id).await
+    }
 }
 
 impl TransportSender for WrtcSender {
-    fn ping(&self, _id: Id) {
-        // WebRTC automatically manages disconnections
+    fn ping(&self, id: Id) {
+        let conn = match self.0.connections.lock().unwrap().get(&id) {
+            Some(x) => x.clone(),
+            None => return,
+        };
+        // Dead connections are closed, removing them from the routing table
+        spawn(conn.ping(self.0.config.ping_timeout));
     }
 
     type Fut = impl Future<Output = Result<RawResponse<Self::Contact>, TransportError>>;
 
     fn send(&self, id: Id, msg: Request) -> Self::Fut {
+        self.send_with_budget(id, msg, ConnectionBudget::unlimited())
+    }
+
+    fn send_with_budget(&self, id: Id, msg: Request, budget: ConnectionBudget) -> Self::Fut {
         let root = self.0.clone();
         async move {
             let contact = root
@@ -123,7 +211,7 @@ impl TransportSender for WrtcSender {
             let res = contact.clone().send_request(WrtcRequest::Req(msg)).await;
 
             match res {
-                Ok(WrtcResponse::Ans(x)) => translate_response(contact, root, x).await,
+                Ok(WrtcResponse::Ans(x)) => translate_response(contact, root, x, budget).await,
                 Ok(_) => Err(TransportError::UnknownError("Invalid response".into())),
                 Err(x) => Err(x),
             }
@@ -146,9 +234,46 @@ impl TransportSender for WrtcSender {
         )
     }
 
+    fn shutdown(&self) {
+        self.0.shutdown();
+    }
+
+    fn connection_headroom(&self) -> Option<u32> {
+        let limit = self.0.config.max_connections?.get();
+        // Half-closed connections are replaced when the limit is reached
+        let free = limit.saturating_sub(self.connection_count()) + self.half_closed_count();
+        Some(free.min(u32::MAX as u64) as u32)
+    }
+
+    type VerifyFut = impl Future<Output = bool>;
+
+    fn verify_record(&self, topic: Id, lifetime: u32, data: &[u8], record: &RecordSignature) -> Self::VerifyFut {
+        let check = Verification::Record(lifetime, data.to_vec(), record.clone());
+        verify(self.0.identity.clone(), topic, check)
+    }
+
+    fn verify_topic_name(&self, topic: Id, name: &str) -> Self::VerifyFut {
+        verify(self.0.identity.clone(), topic, Verification::TopicName(name.to_owned()))
+    }
+
     type Contact = WrtcContact;
 }
 
+// Checks requested by the DHT, every check shares the same future type
+enum Verification {
+    Record(u32, Vec<u8>, RecordSignature),
+    TopicName(String),
+}
+
+async fn verify(identity: Identity, topic: Id, check: Verification) -> bool {
+    match check {
+        Verification::Record(lifetime, data, record) => {
+            signed::verify_record(&identity, topic, lifetime, &data, &record).await
+        }
+        Verification::TopicName(name) => matches!(TopicKey::hash(&name).await, Ok(x) if x.id() == topic),
+    }
+}
+
 #[derive(Clone)]
 pub enum WrtcContact {
     SelfId(Id),
@@ -1,12 +1,12 @@
-use std::{rc::Rc, time::Duration, cell::RefCell, num::NonZeroU64};
+use std::{rc::Rc, time::Duration, cell::{Cell, RefCell}, num::NonZeroU64};
 
-use js_sys::{Uint8Array, Array, Object, Reflect, Function};
+use futures::StreamExt;
+use js_sys::{Uint8Array, Array, Object, Reflect, Function, Date, Promise};
 use reqwest::Url;
 use tracing::warn;
 use wasm_bindgen::{prelude::*, JsCast};
-use wasm_bindgen_futures::{future_to_promise, spawn_local};
-use wdht::{create_dht, TransportConfig, events::TransportEvent, Dht, logic::{Id, config::SystemConfig, search::BasicSearchOptions, transport::{TopicEntry, Contact}, consts::ID_LEN}};
-use wdht_crypto::sha2_hash;
+use wasm_bindgen_futures::future_to_promise;
+use wdht::{wasync::{live_tasks, spawn}, create_dht, IceServer, TransportConfig, RawId, TopicKey, events::TransportEvent, metrics::MetricsCollector, Dht, logic::{Id, config::SystemConfig, consts::ID_LEN_BITS, search::{BasicSearchOptions, SearchCancel}, transport::{TopicEntry, Contact}}};
 use serde::Deserialize;
 
 // When the `wee_alloc` feature is enabled, use `wee_alloc` as the global
@@ -15,7 +15,6 @@ use serde::Deserialize;
 #[global_allocator]
 static ALLOC: wee_alloc::WeeAlloc = wee_alloc::WeeAlloc::INIT;
 
-const TOPIC_HASH_CONTEXT: &'static [u8] = b"wdht.topic";
 const DEFAULT_STUN_SERVERS: &'static [&'static str] = &["stun:stun.l.google.com:19302"];
 const DEFAULT_MAX_CONNECTIONS: Option<NonZeroU64> = NonZeroU64::new(128);
 
@@ -43,15 +42,25 @@ type Topic = string | {
 type BootstrapData = Array<string> | {
     wdht_servers: Array<string>,
     stun_servers?: Array<string>,
+    // TURN servers (or STUN servers with credentials)
+    ice_servers?: Array<{
+        urls: Array<string>,
+        username?: string,
+        credential?: string,
+    }>,
     max_connections?: number,
 };
 
 type InsertPromise = Promise<number>;
 type RemovePromise = Promise<number>;
+// abort() stops the search, the promise resolves with the entries found so far
 type QueryPromise = Promise<Array<{
     data: Uint8Array,
     publisher: string,
-}>>;
+}>> & { abort(): void };
+type QueryEntryListener = (entry: { data: Uint8Array, publisher: string }) => void;
+// Resolves with the number of entries found when the search completes
+type QueryStreamPromise = Promise<number> & { abort(): void };
 type ConnectToPromise = Promise<RTCPeerConnection>;
 interface ChannelOpenEvent {
     peer_id: string,
@@ -59,6 +68,58 @@ interface ChannelOpenEvent {
     connection: RTCPeerConnection,
 }
 type ChannelOpenListener = (event: ChannelOpenEvent) => void;
+interface BootstrapCompleteEvent {
+    // Peers connected when the node finished joining the network
+    peers: number,
+    routing_size: number,
+}
+// Called once, immediately if the bootstrap already completed
+type BootstrapCompleteListener = (event: BootstrapCompleteEvent) => void;
+interface PeerQuality {
+    rtt: number | null,
+    candidate_type: string | null,
+    connected_since: number,
+    messages_exchanged: number,
+}
+interface DhtStats {
+    connection_count: number,
+    raw_connection_count: number,
+    half_closed_count: number,
+    // Connections still in the signaling or handshake phase
+    connecting_count: number,
+    // Bytes of the DHT messages exchanged
+    bytes_sent: number,
+    bytes_recv: number,
+    // Progress of the node joining the network (ex. "ready (5 peers)")
+    bootstrap_state: string,
+    connects: number,
+    // Disconnection count by reason
+    disconnects: Record<string, number>,
+    lost_events: number,
+    // Only available with the task-count feature
+    active_tasks?: number,
+}
+// Best-effort snapshot to diagnose hangs, null fields were locked
+interface DebugState {
+    dht: {
+        tree_locked: boolean,
+        storage_locked: boolean,
+        active_searches: number,
+    },
+    transport: {
+        connections_locked: boolean,
+        connections: Array<{
+            id: string,
+            locked: boolean,
+            priority: "transient" | "pinned" | "bootstrap" | "routing" | null,
+            outstanding_requests: number | null,
+            queued_bytes: number | null,
+        }>,
+        connecting: number | null,
+        half_closed_locked: boolean,
+    },
+    live_tasks: number | null,
+}
 "#;
 
 #[wasm_bindgen]
@@ -78,23 +139,44 @@ extern "C" {
     #[wasm_bindgen(typescript_type = "QueryPromise")]
     pub type QueryPromise;
 
+    #[wasm_bindgen(typescript_type = "QueryEntryListener")]
+    pub type QueryEntryListener;
+
+    #[wasm_bindgen(typescript_type = "QueryStreamPromise")]
+    pub type QueryStreamPromise;
+
     #[wasm_bindgen(typescript_type = "ConnectToPromise")]
     pub type ConnectToPromise;
 
     #[wasm_bindgen(typescript_type = "ChannelOpenListener")]
     pub type ChannelOpenListener;
+
+    #[wasm_bindgen(typescript_type = "BootstrapCompleteListener")]
+    pub type BootstrapCompleteListener;
+
+    #[wasm_bindgen(typescript_type = "PeerQuality | undefined")]
+    pub type PeerQuality;
+
+    #[wasm_bindgen(typescript_type = "DhtStats")]
+    pub type DhtStats;
+
+    #[wasm_bindgen(typescript_type = "DebugState")]
+    pub type DebugState;
 }
 
 #[derive(Deserialize)]
 pub struct BootstrapDataJson {
     pub wdht_server: Vec<String>,
     pub stun_servers: Option<Vec<String>>,
+    #[serde(default)]
+    pub ice_servers: Vec<IceServer>,
     pub max_connections: Option<u64>,
 }
 
 pub struct BootstrapData {
     pub wdht_server: Vec<String>,
     pub stun_servers: Vec<String>,
+    pub ice_servers: Vec<IceServer>,
     pub max_connections: Option<NonZeroU64>,
 }
 
@@ -104,6 +186,7 @@ impl BootstrapData {
             Ok(x) => return Ok(BootstrapData {
                 wdht_server: x,
                 stun_servers: DEFAULT_STUN_SERVERS.iter().map(|&x| x.to_owned()).collect(),
+                ice_servers: Vec::new(),
                 max_connections: DEFAULT_MAX_CONNECTIONS,
             }),
             Err(_) => {}
@@ -113,6 +196,7 @@ impl BootstrapData {
         Ok(Self {
             wdht_server: raw.wdht_server,
             stun_servers: raw.stun_servers.unwrap_or_else(|| DEFAULT_STUN_SERVERS.iter().map(|&x| x.to_owned()).collect()),
+            ice_servers: raw.ice_servers,
             max_connections: match raw.max_connections {
                 Some(x) => NonZeroU64::new(x),
                 None => DEFAULT_MAX_CONNECTIONS,
@@ -125,6 +209,19 @@ impl BootstrapData {
 pub struct WebDht {
     kad: Rc<Dht>,
     channel_open_listener: Rc<RefCell<Option<Function>>>,
+    bootstrap_listener: Rc<RefCell<Option<Function>>>,
+    // Peers and routing table size, set once the bootstrap completes
+    bootstrap_complete: Rc<Cell<Option<(u64, u64)>>>,
+    metrics: MetricsCollector,
+}
+
+fn call_bootstrap_listener(listener: &Function, (peers, routing_size): (u64, u64)) {
+    let event = Object::new();
+    Reflect::set(&event, &"peers".into(), &(peers as f64).into()).unwrap();
+    Reflect::set(&event, &"routing_size".into(), &(routing_size as f64).into()).unwrap();
+    if let Err(x) = listener.call1(&JsValue::UNDEFINED, &event) {
+        warn!("bootstrap_complete handler returned error: {x:?}");
+    }
 }
 
 
@@ -138,17 +235,23 @@ impl WebDht {
         let mut tconfig: TransportConfig = Default::default();
         tconfig.max_connections = bootstrap.max_connections;
         tconfig.stun_servers = bootstrap.stun_servers;
+        tconfig.ice_servers = bootstrap.ice_servers;
 
         let bootstrap2: Vec<Url> = bootstrap.wdht_server.into_iter()
             .map(|x| x.parse())
             .collect::<Result<Vec<Url>, _>>()
             .map_err(|x| JsValue::from(format!("Invalid wdht bootstrap URL: {x}")))?;
 
-        let (kad, mut events_rx) = create_dht(config, tconfig, bootstrap2).await;
+        let (kad, mut events_rx) = create_dht(config, tconfig, bootstrap2).await
+            .map_err(|x| JsValue::from(format!("Invalid configuration: {x}")))?;
+        let metrics = MetricsCollector::spawn(events_rx.clone());
 
         let listener: Rc<RefCell<Option<Function>>> = Rc::new(RefCell::new(None));
         let chan_listener = listener.clone();
-        spawn_local(async move {
+        let bootstrap_listener: Rc<RefCell<Option<Function>>> = Rc::new(RefCell::new(None));
+        let bootstrap_complete = Rc::new(Cell::new(None));
+        let (boot_listener, boot_complete) = (bootstrap_listener.clone(), bootstrap_complete.clone());
+        spawn(async move {
             loop {
                 let ev = match events_rx.recv().await {
                     Ok(x) => x,
@@ -169,6 +272,12 @@ impl WebDht {
                             }
                         }
                     },
+                    TransportEvent::BootstrapComplete { peers, routing_size } => {
+                        boot_complete.set(Some((peers, routing_size)));
+                        if let Some(x) = boot_listener.borrow_mut().take() {
+                            call_bootstrap_listener(&x, (peers, routing_size));
+                        }
+                    },
                     _ => {},
                 }
             }
@@ -177,6 +286,9 @@ impl WebDht {
         Ok(WebDht {
             kad,
             channel_open_listener: listener,
+            bootstrap_listener,
+            bootstrap_complete,
+            metrics,
         })
     }
 
@@ -190,6 +302,43 @@ impl WebDht {
         self.kad.transport().connected_count() as u32
     }
 
+    #[wasm_bindgen(getter)]
+    pub fn connecting_count(&self) -> u32 {
+        self.kad.transport().connecting_count() as u32
+    }
+
+    pub fn stats(&self) -> DhtStats {
+        let transport = self.kad.transport();
+        let transport_metrics = transport.metrics();
+        let res = Object::new();
+        Reflect::set(&res, &"connection_count".into(), &(transport_metrics.connected_count as f64).into()).unwrap();
+        Reflect::set(&res, &"raw_connection_count".into(), &(transport_metrics.connection_count as f64).into()).unwrap();
+        Reflect::set(&res, &"half_closed_count".into(), &(transport_metrics.half_closed_count as f64).into()).unwrap();
+        Reflect::set(&res, &"connecting_count".into(), &(transport_metrics.connecting_count as f64).into()).unwrap();
+        Reflect::set(&res, &"bytes_sent".into(), &(transport_metrics.bytes_sent as f64).into()).unwrap();
+        Reflect::set(&res, &"bytes_recv".into(), &(transport_metrics.bytes_recv as f64).into()).unwrap();
+        Reflect::set(&res, &"bootstrap_state".into(), &transport.bootstrap_state().to_string().into()).unwrap();
+        let metrics = self.metrics.snapshot();
+        let disconnects = Object::new();
+        for (reason, count) in metrics.disconnects {
+            Reflect::set(&disconnects, &reason.to_string().into(), &(count as f64).into()).unwrap();
+        }
+        Reflect::set(&res, &"connects".into(), &(metrics.connects as f64).into()).unwrap();
+        Reflect::set(&res, &"disconnects".into(), &disconnects).unwrap();
+        Reflect::set(&res, &"lost_events".into(), &(metrics.lost_events as f64).into()).unwrap();
+        if let Some(x) = live_tasks() {
+            Reflect::set(&res, &"active_tasks".into(), &(x as f64).into()).unwrap();
+        }
+        res.unchecked_into()
+    }
+
+    pub fn debug_state(&self) -> DebugState {
+        let state = wdht::debug::debug_state(&self.kad);
+        JsValue::from_serde(&state)
+            .expect("Debug state is always serializable")
+            .unchecked_into()
+    }
+
     #[wasm_bindgen(getter)]
     pub fn id(&self) -> String {
         self.kad.id().as_short_hex()
@@ -222,16 +371,47 @@ impl WebDht {
 
     pub fn query(&self, topic: Topic, limit: u32) -> QueryPromise {
         let kad = self.kad.clone();
+        let cancel = SearchCancel::new();
+        let search_cancel = cancel.clone();
         let fut = async move {
             let key = parse_topic(topic).await?;
 
             let search_options = BasicSearchOptions {
                 parallelism: 4,
+                cancel: Some(search_cancel),
+                ..Default::default()
             };
 
             Ok(convert_entry_list(kad.query_value(key, limit, search_options).await).into())
         };
-        future_to_promise(fut).unchecked_into()
+        with_abort(future_to_promise(fut), cancel).unchecked_into()
+    }
+
+    /// Like query, but on_entry is called with each entry as soon as it's found
+    pub fn query_stream(&self, topic: Topic, limit: u32, on_entry: QueryEntryListener) -> QueryStreamPromise {
+        let kad = self.kad.clone();
+        let on_entry: Function = on_entry.unchecked_into();
+        let cancel = SearchCancel::new();
+        let search_cancel = cancel.clone();
+        let fut = async move {
+            let key = parse_topic(topic).await?;
+
+            let search_options = BasicSearchOptions {
+                parallelism: 4,
+                cancel: Some(search_cancel),
+                ..Default::default()
+            };
+
+            let entries = kad.query_value_stream(key, limit, search_options);
+            futures::pin_mut!(entries);
+            let mut count = 0u32;
+            while let Some(entry) = entries.next().await {
+                on_entry.call1(&JsValue::NULL, &convert_entry(entry))?;
+                count += 1;
+            }
+            Ok(count.into())
+        };
+        with_abort(future_to_promise(fut), cancel).unchecked_into()
     }
 
     pub fn connect_to(&self, key: String) -> ConnectToPromise {
@@ -241,25 +421,57 @@ impl WebDht {
 
             let search_options = BasicSearchOptions {
                 parallelism: 4,
+                ..Default::default()
             };
-            let res = kad.query_nodes(key, search_options).await;
-            if res.len() == 0 || res[0].id() != key {
-                Err("Cannot find node")?;
-            }
-            let conn = res[0].raw_connection();
+            let res = kad.query_nodes_with_distance(key, search_options).await;
+            // Only the node itself shares the whole id
+            let node = match res.into_iter().next() {
+                Some((distance, node)) if distance as usize == ID_LEN_BITS => node,
+                _ => Err("Cannot find node")?,
+            };
+            let conn = node.raw_connection();
             Ok(conn.ok_or("Cannot open connection to self")?.into())
         };
         future_to_promise(fut).unchecked_into()
     }
 
+    pub fn peer_quality(&self, key: String) -> Result<PeerQuality, JsValue> {
+        let key: Id = key.parse().map_err(|e| format!("Failed to convert id: {e}"))?;
+
+        let quality = match self.kad.transport().peer_quality(key) {
+            Some(x) => x,
+            None => return Ok(JsValue::UNDEFINED.unchecked_into()),
+        };
+        let rtt = quality.rtt.map_or(JsValue::NULL, |x| (x.as_secs_f64() * 1000.0).into());
+        let candidate_type = quality.candidate_type.map_or(JsValue::NULL, |x| x.into());
+        let connected_since = Date::now() - quality.connected_since.elapsed().as_secs_f64() * 1000.0;
+
+        let res = Object::new();
+        Reflect::set(&res, &"rtt".into(), &rtt).unwrap();
+        Reflect::set(&res, &"candidate_type".into(), &candidate_type).unwrap();
+        Reflect::set(&res, &"connected_since".into(), &connected_since.into()).unwrap();
+        Reflect::set(&res, &"messages_exchanged".into(), &(quality.messages_exchanged as f64).into()).unwrap();
+        Ok(res.unchecked_into())
+    }
+
     pub fn on_connection(&self, fun: Option<ChannelOpenListener>) {
         self.channel_open_listener.replace(fun.map(|x| x.unchecked_into()));
     }
+
+    pub fn on_bootstrap_complete(&self, fun: Option<BootstrapCompleteListener>) {
+        let fun: Option<Function> = fun.map(|x| x.unchecked_into());
+        match (fun, self.bootstrap_complete.get()) {
+            (Some(fun), Some(info)) => call_bootstrap_listener(&fun, info),
+            (fun, _) => {
+                self.bootstrap_listener.replace(fun);
+            },
+        }
+    }
 }
 
 async fn parse_topic(topic: Topic) -> Result<Id, JsValue> {
     if let Some(x) = topic.as_string() {
-        return Ok(hash_key(x).await?);
+        return Ok(hash_key(&x).await?.into());
     }
     if !topic.is_object() {
         return Err("Invalid topic type".into());
@@ -275,22 +487,22 @@ async fn parse_topic(topic: Topic) -> Result<Id, JsValue> {
     let key = get_or_invalid("key")?;
 
     let res = match ttype.as_str() {
-        "topic" => hash_key(key).await?,
-        "raw_id" => key.parse::<Id>().map_err(|x| format!("Failed to parse raw id: {}", x.to_string()))?,
+        "topic" => hash_key(&key).await?.into(),
+        "raw_id" => key.parse::<RawId>().map_err(|x| format!("Failed to parse raw id: {x}"))?.into(),
         _ => Err("Unrecognized topic type")?,
     };
     Ok(res)
 }
 
-async fn hash_key(key: String) -> Result<Id, &'static str> {
-    if key.is_empty() {
-        return Err("Key is empty");
-    }
-    let hash_data = sha2_hash(&TOPIC_HASH_CONTEXT, key.as_bytes()).await
-        .map_err(|_| "Cryptographic error")?;
-    let mut id = Id::ZERO;
-    id.0[..ID_LEN].copy_from_slice(&hash_data[..ID_LEN]);
-    Ok(id)
+async fn hash_key(key: &str) -> Result<TopicKey, String> {
+    TopicKey::hash(key).await.map_err(|e| e.to_string())
+}
+
+// Adds an abort() method cancelling the search to the promise
+fn with_abort(promise: Promise, cancel: SearchCancel) -> Promise {
+    let abort = Closure::wrap(Box::new(move || cancel.cancel()) as Box<dyn Fn()>);
+    Reflect::set(&promise, &"abort".into(), &abort.into_js_value()).unwrap();
+    promise
 }
 
 fn convert_entry_list(entries: Vec<TopicEntry>) -> Array {
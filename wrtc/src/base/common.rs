@@ -1,3 +1,5 @@
+use std::net::IpAddr;
+
 use tokio::sync::{
     mpsc,
     oneshot,
@@ -5,7 +7,7 @@ use tokio::sync::{
 use tracing::debug;
 use wdht_wasync::SenderExt;
 
-use crate::{Result, WrtcError, WrtcEvent};
+use crate::{IceServer, IpPreference, Result, WrtcError, WrtcEvent};
 
 pub struct ChannelHandler {
     ready_tx: Option<oneshot::Sender<Result<()>>>,
@@ -41,7 +43,7 @@ impl ChannelHandler {
 
     pub fn closed(&mut self) {
         debug!("Datachannel closed");
-        let _ = self.inbound_tx.maybe_spawn_send(Err(WrtcError::ConnectionLost));
+        let _ = self.inbound_tx.maybe_spawn_send(Err(WrtcError::ChannelClosed));
     }
 
     pub fn error(&mut self, err: String) {
@@ -58,4 +60,176 @@ impl ChannelHandler {
     pub fn message(&mut self, msg: Vec<u8>) {
         let _ = self.inbound_tx.maybe_spawn_send(Ok(WrtcEvent::Data(msg)));
     }
+
+    pub fn buffered_amount_low(&mut self) {
+        self.inbound_tx.maybe_spawn_send(Ok(WrtcEvent::BufferedAmountLow));
+    }
+}
+
+const CANDIDATE_PREFIX: &str = "a=candidate:";
+// Most significant bit of the candidate's local preference (see RFC 8445 5.1.2.1)
+const PREFERRED_FAMILY_BIT: u32 = 1 << 23;
+
+fn candidate_preference(line: &str, preference: IpPreference) -> Option<bool> {
+    let address: IpAddr = line.split_whitespace().nth(4)?.parse().ok()?;
+    Some(match preference {
+        IpPreference::None => return None,
+        IpPreference::Ipv4 => address.is_ipv4(),
+        IpPreference::Ipv6 => address.is_ipv6(),
+    })
+}
+
+fn rewrite_priority(line: &str, preferred: bool) -> String {
+    line.split(' ')
+        .enumerate()
+        .map(|(i, x)| match (i, x.parse::<u32>()) {
+            (3, Ok(priority)) if preferred => (priority | PREFERRED_FAMILY_BIT).to_string(),
+            (3, Ok(priority)) => (priority & !PREFERRED_FAMILY_BIT).to_string(),
+            _ => x.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// RTCIceServer list of the browser's RTCConfiguration
+#[cfg_attr(not(target_arch = "wasm32"), allow(dead_code))]
+pub fn ice_servers_json(servers: &[IceServer]) -> serde_json::Value {
+    serde_json::to_value(servers).expect("Failed to serialize ICE servers")
+}
+
+/// Urls in the libdatachannel format, where TURN credentials are part of the url
+/// (turn:username:credential@host:port)
+#[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+pub fn ice_server_urls(server: &IceServer) -> Vec<String> {
+    let (username, credential) = match (&server.username, &server.credential) {
+        (Some(u), Some(c)) => (u, c),
+        _ => return server.urls.clone(),
+    };
+    server.urls.iter()
+        .map(|url| match url.split_once(':') {
+            Some((scheme @ ("turn" | "turns"), rest)) if !rest.contains('@') => {
+                format!("{scheme}:{}:{}@{rest}", url_encode(username), url_encode(credential))
+            }
+            _ => url.clone(),
+        })
+        .collect()
+}
+
+fn url_encode(x: &str) -> String {
+    x.bytes()
+        .map(|b| match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+/// Reorders the SDP candidates putting the ones with the preferred address family first,
+/// their priority is also raised so that the remote peer will prefer them too.
+/// Candidates with unknown address (ex. mDNS) are not modified.
+pub fn apply_ip_preference(sdp: &str, preference: IpPreference) -> String {
+    if preference == IpPreference::None {
+        return sdp.to_string();
+    }
+    let lines: Vec<_> = sdp.split_inclusive('\n').collect();
+    let mut res = String::with_capacity(sdp.len());
+    let mut i = 0;
+    while i < lines.len() {
+        if !lines[i].starts_with(CANDIDATE_PREFIX) {
+            res.push_str(lines[i]);
+            i += 1;
+            continue;
+        }
+        // Reorder a contiguous block of candidates
+        let block_len = lines[i..].iter()
+            .take_while(|x| x.starts_with(CANDIDATE_PREFIX))
+            .count();
+        let mut block: Vec<_> = lines[i..i + block_len].iter()
+            .map(|line| {
+                let content = line.trim_end_matches(['\r', '\n']);
+                let ending = &line[content.len()..];
+                match candidate_preference(content, preference) {
+                    Some(preferred) => (preferred, rewrite_priority(content, preferred) + ending),
+                    None => (false, line.to_string()),
+                }
+            })
+            .collect();
+        // Stable sort, preferred candidates first
+        block.sort_by_key(|x| !x.0);
+        block.into_iter().for_each(|x| res.push_str(&x.1));
+        i += block_len;
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SDP: &str = "v=0\r\n\
+        m=application 9 UDP/DTLS/SCTP webrtc-datachannel\r\n\
+        a=candidate:1 1 UDP 2122317823 192.168.1.2 50000 typ host\r\n\
+        a=candidate:2 1 UDP 2122317823 fd00::2 50001 typ host\r\n\
+        a=candidate:3 1 UDP 2122317823 abcd.local 50002 typ host\r\n\
+        a=candidate:4 1 UDP 1686052607 2001:db8::1 50003 typ srflx\r\n\
+        a=end-of-candidates\r\n";
+
+    fn candidates(sdp: &str) -> Vec<(String, u32)> {
+        sdp.lines()
+            .filter(|x| x.starts_with(CANDIDATE_PREFIX))
+            .map(|x| {
+                let fields: Vec<_> = x.split(' ').collect();
+                (fields[0].to_string(), fields[3].parse().unwrap())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn ice_servers() {
+        let servers = [
+            IceServer::new("stun:stun.example.com"),
+            IceServer {
+                urls: vec!["turn:turn.example.com:3478".into(), "turns:turn.example.com?transport=tcp".into()],
+                ..Default::default()
+            }.with_credentials("user", "p@ss:word"),
+        ];
+
+        let json = ice_servers_json(&servers);
+        assert_eq!(json, serde_json::json!([
+            { "urls": ["stun:stun.example.com"] },
+            {
+                "urls": ["turn:turn.example.com:3478", "turns:turn.example.com?transport=tcp"],
+                "username": "user",
+                "credential": "p@ss:word",
+            },
+        ]));
+
+        assert_eq!(ice_server_urls(&servers[0]), ["stun:stun.example.com"]);
+        assert_eq!(ice_server_urls(&servers[1]), [
+            "turn:user:p%40ss%3Aword@turn.example.com:3478",
+            "turns:user:p%40ss%3Aword@turn.example.com?transport=tcp",
+        ]);
+    }
+
+    #[test]
+    fn ip_preference() {
+        assert_eq!(apply_ip_preference(SDP, IpPreference::None), SDP);
+
+        let res = apply_ip_preference(SDP, IpPreference::Ipv6);
+        assert!(res.starts_with("v=0\r\nm=application"));
+        assert!(res.ends_with("abcd.local 50002 typ host\r\na=end-of-candidates\r\n"));
+        let cand = candidates(&res);
+        let order: Vec<_> = cand.iter().map(|x| x.0.as_str()).collect();
+        assert_eq!(order, ["a=candidate:2", "a=candidate:4", "a=candidate:1", "a=candidate:3"]);
+        // IPv6 hosts are now preferred over IPv4 ones
+        assert!(cand[0].1 > cand[2].1);
+        // but candidate types still take precedence
+        assert!(cand[1].1 < cand[2].1);
+        // Unknown addresses are untouched
+        assert_eq!(cand[3].1, 2122317823);
+
+        let res = apply_ip_preference(SDP, IpPreference::Ipv4);
+        let order: Vec<_> = candidates(&res).into_iter().map(|x| x.0).collect();
+        assert_eq!(order, ["a=candidate:1", "a=candidate:2", "a=candidate:3", "a=candidate:4"]);
+    }
 }
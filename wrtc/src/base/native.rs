@@ -1,41 +1,35 @@
-use std::sync::{Arc, Mutex, Weak};
+use std::{fmt, sync::{Arc, Mutex, Weak}};
 
 use datachannel::{
     ConnectionState, DataChannelHandler, DataChannelInit, GatheringState, IceCandidate,
     PeerConnectionHandler, RtcConfig as InnerConfig, RtcDataChannel, RtcPeerConnection, SdpType,
-    SignalingState, sdp::attribute_type::SdpAttribute,
+    SignalingState, TransportPolicy, sdp::attribute_type::SdpAttribute,
 };
 use tokio::sync::{oneshot, mpsc};
 use tracing::{debug, error, info};
+use wdht_wasync::SenderExt;
 
-use super::common::ChannelHandler;
+use super::common::{apply_ip_preference, ice_server_urls, ChannelHandler};
 use crate::{
-    error::WrtcError, ConnectionRole, SessionDescription as WrappedSessionDescription, WrtcChannel,
-    WrtcDataChannel as WrappedWrtcDataChannel,
+    error::WrtcError, ChannelPriority, ConnectionRole, IceServer, IceTransportPolicy, IpPreference, SessionDescription as WrappedSessionDescription, WrtcChannel,
+    WrtcDataChannel as WrappedWrtcDataChannel, WrtcEvent,
 };
 
 use datachannel::SessionDescription as RawSessionDescription;
 
 pub type SessionDescription = Box<RawSessionDescription>;
-pub type RawConnection = ();// Not available on native!
 pub type RawChannel = ();// Not available on native!
 
 type Connection = Arc<Mutex<Box<RtcPeerConnection<ConnectionHandler>>>>;
-pub struct WrtcDataChannel {
-    // Only kept to keep the original connection from being deallocated
-    _peer_connection: Connection,
-    data_channel: Box<RtcDataChannel<ChannelHandler>>,
-}
 
-impl WrtcDataChannel {
-    pub fn send(&mut self, msg: &[u8]) -> Result<(), WrtcError> {
-        self.data_channel
-            .send(msg)
-            .map_err(|_| WrtcError::DataChannelError("runtime error".into()))
-    }
+/// Handle to the underlying peer connection.
+/// It does not keep the connection alive, once the channel is dropped every call fails.
+#[derive(Clone, Default)]
+pub struct RawConnection(Weak<Mutex<Box<RtcPeerConnection<ConnectionHandler>>>>);
 
-    pub fn raw_connection(&self) -> RawConnection {
-        ()
+impl RawConnection {
+    fn connection(&self) -> Result<Connection, WrtcError> {
+        self.0.upgrade().ok_or(WrtcError::ConnectionLost)
     }
 
     fn desc_to_fingerprint(desc: Option<RawSessionDescription>) -> Result<Vec<u8>, WrtcError> {
@@ -50,29 +44,119 @@ impl WrtcDataChannel {
             .map(|x| x.to_string().into_bytes())
     }
 
+    /// Fingerprint of the local DTLS certificate, as found in the local SDP
     pub fn local_certificate_fingerprint(&self) -> Result<Vec<u8>, WrtcError> {
-        let desc = self._peer_connection.lock()
+        let desc = self.connection()?.lock()
             .unwrap()
             .local_description();
         Self::desc_to_fingerprint(desc)
     }
 
+    /// Fingerprint of the remote DTLS certificate, as found in the remote SDP
     pub fn remote_certificate_fingerprint(&self) -> Result<Vec<u8>, WrtcError> {
-        let desc = self._peer_connection.lock()
+        let desc = self.connection()?.lock()
             .unwrap()
             .remote_description();
         Self::desc_to_fingerprint(desc)
     }
+
+    pub fn selected_candidate_type(&self) -> Option<String> {
+        let pair = self.connection().ok()?
+            .lock()
+            .unwrap()
+            .selected_candidate_pair()?;
+        // Candidate format: "candidate:<foundation> <component> <transport> <priority> <address> <port> typ <type> ..."
+        pair.local.split_whitespace()
+            .skip_while(|x| *x != "typ")
+            .nth(1)
+            .map(|x| x.to_owned())
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.0.strong_count() == 0
+    }
+}
+
+impl fmt::Debug for RawConnection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RawConnection")
+            .field("closed", &self.is_closed())
+            .finish()
+    }
+}
+type InboundSender = mpsc::Sender<Result<WrtcEvent, WrtcError>>;
+pub struct WrtcDataChannel {
+    // Only kept to keep the original connection from being deallocated
+    _peer_connection: Connection,
+    // None once closed
+    data_channel: Option<Box<RtcDataChannel<ChannelHandler>>>,
+    inbound_tx: InboundSender,
+}
+
+impl WrtcDataChannel {
+    pub fn send(&mut self, msg: &[u8]) -> Result<(), WrtcError> {
+        self.data_channel
+            .as_mut()
+            .ok_or(WrtcError::ChannelClosed)?
+            .send(msg)
+            .map_err(|_| WrtcError::DataChannelError("runtime error".into()))
+    }
+
+    pub fn raw_connection(&self) -> RawConnection {
+        RawConnection(Arc::downgrade(&self._peer_connection))
+    }
+
+    pub fn close(&mut self) {
+        // Dropping the channel closes it but its handler is dropped too
+        if self.data_channel.take().is_some() {
+            self.inbound_tx.maybe_spawn_send(Err(WrtcError::ChannelClosed));
+        }
+    }
+
+    pub fn local_certificate_fingerprint(&self) -> Result<Vec<u8>, WrtcError> {
+        self.raw_connection().local_certificate_fingerprint()
+    }
+
+    pub fn remote_certificate_fingerprint(&self) -> Result<Vec<u8>, WrtcError> {
+        self.raw_connection().remote_certificate_fingerprint()
+    }
+
+    pub fn selected_candidate_type(&self) -> Option<String> {
+        self.raw_connection().selected_candidate_type()
+    }
+
+    pub fn buffered_amount(&self) -> usize {
+        self.data_channel.as_ref().map_or(0, |x| x.buffered_amount())
+    }
+
+    pub fn set_buffered_amount_low_threshold(&mut self, threshold: usize) -> Result<(), WrtcError> {
+        self.data_channel
+            .as_mut()
+            .ok_or(WrtcError::ChannelClosed)?
+            .set_buffered_amount_low_threshold(threshold)
+            .map_err(|_| WrtcError::DataChannelError("runtime error".into()))
+    }
 }
 
 #[derive(Clone, Debug)]
-pub struct RtcConfig(InnerConfig);
+pub struct RtcConfig {
+    inner: InnerConfig,
+    pub ip_preference: IpPreference,
+    pub ice_transport_policy: IceTransportPolicy,
+    pub channel_priority: ChannelPriority,
+}
 
 impl RtcConfig {
-    pub fn new<S: AsRef<str>>(ice_servers: &[S]) -> Self {
-        let mut conf = InnerConfig::new(ice_servers);
+    pub fn new(ice_servers: &[IceServer]) -> Self {
+        let urls: Vec<_> = ice_servers.iter().flat_map(ice_server_urls).collect();
+        let mut conf = InnerConfig::new(&urls);
         conf.disable_auto_negotiation = true;
-        RtcConfig(conf)
+        RtcConfig {
+            inner: conf,
+            ip_preference: IpPreference::None,
+            ice_transport_policy: IceTransportPolicy::All,
+            channel_priority: ChannelPriority::Low,
+        }
     }
 }
 
@@ -85,9 +169,11 @@ where
     E: From<WrtcError>,
 {
     let (inbound_tx, inbound_rx) = mpsc::channel(16);
-    let (conn, state_rx) = create_connection(config, answer);
+    let (conn, state_rx) = create_connection(config, inbound_tx.clone(), answer);
 
-    let (ready, chan) = ChannelHandler::new(inbound_tx);
+    let (ready, chan) = ChannelHandler::new(inbound_tx.clone());
+    // libdatachannel doesn't expose stream priorities (config.channel_priority),
+    // the SCTP streams of a connection share the bandwidth in round-robin
     let dc_init = DataChannelInit::default()
         .negotiated()
         .manual_stream()
@@ -130,7 +216,8 @@ where
     Ok(WrtcChannel {
         sender: WrappedWrtcDataChannel(WrtcDataChannel {
             _peer_connection: conn,
-            data_channel: dc,
+            data_channel: Some(dc),
+            inbound_tx,
         }),
         listener: inbound_rx,
     })
@@ -140,17 +227,25 @@ where
 
 fn create_connection(
     config: &RtcConfig,
+    inbound_tx: InboundSender,
     signal_tx: oneshot::Sender<WrappedSessionDescription>,
 ) -> (Connection, oneshot::Receiver<bool>) {
     let (state_tx, state_rx) = oneshot::channel();
+    let mut inner = config.inner.clone();
+    inner.ice_transport_policy = match config.ice_transport_policy {
+        IceTransportPolicy::All => TransportPolicy::All,
+        IceTransportPolicy::Relay => TransportPolicy::Relay,
+    };
     let conn = Arc::new_cyclic(|parent| {
         Mutex::new(
             RtcPeerConnection::new(
-                &config.0,
+                &inner,
                 ConnectionHandler {
                     signal_tx: Some(signal_tx),
                     ready_tx: Some(state_tx),
+                    inbound_tx,
                     parent: parent.clone(),
+                    ip_preference: config.ip_preference,
                 },
             )
             .expect("Failed to create RtcPeerConnection"),
@@ -177,8 +272,9 @@ impl DataChannelHandler for ChannelHandler {
         self.message(msg.to_vec());
     }
 
-    // TODO: implement back-pressure
-    fn on_buffered_amount_low(&mut self) {}
+    fn on_buffered_amount_low(&mut self) {
+        self.buffered_amount_low();
+    }
 
     fn on_available(&mut self) {}
 }
@@ -186,7 +282,9 @@ impl DataChannelHandler for ChannelHandler {
 struct ConnectionHandler {
     signal_tx: Option<oneshot::Sender<WrappedSessionDescription>>,
     ready_tx: Option<oneshot::Sender<bool>>,
+    inbound_tx: InboundSender,
     parent: Weak<Mutex<Box<RtcPeerConnection<ConnectionHandler>>>>,
+    ip_preference: IpPreference,
 }
 
 impl PeerConnectionHandler for ConnectionHandler {
@@ -214,7 +312,16 @@ impl PeerConnectionHandler for ConnectionHandler {
             Connected => true,
             Disconnected | Failed | Closed => false,
         };
-        self.ready_tx.take().map(|x| x.send(is_successful));
+        match self.ready_tx.take() {
+            Some(x) => {
+                let _ = x.send(is_successful);
+            }
+            // Disconnected might still recover, the connection is lost only when it fails
+            None if matches!(state, Failed | Closed) => {
+                self.inbound_tx.maybe_spawn_send(Err(WrtcError::ConnectionLost));
+            }
+            None => {}
+        }
     }
 
     fn on_gathering_state_change(&mut self, state: GatheringState) {
@@ -228,13 +335,20 @@ impl PeerConnectionHandler for ConnectionHandler {
                 Some(x) => x,
                 None => return, // Connection closed
             };
-            let sess_desc = match par.lock().unwrap().local_description() {
+            let mut sess_desc = match par.lock().unwrap().local_description() {
                 Some(x) => x,
                 None => {
                     error!("Gathering complete but no local description provided");
                     return;
                 }
             };
+            if self.ip_preference != IpPreference::None {
+                let sdp = apply_ip_preference(&sess_desc.sdp.to_string(), self.ip_preference);
+                match datachannel::sdp::parse_sdp(&sdp, false) {
+                    Ok(x) => sess_desc.sdp = x,
+                    Err(e) => error!("Cannot apply IP preference to local description: {e}"),
+                }
+            }
 
             // Ignore if signal is not needed
             let _ = signal_listener.send(WrappedSessionDescription(Box::new(sess_desc)));
@@ -8,15 +8,15 @@ use wasm_bindgen_futures::JsFuture;
 use wdht_wasync::SenderExt;
 use web_sys::{
     MessageEvent, RtcConfiguration, RtcDataChannel, RtcDataChannelInit, RtcDataChannelType,
-    RtcIceConnectionState, RtcPeerConnection, RtcPeerConnectionIceEvent, RtcDataChannelEvent
+    RtcIceConnectionState, RtcIceTransportPolicy, RtcPeerConnection, RtcPeerConnectionIceEvent, RtcDataChannelEvent
 };
 
 use crate::{
     ConnectionRole, SessionDescription as WrappedSessionDescription, WrtcChannel,
-    WrtcDataChannel as WrappedWrtcDataChannel, WrtcError, WrtcEvent,
+    WrtcDataChannel as WrappedWrtcDataChannel, WrtcError, WrtcEvent, IpPreference, ChannelPriority, IceServer, IceTransportPolicy,
 };
 
-use super::common::ChannelHandler;
+use super::common::{apply_ip_preference, ice_servers_json, ChannelHandler};
 
 pub type SessionDescription = serde_json::Value;
 pub type RawConnection = RtcPeerConnection;
@@ -30,13 +30,19 @@ impl From<JsValue> for WrtcError {
 
 #[derive(Clone, Debug)]
 pub struct RtcConfig {
-    ice_servers: Vec<String>,
+    ice_servers: Vec<IceServer>,
+    pub ip_preference: IpPreference,
+    pub ice_transport_policy: IceTransportPolicy,
+    pub channel_priority: ChannelPriority,
 }
 
 impl RtcConfig {
-    pub fn new<S: AsRef<str>>(ice_servers: &[S]) -> Self {
+    pub fn new(ice_servers: &[IceServer]) -> Self {
         RtcConfig {
-            ice_servers: ice_servers.iter().map(|x| x.as_ref().to_string()).collect(),
+            ice_servers: ice_servers.to_vec(),
+            ip_preference: IpPreference::None,
+            ice_transport_policy: IceTransportPolicy::All,
+            channel_priority: ChannelPriority::Low,
         }
     }
 }
@@ -61,6 +67,10 @@ impl WrtcDataChannel {
         self.connection.connection.clone()
     }
 
+    pub fn close(&mut self) {
+        self.channel.channel.close();
+    }
+
     fn desc_to_fingerprint(desc: &str) -> Result<Vec<u8>, WrtcError> {
         let sdp = desc.as_bytes();
         // Find fingerprint position
@@ -81,11 +91,27 @@ impl WrtcDataChannel {
         Self::desc_to_fingerprint(&desc.sdp())
     }
 
+
     pub fn remote_certificate_fingerprint(&self) -> Result<Vec<u8>, WrtcError> {
         let desc = self.connection.connection.remote_description()
             .ok_or_else(|| WrtcError::RuntimeError("No remote description".into()))?;
         Self::desc_to_fingerprint(&desc.sdp())
     }
+
+    pub fn selected_candidate_type(&self) -> Option<String> {
+        // Only available through the (async) getStats API
+        None
+    }
+
+    pub fn buffered_amount(&self) -> usize {
+        self.channel.channel.buffered_amount() as usize
+    }
+
+    pub fn set_buffered_amount_low_threshold(&mut self, threshold: usize) -> Result<(), WrtcError> {
+        let threshold = threshold.try_into().unwrap_or(u32::MAX);
+        self.channel.channel.set_buffered_amount_low_threshold(threshold);
+        Ok(())
+    }
 }
 
 #[instrument(skip_all)]
@@ -99,7 +125,7 @@ where
 {
     let (inbound_tx, inbound_rx) = mpsc::channel(16);
     let (connection, con_ready_rx) = create_connection(config, inbound_tx.clone(), answer)?;
-    let (channel, chan_ready_rx) = create_data_channel(&connection.connection, inbound_tx);
+    let (channel, chan_ready_rx) = create_data_channel(&connection.connection, config.channel_priority, inbound_tx);
 
     let conn = &connection.connection;
     match role {
@@ -160,6 +186,7 @@ where
 #[allow(clippy::type_complexity)]
 fn create_data_channel(
     pc: &RtcPeerConnection,
+    priority: ChannelPriority,
     inbound_tx: mpsc::Sender<Result<WrtcEvent, WrtcError>>,
 ) -> (
     DataChannelHandler,
@@ -167,6 +194,8 @@ fn create_data_channel(
 ) {
     let mut dc_config = RtcDataChannelInit::new();
     dc_config.id(0).protocol("wrtc_json").negotiated(true);
+    // Not in web-sys, browsers that don't support it ignore the field
+    let _ = Reflect::set(&dc_config, &"priority".into(), &priority.as_str().into());
     let dc = pc.create_data_channel_with_data_channel_dict("wdht", &dc_config);
     dc.set_binary_type(RtcDataChannelType::Arraybuffer);
 
@@ -210,6 +239,12 @@ fn create_data_channel(
         handler.borrow_mut().closed();
     }) as Box<dyn Fn()>);
     dc.set_onclose(Some(onclose.as_ref().unchecked_ref()));
+
+    let handler = handler0.clone();
+    let onbufferedamountlow = Closure::wrap(Box::new(move || {
+        handler.borrow_mut().buffered_amount_low();
+    }) as Box<dyn Fn()>);
+    dc.set_onbufferedamountlow(Some(onbufferedamountlow.as_ref().unchecked_ref()));
     let handler = DataChannelHandler {
         channel: dc,
         _handler: handler0,
@@ -217,6 +252,7 @@ fn create_data_channel(
         _onerror: onerror,
         _onopen: onopen,
         _onclose: onclose,
+        _onbufferedamountlow: onbufferedamountlow,
     };
     (handler, ready_rx)
 }
@@ -228,6 +264,7 @@ struct DataChannelHandler {
     _onerror: Closure<dyn Fn(JsValue)>,
     _onopen: Closure<dyn Fn()>,
     _onclose: Closure<dyn Fn()>,
+    _onbufferedamountlow: Closure<dyn Fn()>,
 }
 
 impl Drop for DataChannelHandler {
@@ -236,26 +273,34 @@ impl Drop for DataChannelHandler {
         self.channel.set_onerror(None);
         self.channel.set_onopen(None);
         self.channel.set_onclose(None);
+        self.channel.set_onbufferedamountlow(None);
     }
 }
 
+fn rtc_configuration(config: &RtcConfig) -> RtcConfiguration {
+    let mut pc_config = RtcConfiguration::new();
+    if !config.ice_servers.is_empty() {
+        let val = ice_servers_json(&config.ice_servers);
+        pc_config.ice_servers(&JsValue::from_serde(&val).unwrap());
+    }
+    pc_config.ice_transport_policy(match config.ice_transport_policy {
+        IceTransportPolicy::All => RtcIceTransportPolicy::All,
+        IceTransportPolicy::Relay => RtcIceTransportPolicy::Relay,
+    });
+    pc_config
+}
+
 fn create_connection(
     config: &RtcConfig,
     inbound_tx: mpsc::Sender<Result<WrtcEvent, WrtcError>>,
     signal_tx: oneshot::Sender<WrappedSessionDescription>,
 ) -> Result<(ConnectionHandler, oneshot::Receiver<bool>), WrtcError> {
-    let mut pc_config = RtcConfiguration::new();
-    if !config.ice_servers.is_empty() {
-        let val = serde_json::json!([{
-            "urls": config.ice_servers
-        }]);
-        pc_config.ice_servers(&JsValue::from_serde(&val).unwrap());
-    }
-    let pc = RtcPeerConnection::new_with_configuration(&pc_config)?;
+    let pc = RtcPeerConnection::new_with_configuration(&rtc_configuration(config))?;
 
     let (ready_tx, ready_rx) = oneshot::channel();
     let ready_tx = RefCell::new(Some(ready_tx));
     let connection = pc.clone();
+    let lost_tx = inbound_tx.clone();
     let oniceconnectionstatechange = Closure::wrap(Box::new(move || {
         let state = connection.ice_connection_state();
         debug!("Connection state change: {state:?}");
@@ -266,11 +311,21 @@ fn create_connection(
             // new, checking, completed or anything else
             _ => return,
         };
-        ready_tx.borrow_mut().take().map(|x| x.send(is_successful));
+        match ready_tx.borrow_mut().take() {
+            Some(x) => {
+                let _ = x.send(is_successful);
+            }
+            // Disconnected might still recover, the connection is lost only when it fails
+            None if matches!(state, Failed | Closed) => {
+                lost_tx.maybe_spawn_send(Err(WrtcError::ConnectionLost));
+            }
+            None => {}
+        }
     }) as Box<dyn Fn()>);
     pc.set_oniceconnectionstatechange(Some(oniceconnectionstatechange.as_ref().unchecked_ref()));
 
     let connection = pc.clone();
+    let ip_preference = config.ip_preference;
     let signal_tx = RefCell::new(Some(signal_tx));
     let onicecandidate = Closure::wrap(Box::new(move |ev: RtcPeerConnectionIceEvent| {
         if ev.candidate().is_none() {
@@ -289,9 +344,14 @@ fn create_connection(
             };
 
             // Ignore if signal is not needed
-            let description = sess_desc
+            let mut description: serde_json::Value = sess_desc
                 .into_serde()
                 .expect("Cannot convert local description to json");
+            if let Some(sdp) = description.get_mut("sdp") {
+                if let Some(x) = sdp.as_str() {
+                    *sdp = apply_ip_preference(x, ip_preference).into();
+                }
+            }
             let _ = signal_listener.send(WrappedSessionDescription(description));
         }
     }) as Box<dyn Fn(RtcPeerConnectionIceEvent)>);
@@ -326,3 +386,29 @@ impl Drop for ConnectionHandler {
         self.connection.set_onicecandidate(None);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    wasm_bindgen_test::wasm_bindgen_test_configure!(run_in_browser);
+
+    #[wasm_bindgen_test]
+    fn ice_transport_policy() {
+        let policy = |config: &RtcConfig| {
+            Reflect::get(&rtc_configuration(config), &"iceTransportPolicy".into())
+                .unwrap()
+                .as_string()
+        };
+        let config = RtcConfig::new(&[IceServer::new("turn:turn.example.com").with_credentials("user", "pass")]);
+        assert_eq!(policy(&config).as_deref(), Some("all"));
+
+        let config = RtcConfig {
+            ice_transport_policy: IceTransportPolicy::Relay,
+            ..config
+        };
+        assert_eq!(policy(&config).as_deref(), Some(IceTransportPolicy::Relay.as_str()));
+    }
+}
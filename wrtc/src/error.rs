@@ -9,6 +9,8 @@ pub enum WrtcError {
     DataChannelError(Cow<'static, str>),
     #[error("Connection lost")]
     ConnectionLost,
+    #[error("Data channel closed")]
+    ChannelClosed,
     #[error("Singaling failed: {0}")]
     SignalingFailed(Cow<'static, str>),
     #[error("Invalid session description")]
@@ -2,7 +2,7 @@ mod base;
 mod error;
 
 pub use error::{Result, WrtcError};
-use serde::{Deserialize, Serialize};
+use serde::{de::Error as _, ser::Error as _, Deserialize, Deserializer, Serialize, Serializer};
 use tokio::sync::{mpsc, oneshot};
 
 pub use base::RawConnection;
@@ -10,10 +10,34 @@ pub use base::RawChannel;
 
 // Re-export things to make them prettier and consistent with base changes.
 
-#[derive(Serialize, Deserialize, Debug)]
-#[serde(transparent)]
+#[derive(Debug)]
 pub struct SessionDescription(base::SessionDescription);
 
+// Descriptions are kept as JSON in binary formats too, some backends
+// can only be (de)serialized through self-describing formats
+impl Serialize for SessionDescription {
+    fn serialize<S: Serializer>(&self, s: S) -> core::result::Result<S::Ok, S::Error> {
+        if s.is_human_readable() {
+            self.0.serialize(s)
+        } else {
+            let json = serde_json::to_string(&self.0).map_err(S::Error::custom)?;
+            s.serialize_str(&json)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for SessionDescription {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> core::result::Result<Self, D::Error> {
+        let desc = if d.is_human_readable() {
+            base::SessionDescription::deserialize(d)?
+        } else {
+            let json = String::deserialize(d)?;
+            serde_json::from_str(&json).map_err(D::Error::custom)?
+        };
+        Ok(SessionDescription(desc))
+    }
+}
+
 pub enum ConnectionRole<E: From<WrtcError>> {
     // Active: sends offer and awaits an answer
     Active(oneshot::Receiver<core::result::Result<SessionDescription, E>>),
@@ -24,6 +48,8 @@ pub enum ConnectionRole<E: From<WrtcError>> {
 pub enum WrtcEvent {
     Data(Vec<u8>),
     OpenChannel(RawChannel),
+    // The buffered amount dropped below the low threshold, more data can be sent
+    BufferedAmountLow,
 }
 
 impl WrtcEvent {
@@ -55,11 +81,18 @@ impl WrtcDataChannel {
         self.0.send(msg)
     }
 
-    // Use with caution! Not supported in native (for now)
+    // Use with caution! On native this is only a weak handle to the peer connection
     pub fn raw_connection(&self) -> RawConnection {
         self.0.raw_connection()
     }
 
+    // Closes only the data channel, the peer connection stays open until this is dropped.
+    // The listener receives ChannelClosed, ConnectionLost is only sent when the peer
+    // connection itself fails
+    pub fn close(&mut self) {
+        self.0.close()
+    }
+
     pub fn local_certificate_fingerprint(&self) -> Result<Vec<u8>> {
         self.0.local_certificate_fingerprint()
     }
@@ -67,15 +100,136 @@ impl WrtcDataChannel {
     pub fn remote_certificate_fingerprint(&self) -> Result<Vec<u8>> {
         self.0.remote_certificate_fingerprint()
     }
+
+    // Type of the selected local ICE candidate (host, srflx, prflx, relay), if known
+    pub fn selected_candidate_type(&self) -> Option<String> {
+        self.0.selected_candidate_type()
+    }
+
+    // Bytes queued by send but not yet transmitted
+    pub fn buffered_amount(&self) -> usize {
+        self.0.buffered_amount()
+    }
+
+    // BufferedAmountLow is sent to the listener when the buffered amount drops to (or below) threshold
+    pub fn set_buffered_amount_low_threshold(&mut self, threshold: usize) -> Result<()> {
+        self.0.set_buffered_amount_low_threshold(threshold)
+    }
+}
+
+/// Address family preferred when choosing ICE candidates
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IpPreference {
+    #[default]
+    None,
+    Ipv4,
+    Ipv6,
+}
+
+/// ICE candidates used to connect (RTCIceTransportPolicy).
+///
+/// With [`IceTransportPolicy::Relay`] the host and STUN candidates are suppressed,
+/// so connections only succeed through a TURN server (with credentials, see
+/// [`IceServer::with_credentials`]), this is useful to test NAT traversal.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IceTransportPolicy {
+    #[default]
+    All,
+    Relay,
+}
+
+impl IceTransportPolicy {
+    /// Name used by the WebRTC API
+    pub fn as_str(self) -> &'static str {
+        match self {
+            IceTransportPolicy::All => "all",
+            IceTransportPolicy::Relay => "relay",
+        }
+    }
+}
+
+/// STUN or TURN server used to gather the ICE candidates
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IceServer {
+    pub urls: Vec<String>,
+    // Credentials, only used by TURN servers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub credential: Option<String>,
+}
+
+impl IceServer {
+    /// Server without credentials (ex. STUN)
+    pub fn new(url: impl Into<String>) -> Self {
+        IceServer {
+            urls: vec![url.into()],
+            ..Default::default()
+        }
+    }
+
+    pub fn with_credentials(mut self, username: impl Into<String>, credential: impl Into<String>) -> Self {
+        self.username = Some(username.into());
+        self.credential = Some(credential.into());
+        self
+    }
+}
+
+/// Priority of a data channel when the peer connection is congested
+/// (RTCPriorityType), higher priority channels get a larger share of the bandwidth.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChannelPriority {
+    VeryLow,
+    #[default]
+    Low,
+    Medium,
+    High,
+}
+
+impl ChannelPriority {
+    /// Name used by the WebRTC API
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ChannelPriority::VeryLow => "very-low",
+            ChannelPriority::Low => "low",
+            ChannelPriority::Medium => "medium",
+            ChannelPriority::High => "high",
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct RtcConfig(base::RtcConfig);
 
 impl RtcConfig {
+    /// Config using STUN servers only (no credentials)
     pub fn new<S: AsRef<str>>(ice_servers: &[S]) -> Self {
+        let servers: Vec<_> = ice_servers.iter().map(|x| IceServer::new(x.as_ref())).collect();
+        Self::with_ice_servers(&servers)
+    }
+
+    pub fn with_ice_servers(ice_servers: &[IceServer]) -> Self {
         RtcConfig(base::RtcConfig::new(ice_servers))
     }
+
+    pub fn with_ip_preference(mut self, preference: IpPreference) -> Self {
+        self.0.ip_preference = preference;
+        self
+    }
+
+    pub fn with_ice_transport_policy(mut self, policy: IceTransportPolicy) -> Self {
+        self.0.ice_transport_policy = policy;
+        self
+    }
+
+    /// Priority of the channel created with the connection, where supported
+    pub fn with_channel_priority(mut self, priority: ChannelPriority) -> Self {
+        self.0.channel_priority = priority;
+        self
+    }
 }
 
 pub async fn create_channel<E>(